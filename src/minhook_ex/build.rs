@@ -0,0 +1,28 @@
+/// Compile the instruction length decoder of the library for the tests
+/// comparing the `decoder` module against it, if its sources are present.
+/// It is only linked into the integration tests.
+fn main() {
+    use std::{env, path::Path};
+    const HDE_SOURCE: &str = "../minhook_ex_sys/minhook/src/hde/hde64.c";
+
+    println!("cargo:rustc-check-cfg=cfg(hde64)");
+    println!("cargo:rerun-if-changed=../minhook_ex_sys");
+    if env::var("CARGO_CFG_TARGET_ARCH").unwrap() != "x86_64" {
+        return;
+    }
+    if !Path::new(HDE_SOURCE).exists() {
+        println!("cargo:warning={} not found, the decoder is not compared against HDE",
+            HDE_SOURCE);
+        return;
+    }
+
+    let objects = cc::Build::new()
+        .file(HDE_SOURCE)
+        .include("../minhook_ex_sys/minhook/src/hde/")
+        .cargo_metadata(false)
+        .compile_intermediates();
+    for object in objects {
+        println!("cargo:rustc-link-arg-tests={}", object.display());
+    }
+    println!("cargo:rustc-cfg=hde64");
+}
@@ -0,0 +1,598 @@
+//! Executable memory within reach of given addresses.
+//!
+//! Relative jumps and calls only reach so far, so code placed next to
+//! a function has to be allocated near it, which is what the library's
+//! `buffer.c` does for trampolines. [`NearAllocator`] does the same for
+//! any generated code: it maps regions of [`REGION_SIZE`] bytes near the
+//! requested address, carves them into slots of a fixed size, and unmaps
+//! a region once all of its slots are free again.
+//!
+//! Free address space is found through `/proc/self/maps` on Linux, and
+//! by walking it with `VirtualQuery` on Windows.
+
+use std::sync::{Mutex, PoisonError};
+
+/// Size and alignment of a mapped region, which is the
+/// allocation granularity of `VirtualAlloc` on Windows.
+pub const REGION_SIZE: usize = 0x10000;
+
+/// Allocator of fixed-size slots of executable memory near given addresses.
+///
+/// Slots are readable, writable and executable. Regions which still
+/// have slots in use when the allocator is dropped stay mapped.
+#[derive(Debug)]
+pub struct NearAllocator {
+    slot_size: usize,
+    max_distance: usize,
+    regions: Vec<Region>,
+}
+
+/// A mapped region carved into slots.
+#[derive(Debug)]
+struct Region {
+    address: usize,
+    free: Vec<usize>,
+}
+
+impl Region {
+    fn contains(&self, address: usize) -> bool {
+        (self.address..self.address + REGION_SIZE).contains(&address)
+    }
+}
+
+impl NearAllocator {
+    /// Create an allocator without any regions.
+    ///
+    /// # Arguments
+    ///
+    /// * `slot_size` - size and alignment of each slot, which has
+    ///     to be a power of two no larger than [`REGION_SIZE`].
+    /// * `max_distance` - maximum distance of any byte of a slot
+    ///     from the address it is allocated near.
+    pub const fn new(slot_size: usize, max_distance: usize) -> Self {
+        assert!(slot_size.is_power_of_two() && slot_size <= REGION_SIZE);
+        Self { slot_size, max_distance, regions: Vec::new() }
+    }
+
+    /// Get the size of each slot.
+    pub fn slot_size(&self) -> usize {
+        self.slot_size
+    }
+
+    /// Get the maximum distance of slots from the addresses they are allocated near.
+    pub fn max_distance(&self) -> usize {
+        self.max_distance
+    }
+
+    /// Get the addresses of all mapped regions.
+    pub fn regions(&self) -> impl Iterator<Item = usize> + '_ {
+        self.regions.iter().map(|region| region.address)
+    }
+
+    /// Check whether an address is within a slot of this allocator.
+    pub fn contains(&self, address: usize) -> bool {
+        self.regions.iter().any(|region| region.contains(address))
+    }
+
+    /// Take a free slot within reach of an address, mapping a new region
+    /// if needed. Returns `None` if there is no free address space nearby.
+    pub fn allocate(&mut self, near: usize) -> Option<usize> {
+        let reachable = |region: &Region| {
+            region.address.abs_diff(near) <= self.max_distance
+                && (region.address + REGION_SIZE).abs_diff(near) <= self.max_distance
+        };
+        let index = match self.regions.iter().position(|region| {
+            reachable(region) && !region.free.is_empty()
+        }) {
+            Some(index) => index,
+            None => {
+                let distance = self.max_distance.checked_sub(REGION_SIZE)?;
+                let address = sys::map_near(near, REGION_SIZE, distance)?;
+                let free = (0..REGION_SIZE / self.slot_size).rev()
+                    .map(|index| address + index * self.slot_size)
+                    .collect();
+                self.regions.push(Region { address, free });
+                self.regions.len() - 1
+            }
+        };
+        self.regions[index].free.pop()
+    }
+
+    /// Return a slot, unmapping its region once all of its slots are free.
+    /// Returns `false` if the slot doesn't belong to this allocator.
+    ///
+    /// # Safety
+    ///
+    /// The slot must not be used anymore, including by code still
+    /// running in it, and must not be freed twice.
+    pub unsafe fn free(&mut self, slot: usize) -> bool {
+        let Some(index) = self.regions.iter().position(|region| region.contains(slot)) else {
+            return false;
+        };
+        let region = &mut self.regions[index];
+        region.free.push(slot & !(self.slot_size - 1));
+        if region.free.len() == REGION_SIZE / self.slot_size {
+            let region = self.regions.swap_remove(index);
+            sys::unmap(region.address, REGION_SIZE);
+        }
+        true
+    }
+
+    /// Unmap all regions at once, regardless of slots in use.
+    ///
+    /// # Safety
+    ///
+    /// No slot may be used anymore.
+    pub unsafe fn clear(&mut self) {
+        for region in self.regions.drain(..) {
+            sys::unmap(region.address, REGION_SIZE);
+        }
+    }
+}
+
+/// Check whether an address is in committed executable memory.
+pub(crate) fn is_executable(address: usize) -> bool {
+    sys::is_executable(address)
+}
+
+/// Get the number of bytes which can be read starting at an address.
+pub(crate) fn readable_len(address: usize) -> usize {
+    sys::readable_len(address)
+}
+
+/// Held while pages are writable for [`with_writable`], so that one write
+/// doesn't take away the access of another to the same page before it is done.
+static WRITABLE: Mutex<()> = Mutex::new(());
+
+/// Run `write` while the pages of `len` bytes at an address are writable,
+/// restoring the protection of each afterwards. Returns `None` if the
+/// protection can't be changed, without running `write`, or if it can't
+/// be restored after running it.
+pub(crate) unsafe fn with_writable<R>(address: usize, len: usize, write: impl FnOnce() -> R)
+    -> Option<R>
+{
+    let _lock = WRITABLE.lock().unwrap_or_else(PoisonError::into_inner);
+    let changed = sys::make_writable(address, len)?;
+    let result = write();
+    sys::restore_protection(&changed).then_some(result)
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::ffi::{c_int, c_void};
+
+    use super::maps;
+    pub(super) use super::maps::{is_executable, map_near, readable_len};
+
+    /// Page made writable, and its previous protection.
+    pub(super) type Changed = (usize, c_int);
+
+    pub(super) unsafe fn make_writable(address: usize, len: usize) -> Option<Vec<Changed>> {
+        let page_size = maps::page_size();
+        let start = address & !(page_size - 1);
+        let end = (address + len).next_multiple_of(page_size);
+
+        // Pages may differ in protection, so each one gets its own back.
+        let mut changed = Vec::new();
+        for page in (start..end).step_by(page_size) {
+            let protection = maps::protection(page).filter(|&protection| {
+                libc::mprotect(page as *mut c_void, page_size, protection | libc::PROT_WRITE) == 0
+            });
+            let Some(protection) = protection else {
+                restore_protection(&changed);
+                return None;
+            };
+            changed.push((page, protection));
+        }
+        Some(changed)
+    }
+
+    pub(super) unsafe fn restore_protection(changed: &[Changed]) -> bool {
+        let page_size = maps::page_size();
+        changed.iter().fold(true, |restored, &(page, protection)| {
+            libc::mprotect(page as *mut c_void, page_size, protection) == 0 && restored
+        })
+    }
+
+    pub(super) unsafe fn unmap(address: usize, size: usize) {
+        libc::munmap(address as *mut c_void, size);
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use std::ffi::c_void;
+
+    const MEM_COMMIT: u32 = 0x1000;
+    const MEM_RESERVE: u32 = 0x2000;
+    const MEM_RELEASE: u32 = 0x8000;
+    const MEM_FREE: u32 = 0x10000;
+    const PAGE_READONLY: u32 = 0x02;
+    const PAGE_READWRITE: u32 = 0x04;
+    const PAGE_WRITECOPY: u32 = 0x08;
+    const PAGE_EXECUTE: u32 = 0x10;
+    const PAGE_EXECUTE_READ: u32 = 0x20;
+    const PAGE_EXECUTE_READWRITE: u32 = 0x40;
+    const PAGE_EXECUTE_WRITECOPY: u32 = 0x80;
+    const PAGE_GUARD: u32 = 0x100;
+
+    const PAGE_EXECUTE_ANY: u32 =
+        PAGE_EXECUTE | PAGE_EXECUTE_READ | PAGE_EXECUTE_READWRITE | PAGE_EXECUTE_WRITECOPY;
+    const PAGE_READ_ANY: u32 = PAGE_READONLY | PAGE_READWRITE | PAGE_WRITECOPY
+        | PAGE_EXECUTE_READ | PAGE_EXECUTE_READWRITE | PAGE_EXECUTE_WRITECOPY;
+
+    #[repr(C)]
+    struct MemoryBasicInformation {
+        base_address: *mut c_void,
+        allocation_base: *mut c_void,
+        allocation_protect: u32,
+        #[cfg(target_pointer_width = "64")]
+        partition_id: u16,
+        region_size: usize,
+        state: u32,
+        protect: u32,
+        kind: u32,
+    }
+
+    #[repr(C)]
+    struct SystemInfo {
+        processor_architecture: u16,
+        reserved: u16,
+        page_size: u32,
+        minimum_application_address: *mut c_void,
+        maximum_application_address: *mut c_void,
+        active_processor_mask: usize,
+        number_of_processors: u32,
+        processor_type: u32,
+        allocation_granularity: u32,
+        processor_level: u16,
+        processor_revision: u16,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetSystemInfo(info: *mut SystemInfo);
+        fn VirtualQuery(address: *const c_void, buffer: *mut MemoryBasicInformation,
+            length: usize) -> usize;
+        fn VirtualAlloc(address: *mut c_void, size: usize, allocation_type: u32,
+            protect: u32) -> *mut c_void;
+        fn VirtualFree(address: *mut c_void, size: usize, free_type: u32) -> i32;
+        fn VirtualProtect(address: *const c_void, size: usize, protect: u32,
+            old_protect: *mut u32) -> i32;
+    }
+
+    fn query(address: usize) -> Option<MemoryBasicInformation> {
+        let mut info = std::mem::MaybeUninit::<MemoryBasicInformation>::uninit();
+        let size = std::mem::size_of::<MemoryBasicInformation>();
+        let written = unsafe { VirtualQuery(address as *const c_void, info.as_mut_ptr(), size) };
+        (written != 0).then(|| unsafe { info.assume_init() })
+    }
+
+    fn committed(address: usize) -> Option<MemoryBasicInformation> {
+        query(address).filter(|info| info.state == MEM_COMMIT && info.protect & PAGE_GUARD == 0)
+    }
+
+    pub(super) fn is_executable(address: usize) -> bool {
+        committed(address).is_some_and(|info| info.protect & PAGE_EXECUTE_ANY != 0)
+    }
+
+    pub(super) fn readable_len(address: usize) -> usize {
+        committed(address)
+            .filter(|info| info.protect & PAGE_READ_ANY != 0)
+            .map_or(0, |info| info.base_address as usize + info.region_size - address)
+    }
+
+    /// Range made writable, and its previous protection.
+    pub(super) type Changed = (usize, usize, u32);
+
+    pub(super) unsafe fn make_writable(address: usize, len: usize) -> Option<Vec<Changed>> {
+        // Regions reported by `VirtualQuery` share their protection,
+        // so each one gets its own back.
+        let end = address + len;
+        let mut changed = Vec::new();
+        let mut start = address;
+        while start < end {
+            let protection = committed(start).and_then(|info| {
+                let size = (info.base_address as usize + info.region_size).min(end) - start;
+                let writable = match info.protect & PAGE_EXECUTE_ANY {
+                    0 => PAGE_READWRITE,
+                    _ => PAGE_EXECUTE_READWRITE,
+                };
+                let mut protection = 0;
+                let done = VirtualProtect(start as *const c_void, size, writable, &mut protection);
+                (done != 0).then_some((size, protection))
+            });
+            let Some((size, protection)) = protection else {
+                restore_protection(&changed);
+                return None;
+            };
+            changed.push((start, size, protection));
+            start += size;
+        }
+        Some(changed)
+    }
+
+    pub(super) unsafe fn restore_protection(changed: &[Changed]) -> bool {
+        changed.iter().fold(true, |restored, &(start, size, protection)| {
+            let mut previous = 0;
+            VirtualProtect(start as *const c_void, size, protection, &mut previous) != 0 && restored
+        })
+    }
+
+    /// Find the closest free region below an address, like `FindPrevFreeRegion`.
+    fn free_below(address: usize, low: usize, granularity: usize) -> Option<usize> {
+        let mut candidate = (address & !(granularity - 1)).checked_sub(granularity)?;
+        while candidate >= low {
+            let info = query(candidate)?;
+            if info.state == MEM_FREE {
+                return Some(candidate);
+            }
+            candidate = (info.allocation_base as usize).checked_sub(granularity)?;
+        }
+        None
+    }
+
+    /// Find the closest free region above an address, like `FindNextFreeRegion`.
+    fn free_above(address: usize, high: usize, granularity: usize) -> Option<usize> {
+        let mut candidate = (address & !(granularity - 1)).checked_add(granularity)?;
+        while candidate <= high {
+            let info = query(candidate)?;
+            if info.state == MEM_FREE {
+                return Some(candidate);
+            }
+            let end = (info.base_address as usize).checked_add(info.region_size)?;
+            candidate = end.checked_next_multiple_of(granularity)?;
+        }
+        None
+    }
+
+    /// Map an executable region of `size` bytes, aligned to the allocation
+    /// granularity, whose bounds are within `max_distance` bytes of an address.
+    pub(super) fn map_near(address: usize, size: usize, max_distance: usize) -> Option<usize> {
+        let info = unsafe {
+            let mut info = std::mem::MaybeUninit::<SystemInfo>::uninit();
+            GetSystemInfo(info.as_mut_ptr());
+            info.assume_init()
+        };
+        let granularity = info.allocation_granularity as usize;
+        let low = address.saturating_sub(max_distance)
+            .max(info.minimum_application_address as usize);
+        let high = address.saturating_add(max_distance)
+            .min(info.maximum_application_address as usize)
+            .saturating_sub(size);
+
+        // Alternate between both directions, nearest candidate first,
+        // as another thread may take a free region before it is mapped.
+        let (mut below, mut above) = (address, address);
+        loop {
+            let lower = free_below(below, low, granularity);
+            let upper = free_above(above, high, granularity);
+            let candidate = match (lower, upper) {
+                (Some(lower), Some(upper)) if address - lower <= upper - address => lower,
+                (Some(lower), None) => lower,
+                (_, Some(upper)) => upper,
+                (None, None) => return None,
+            };
+            let mapped = unsafe {
+                VirtualAlloc(candidate as *mut c_void, size, MEM_COMMIT | MEM_RESERVE,
+                    PAGE_EXECUTE_READWRITE)
+            };
+            if !mapped.is_null() {
+                return Some(mapped as usize);
+            }
+            if Some(candidate) == lower {
+                below = candidate;
+            } else {
+                above = candidate;
+            }
+        }
+    }
+
+    pub(super) unsafe fn unmap(address: usize, _size: usize) {
+        VirtualFree(address as *mut c_void, 0, MEM_RELEASE);
+    }
+}
+
+/// Queries of the address space layout.
+#[cfg(target_os = "linux")]
+pub(crate) mod maps {
+    use std::ffi::{c_int, c_void};
+
+    /// A mapped region, as listed in `/proc/self/maps`.
+    struct Mapping {
+        start: usize,
+        end: usize,
+        protection: c_int,
+    }
+
+    /// Lowest address ever mapped without `MAP_FIXED`.
+    const MIN_ADDRESS: usize = 0x10000;
+    /// Highest user space address on x86-64 with 4-level paging,
+    /// which AArch64 with 48-bit addresses covers as well.
+    const MAX_ADDRESS: usize = 0x7FFF_FFFF_0000;
+
+    fn mappings() -> Vec<Mapping> {
+        let Ok(maps) = std::fs::read_to_string("/proc/self/maps") else {
+            return Vec::new();
+        };
+        maps.lines().filter_map(|line| {
+            let mut fields = line.split_ascii_whitespace();
+            let (start, end) = fields.next()?.split_once('-')?;
+            let permissions = fields.next()?.as_bytes();
+            let mut protection = libc::PROT_NONE;
+            for (flag, letter) in [(libc::PROT_READ, b'r'), (libc::PROT_WRITE, b'w'), (libc::PROT_EXEC, b'x')] {
+                if permissions.contains(&letter) {
+                    protection |= flag;
+                }
+            }
+            Some(Mapping {
+                start: usize::from_str_radix(start, 16).ok()?,
+                end: usize::from_str_radix(end, 16).ok()?,
+                protection,
+            })
+        }).collect()
+    }
+
+    fn find(address: usize) -> Option<Mapping> {
+        mappings().into_iter().find(|mapping| (mapping.start..mapping.end).contains(&address))
+    }
+
+    pub(crate) fn page_size() -> usize {
+        unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+    }
+
+    pub(crate) fn protection(address: usize) -> Option<c_int> {
+        find(address).map(|mapping| mapping.protection)
+    }
+
+    pub(crate) fn is_executable(address: usize) -> bool {
+        protection(address).is_some_and(|protection| protection & libc::PROT_EXEC != 0)
+    }
+
+    /// Get the number of bytes which can be read starting at an address.
+    pub(crate) fn readable_len(address: usize) -> usize {
+        find(address)
+            .filter(|mapping| mapping.protection & libc::PROT_READ != 0)
+            .map_or(0, |mapping| mapping.end - address)
+    }
+
+    /// Map an executable region of `size` bytes, aligned to it, whose
+    /// bounds are within `max_distance` bytes of an address.
+    pub(crate) fn map_near(address: usize, size: usize, max_distance: usize) -> Option<usize> {
+        let low = address.saturating_sub(max_distance).max(MIN_ADDRESS);
+        let high = address.saturating_add(max_distance).min(MAX_ADDRESS);
+
+        // Free ranges between mappings, nearest to the address first.
+        let mappings = mappings();
+        let mut gaps: Vec<(usize, usize)> = std::iter::once(MIN_ADDRESS)
+            .chain(mappings.iter().map(|mapping| mapping.end))
+            .zip(mappings.iter().map(|mapping| mapping.start).chain(std::iter::once(MAX_ADDRESS)))
+            .map(|(start, end)| (start.max(low), end.min(high)))
+            .filter(|(start, end)| start < end)
+            .collect();
+        let distance = |&(start, end): &(usize, usize)| {
+            if address < start { start - address } else { address.saturating_sub(end) }
+        };
+        gaps.sort_by_key(distance);
+
+        for (start, end) in gaps {
+            // The aligned candidate in the gap closest to the address.
+            let first = start.next_multiple_of(size);
+            let Some(last) = end.checked_sub(size).map(|last| last & !(size - 1)) else {
+                continue;
+            };
+            if first > last {
+                continue;
+            }
+            let candidate = (address & !(size - 1)).clamp(first, last);
+            if let Some(mapped) = map_at(candidate, size) {
+                return Some(mapped);
+            }
+        }
+        None
+    }
+
+    fn map_at(address: usize, size: usize) -> Option<usize> {
+        let protection = libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC;
+        let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED_NOREPLACE;
+        let mapped = unsafe {
+            libc::mmap(address as *mut c_void, size, protection, flags, -1, 0)
+        };
+        if mapped == libc::MAP_FAILED {
+            return None;
+        }
+        // Kernels before 4.17 treat the address as a mere hint.
+        if mapped as usize != address {
+            unsafe { libc::munmap(mapped, size) };
+            return None;
+        }
+        Some(address)
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    const SLOT_SIZE: usize = 0x40;
+    const SLOTS: usize = REGION_SIZE / SLOT_SIZE;
+
+    fn mapped(address: usize) -> bool {
+        maps::protection(address).is_some()
+    }
+
+    /// Allocate slots near an address, checking that each is within reach and usable.
+    fn allocate(allocator: &mut NearAllocator, near: usize, count: usize) -> Vec<usize> {
+        let distance = allocator.max_distance();
+        (0..count).map(|_| {
+            let slot = allocator.allocate(near).unwrap();
+            assert_eq!(slot % SLOT_SIZE, 0);
+            let reach = [slot, slot + SLOT_SIZE].map(|bound| bound.abs_diff(near));
+            assert!(reach.iter().all(|&reach| reach <= distance),
+                "{:#X} out of reach of {:#X}", slot, near);
+            assert!(allocator.contains(slot) && is_executable(slot));
+            unsafe { (slot as *mut u8).write_bytes(0xCC, SLOT_SIZE) };
+            slot
+        }).collect()
+    }
+
+    #[test]
+    fn allocating_near() {
+        let near = allocating_near as *const () as usize;
+        for distance in [0x7FFF_0000, 0x1000_0000] {
+            let mut allocator = NearAllocator::new(SLOT_SIZE, distance);
+            let mut slots = allocate(&mut allocator, near, SLOTS + 1);
+            assert_eq!(allocator.regions().count(), 2);
+            slots.sort_unstable();
+            slots.dedup();
+            assert_eq!(slots.len(), SLOTS + 1);
+            unsafe { allocator.clear() };
+        }
+        // Regions themselves have to be within reach.
+        let mut allocator = NearAllocator::new(SLOT_SIZE, REGION_SIZE - 1);
+        assert_eq!(allocator.allocate(near), None);
+    }
+
+    #[test]
+    fn freeing() {
+        let near = freeing as *const () as usize;
+        let mut allocator = NearAllocator::new(SLOT_SIZE, 0x7FFF_0000);
+        let slots = allocate(&mut allocator, near, SLOTS + 1);
+        let regions: Vec<_> = allocator.regions().collect();
+        let (last, first) = (slots[SLOTS], slots[0]);
+        unsafe {
+            // Freed slots are taken again first.
+            assert!(allocator.free(first));
+            assert_eq!(allocator.allocate(near), Some(first));
+
+            // A region is unmapped along with its last slot.
+            assert!(allocator.free(last));
+            assert_eq!(allocator.regions().collect::<Vec<_>>(), &regions[..1]);
+            assert!(!mapped(regions[1]) && !allocator.contains(last));
+            assert!(!allocator.free(last));
+            assert!(!allocator.free(near));
+
+            for &slot in &slots[..SLOTS] {
+                assert!(allocator.free(slot));
+            }
+            assert_eq!(allocator.regions().count(), 0);
+            assert!(!mapped(regions[0]));
+        }
+    }
+
+    #[test]
+    fn clearing() {
+        let near = clearing as *const () as usize;
+        let mut allocator = NearAllocator::new(SLOT_SIZE, 0x7FFF_0000);
+        let slots = allocate(&mut allocator, near, SLOTS + 1);
+        let regions: Vec<_> = allocator.regions().collect();
+        unsafe { allocator.clear() };
+        assert_eq!(allocator.regions().count(), 0);
+        assert!(regions.iter().all(|&region| !mapped(region)));
+        assert!(!allocator.contains(slots[0]));
+        assert!(!unsafe { allocator.free(slots[0]) });
+        // The allocator maps regions again afterwards.
+        allocate(&mut allocator, near, 1);
+        unsafe { allocator.clear() };
+    }
+}
@@ -0,0 +1,463 @@
+//! Dry runs of hook creation, explaining why a function can't be hooked.
+//!
+//! The analysis decodes the instructions a hook would overwrite, builds
+//! a trampoline for them the same way as when creating a hook, and scans
+//! the rest of the function for branches back into the overwritten bytes.
+//! Nothing is allocated or written, so any function can be analyzed.
+//!
+//! Trampolines are built by this crate on Linux, and by the MinHook library
+//! on Windows, which refuses some functions this crate's builder accepts.
+//! [`analyze_code_for_library`] checks for those as well, and reports them
+//! as [`Rejection::RefusedByLibrary`].
+
+use crate::decoder::{self, Bitness, BranchKind, Instruction};
+use crate::trampoline::{self, TrampolineError};
+use crate::ErrorKind;
+
+/// Number of bytes of a function read by [`analyze`], which covers the
+/// overwritten bytes and the body of most functions scanned for branches.
+pub const SCAN_LEN: usize = 4096;
+
+/// Reason a function would be rejected by hook creation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    /// The function isn't in executable memory.
+    NotExecutable,
+    /// No trampoline can be built for the start of the function.
+    Unsupported(TrampolineError),
+    /// A trampoline can be built, but the MinHook library refuses to.
+    RefusedByLibrary(LibraryRefusal),
+}
+
+impl Rejection {
+    /// Get the kind of error hook creation fails with.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::NotExecutable => ErrorKind::PointerNotExecutable,
+            Self::Unsupported(_) | Self::RefusedByLibrary(_) => ErrorKind::UnsupportedFunction,
+        }
+    }
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotExecutable => f.write_str("function is not in executable memory"),
+            Self::Unsupported(error) => write!(f, "trampoline can't be built: {}", error),
+            Self::RefusedByLibrary(refusal) => {
+                write!(f, "library refuses the function: {}", refusal)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Rejection {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Unsupported(error) => Some(error),
+            Self::RefusedByLibrary(refusal) => Some(refusal),
+            _ => None,
+        }
+    }
+}
+
+/// Construct the MinHook library refuses to build a trampoline for,
+/// although this crate's builder can.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LibraryRefusal {
+    /// `loop`, `loope`, `loopne` or `jcxz`/`jecxz`/`jrcxz` leading out of
+    /// the overwritten bytes, which the library doesn't rewrite.
+    ExternalLoop {
+        /// Offset of the instruction in the function.
+        offset: usize,
+    },
+    /// An instruction before the destination of a branch within the
+    /// overwritten bytes would change its length when copied, which
+    /// the library can't account for, as it copies such branches as they are.
+    ResizedBeforeBranch {
+        /// Offset of the instruction in the function.
+        offset: usize,
+    },
+    /// The library's trampoline would be longer than the slot it is placed in.
+    TooLong {
+        /// Length of the trampoline in bytes, as far as it was built.
+        len: usize,
+    },
+}
+
+impl std::fmt::Display for LibraryRefusal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ExternalLoop { offset } => {
+                write!(f, "loop or jcxz at offset {} leads out of the patch", offset)
+            }
+            Self::ResizedBeforeBranch { offset } => {
+                write!(f, "instruction at offset {} changes length before a branch target", offset)
+            }
+            Self::TooLong { len } => write!(f, "trampoline is too long ({} bytes)", len),
+        }
+    }
+}
+
+impl std::error::Error for LibraryRefusal {}
+
+/// Change a copied instruction needs to keep its meaning in the trampoline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Relocation {
+    /// Memory operand relative to the instruction pointer,
+    /// whose displacement is adjusted.
+    RipRelative {
+        /// Address of the operand.
+        target: usize,
+    },
+    /// Relative branch out of the overwritten bytes, which is adjusted
+    /// or replaced by an equivalent with an absolute destination.
+    Branch {
+        /// Kind of the branch.
+        kind: BranchKind,
+        /// Address of the destination.
+        target: usize,
+    },
+    /// Relative branch within the overwritten bytes, which is pointed
+    /// at the copy of its destination.
+    Internal {
+        /// Kind of the branch.
+        kind: BranchKind,
+        /// Offset of the destination in the function.
+        destination: usize,
+    },
+}
+
+/// An instruction which would be overwritten and copied into the trampoline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StolenInstruction {
+    offset: usize,
+    instruction: Instruction,
+    relocation: Option<Relocation>,
+}
+
+impl StolenInstruction {
+    /// Get the offset of the instruction in the function.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Get the decoded instruction.
+    pub fn instruction(&self) -> &Instruction {
+        &self.instruction
+    }
+
+    /// Get the change the instruction needs, if any.
+    pub fn relocation(&self) -> Option<Relocation> {
+        self.relocation
+    }
+
+    /// Check whether the instruction can't be copied as it is.
+    pub fn needs_relocation(&self) -> bool {
+        self.relocation.is_some()
+    }
+}
+
+/// Result of analyzing a function for hooking.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Analysis {
+    address: usize,
+    patch_len: usize,
+    stolen_len: usize,
+    instructions: Vec<StolenInstruction>,
+    branches_into_patch: Vec<usize>,
+    trampoline_len: Option<usize>,
+    rejection: Option<Rejection>,
+}
+
+impl Analysis {
+    /// Get the address of the function.
+    pub fn address(&self) -> usize {
+        self.address
+    }
+
+    /// Get the number of bytes the patch overwrites.
+    pub fn patch_len(&self) -> usize {
+        self.patch_len
+    }
+
+    /// Get the number of bytes which would be copied into the trampoline,
+    /// which are the instructions overlapping the patch, or all of them
+    /// if the function ends before it.
+    pub fn stolen_len(&self) -> usize {
+        self.stolen_len
+    }
+
+    /// Get the instructions which would be copied into the trampoline,
+    /// as far as they could be decoded.
+    pub fn instructions(&self) -> &[StolenInstruction] {
+        &self.instructions
+    }
+
+    /// Get the instructions which would be copied with changes.
+    pub fn relocations(&self) -> impl Iterator<Item = &StolenInstruction> {
+        self.instructions.iter().filter(|instruction| instruction.needs_relocation())
+    }
+
+    /// Get offsets of instructions past the copied ones which branch back
+    /// into the middle of them. Hook creation doesn't detect such branches,
+    /// which would land inside the patch once the hook is enabled.
+    pub fn branches_into_patch(&self) -> &[usize] {
+        &self.branches_into_patch
+    }
+
+    /// Check whether the function ends before the patch does, and isn't
+    /// followed by padding which could be overwritten instead.
+    pub fn is_too_short(&self) -> bool {
+        matches!(self.rejection, Some(Rejection::Unsupported(TrampolineError::TooShort { .. })))
+    }
+
+    /// Get the size of the trampoline, if it can be built.
+    pub fn trampoline_len(&self) -> Option<usize> {
+        self.trampoline_len
+    }
+
+    /// Get the reason hook creation would fail, if it would.
+    pub fn rejection(&self) -> Option<Rejection> {
+        self.rejection
+    }
+
+    /// Check whether hook creation would succeed, and no branches
+    /// lead back into the overwritten bytes.
+    pub fn is_hookable(&self) -> bool {
+        self.rejection.is_none() && self.branches_into_patch.is_empty()
+    }
+}
+
+/// Analyze hooking a function with the usual `jmp rel32` patch.
+///
+/// Up to [`SCAN_LEN`] bytes of the function are read, as far as they
+/// are readable. The trampoline is assumed to be placed next to the
+/// function, as hook creation does.
+///
+/// # Safety
+///
+/// The memory of the function must not be unmapped or changed
+/// while it is being read.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub unsafe fn analyze(target: *const std::ffi::c_void) -> Analysis {
+    let address = target as usize;
+    if !crate::allocator::is_executable(address) {
+        return Analysis {
+            address,
+            patch_len: trampoline::JMP_REL_LEN,
+            stolen_len: 0,
+            instructions: Vec::new(),
+            branches_into_patch: Vec::new(),
+            trampoline_len: None,
+            rejection: Some(Rejection::NotExecutable),
+        };
+    }
+    let len = crate::allocator::readable_len(address).min(SCAN_LEN);
+    let code = std::slice::from_raw_parts(target as *const u8, len);
+    match cfg!(windows) {
+        true => analyze_code_for_library(code, address, Bitness::NATIVE),
+        false => analyze_code(code, address, Bitness::NATIVE, trampoline::JMP_REL_LEN),
+    }
+}
+
+/// Analyze hooking a function given its code,
+/// with a trampoline built by this crate as on Linux.
+///
+/// # Arguments
+///
+/// * `code` - bytes of the function, which are scanned for branches
+///     into the patch up to their end, or the end of the function.
+/// * `address` - address of the function.
+/// * `bitness` - mode of the function's code.
+/// * `patch_len` - number of bytes which would be overwritten,
+///     normally [`JMP_REL_LEN`](trampoline::JMP_REL_LEN).
+pub fn analyze_code(code: &[u8], address: usize, bitness: Bitness, patch_len: usize)
+    -> Analysis
+{
+    let mut instructions = Vec::new();
+    let mut decoded_len = 0;
+    for (offset, instruction) in decoder::instructions(code, bitness).map_while(Result::ok) {
+        if offset >= patch_len {
+            break;
+        }
+        let source = address.wrapping_add(offset);
+        let relocation = if let Some(target) = instruction.rip_target(source) {
+            Some(Relocation::RipRelative { target })
+        } else if let Some(kind) = instruction.relative_branch() {
+            let target = instruction.branch_target(source).unwrap();
+            let destination = target.wrapping_sub(address);
+            Some(match destination < patch_len {
+                true => Relocation::Internal { kind, destination },
+                false => Relocation::Branch { kind, target },
+            })
+        } else {
+            None
+        };
+        instructions.push(StolenInstruction { offset, instruction, relocation });
+        decoded_len = offset + instruction.len();
+    }
+
+    let (stolen_len, trampoline_len, rejection) =
+        match trampoline::build(code, address, address, bitness, patch_len) {
+            Ok(built) => (built.original_len(), Some(built.code().len()), None),
+            Err(error) => (decoded_len, None, Some(Rejection::Unsupported(error))),
+        };
+    instructions.retain(|instruction| instruction.offset < stolen_len);
+
+    Analysis {
+        address,
+        patch_len,
+        stolen_len,
+        instructions,
+        branches_into_patch: branches_into(code, address, bitness, stolen_len),
+        trampoline_len,
+        rejection,
+    }
+}
+
+/// Analyze hooking a function given its code, with a trampoline
+/// built by the MinHook library as on Windows.
+///
+/// Functions are rejected as by [`analyze_code`] with the usual
+/// `jmp rel32` patch, and also with [`Rejection::RefusedByLibrary`]
+/// for constructs the library refuses. The library may accept a few
+/// functions this crate's builder doesn't, such as ones too short
+/// for the patch which are preceded by padding.
+///
+/// # Arguments
+///
+/// * `code` - bytes of the function, which are scanned for branches
+///     into the patch up to their end, or the end of the function.
+/// * `address` - address of the function.
+/// * `bitness` - mode of the function's code.
+pub fn analyze_code_for_library(code: &[u8], address: usize, bitness: Bitness) -> Analysis {
+    let mut analysis = analyze_code(code, address, bitness, trampoline::JMP_REL_LEN);
+    if analysis.rejection.is_none() {
+        let jumps_back = trampoline::build(code, address, address, bitness, analysis.patch_len)
+            .is_ok_and(|built| built.jumps_back());
+        analysis.rejection = library_refusal(&analysis.instructions, bitness, jumps_back)
+            .map(Rejection::RefusedByLibrary);
+    }
+    analysis
+}
+
+/// Size of the library's `jmp [rip+0]` followed by the destination.
+const LIBRARY_JMP_ABS_LEN: usize = 14;
+/// Size of the library's `call [rip+2]; jmp +8` followed by the destination.
+const LIBRARY_CALL_ABS_LEN: usize = 16;
+/// Size of the library's inverted `jcc +14` followed by an absolute jump.
+const LIBRARY_JCC_ABS_LEN: usize = 16;
+
+/// Check instructions copied into a trampoline against the rules of
+/// the library's own builder, which turns calls and branches out of the
+/// patch into fixed-size equivalents, and copies others as they are.
+fn library_refusal(instructions: &[StolenInstruction], bitness: Bitness, jumps_back: bool)
+    -> Option<LibraryRefusal>
+{
+    let wide = bitness == Bitness::Bits64;
+    // Slots are 64 bytes on x86-64, which also hold the jump to the detour.
+    let max_len = match wide {
+        true => 64 - LIBRARY_JMP_ABS_LEN,
+        false => 32,
+    };
+    let mut len = 0;
+    // Furthest destination of branches within the patch.
+    let mut internal_end = 0;
+    for stolen in instructions {
+        let (offset, original_len) = (stolen.offset, stolen.instruction.len());
+        let branch = match stolen.relocation {
+            // `xbegin` is copied as any other instruction.
+            _ if stolen.instruction.opcode() == 0xC7 => None,
+            Some(Relocation::Branch { kind, .. }) => Some((kind, None)),
+            Some(Relocation::Internal { kind, destination }) => Some((kind, Some(destination))),
+            _ => None,
+        };
+        let copy_len = match branch {
+            Some((BranchKind::Call, _)) if wide => LIBRARY_CALL_ABS_LEN,
+            Some((BranchKind::Call, _)) => trampoline::JMP_REL_LEN,
+            Some((_, Some(destination))) => {
+                internal_end = internal_end.max(destination);
+                original_len
+            }
+            Some((BranchKind::Loop, None)) => return Some(LibraryRefusal::ExternalLoop { offset }),
+            Some((BranchKind::Jump, None)) if wide => LIBRARY_JMP_ABS_LEN,
+            Some((BranchKind::Jump, None)) => trampoline::JMP_REL_LEN,
+            Some((_, None)) if wide => LIBRARY_JCC_ABS_LEN,
+            Some((_, None)) => trampoline::JMP_REL_LEN + 1,
+            None => original_len,
+        };
+        if offset < internal_end && copy_len != original_len {
+            return Some(LibraryRefusal::ResizedBeforeBranch { offset });
+        }
+        if len + copy_len > max_len {
+            return Some(LibraryRefusal::TooLong { len: len + copy_len });
+        }
+        len += copy_len;
+    }
+    let jump_len = match wide {
+        true => LIBRARY_JMP_ABS_LEN,
+        false => trampoline::JMP_REL_LEN,
+    };
+    (jumps_back && len + jump_len > max_len)
+        .then_some(LibraryRefusal::TooLong { len: len + jump_len })
+}
+
+/// Find branches past the first `len` bytes of a function which lead
+/// into the middle of them, up to the first return or jump which
+/// is followed by padding.
+fn branches_into(code: &[u8], address: usize, bitness: Bitness, len: usize) -> Vec<usize> {
+    let Some(rest) = code.get(len..) else {
+        return Vec::new();
+    };
+    let mut branches = Vec::new();
+    for (offset, instruction) in decoder::instructions(rest, bitness).map_while(Result::ok) {
+        let offset = len + offset;
+        let source = address.wrapping_add(offset);
+        if let Some(target) = instruction.branch_target(source) {
+            let destination = target.wrapping_sub(address);
+            if destination > 0 && destination < len {
+                branches.push(offset);
+            }
+        }
+        let end = offset + instruction.len();
+        let ends = trampoline::ends_function(&instruction)
+            || instruction.relative_branch() == Some(BranchKind::Jump);
+        if ends && code.get(end).is_some_and(|&byte| trampoline::is_padding(byte))
+        {
+            break;
+        }
+    }
+    branches
+}
+
+// Addresses of the tests are above 4 GiB.
+#[cfg(all(test, target_pointer_width = "64"))]
+mod tests {
+    use super::*;
+
+    const TARGET: usize = 0x1_4000_1000;
+
+    fn refusal(code: &[u8]) -> Option<Rejection> {
+        let analysis = analyze_code(code, TARGET, Bitness::Bits64, trampoline::JMP_REL_LEN);
+        assert_eq!(analysis.rejection(), None);
+        analyze_code_for_library(code, TARGET, Bitness::Bits64).rejection()
+    }
+
+    #[test]
+    fn library_rules() {
+        // push rbp; mov rbp, rsp; sub rsp, 0x20
+        assert_eq!(refusal(&[0x55, 0x48, 0x89, 0xE5, 0x48, 0x83, 0xEC, 0x20]), None);
+        // loop +0x10; nop; nop; nop
+        assert_eq!(refusal(&[0xE2, 0x10, 0x90, 0x90, 0x90]),
+            Some(Rejection::RefusedByLibrary(LibraryRefusal::ExternalLoop { offset: 0 })));
+        // loop +1; nop; nop; nop; nop
+        assert_eq!(refusal(&[0xE2, 0x01, 0x90, 0x90, 0x90, 0x90]), None);
+        // jz +2; jmp +0x10; nop; nop; nop
+        assert_eq!(refusal(&[0x74, 0x02, 0xEB, 0x10, 0x90, 0x90, 0x90]),
+            Some(Rejection::RefusedByLibrary(LibraryRefusal::ResizedBeforeBranch { offset: 2 })));
+        // jz +0x10; jz +0x10; jz +0x10
+        assert_eq!(refusal(&[0x74, 0x10, 0x74, 0x10, 0x74, 0x10]),
+            Some(Rejection::RefusedByLibrary(LibraryRefusal::TooLong { len: 62 })));
+    }
+}
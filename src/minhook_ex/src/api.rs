@@ -0,0 +1,184 @@
+//! Hooking exported functions of loaded modules by name or ordinal.
+
+use std::ffi::{c_void, CString};
+
+use crate::pe::PeError;
+#[cfg(windows)]
+use crate::resolver::Resolver;
+use crate::{Error, HookIdent};
+
+/// Reference to a function exported from a module.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Export {
+    /// Export referenced by its name.
+    Name(String),
+    /// Export referenced by its ordinal.
+    Ordinal(u16),
+}
+
+impl From<&str> for Export {
+    fn from(name: &str) -> Self {
+        Export::Name(name.to_owned())
+    }
+}
+
+impl From<String> for Export {
+    fn from(name: String) -> Self {
+        Export::Name(name)
+    }
+}
+
+impl From<u16> for Export {
+    fn from(ordinal: u16) -> Self {
+        Export::Ordinal(ordinal)
+    }
+}
+
+impl std::fmt::Display for Export {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Export::Name(name) => f.write_str(name),
+            Export::Ordinal(ordinal) => write!(f, "#{}", ordinal),
+        }
+    }
+}
+
+/// Possible errors of hooking an exported function.
+#[derive(Clone, Debug)]
+pub enum ApiError {
+    /// Module or export name contained an interior nul character.
+    InvalidName {
+        /// The offending name.
+        name: String,
+    },
+    /// Specified module is not loaded.
+    ModuleNotFound {
+        /// Name of the module.
+        module: String,
+    },
+    /// Specified module doesn't export the function.
+    FunctionNotFound {
+        /// Name of the module.
+        module: String,
+        /// The missing export.
+        export: Export,
+    },
+    /// Module is not a valid PE image.
+    InvalidImage {
+        /// Name of the module.
+        module: String,
+        /// Underlying error.
+        error: PeError,
+    },
+    /// A forwarder is malformed, or forwards too many times.
+    InvalidForwarder {
+        /// The forwarder, like `"NTDLL.RtlAllocateHeap"`.
+        forwarder: String,
+    },
+    /// Export was found, but the hook couldn't be created.
+    Hook(Error),
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::InvalidName { name } => write!(f, "name {:?} contains a nul character", name),
+            ApiError::ModuleNotFound { module } => write!(f, "module {} not loaded", module),
+            ApiError::FunctionNotFound { module, export } => {
+                write!(f, "function {} not exported from module {}", export, module)
+            }
+            ApiError::InvalidImage { module, error } => {
+                write!(f, "module {} is not a valid image: {}", module, error)
+            }
+            ApiError::InvalidForwarder { forwarder } => {
+                write!(f, "forwarder {} can't be resolved", forwarder)
+            }
+            ApiError::Hook(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ApiError::InvalidImage { error, .. } => Some(error),
+            ApiError::Hook(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for ApiError {
+    fn from(err: Error) -> Self {
+        ApiError::Hook(err)
+    }
+}
+
+fn encode_name(name: &str) -> Result<CString, ApiError> {
+    CString::new(name).map_err(|_| ApiError::InvalidName { name: name.to_owned() })
+}
+
+/// Find an export of a loaded module by walking export directories,
+/// following forwarders and API set redirections.
+#[cfg(windows)]
+unsafe fn resolve_export(module: &str, export: &Export) -> Result<*mut c_void, ApiError> {
+    Ok(Resolver::loaded().resolve(module, export)?.address() as *mut c_void)
+}
+
+/// Find a symbol of a loaded shared object. Shared objects
+/// have no ordinals, so those are never found.
+#[cfg(not(windows))]
+unsafe fn resolve_export(module: &str, export: &Export) -> Result<*mut c_void, ApiError> {
+    let name = encode_name(module)?;
+    let handle = libc::dlopen(name.as_ptr(), libc::RTLD_LAZY | libc::RTLD_NOLOAD);
+    if handle.is_null() {
+        return Err(ApiError::ModuleNotFound { module: module.to_owned() });
+    }
+    let target = match export {
+        Export::Name(name) => match encode_name(name) {
+            Ok(name) => libc::dlsym(handle, name.as_ptr()),
+            Err(_) => std::ptr::null_mut(),
+        },
+        Export::Ordinal(_) => std::ptr::null_mut(),
+    };
+    // Only drops the reference taken above, the module stays loaded.
+    libc::dlclose(handle);
+    if target.is_null() {
+        return Err(ApiError::FunctionNotFound { module: module.to_owned(), export: export.clone() });
+    }
+    Ok(target)
+}
+
+/// Create a disabled hook for a function exported from a loaded module.
+/// Returns pointers to the resolved target and the trampoline function.
+///
+/// # Arguments
+///
+/// * `module` - name of the loaded module, like `"user32"`,
+///     or of a shared object on Linux, like `"libc.so.6"`.
+/// * `export` - name or ordinal of the exported function.
+/// * `detour` - pointer to the overwriting function.
+/// * `ident` - hook identifier, set different ones to create
+///     multiple hooks for the same target function.
+///
+/// # Safety
+///
+/// Same as for [`create_hook`](crate::create_hook).
+#[track_caller]
+pub unsafe fn create_hook_api(module: &str, export: impl Into<Export>, detour: *const c_void,
+    ident: HookIdent) -> Result<(*const c_void, *const c_void), ApiError>
+{
+    let export = export.into();
+    if module.contains('\0') {
+        return Err(ApiError::InvalidName { name: module.to_owned() });
+    }
+    if let Export::Name(name) = &export {
+        encode_name(name)?;
+    }
+
+    // Resolve the export here rather than in the backend,
+    // so that every backend only has to hook plain functions.
+    let target = resolve_export(module, &export)?;
+    let trampoline = crate::create_hook(target, detour, ident)?;
+    Ok((target, trampoline))
+}
@@ -0,0 +1,235 @@
+//! Engines behind the raw hooking functions.
+//!
+//! Every function of this crate which creates, changes or removes hooks
+//! goes through the selected [`HookBackend`], which is the MinHook library
+//! on Windows, and a reimplementation of its API on Linux. Another engine
+//! can be selected with [`set`] while the library is not initialized,
+//! with the same [`Hook`](crate::Hook) and [`Error`] surface on top of it.
+
+use std::ffi::c_void;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use minhook_ex_sys::MH_ALL_HOOKS;
+
+use crate::{Error, ErrorKind, HookIdent, Result, StatusExt, ThreadFreezeMethod};
+
+/// Engine which creates, enables, disables and removes hooks.
+///
+/// Methods follow the semantics of the MinHook library functions of the
+/// same names, and report errors as the library would, without context.
+/// A `target` of `None` stands for [`MH_ALL_HOOKS`], and [`HookIdent::ALL`]
+/// for all identifiers where the library accepts it.
+pub trait HookBackend: Send + Sync {
+    /// Initialize the engine, see `MH_Initialize`.
+    fn initialize(&self) -> Result<()>;
+
+    /// Uninitialize the engine, removing all of its hooks, see `MH_Uninitialize`.
+    fn uninitialize(&self) -> Result<()>;
+
+    /// Select a method of suspending/resuming threads, see `MH_SetThreadFreezeMethod`.
+    fn set_thread_freeze_method(&self, freeze: ThreadFreezeMethod) -> Result<()>;
+
+    /// Create a disabled hook and return its trampoline, see `MH_CreateHookEx`.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`crate::create_hook`].
+    unsafe fn create_hook(&self, target: *const c_void, detour: *const c_void,
+        ident: HookIdent) -> Result<*const c_void>;
+
+    /// Remove created hooks, see `MH_RemoveHookEx`.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`crate::remove_hook`], for every affected hook.
+    unsafe fn remove_hook(&self, target: Option<*const c_void>, ident: HookIdent) -> Result<()>;
+
+    /// Remove created hooks which are disabled, see `MH_RemoveDisabledHooksEx`.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`crate::remove_hook`], for every affected hook.
+    unsafe fn remove_disabled_hooks(&self, ident: HookIdent) -> Result<()>;
+
+    /// Enable created hooks, see `MH_EnableHookEx`.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`crate::enable_hook`], for every affected hook.
+    unsafe fn enable_hook(&self, target: Option<*const c_void>, ident: HookIdent) -> Result<()>;
+
+    /// Disable created hooks, see `MH_DisableHookEx`.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`crate::disable_hook`], for every affected hook.
+    unsafe fn disable_hook(&self, target: Option<*const c_void>, ident: HookIdent) -> Result<()>;
+
+    /// Queue created hooks to be enabled, see `MH_QueueEnableHookEx`.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`crate::queue_enable_hook`], for every affected hook.
+    unsafe fn queue_enable_hook(&self, target: Option<*const c_void>, ident: HookIdent)
+        -> Result<()>;
+
+    /// Queue created hooks to be disabled, see `MH_QueueDisableHookEx`.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`crate::queue_disable_hook`], for every affected hook.
+    unsafe fn queue_disable_hook(&self, target: Option<*const c_void>, ident: HookIdent)
+        -> Result<()>;
+
+    /// Apply queued changes, see `MH_ApplyQueuedEx`.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`crate::apply_queued`].
+    unsafe fn apply_queued(&self, ident: HookIdent) -> Result<()>;
+}
+
+/// Implement [`HookBackend`] for a unit type on top of a module
+/// exposing the `MH_*` functions of the library.
+macro_rules! minhook_api_backend {
+    ($backend:ident, $api:ident) => {
+        impl HookBackend for $backend {
+            fn initialize(&self) -> Result<()> {
+                unsafe { $api::MH_Initialize() }.into_result()
+            }
+
+            fn uninitialize(&self) -> Result<()> {
+                unsafe { $api::MH_Uninitialize() }.into_result()
+            }
+
+            fn set_thread_freeze_method(&self, freeze: ThreadFreezeMethod) -> Result<()> {
+                unsafe { $api::MH_SetThreadFreezeMethod(freeze.into()) }.into_result()
+            }
+
+            unsafe fn create_hook(&self, target: *const c_void, detour: *const c_void,
+                ident: HookIdent) -> Result<*const c_void>
+            {
+                let mut trampoline: *mut c_void = std::ptr::null_mut();
+                $api::MH_CreateHookEx(ident.get(), target, detour, &mut trampoline).into_result()?;
+                Ok(trampoline)
+            }
+
+            unsafe fn remove_hook(&self, target: Option<*const c_void>, ident: HookIdent)
+                -> Result<()>
+            {
+                $api::MH_RemoveHookEx(ident.get(), target.unwrap_or(MH_ALL_HOOKS)).into_result()
+            }
+
+            unsafe fn remove_disabled_hooks(&self, ident: HookIdent) -> Result<()> {
+                $api::MH_RemoveDisabledHooksEx(ident.get()).into_result()
+            }
+
+            unsafe fn enable_hook(&self, target: Option<*const c_void>, ident: HookIdent)
+                -> Result<()>
+            {
+                $api::MH_EnableHookEx(ident.get(), target.unwrap_or(MH_ALL_HOOKS)).into_result()
+            }
+
+            unsafe fn disable_hook(&self, target: Option<*const c_void>, ident: HookIdent)
+                -> Result<()>
+            {
+                $api::MH_DisableHookEx(ident.get(), target.unwrap_or(MH_ALL_HOOKS)).into_result()
+            }
+
+            unsafe fn queue_enable_hook(&self, target: Option<*const c_void>, ident: HookIdent)
+                -> Result<()>
+            {
+                $api::MH_QueueEnableHookEx(ident.get(), target.unwrap_or(MH_ALL_HOOKS))
+                    .into_result()
+            }
+
+            unsafe fn queue_disable_hook(&self, target: Option<*const c_void>, ident: HookIdent)
+                -> Result<()>
+            {
+                $api::MH_QueueDisableHookEx(ident.get(), target.unwrap_or(MH_ALL_HOOKS))
+                    .into_result()
+            }
+
+            unsafe fn apply_queued(&self, ident: HookIdent) -> Result<()> {
+                $api::MH_ApplyQueuedEx(ident.get()).into_result()
+            }
+        }
+    };
+}
+
+/// The MinHook library itself.
+#[cfg(windows)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MinHookLibrary;
+
+#[cfg(windows)]
+minhook_api_backend!(MinHookLibrary, minhook_ex_sys);
+
+/// Reimplementation of the MinHook library for Linux on x86-64 and AArch64.
+///
+/// Threads are not suspended, so the freeze method has no effect.
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LinuxBackend;
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+use crate::linux;
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+minhook_api_backend!(LinuxBackend, linux);
+
+#[cfg(windows)]
+type Native = MinHookLibrary;
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+type Native = LinuxBackend;
+
+/// Get the backend used unless another one is selected.
+pub fn native() -> &'static dyn HookBackend {
+    &Native {}
+}
+
+struct Selection {
+    backend: Option<&'static dyn HookBackend>,
+    initialized: bool,
+}
+
+static SELECTION: Mutex<Selection> = Mutex::new(Selection { backend: None, initialized: false });
+
+fn selection() -> MutexGuard<'static, Selection> {
+    // The selection is only changed by single stores.
+    SELECTION.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Get the currently selected backend.
+pub fn current() -> &'static dyn HookBackend {
+    selection().backend.unwrap_or_else(native)
+}
+
+/// Select the backend for all following hooking functions.
+///
+/// Fails with [`ErrorKind::AlreadyInitialized`] while the current backend
+/// is initialized, as its hooks would be left behind.
+pub fn set(backend: &'static dyn HookBackend) -> Result<()> {
+    let mut selection = selection();
+    if selection.initialized {
+        return Err(Error::new(ErrorKind::AlreadyInitialized));
+    }
+    selection.backend = Some(backend);
+    Ok(())
+}
+
+/// Initialize the current backend, pinning it until uninitialized.
+pub(crate) fn initialize() -> Result<()> {
+    let mut selection = selection();
+    selection.backend.unwrap_or_else(native).initialize()?;
+    selection.initialized = true;
+    Ok(())
+}
+
+/// Uninitialize the current backend, allowing to select another one.
+pub(crate) fn uninitialize() -> Result<()> {
+    let mut selection = selection();
+    selection.backend.unwrap_or_else(native).uninitialize()?;
+    selection.initialized = false;
+    Ok(())
+}
@@ -0,0 +1,328 @@
+//! Library initialization as an owned context value.
+
+use std::marker::PhantomData;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use crate::{backend, registry, Error, ErrorKind, Operation, Result, StatusExt, ThreadFreezeMethod};
+
+/// Proof that the MinHook library is initialized.
+///
+/// Every safe hook-creating API borrows this context, and every hook
+/// it produces keeps the borrow alive. The borrow checker therefore
+/// rejects creating a hook before initialization, as well as dropping
+/// the context (which uninitializes the library) while hooks remain.
+///
+/// A context is either exclusive, see [`MinHook::initialize`], or one
+/// of several shares of a process-wide initialization, see
+/// [`MinHook::acquire_shared`]. The two modes can't be mixed.
+#[derive(Debug)]
+pub struct MinHook {
+    shared: bool,
+}
+
+/// Process-wide state of the shared initialization mode.
+#[derive(Debug)]
+struct SharedState {
+    shares: usize,
+}
+
+static SHARED: Mutex<Option<SharedState>> = Mutex::new(None);
+
+/// Thread freeze methods selected in the library, tracked here
+/// as the library itself can't be queried for them.
+#[derive(Debug)]
+struct FreezeState {
+    /// Method selected at initialization or set since.
+    base: ThreadFreezeMethod,
+    /// Methods of live overrides by their ids, the last of which is in effect.
+    overrides: Vec<(u64, ThreadFreezeMethod)>,
+    next_override: u64,
+}
+
+impl FreezeState {
+    fn effective(&self) -> ThreadFreezeMethod {
+        self.overrides.last().map_or(self.base, |&(_, freeze)| freeze)
+    }
+}
+
+static FREEZE: Mutex<Option<FreezeState>> = Mutex::new(None);
+
+fn freeze_state() -> Result<MutexGuard<'static, Option<FreezeState>>> {
+    FREEZE.lock().map_err(|_| Error::new(ErrorKind::MutexFailure))
+}
+
+impl MinHook {
+    /// Initialize the MinHook library and select an
+    /// internal method of suspending/resuming threads.
+    ///
+    /// Only one exclusive context can exist at a time, as the library
+    /// itself refuses to be initialized twice.
+    pub fn initialize(freeze: ThreadFreezeMethod) -> Result<Self> {
+        Self::initialize_raw(freeze)?;
+        Ok(Self { shared: false })
+    }
+
+    /// Acquire a share of a process-wide MinHook initialization.
+    ///
+    /// The library is initialized by the first share, and uninitialized
+    /// once the last one is released. Requesting a different freeze method
+    /// than the first share did fails with [`ErrorKind::FreezeMethodConflict`].
+    pub fn acquire_shared(freeze: ThreadFreezeMethod) -> Result<Self> {
+        let mut state = SHARED.lock().map_err(|_| Error::new(ErrorKind::MutexFailure))?;
+        match state.as_mut() {
+            Some(state) => {
+                let active = freeze_state()?.as_ref().map(|freeze| freeze.base);
+                if let Some(active) = active.filter(|&active| active != freeze) {
+                    let kind = ErrorKind::FreezeMethodConflict { requested: freeze, active };
+                    return Err(Error::new(kind).with_operation(Operation::Initialize));
+                }
+                state.shares += 1;
+                Ok(Self { shared: true })
+            }
+            None => {
+                Self::initialize_raw(freeze)?;
+                *state = Some(SharedState { shares: 1 });
+                Ok(Self { shared: true })
+            }
+        }
+    }
+
+    /// Check whether this context is a share of a process-wide initialization.
+    pub fn is_shared(&self) -> bool {
+        self.shared
+    }
+
+    /// Get the method of suspending/resuming threads currently in effect,
+    /// which is the one of the latest live override, if any.
+    pub fn thread_freeze_method(&self) -> ThreadFreezeMethod {
+        let state = FREEZE.lock().unwrap_or_else(PoisonError::into_inner);
+        state.as_ref().expect("freeze method is selected during initialization").effective()
+    }
+
+    /// Select another method of suspending/resuming threads.
+    ///
+    /// While overrides are live, the method takes effect once they are
+    /// all dropped. For a shared context, this changes the method for
+    /// all share holders, and new shares have to request this one.
+    pub fn set_thread_freeze_method(&self, freeze: ThreadFreezeMethod) -> Result<()> {
+        let mut state = freeze_state()?;
+        let state = state.as_mut().expect("freeze method is selected during initialization");
+        if state.overrides.is_empty() {
+            select_freeze_method(freeze)?;
+        }
+        state.base = freeze;
+        Ok(())
+    }
+
+    /// Temporarily select another method of suspending/resuming threads.
+    ///
+    /// The method is in effect until the returned guard is dropped, or
+    /// another override is made. Guards may be dropped in any order, after
+    /// which the method of the latest live override, or the one set with
+    /// [`MinHook::set_thread_freeze_method`], is in effect again. As the
+    /// method is process-wide, hook changes made from other threads in
+    /// the meantime use the overriding method as well.
+    pub fn override_thread_freeze_method(&self, freeze: ThreadFreezeMethod)
+        -> Result<FreezeOverride<'_>>
+    {
+        let mut state = freeze_state()?;
+        let state = state.as_mut().expect("freeze method is selected during initialization");
+        select_freeze_method(freeze)?;
+        let id = state.next_override;
+        state.next_override += 1;
+        state.overrides.push((id, freeze));
+        Ok(FreezeOverride { id, _minhook: PhantomData })
+    }
+
+    /// Uninitialize the MinHook library, reporting any failure.
+    /// For a shared context, only does so if this is the last share.
+    ///
+    /// Dropping the context does the same, but ignores errors.
+    pub fn uninitialize(self) -> Result<()> {
+        let result = self.release();
+        std::mem::forget(self);
+        result
+    }
+
+    fn initialize_raw(freeze: ThreadFreezeMethod) -> Result<()> {
+        backend::initialize().into_result_for(Operation::Initialize)?;
+        select_freeze_method(freeze).inspect_err(|_| { let _ = backend::uninitialize(); })?;
+        let state = FreezeState { base: freeze, overrides: Vec::new(), next_override: 0 };
+        *FREEZE.lock().unwrap_or_else(PoisonError::into_inner) = Some(state);
+        Ok(())
+    }
+
+    fn release(&self) -> Result<()> {
+        if self.shared {
+            // Releasing must not be skipped even if another holder panicked,
+            // otherwise the library would never be uninitialized.
+            let mut state = SHARED.lock().unwrap_or_else(PoisonError::into_inner);
+            let shares = state.as_mut().map(|state| {
+                state.shares -= 1;
+                state.shares
+            });
+            if shares != Some(0) {
+                return Ok(());
+            }
+            *state = None;
+        }
+        *FREEZE.lock().unwrap_or_else(PoisonError::into_inner) = None;
+        backend::uninitialize().into_result_for(Operation::Uninitialize)?;
+        registry::clear();
+        Ok(())
+    }
+}
+
+impl Drop for MinHook {
+    fn drop(&mut self) {
+        let _ = self.release();
+    }
+}
+
+/// Guard of a temporarily selected thread freeze method,
+/// see [`MinHook::override_thread_freeze_method`].
+#[derive(Debug)]
+pub struct FreezeOverride<'mh> {
+    id: u64,
+    _minhook: PhantomData<&'mh MinHook>,
+}
+
+impl Drop for FreezeOverride<'_> {
+    fn drop(&mut self) {
+        let mut state = FREEZE.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(state) = state.as_mut() else {
+            return;
+        };
+        let previous = state.effective();
+        state.overrides.retain(|&(id, _)| id != self.id);
+        let current = state.effective();
+        // There is nothing sensible to do on failure.
+        if current != previous {
+            let _ = select_freeze_method(current);
+        }
+    }
+}
+
+/// Select a thread freeze method in the library.
+fn select_freeze_method(freeze: ThreadFreezeMethod) -> Result<()> {
+    backend::current().set_thread_freeze_method(freeze)
+        .into_result_for(Operation::SetThreadFreezeMethod)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{Call, MockBackend};
+
+    const SNAPSHOT: ThreadFreezeMethod = ThreadFreezeMethod::OriginalSnapshot;
+    const NEXT_THREAD: ThreadFreezeMethod = ThreadFreezeMethod::KernelNextThread;
+
+    static MOCK: MockBackend = MockBackend::new();
+
+    fn is_freeze_selected() -> bool {
+        FREEZE.lock().unwrap_or_else(PoisonError::into_inner).is_some()
+    }
+
+    #[test]
+    fn shared_initialization() {
+        let _selection = MOCK.select().unwrap();
+        let first = MinHook::acquire_shared(SNAPSHOT).unwrap();
+        let second = MinHook::acquire_shared(SNAPSHOT).unwrap();
+        assert!(first.is_shared() && second.is_shared());
+        assert_eq!(MOCK.take_calls(), [Call::Initialize, Call::SetThreadFreezeMethod(SNAPSHOT)]);
+        // Exclusive contexts can't be mixed in.
+        let error = MinHook::initialize(SNAPSHOT).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::AlreadyInitialized);
+
+        drop(first);
+        assert!(MOCK.is_initialized());
+        assert_eq!(second.thread_freeze_method(), SNAPSHOT);
+        second.uninitialize().unwrap();
+        assert!(!MOCK.is_initialized());
+        assert!(!is_freeze_selected());
+
+        // The next share initializes the library again.
+        let third = MinHook::acquire_shared(NEXT_THREAD).unwrap();
+        assert_eq!(MOCK.thread_freeze_method(), Some(NEXT_THREAD));
+        drop(third);
+        assert!(!MOCK.is_initialized());
+    }
+
+    #[test]
+    fn shared_freeze_method_conflicts() {
+        let _selection = MOCK.select().unwrap();
+        let first = MinHook::acquire_shared(SNAPSHOT).unwrap();
+        let error = MinHook::acquire_shared(NEXT_THREAD).unwrap_err();
+        let conflict = ErrorKind::FreezeMethodConflict { requested: NEXT_THREAD, active: SNAPSHOT };
+        assert_eq!((error.kind(), error.operation()), (conflict, Some(Operation::Initialize)));
+
+        // Shares have to request the method set since.
+        first.set_thread_freeze_method(NEXT_THREAD).unwrap();
+        let second = MinHook::acquire_shared(NEXT_THREAD).unwrap();
+        assert!(MinHook::acquire_shared(SNAPSHOT).is_err());
+        // The failed requests didn't count as shares.
+        drop(first);
+        assert!(MOCK.is_initialized());
+        drop(second);
+        assert!(!MOCK.is_initialized());
+    }
+
+    #[test]
+    fn overrides_restored_out_of_order() {
+        let none = unsafe { ThreadFreezeMethod::none_unsafe() };
+        let _selection = MOCK.select().unwrap();
+        let minhook = MinHook::initialize(SNAPSHOT).unwrap();
+        let first = minhook.override_thread_freeze_method(NEXT_THREAD).unwrap();
+        let second = minhook.override_thread_freeze_method(none).unwrap();
+        assert_eq!(minhook.thread_freeze_method(), none);
+        // The base method takes effect once all overrides are gone.
+        minhook.set_thread_freeze_method(NEXT_THREAD).unwrap();
+        assert_eq!(minhook.thread_freeze_method(), none);
+        MOCK.take_calls();
+
+        // Dropping an older override leaves the latest one in effect.
+        drop(first);
+        assert_eq!(minhook.thread_freeze_method(), none);
+        assert!(MOCK.take_calls().is_empty());
+        drop(second);
+        assert_eq!(minhook.thread_freeze_method(), NEXT_THREAD);
+        assert_eq!(MOCK.take_calls(), [Call::SetThreadFreezeMethod(NEXT_THREAD)]);
+
+        let first = minhook.override_thread_freeze_method(SNAPSHOT).unwrap();
+        let second = minhook.override_thread_freeze_method(none).unwrap();
+        MOCK.take_calls();
+        // Dropping the latest override restores the previous live one.
+        drop(second);
+        assert_eq!(MOCK.take_calls(), [Call::SetThreadFreezeMethod(SNAPSHOT)]);
+        drop(first);
+        assert_eq!(MOCK.thread_freeze_method(), Some(NEXT_THREAD));
+    }
+
+    #[test]
+    fn freeze_method_selected_while_contexts_live() {
+        let _selection = MOCK.select().unwrap();
+        // No context is handed out if selecting the method fails.
+        MOCK.fail_next(Some(Operation::SetThreadFreezeMethod), ErrorKind::MutexFailure);
+        let error = MinHook::initialize(SNAPSHOT).unwrap_err();
+        assert_eq!(error.operation(), Some(Operation::SetThreadFreezeMethod));
+        assert!(!MOCK.is_initialized());
+        assert!(!is_freeze_selected());
+        MOCK.fail_next(Some(Operation::SetThreadFreezeMethod), ErrorKind::MutexFailure);
+        assert!(MinHook::acquire_shared(SNAPSHOT).is_err());
+        assert!(!is_freeze_selected());
+
+        // Failing to initialize again leaves the method of the live context.
+        let minhook = MinHook::initialize(SNAPSHOT).unwrap();
+        assert!(MinHook::initialize(NEXT_THREAD).is_err());
+        assert!(MinHook::acquire_shared(NEXT_THREAD).is_err());
+        assert_eq!(minhook.thread_freeze_method(), SNAPSHOT);
+        minhook.set_thread_freeze_method(NEXT_THREAD).unwrap();
+        drop(minhook.override_thread_freeze_method(SNAPSHOT).unwrap());
+
+        // Uninitializing forgets the method, and the next context selects its own.
+        drop(minhook);
+        assert!(!is_freeze_selected());
+        let minhook = MinHook::initialize(SNAPSHOT).unwrap();
+        assert_eq!(minhook.thread_freeze_method(), SNAPSHOT);
+    }
+}
@@ -0,0 +1,1001 @@
+//! Instruction length decoder for x86 and x86-64.
+//!
+//! Fills the same role as the HDE decoder compiled into the library:
+//! it finds instruction boundaries and the parts of an instruction
+//! which have to be patched when it is moved, without disassembling
+//! operands any further. Unlike HDE, it also understands VEX, EVEX and
+//! XOP encoded instructions, which appear in optimized prologues.
+
+/// Maximum length of a single instruction, as enforced by the processor.
+pub const MAX_INSTRUCTION_LEN: usize = 15;
+
+/// Processor mode to decode instructions for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Bitness {
+    /// 32-bit protected mode.
+    Bits32,
+    /// 64-bit long mode.
+    Bits64,
+}
+
+impl Bitness {
+    /// Mode of the current process.
+    #[cfg(target_pointer_width = "64")]
+    pub const NATIVE: Self = Self::Bits64;
+    /// Mode of the current process.
+    #[cfg(not(target_pointer_width = "64"))]
+    pub const NATIVE: Self = Self::Bits32;
+}
+
+/// Failure to decode an instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The buffer ends in the middle of the instruction.
+    Truncated,
+    /// The instruction is longer than [`MAX_INSTRUCTION_LEN`].
+    TooLong,
+    /// The opcode is not valid in the decoded mode.
+    Invalid,
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Truncated => "instruction is truncated",
+            Self::TooLong => "instruction exceeds the maximum length",
+            Self::Invalid => "invalid instruction",
+        })
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Set of legacy prefixes of an instruction.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Prefixes(u8);
+
+impl Prefixes {
+    /// `F0` bus lock.
+    pub const LOCK: Self = Self(1 << 0);
+    /// `F2` repeat while not equal, also a mandatory SSE prefix.
+    pub const REPNE: Self = Self(1 << 1);
+    /// `F3` repeat while equal, also a mandatory SSE prefix.
+    pub const REP: Self = Self(1 << 2);
+    /// `66` operand size override, also a mandatory SSE prefix.
+    pub const OPERAND_SIZE: Self = Self(1 << 3);
+    /// `67` address size override.
+    pub const ADDRESS_SIZE: Self = Self(1 << 4);
+    /// Any of the `2E`, `36`, `3E`, `26`, `64` or `65` segment overrides.
+    pub const SEGMENT: Self = Self(1 << 5);
+
+    /// Check whether all prefixes of `other` are present.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Check whether there are no prefixes.
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Get the raw set of flags.
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    fn insert(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+}
+
+/// Encoding scheme of an instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    /// Legacy and REX encoded instructions.
+    Legacy,
+    /// Two or three byte `C5`/`C4` prefix.
+    Vex,
+    /// Four byte `62` prefix.
+    Evex,
+    /// AMD three byte `8F` prefix.
+    Xop,
+}
+
+/// Opcode map an instruction's opcode byte belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum OpcodeMap {
+    /// One byte opcodes.
+    Primary,
+    /// Opcodes escaped by `0F`.
+    Map0F,
+    /// Opcodes escaped by `0F 38`.
+    Map0F38,
+    /// Opcodes escaped by `0F 3A`.
+    Map0F3A,
+    /// EVEX map 5.
+    Map5,
+    /// EVEX map 6.
+    Map6,
+    /// XOP map 8.
+    Xop8,
+    /// XOP map 9.
+    Xop9,
+    /// XOP map 10.
+    XopA,
+}
+
+/// Kind of a branch with an operand relative to the next instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BranchKind {
+    /// Unconditional `jmp`.
+    Jump,
+    /// Near `call`.
+    Call,
+    /// `jcc`, and `xbegin` whose operand is the abort handler.
+    Conditional,
+    /// `loop`, `loope`, `loopne` and `jcxz`/`jecxz`/`jrcxz`,
+    /// which only exist with an 8-bit operand.
+    Loop,
+}
+
+/// Location and value of a displacement or immediate in an instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Field {
+    offset: u8,
+    size: u8,
+    value: i64,
+}
+
+impl Field {
+    /// Get the offset of the field from the start of the instruction.
+    pub fn offset(&self) -> usize {
+        self.offset as usize
+    }
+
+    /// Get the size of the field in bytes.
+    pub fn size(&self) -> usize {
+        self.size as usize
+    }
+
+    /// Get the sign-extended value of the field.
+    pub fn value(&self) -> i64 {
+        self.value
+    }
+}
+
+/// A decoded instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Instruction {
+    len: u8,
+    bitness: Bitness,
+    prefixes: Prefixes,
+    segment: Option<u8>,
+    rex: Option<u8>,
+    encoding: Encoding,
+    map: OpcodeMap,
+    opcode: u8,
+    modrm: Option<u8>,
+    sib: Option<u8>,
+    displacement: Option<Field>,
+    immediate: Option<Field>,
+    second_immediate: Option<Field>,
+    branch: Option<BranchKind>,
+    rip_relative: bool,
+}
+
+impl Instruction {
+    /// Get the length of the instruction in bytes, which is never zero.
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    /// Get the mode the instruction was decoded for.
+    pub fn bitness(&self) -> Bitness {
+        self.bitness
+    }
+
+    /// Get the legacy prefixes.
+    pub fn prefixes(&self) -> Prefixes {
+        self.prefixes
+    }
+
+    /// Get the last segment override prefix byte, if any.
+    pub fn segment(&self) -> Option<u8> {
+        self.segment
+    }
+
+    /// Get the REX prefix byte, if any.
+    pub fn rex(&self) -> Option<u8> {
+        self.rex
+    }
+
+    /// Check whether REX.W selects a 64-bit operand size.
+    pub fn rex_w(&self) -> bool {
+        self.rex.is_some_and(|rex| rex & 0x08 != 0)
+    }
+
+    /// Get the encoding scheme.
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// Get the opcode map of the opcode byte.
+    pub fn opcode_map(&self) -> OpcodeMap {
+        self.map
+    }
+
+    /// Get the opcode byte, following any escapes.
+    pub fn opcode(&self) -> u8 {
+        self.opcode
+    }
+
+    /// Get the ModRM byte, if any.
+    pub fn modrm(&self) -> Option<u8> {
+        self.modrm
+    }
+
+    /// Get the `reg` field of the ModRM byte, which
+    /// selects the operation of group opcodes.
+    pub fn modrm_reg(&self) -> Option<u8> {
+        self.modrm.map(|modrm| (modrm >> 3) & 7)
+    }
+
+    /// Get the SIB byte, if any.
+    pub fn sib(&self) -> Option<u8> {
+        self.sib
+    }
+
+    /// Get the memory operand displacement, if any.
+    pub fn displacement(&self) -> Option<Field> {
+        self.displacement
+    }
+
+    /// Get the immediate operand, if any. For relative
+    /// branches, this is the offset of the destination.
+    pub fn immediate(&self) -> Option<Field> {
+        self.immediate
+    }
+
+    /// Get the second immediate operand of `enter`, as well as the
+    /// selector of far `call` and `jmp` to an absolute address.
+    pub fn second_immediate(&self) -> Option<Field> {
+        self.second_immediate
+    }
+
+    /// Get the kind of the branch if this is one with
+    /// an operand relative to the next instruction.
+    pub fn relative_branch(&self) -> Option<BranchKind> {
+        self.branch
+    }
+
+    /// Check whether the memory operand is addressed relative to the
+    /// next instruction, which is only the case in 64-bit mode.
+    pub fn is_rip_relative(&self) -> bool {
+        self.rip_relative
+    }
+
+    /// Get the destination of a relative branch.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - address the instruction is located at.
+    pub fn branch_target(&self, address: usize) -> Option<usize> {
+        self.branch?;
+        let offset = self.immediate?.value;
+        Some(self.wrap(address.wrapping_add(self.len()).wrapping_add(offset as usize)))
+    }
+
+    /// Get the address of a RIP-relative memory operand.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - address the instruction is located at.
+    pub fn rip_target(&self, address: usize) -> Option<usize> {
+        if !self.rip_relative {
+            return None;
+        }
+        let offset = self.displacement?.value;
+        Some(self.wrap(address.wrapping_add(self.len()).wrapping_add(offset as usize)))
+    }
+
+    /// Truncate an address to the size of the instruction pointer.
+    fn wrap(&self, address: usize) -> usize {
+        match self.bitness {
+            Bitness::Bits32 => address as u32 as usize,
+            Bitness::Bits64 => address,
+        }
+    }
+}
+
+/// Decode the instruction at the start of a buffer.
+///
+/// # Arguments
+///
+/// * `code` - bytes of the instruction, which may be followed by any others.
+/// * `bitness` - mode to decode the instruction for.
+pub fn decode(code: &[u8], bitness: Bitness) -> Result<Instruction, DecodeError> {
+    Decoder { code, position: 0, bitness }.decode()
+}
+
+/// Decode consecutive instructions until the end of a buffer.
+///
+/// Stops at the first instruction which fails to decode,
+/// yielding its error.
+pub fn instructions(code: &[u8], bitness: Bitness)
+    -> impl Iterator<Item = Result<(usize, Instruction), DecodeError>> + '_
+{
+    let mut offset = 0;
+    let mut failed = false;
+    std::iter::from_fn(move || {
+        if failed || offset >= code.len() {
+            return None;
+        }
+        match decode(&code[offset..], bitness) {
+            Ok(instruction) => {
+                let item = (offset, instruction);
+                offset += instruction.len();
+                Some(Ok(item))
+            }
+            Err(error) => {
+                failed = true;
+                Some(Err(error))
+            }
+        }
+    })
+}
+
+// Operand layout of an opcode, a ModRM flag and at most one immediate kind.
+const NONE: u16 = 0;
+const MODRM: u16 = 1 << 0;
+const IMM8: u16 = 1 << 1;
+const IMM16: u16 = 1 << 2;
+const IMM32: u16 = 1 << 3;
+/// 16 or 32 bits depending on the operand size.
+const IMMZ: u16 = 1 << 4;
+/// 16, 32 or 64 bits depending on the operand size.
+const IMMV: u16 = 1 << 5;
+/// Absolute memory offset of address size.
+const MOFFS: u16 = 1 << 6;
+const REL8: u16 = 1 << 7;
+/// 16 or 32 bits depending on the operand size, always 32 in 64-bit mode.
+const RELZ: u16 = 1 << 8;
+/// 16-bit frame size followed by an 8-bit nesting level of `enter`.
+const ENTER: u16 = 1 << 9;
+/// Offset of operand size followed by a 16-bit selector.
+const FAR: u16 = 1 << 10;
+/// 16 or 32 bits depending on the operand size, also in 64-bit mode,
+/// as the offset of `xbegin` is the only one not fixed there.
+const RELX: u16 = 1 << 11;
+
+/// Operand layout of primary opcodes, or `None` if invalid in 64-bit mode.
+fn primary_operands(opcode: u8, bitness: Bitness) -> Option<u16> {
+    let long = bitness == Bitness::Bits64;
+    let legacy_only = |operands| (!long).then_some(operands);
+    Some(match opcode {
+        0x06 | 0x07 | 0x0E | 0x16 | 0x17 | 0x1E | 0x1F |
+        0x27 | 0x2F | 0x37 | 0x3F | 0x60 | 0x61 | 0xCE | 0xD6 => legacy_only(NONE)?,
+        0x82 => legacy_only(MODRM | IMM8)?,
+        0xD4 | 0xD5 => legacy_only(IMM8)?,
+        0x9A | 0xEA => legacy_only(FAR)?,
+        // BOUND, LES and LDS, which are reinterpreted as EVEX and VEX
+        // prefixes before getting here when that is possible.
+        0x62 | 0xC4 | 0xC5 => legacy_only(MODRM)?,
+
+        0x00..=0x3F => match opcode & 7 {
+            0..=3 => MODRM,
+            4 => IMM8,
+            5 => IMMZ,
+            _ => NONE,
+        },
+        0x40..=0x5F => NONE,
+        0x63 => MODRM,
+        0x68 => IMMZ,
+        0x69 => MODRM | IMMZ,
+        0x6A => IMM8,
+        0x6B => MODRM | IMM8,
+        0x6C..=0x6F => NONE,
+        0x70..=0x7F => REL8,
+        0x80 | 0x83 => MODRM | IMM8,
+        0x81 => MODRM | IMMZ,
+        0x84..=0x8F => MODRM,
+        0x90..=0x9F => NONE,
+        0xA0..=0xA3 => MOFFS,
+        0xA4..=0xA7 | 0xAA..=0xAF => NONE,
+        0xA8 => IMM8,
+        0xA9 => IMMZ,
+        0xB0..=0xB7 => IMM8,
+        0xB8..=0xBF => IMMV,
+        0xC0 | 0xC1 => MODRM | IMM8,
+        0xC2 | 0xCA => IMM16,
+        0xC6 => MODRM | IMM8,
+        0xC7 => MODRM | IMMZ,
+        0xC8 => ENTER,
+        0xC3 | 0xC9 | 0xCB | 0xCC | 0xCF => NONE,
+        0xCD => IMM8,
+        0xD0..=0xD3 => MODRM,
+        0xD7 => NONE,
+        0xD8..=0xDF => MODRM,
+        0xE0..=0xE3 | 0xEB => REL8,
+        0xE4..=0xE7 => IMM8,
+        0xE8 | 0xE9 => RELZ,
+        0xEC..=0xEF => NONE,
+        0xF1 | 0xF4 | 0xF5 => NONE,
+        // Immediates of the TEST forms are added once ModRM is known.
+        0xF6 | 0xF7 | 0xFE | 0xFF => MODRM,
+        0xF8..=0xFD => NONE,
+        _ => return None,
+    })
+}
+
+/// Operand layout of opcodes escaped by `0F`, or `None` if invalid.
+fn map_0f_operands(opcode: u8) -> Option<u16> {
+    Some(match opcode {
+        0x04 | 0x0A | 0x0C | 0x24..=0x27 | 0x36 | 0x39 | 0x3B..=0x3F |
+        0x7A | 0x7B | 0xA6 | 0xA7 => return None,
+        // Escapes to three byte maps, handled by the caller.
+        0x38 | 0x3A => return None,
+        0x05..=0x09 | 0x0B | 0x0E => NONE,
+        0x30..=0x37 | 0x77 | 0xA0..=0xA2 | 0xA8..=0xAA | 0xC8..=0xCF => NONE,
+        // 3DNow! instructions have an opcode suffix in the immediate position.
+        0x0F => MODRM | IMM8,
+        0x70..=0x73 | 0xA4 | 0xAC | 0xBA | 0xC2 | 0xC4..=0xC6 => MODRM | IMM8,
+        0x80..=0x8F => RELZ,
+        _ => MODRM,
+    })
+}
+
+/// Operand layout of VEX, EVEX and XOP opcodes.
+fn extended_operands(map: OpcodeMap, opcode: u8) -> u16 {
+    match map {
+        OpcodeMap::Map0F => match opcode {
+            // vzeroupper and vzeroall have no operands at all.
+            0x77 => NONE,
+            0x70..=0x73 | 0xC2 | 0xC4..=0xC6 => MODRM | IMM8,
+            _ => MODRM,
+        },
+        OpcodeMap::Map0F3A | OpcodeMap::Xop8 => MODRM | IMM8,
+        OpcodeMap::XopA => MODRM | IMM32,
+        _ => MODRM,
+    }
+}
+
+struct Decoder<'a> {
+    code: &'a [u8],
+    position: usize,
+    bitness: Bitness,
+}
+
+impl Decoder<'_> {
+    fn decode(mut self) -> Result<Instruction, DecodeError> {
+        let long = self.bitness == Bitness::Bits64;
+        let mut prefixes = Prefixes::default();
+        let mut segment = None;
+        let mut rex = None;
+
+        loop {
+            let byte = self.peek(0)?;
+            let prefix = match byte {
+                0xF0 => Prefixes::LOCK,
+                0xF2 => Prefixes::REPNE,
+                0xF3 => Prefixes::REP,
+                0x66 => Prefixes::OPERAND_SIZE,
+                0x67 => Prefixes::ADDRESS_SIZE,
+                0x26 | 0x2E | 0x36 | 0x3E | 0x64 | 0x65 => {
+                    segment = Some(byte);
+                    Prefixes::SEGMENT
+                }
+                0x40..=0x4F if long => {
+                    self.position += 1;
+                    rex = Some(byte);
+                    continue;
+                }
+                _ => break,
+            };
+            self.position += 1;
+            prefixes.insert(prefix);
+            // REX only takes effect immediately before the opcode.
+            rex = None;
+        }
+
+        let rex_w = rex.is_some_and(|rex| rex & 0x08 != 0);
+        let operand16 = prefixes.contains(Prefixes::OPERAND_SIZE) && !rex_w;
+        let address_size = match (self.bitness, prefixes.contains(Prefixes::ADDRESS_SIZE)) {
+            (Bitness::Bits64, false) => 8,
+            (Bitness::Bits64, true) | (Bitness::Bits32, false) => 4,
+            (Bitness::Bits32, true) => 2,
+        };
+
+        let mut encoding = Encoding::Legacy;
+        let mut map = OpcodeMap::Primary;
+        let first = self.next()?;
+        let operands = match first {
+            0xC4 | 0xC5 | 0x62 if long || self.peek(0)? >= 0xC0 => {
+                // Mandatory prefixes are encoded in the payload instead.
+                let forbidden = Prefixes::LOCK.0 | Prefixes::REPNE.0
+                    | Prefixes::REP.0 | Prefixes::OPERAND_SIZE.0;
+                if prefixes.0 & forbidden != 0 || rex.is_some() {
+                    return Err(DecodeError::Invalid);
+                }
+                let (extended_encoding, payload) = match first {
+                    0xC5 => (Encoding::Vex, 1),
+                    0xC4 => (Encoding::Vex, 2),
+                    _ => (Encoding::Evex, 3),
+                };
+                encoding = extended_encoding;
+                // VEX selects the map with five bits, EVEX with three.
+                let selector = self.peek(0)? & if first == 0x62 { 0x07 } else { 0x1F };
+                map = match (first, selector) {
+                    (0xC5, _) => OpcodeMap::Map0F,
+                    (0xC4, 1) | (0x62, 1) => OpcodeMap::Map0F,
+                    (0xC4, 2) | (0x62, 2) => OpcodeMap::Map0F38,
+                    (0xC4, 3) | (0x62, 3) => OpcodeMap::Map0F3A,
+                    (0x62, 5) => OpcodeMap::Map5,
+                    (0x62, 6) => OpcodeMap::Map6,
+                    _ => return Err(DecodeError::Invalid),
+                };
+                self.skip(payload)?;
+                None
+            }
+            0x8F if self.peek(0)? & 0x1F >= 8 => {
+                encoding = Encoding::Xop;
+                map = match self.peek(0)? & 0x1F {
+                    8 => OpcodeMap::Xop8,
+                    9 => OpcodeMap::Xop9,
+                    10 => OpcodeMap::XopA,
+                    _ => return Err(DecodeError::Invalid),
+                };
+                self.skip(2)?;
+                None
+            }
+            0x0F => Some(match self.next()? {
+                0x38 => {
+                    map = OpcodeMap::Map0F38;
+                    MODRM
+                }
+                0x3A => {
+                    map = OpcodeMap::Map0F3A;
+                    MODRM | IMM8
+                }
+                opcode => {
+                    map = OpcodeMap::Map0F;
+                    self.position -= 1;
+                    map_0f_operands(opcode).ok_or(DecodeError::Invalid)?
+                }
+            }),
+            opcode => {
+                self.position -= 1;
+                Some(primary_operands(opcode, self.bitness).ok_or(DecodeError::Invalid)?)
+            }
+        };
+
+        let opcode = self.next()?;
+        let mut operands = operands.unwrap_or_else(|| extended_operands(map, opcode));
+
+        let mut modrm = None;
+        let mut sib = None;
+        let mut displacement_size = 0;
+        let mut rip_relative = false;
+        if operands & MODRM != 0 {
+            let byte = self.next()?;
+            modrm = Some(byte);
+            let (mode, reg, rm) = (byte >> 6, (byte >> 3) & 7, byte & 7);
+            if mode != 3 {
+                displacement_size = if address_size == 2 {
+                    match (mode, rm) {
+                        (0, 6) | (2, _) => 2,
+                        (1, _) => 1,
+                        _ => 0,
+                    }
+                } else {
+                    if rm == 4 {
+                        let byte = self.next()?;
+                        sib = Some(byte);
+                        if mode == 0 && byte & 7 == 5 {
+                            displacement_size = 4;
+                        }
+                    }
+                    match (mode, rm) {
+                        (0, 5) => {
+                            rip_relative = long;
+                            4
+                        }
+                        (1, _) => 1,
+                        (2, _) => 4,
+                        _ => displacement_size,
+                    }
+                };
+            }
+
+            if map == OpcodeMap::Primary {
+                match (opcode, reg) {
+                    (0xF6, 0 | 1) => operands |= IMM8,
+                    (0xF7, 0 | 1) => operands |= IMMZ,
+                    // xbegin, with the abort handler in place of the immediate.
+                    (0xC7, _) if byte == 0xF8 => operands = MODRM | RELX,
+                    _ => (),
+                }
+            }
+        }
+
+        let displacement = self.field(displacement_size)?;
+
+        let branch = match (map, opcode) {
+            _ if operands & (REL8 | RELZ | RELX) == 0 => None,
+            (OpcodeMap::Primary, 0xE8) => Some(BranchKind::Call),
+            (OpcodeMap::Primary, 0xE9 | 0xEB) => Some(BranchKind::Jump),
+            (OpcodeMap::Primary, 0xE0..=0xE3) => Some(BranchKind::Loop),
+            _ => Some(BranchKind::Conditional),
+        };
+
+        let operand_size = if operand16 { 2 } else { 4 };
+        let immediate_size = match operands & !MODRM {
+            IMM8 | REL8 => 1,
+            IMM16 | ENTER => 2,
+            IMM32 => 4,
+            IMMZ | FAR => operand_size,
+            IMMV if rex_w => 8,
+            IMMV => operand_size,
+            MOFFS => address_size,
+            RELZ if long => 4,
+            RELZ | RELX => operand_size,
+            _ => 0,
+        };
+        let second_size = match operands & !MODRM {
+            ENTER => 1,
+            FAR => 2,
+            _ => 0,
+        };
+        let immediate = self.field(immediate_size)?;
+        let second_immediate = self.field(second_size)?;
+
+        Ok(Instruction {
+            len: self.position as u8,
+            bitness: self.bitness,
+            prefixes,
+            segment,
+            rex,
+            encoding,
+            map,
+            opcode,
+            modrm,
+            sib,
+            displacement,
+            immediate,
+            second_immediate,
+            branch,
+            rip_relative,
+        })
+    }
+
+    fn peek(&self, ahead: usize) -> Result<u8, DecodeError> {
+        let position = self.position + ahead;
+        if position >= MAX_INSTRUCTION_LEN {
+            return Err(DecodeError::TooLong);
+        }
+        self.code.get(position).copied().ok_or(DecodeError::Truncated)
+    }
+
+    fn next(&mut self) -> Result<u8, DecodeError> {
+        let byte = self.peek(0)?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn skip(&mut self, count: usize) -> Result<(), DecodeError> {
+        for _ in 0..count {
+            self.next()?;
+        }
+        Ok(())
+    }
+
+    /// Read a little-endian field of `size` bytes, if it's not empty.
+    fn field(&mut self, size: usize) -> Result<Option<Field>, DecodeError> {
+        if size == 0 {
+            return Ok(None);
+        }
+        let offset = self.position as u8;
+        let mut raw = 0u64;
+        for index in 0..size {
+            raw |= (self.next()? as u64) << (index * 8);
+        }
+        let shift = 64 - size * 8;
+        let value = ((raw << shift) as i64) >> shift;
+        Ok(Some(Field { offset, size: size as u8, value }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Instruction, and the offset and size of its displacement
+    /// and immediate. The instruction fills its whole buffer.
+    type Case = (&'static [u8], Option<(usize, usize)>, Option<(usize, usize)>);
+
+    fn check(cases: &[Case], bitness: Bitness) {
+        let layout = |field: Option<Field>| field.map(|field| (field.offset(), field.size()));
+        for &(code, displacement, immediate) in cases {
+            let instruction = decode(code, bitness)
+                .unwrap_or_else(|error| panic!("{:02X?}: {}", code, error));
+            let displacement_layout = layout(instruction.displacement());
+            let immediate_layout = layout(instruction.immediate());
+            assert_eq!((instruction.len(), displacement_layout, immediate_layout),
+                (code.len(), displacement, immediate), "{:02X?}", code);
+        }
+    }
+
+    const PRIMARY: &[Case] = &[
+        (&[0x90], None, None),
+        (&[0x41, 0x54], None, None),
+        (&[0x48, 0x89, 0xE5], None, None),
+        (&[0x48, 0x83, 0xEC, 0x28], None, Some((3, 1))),
+        (&[0x48, 0x81, 0xEC, 0x00, 0x10, 0x00, 0x00], None, Some((3, 4))),
+        (&[0xB8, 0x78, 0x56, 0x34, 0x12], None, Some((1, 4))),
+        (&[0x48, 0xB8, 0xF0, 0xDE, 0xBC, 0x9A, 0x78, 0x56, 0x34, 0x12], None, Some((2, 8))),
+        (&[0x66, 0xB8, 0x34, 0x12], None, Some((2, 2))),
+        (&[0x48, 0xC7, 0xC0, 0xFF, 0xFF, 0xFF, 0xFF], None, Some((3, 4))),
+        (&[0xA1, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11], None, Some((1, 8))),
+        (&[0x67, 0xA1, 0x88, 0x77, 0x66, 0x55], None, Some((2, 4))),
+        (&[0xC6, 0x40, 0x01, 0x05], Some((2, 1)), Some((3, 1))),
+        (&[0x66, 0xC7, 0x00, 0x34, 0x12], None, Some((3, 2))),
+        (&[0xC7, 0x05, 0x20, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00], Some((2, 4)), Some((6, 4))),
+        (&[0xF6, 0x00, 0x01], None, Some((2, 1))),
+        (&[0xF7, 0x00, 0x01, 0x00, 0x00, 0x00], None, Some((2, 4))),
+        (&[0x66, 0xF7, 0x00, 0x01, 0x00], None, Some((3, 2))),
+        (&[0xF7, 0x10], None, None),
+        (&[0xC8, 0x10, 0x00, 0x01], None, Some((1, 2))),
+        (&[0xC2, 0x08, 0x00], None, Some((1, 2))),
+        (&[0xCD, 0x2E], None, Some((1, 1))),
+        (&[0x6B, 0xC1, 0x10], None, Some((2, 1))),
+        (&[0x69, 0xC1, 0x00, 0x10, 0x00, 0x00], None, Some((2, 4))),
+        (&[0x6A, 0x10], None, Some((1, 1))),
+        (&[0x68, 0x00, 0x10, 0x00, 0x00], None, Some((1, 4))),
+        (&[0xF3, 0xA4], None, None),
+        (&[0x65, 0x48, 0x8B, 0x04, 0x25, 0x30, 0x00, 0x00, 0x00], Some((5, 4)), None),
+        (&[0xEB, 0x10], None, Some((1, 1))),
+        (&[0xE8, 0x00, 0x00, 0x00, 0x00], None, Some((1, 4))),
+        (&[0x74, 0xFE], None, Some((1, 1))),
+        (&[0xE3, 0x10], None, Some((1, 1))),
+    ];
+
+    const MODRM_SIB: &[Case] = &[
+        (&[0x8B, 0x00], None, None),
+        (&[0x8B, 0xC1], None, None),
+        (&[0x8B, 0x45, 0x00], Some((2, 1)), None),
+        (&[0x8B, 0x85, 0x00, 0x01, 0x00, 0x00], Some((2, 4)), None),
+        (&[0x8B, 0x05, 0x00, 0x01, 0x00, 0x00], Some((2, 4)), None),
+        (&[0x8B, 0x04, 0x24], None, None),
+        (&[0x8B, 0x44, 0x24, 0x08], Some((3, 1)), None),
+        (&[0x8B, 0x84, 0x24, 0x00, 0x08, 0x00, 0x00], Some((3, 4)), None),
+        (&[0x8B, 0x04, 0x25, 0x30, 0x00, 0x00, 0x00], Some((3, 4)), None),
+        (&[0x8B, 0x04, 0x85, 0x10, 0x00, 0x00, 0x00], Some((3, 4)), None),
+        (&[0x8B, 0x44, 0x85, 0x10], Some((3, 1)), None),
+        (&[0x8B, 0x04, 0x98], None, None),
+        (&[0x41, 0x8B, 0x45, 0x00], Some((3, 1)), None),
+        (&[0x67, 0x8B, 0x00], None, None),
+        (&[0x67, 0x8B, 0x05, 0x10, 0x00, 0x00, 0x00], Some((3, 4)), None),
+    ];
+
+    const MAP_0F: &[Case] = &[
+        (&[0x0F, 0x05], None, None),
+        (&[0x0F, 0xA2], None, None),
+        (&[0x0F, 0x0B], None, None),
+        (&[0x0F, 0xB6, 0x01], None, None),
+        (&[0x0F, 0x45, 0xC1], None, None),
+        (&[0x0F, 0xBA, 0xE0, 0x03], None, Some((3, 1))),
+        (&[0x0F, 0xA4, 0xC8, 0x04], None, Some((3, 1))),
+        (&[0x0F, 0xC2, 0xC1, 0x00], None, Some((3, 1))),
+        (&[0x0F, 0x0F, 0xC1, 0x9E], None, Some((3, 1))),
+        (&[0xF3, 0x0F, 0x1E, 0xFA], None, None),
+        (&[0xF0, 0x0F, 0xC1, 0x01], None, None),
+        (&[0x0F, 0xC7, 0x0E], None, None),
+        (&[0x0F, 0x1F, 0x44, 0x00, 0x00], Some((4, 1)), None),
+        (&[0x66, 0x0F, 0x1F, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00], Some((5, 4)), None),
+        (&[0x66, 0x0F, 0x70, 0xC1, 0x1B], None, Some((4, 1))),
+        (&[0x66, 0x0F, 0x6F, 0x05, 0x40, 0x00, 0x00, 0x00], Some((4, 4)), None),
+        (&[0x0F, 0x11, 0x74, 0x24, 0x20], Some((4, 1)), None),
+        (&[0x0F, 0x84, 0x10, 0x00, 0x00, 0x00], None, Some((2, 4))),
+    ];
+
+    const MAP_0F38_0F3A: &[Case] = &[
+        (&[0x66, 0x0F, 0x38, 0x00, 0xC1], None, None),
+        (&[0xF2, 0x0F, 0x38, 0xF0, 0x01], None, None),
+        (&[0x66, 0x0F, 0x38, 0x00, 0x44, 0x24, 0x10], Some((6, 1)), None),
+        (&[0x66, 0x0F, 0x3A, 0x0F, 0xC1, 0x04], None, Some((5, 1))),
+        (&[0x66, 0x0F, 0x3A, 0x16, 0xC8, 0x02], None, Some((5, 1))),
+        (&[0x66, 0x0F, 0x3A, 0x0F, 0x05, 0x00, 0x00, 0x00, 0x00, 0x04], Some((5, 4)), Some((9, 1))),
+    ];
+
+    const EXTENDED: &[Case] = &[
+        (&[0xC5, 0xF8, 0x77], None, None),
+        (&[0xC5, 0xFE, 0x6F, 0x01], None, None),
+        (&[0xC5, 0xF9, 0x70, 0xC1, 0x1B], None, Some((4, 1))),
+        (&[0xC4, 0x41, 0x7E, 0x6F, 0x41, 0x40], Some((5, 1)), None),
+        (&[0xC4, 0xE2, 0x75, 0x00, 0xC2], None, None),
+        (&[0xC4, 0xE2, 0x75, 0xB8, 0x05, 0x80, 0x00, 0x00, 0x00], Some((5, 4)), None),
+        (&[0xC4, 0xE3, 0xFD, 0x00, 0xC1, 0x4E], None, Some((5, 1))),
+        (&[0xC4, 0xE3, 0x75, 0x0C, 0xC2, 0x05], None, Some((5, 1))),
+        (&[0x62, 0xF1, 0x7E, 0x48, 0x6F, 0x01], None, None),
+        (&[0x62, 0xF1, 0x7E, 0x48, 0x6F, 0x41, 0x01], Some((6, 1)), None),
+        (&[0x62, 0xF1, 0x74, 0x48, 0x58, 0x05, 0x00, 0x01, 0x00, 0x00], Some((6, 4)), None),
+        (&[0x62, 0xF3, 0x75, 0x48, 0x25, 0xC2, 0xFF], None, Some((6, 1))),
+        (&[0x62, 0xF5, 0x74, 0x48, 0x58, 0xC2], None, None),
+        (&[0x8F, 0xE8, 0x78, 0xC2, 0xC1, 0x05], None, Some((5, 1))),
+        (&[0x8F, 0xE9, 0x78, 0x80, 0xC1], None, None),
+        (&[0x8F, 0xEA, 0x78, 0x10, 0xC1, 0x04, 0x00, 0x00, 0x00], None, Some((5, 4))),
+    ];
+
+    const LEGACY_32: &[Case] = &[
+        (&[0x06], None, None),
+        (&[0x60], None, None),
+        (&[0x40], None, None),
+        (&[0xD4, 0x0A], None, Some((1, 1))),
+        (&[0x82, 0xC0, 0x01], None, Some((2, 1))),
+        (&[0x9A, 0x78, 0x56, 0x34, 0x12, 0x00, 0x10], None, Some((1, 4))),
+        (&[0x66, 0xEA, 0x34, 0x12, 0x00, 0x10], None, Some((2, 2))),
+        (&[0xC4, 0x01], None, None),
+        (&[0xC5, 0x06], None, None),
+        (&[0x62, 0x01], None, None),
+        (&[0xC5, 0xF8, 0x77], None, None),
+        (&[0x8B, 0x05, 0x00, 0x10, 0x00, 0x00], Some((2, 4)), None),
+        (&[0xA1, 0x00, 0x10, 0x00, 0x00], None, Some((1, 4))),
+        (&[0x67, 0xA1, 0x34, 0x12], None, Some((2, 2))),
+        (&[0x67, 0x8B, 0x00], None, None),
+        (&[0x67, 0x8B, 0x46, 0x10], Some((3, 1)), None),
+        (&[0x67, 0x8B, 0x06, 0x34, 0x12], Some((3, 2)), None),
+        (&[0x67, 0x8B, 0x87, 0x00, 0x01], Some((3, 2)), None),
+        (&[0x66, 0xE8, 0x34, 0x12], None, Some((2, 2))),
+        (&[0x66, 0x0F, 0x84, 0x34, 0x12], None, Some((3, 2))),
+        (&[0xE9, 0x00, 0x00, 0x00, 0x00], None, Some((1, 4))),
+    ];
+
+    #[test]
+    fn primary_lengths() {
+        check(PRIMARY, Bitness::Bits64);
+    }
+
+    #[test]
+    fn modrm_sib_lengths() {
+        check(MODRM_SIB, Bitness::Bits64);
+    }
+
+    #[test]
+    fn map_0f_lengths() {
+        check(MAP_0F, Bitness::Bits64);
+    }
+
+    #[test]
+    fn map_0f38_0f3a_lengths() {
+        check(MAP_0F38_0F3A, Bitness::Bits64);
+    }
+
+    #[test]
+    fn extended_lengths() {
+        check(EXTENDED, Bitness::Bits64);
+    }
+
+    #[test]
+    fn legacy_32_lengths() {
+        check(LEGACY_32, Bitness::Bits32);
+    }
+
+    #[test]
+    fn xbegin_offset_follows_operand_size() {
+        for bitness in [Bitness::Bits32, Bitness::Bits64] {
+            check(&[
+                (&[0xC7, 0xF8, 0x00, 0x01, 0x00, 0x00], None, Some((2, 4))),
+                (&[0x66, 0xC7, 0xF8, 0x10, 0x00], None, Some((3, 2))),
+            ], bitness);
+            let instruction = decode(&[0x66, 0xC7, 0xF8, 0x10, 0x00], bitness).unwrap();
+            assert_eq!(instruction.relative_branch(), Some(BranchKind::Conditional));
+            assert_eq!(instruction.branch_target(0x1000), Some(0x1015));
+        }
+    }
+
+    #[test]
+    fn encodings_and_maps() {
+        let cases: &[(&[u8], Encoding, OpcodeMap, u8)] = &[
+            (&[0x90], Encoding::Legacy, OpcodeMap::Primary, 0x90),
+            (&[0x0F, 0x05], Encoding::Legacy, OpcodeMap::Map0F, 0x05),
+            (&[0x66, 0x0F, 0x38, 0x00, 0xC1], Encoding::Legacy, OpcodeMap::Map0F38, 0x00),
+            (&[0x66, 0x0F, 0x3A, 0x0F, 0xC1, 0x04], Encoding::Legacy, OpcodeMap::Map0F3A, 0x0F),
+            (&[0xC5, 0xF8, 0x77], Encoding::Vex, OpcodeMap::Map0F, 0x77),
+            (&[0xC4, 0xE2, 0x75, 0x00, 0xC2], Encoding::Vex, OpcodeMap::Map0F38, 0x00),
+            (&[0x62, 0xF3, 0x75, 0x48, 0x25, 0xC2, 0xFF], Encoding::Evex, OpcodeMap::Map0F3A, 0x25),
+            (&[0x62, 0xF5, 0x74, 0x48, 0x58, 0xC2], Encoding::Evex, OpcodeMap::Map5, 0x58),
+            (&[0x8F, 0xE9, 0x78, 0x80, 0xC1], Encoding::Xop, OpcodeMap::Xop9, 0x80),
+        ];
+        for &(code, encoding, map, opcode) in cases {
+            let instruction = decode(code, Bitness::Bits64).unwrap();
+            assert_eq!((instruction.encoding(), instruction.opcode_map(), instruction.opcode()),
+                (encoding, map, opcode), "{:02X?}", code);
+        }
+    }
+
+    #[test]
+    fn prefixes() {
+        let instruction = decode(&[0xF0, 0x0F, 0xC1, 0x01], Bitness::Bits64).unwrap();
+        assert_eq!(instruction.prefixes(), Prefixes::LOCK);
+
+        let code = [0x65, 0x48, 0x8B, 0x04, 0x25, 0x00, 0x00, 0x00, 0x00];
+        let instruction = decode(&code, Bitness::Bits64).unwrap();
+        assert!(instruction.prefixes().contains(Prefixes::SEGMENT));
+        assert_eq!((instruction.segment(), instruction.rex()), (Some(0x65), Some(0x48)));
+        assert!(instruction.rex_w());
+
+        // REX followed by another prefix is ignored.
+        let instruction = decode(&[0x48, 0x66, 0xB8, 0x34, 0x12], Bitness::Bits64).unwrap();
+        assert_eq!((instruction.len(), instruction.rex()), (5, None));
+    }
+
+    /// Branch, the address it is decoded at, its kind and its destination.
+    type BranchCase = (&'static [u8], Bitness, usize, Option<BranchKind>, Option<usize>);
+
+    #[test]
+    fn relative_branches() {
+        let cases: &[BranchCase] = &[
+            (&[0xEB, 0x10], Bitness::Bits64, 0x1000, Some(BranchKind::Jump), Some(0x1012)),
+            (&[0xE9, 0x00, 0x01, 0x00, 0x00], Bitness::Bits64, 0x1000,
+                Some(BranchKind::Jump), Some(0x1105)),
+            (&[0xE8, 0xFB, 0xFF, 0xFF, 0xFF], Bitness::Bits64, 0x1000,
+                Some(BranchKind::Call), Some(0x1000)),
+            (&[0x74, 0xFE], Bitness::Bits64, 0x1000, Some(BranchKind::Conditional), Some(0x1000)),
+            (&[0x0F, 0x85, 0x10, 0x00, 0x00, 0x00], Bitness::Bits64, 0x1000,
+                Some(BranchKind::Conditional), Some(0x1016)),
+            (&[0xE2, 0xF0], Bitness::Bits64, 0x1000, Some(BranchKind::Loop), Some(0xFF2)),
+            (&[0xE3, 0x10], Bitness::Bits64, 0x1000, Some(BranchKind::Loop), Some(0x1012)),
+            (&[0xFF, 0x25, 0x00, 0x00, 0x00, 0x00], Bitness::Bits64, 0x1000, None, None),
+            (&[0xE9, 0x20, 0x00, 0x00, 0x00], Bitness::Bits32, 0xFFFF_FFF0,
+                Some(BranchKind::Jump), Some(0x15)),
+        ];
+        for &(code, bitness, address, kind, target) in cases {
+            let instruction = decode(code, bitness).unwrap();
+            assert_eq!(instruction.relative_branch(), kind, "{:02X?}", code);
+            assert_eq!(instruction.branch_target(address), target, "{:02X?}", code);
+        }
+    }
+
+    #[test]
+    fn rip_relative_operands() {
+        let code = [0x48, 0x8D, 0x05, 0x10, 0x00, 0x00, 0x00];
+        let instruction = decode(&code, Bitness::Bits64).unwrap();
+        assert!(instruction.is_rip_relative());
+        assert_eq!(instruction.rip_target(0x1000), Some(0x1017));
+
+        let code = [0x8B, 0x04, 0x25, 0x30, 0x00, 0x00, 0x00];
+        let instruction = decode(&code, Bitness::Bits64).unwrap();
+        assert!(!instruction.is_rip_relative());
+        let instruction = decode(&[0x8B, 0x05, 0x00, 0x10, 0x00, 0x00], Bitness::Bits32).unwrap();
+        assert_eq!(instruction.rip_target(0x1000), None);
+    }
+
+    #[test]
+    fn errors() {
+        let too_long = [[0x66; MAX_INSTRUCTION_LEN].as_slice(), &[0x90]].concat();
+        let cases: &[(&[u8], Bitness, DecodeError)] = &[
+            (&[], Bitness::Bits64, DecodeError::Truncated),
+            (&[0x48, 0x8B], Bitness::Bits64, DecodeError::Truncated),
+            (&[0xE8, 0x00, 0x00], Bitness::Bits64, DecodeError::Truncated),
+            (&too_long, Bitness::Bits64, DecodeError::TooLong),
+            (&[0x06], Bitness::Bits64, DecodeError::Invalid),
+            (&[0x9A, 0, 0, 0, 0, 0, 0], Bitness::Bits64, DecodeError::Invalid),
+            (&[0x0F, 0x0A], Bitness::Bits64, DecodeError::Invalid),
+            (&[0x66, 0xC5, 0xF8, 0x77], Bitness::Bits64, DecodeError::Invalid),
+            (&[0x48, 0xC5, 0xF8, 0x77], Bitness::Bits64, DecodeError::Invalid),
+            (&[0xC4, 0xE4, 0x75, 0x00, 0xC2], Bitness::Bits64, DecodeError::Invalid),
+        ];
+        for &(code, bitness, error) in cases {
+            assert_eq!(decode(code, bitness), Err(error), "{:02X?}", code);
+        }
+    }
+
+    #[test]
+    fn instruction_boundaries() {
+        let code = [0x55, 0x48, 0x89, 0xE5, 0x48, 0x83, 0xEC, 0x20, 0x0F, 0x0A];
+        let items: Vec<_> = instructions(&code, Bitness::Bits64)
+            .map(|item| item.map(|(offset, instruction)| (offset, instruction.len())))
+            .collect();
+        assert_eq!(items, [Ok((0, 1)), Ok((1, 3)), Ok((4, 4)), Err(DecodeError::Invalid)]);
+    }
+}
@@ -0,0 +1,206 @@
+//! Statically declared hooks, see [`static_detour!`](crate::static_detour).
+
+use std::ffi::c_void;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::{Error, ErrorKind, Function, Hook, HookIdent, MinHook, Result};
+
+/// Declare one or more named static hooks with fixed signatures.
+///
+/// Each declaration expands into a `static` [`StaticDetour`], which holds
+/// the hook once it's initialized and exposes a typed `call` method for
+/// the trampoline, so the detour can invoke the original function:
+///
+/// ```ignore
+/// minhook_ex::static_detour! {
+///     static SLEEP: extern "system" fn(u32);
+/// }
+///
+/// extern "system" fn sleep_detour(ms: u32) {
+///     SLEEP.call(ms / 2)
+/// }
+///
+/// unsafe { SLEEP.initialize(minhook, sleep_target, sleep_detour, HookIdent::DEFAULT) }?;
+/// SLEEP.enable()?;
+/// ```
+#[macro_export]
+macro_rules! static_detour {
+    ($(#[$attr:meta])* $vis:vis static $name:ident : $fn_type:ty ; $($rest:tt)*) => {
+        $(#[$attr])*
+        $vis static $name: $crate::StaticDetour<$fn_type> = $crate::StaticDetour::new();
+        $crate::static_detour! { $($rest)* }
+    };
+    () => {};
+}
+
+/// A hook slot which can be placed in a `static`.
+///
+/// Usually declared with [`static_detour!`](crate::static_detour).
+/// The slot is empty until [`initialize`](Self::initialize) creates the
+/// hook, and can be emptied again with [`remove`](Self::remove) once
+/// no thread uses the trampoline any more. As the slot lives forever,
+/// the hook needs a [`MinHook`] context which does as well, e.g. one
+/// stored in a [`std::sync::OnceLock`].
+#[derive(Debug)]
+pub struct StaticDetour<F: Function> {
+    trampoline: AtomicPtr<c_void>,
+    hook: Mutex<Option<Hook<'static, F>>>,
+    marker: PhantomData<F>,
+}
+
+impl<F: Function> StaticDetour<F> {
+    /// Create an empty slot.
+    pub const fn new() -> Self {
+        Self {
+            trampoline: AtomicPtr::new(std::ptr::null_mut()),
+            hook: Mutex::new(None),
+            marker: PhantomData,
+        }
+    }
+
+    /// Create a disabled hook for a `target` function and store it.
+    ///
+    /// Fails with [`ErrorKind::AlreadyCreated`] if the slot is already occupied.
+    ///
+    /// # Arguments
+    ///
+    /// * `minhook` - the initialized library context.
+    /// * `target` - the hooked function.
+    /// * `detour` - the overwriting function.
+    /// * `ident` - hook identifier, set different ones to create
+    ///     multiple hooks for the same target function.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`Hook::new`].
+    #[track_caller]
+    pub unsafe fn initialize(&self, minhook: &'static MinHook, target: F, detour: F,
+        ident: HookIdent) -> Result<()>
+    {
+        let mut slot = self.lock()?;
+        if slot.is_some() {
+            return Err(Error::new(ErrorKind::AlreadyCreated).with_hook(target.to_ptr(), ident));
+        }
+        let hook = Hook::new(minhook, target, detour, ident)?;
+        self.trampoline.store(hook.trampoline().to_ptr() as *mut c_void, Ordering::Release);
+        *slot = Some(hook);
+        Ok(())
+    }
+
+    /// Remove the stored hook, emptying the slot.
+    ///
+    /// # Safety
+    ///
+    /// The trampoline is freed along with the hook, so no thread may be
+    /// calling the original function through the slot, like from within
+    /// the detour, and a trampoline got from the slot must not be called
+    /// once it's removed.
+    pub unsafe fn remove(&self) -> Result<()> {
+        let mut slot = self.lock()?;
+        let hook = slot.take().ok_or(Error::new(ErrorKind::NotCreated))?;
+        self.trampoline.store(std::ptr::null_mut(), Ordering::Release);
+        drop(hook);
+        Ok(())
+    }
+
+    /// Enable the stored hook.
+    pub fn enable(&self) -> Result<()> {
+        self.lock()?.as_ref().ok_or(Error::new(ErrorKind::NotCreated))?.enable()
+    }
+
+    /// Disable the stored hook.
+    pub fn disable(&self) -> Result<()> {
+        self.lock()?.as_ref().ok_or(Error::new(ErrorKind::NotCreated))?.disable()
+    }
+
+    /// Check whether a hook is stored in the slot.
+    pub fn is_initialized(&self) -> bool {
+        !self.trampoline.load(Ordering::Acquire).is_null()
+    }
+
+    /// Check whether the stored hook is currently enabled.
+    pub fn is_enabled(&self) -> bool {
+        self.lock().is_ok_and(|slot| slot.as_ref().is_some_and(Hook::is_enabled))
+    }
+
+    /// Get the trampoline of the stored hook.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the slot is empty.
+    ///
+    /// # Safety
+    ///
+    /// The trampoline is freed along with the hook, so the returned
+    /// function must not be called once the hook is [removed](Self::remove).
+    pub unsafe fn trampoline(&self) -> F {
+        let trampoline = self.trampoline.load(Ordering::Acquire);
+        assert!(!trampoline.is_null(), "static detour used before initialization");
+        F::from_ptr(trampoline)
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Option<Hook<'static, F>>>> {
+        self.hook.lock().map_err(|_| Error::new(ErrorKind::MutexFailure))
+    }
+}
+
+impl<F: Function> Default for StaticDetour<F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+macro_rules! impl_call {
+    ($($arg:ident: $arg_type:ident),*) => {
+        impl_call!(@abi "C"; $($arg: $arg_type),*);
+        impl_call!(@abi "system"; $($arg: $arg_type),*);
+    };
+    (@abi $abi:literal; $($arg:ident: $arg_type:ident),*) => {
+        impl<Ret: 'static, $($arg_type: 'static),*> StaticDetour<extern $abi fn($($arg_type),*) -> Ret> {
+            /// Call the original function through the trampoline.
+            ///
+            /// # Panics
+            ///
+            /// Panics if the slot is empty.
+            #[inline]
+            #[allow(clippy::too_many_arguments)]
+            pub fn call(&self, $($arg: $arg_type),*) -> Ret {
+                // Removing the hook requires that no thread is calling it.
+                (unsafe { self.trampoline() })($($arg),*)
+            }
+        }
+
+        impl<Ret: 'static, $($arg_type: 'static),*> StaticDetour<unsafe extern $abi fn($($arg_type),*) -> Ret> {
+            /// Call the original function through the trampoline.
+            ///
+            /// # Panics
+            ///
+            /// Panics if the slot is empty.
+            ///
+            /// # Safety
+            ///
+            /// Same as for calling the original function directly.
+            #[inline]
+            #[allow(clippy::too_many_arguments)]
+            pub unsafe fn call(&self, $($arg: $arg_type),*) -> Ret {
+                (self.trampoline())($($arg),*)
+            }
+        }
+    };
+}
+
+impl_call!();
+impl_call!(a: A);
+impl_call!(a: A, b: B);
+impl_call!(a: A, b: B, c: C);
+impl_call!(a: A, b: B, c: C, d: D);
+impl_call!(a: A, b: B, c: C, d: D, e: E);
+impl_call!(a: A, b: B, c: C, d: D, e: E, g: G);
+impl_call!(a: A, b: B, c: C, d: D, e: E, g: G, h: H);
+impl_call!(a: A, b: B, c: C, d: D, e: E, g: G, h: H, i: I);
+impl_call!(a: A, b: B, c: C, d: D, e: E, g: G, h: H, i: I, j: J);
+impl_call!(a: A, b: B, c: C, d: D, e: E, g: G, h: H, i: I, j: J, k: K);
+impl_call!(a: A, b: B, c: C, d: D, e: E, g: G, h: H, i: I, j: J, k: K, l: L);
+impl_call!(a: A, b: B, c: C, d: D, e: E, g: G, h: H, i: I, j: J, k: K, l: L, m: M);
@@ -0,0 +1,409 @@
+//! Error type with the context of the failed operation.
+
+#[cfg(windows)]
+use std::ffi::CStr;
+use std::ffi::{c_int, c_void};
+
+use minhook_ex_sys::MH_STATUS;
+
+use crate::{HookIdent, ThreadFreezeMethod};
+
+/// Return [`std::result::Result`] specialized for MinHook [`Error`]s.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Possible kinds of errors returned by the underlying implementation.
+///
+/// Apart from [`ErrorKind::FreezeMethodConflict`], directly map to
+/// error enumerations in [`minhook_ex_sys::MH_STATUS`], so converting
+/// to a status and back gives the same kind.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Unknown error, which the library should never report.
+    /// Holds the raw status, to also represent codes which are
+    /// unknown to these bindings.
+    Unknown(c_int),
+    /// MinHook has already been initialized.
+    AlreadyInitialized,
+    /// MinHook has not been initialized yet,
+    /// or has already been uninitialized.
+    NotInitialized,
+    /// Hook for a target function has already been created.
+    AlreadyCreated,
+    /// Hook for a target function has not been created yet.
+    NotCreated,
+    /// Hook for a target function has already been enabled.
+    HookEnabled,
+    /// Hook for a target function has not enabled yet,
+    /// or has already been disabled.
+    HookDisabled,
+    /// Specified target pointer was invalid as it points to
+    /// a non-allocated and/or non-executable memory region.
+    PointerNotExecutable,
+    /// Specified target function could not be hooked.
+    UnsupportedFunction,
+    /// Internal memory allocation failed.
+    AllocationFailure,
+    /// Internal memory protection change failed.
+    ProtectionFailure,
+    /// Specified target module could not be found.
+    ModuleNotFound,
+    /// Specified target function could not be found.
+    FunctionNotFound,
+    /// Internal mutex creation/wait failed.
+    MutexFailure,
+    /// A shared initialization was requested with a different
+    /// thread freeze method than the one already in effect.
+    FreezeMethodConflict {
+        /// Method requested by the caller.
+        requested: ThreadFreezeMethod,
+        /// Method selected by the first share holder.
+        active: ThreadFreezeMethod,
+    },
+}
+
+impl ErrorKind {
+    /// Get the library status corresponding to this kind of error.
+    ///
+    /// [`ErrorKind::FreezeMethodConflict`] has no status of its own,
+    /// and is reported as [`MH_STATUS::MH_ERROR_ALREADY_INITIALIZED`].
+    /// This conversion is one-way, as that status converts back to
+    /// [`ErrorKind::AlreadyInitialized`].
+    pub fn status(&self) -> MH_STATUS {
+        use ErrorKind::*;
+        match self {
+            Unknown(code) => MH_STATUS(*code),
+            AlreadyInitialized => MH_STATUS::MH_ERROR_ALREADY_INITIALIZED,
+            NotInitialized => MH_STATUS::MH_ERROR_NOT_INITIALIZED,
+            AlreadyCreated => MH_STATUS::MH_ERROR_ALREADY_CREATED,
+            NotCreated => MH_STATUS::MH_ERROR_NOT_CREATED,
+            HookEnabled => MH_STATUS::MH_ERROR_ENABLED,
+            HookDisabled => MH_STATUS::MH_ERROR_DISABLED,
+            PointerNotExecutable => MH_STATUS::MH_ERROR_NOT_EXECUTABLE,
+            UnsupportedFunction => MH_STATUS::MH_ERROR_UNSUPPORTED_FUNCTION,
+            AllocationFailure => MH_STATUS::MH_ERROR_MEMORY_ALLOC,
+            ProtectionFailure => MH_STATUS::MH_ERROR_MEMORY_PROTECT,
+            ModuleNotFound => MH_STATUS::MH_ERROR_MODULE_NOT_FOUND,
+            FunctionNotFound => MH_STATUS::MH_ERROR_FUNCTION_NOT_FOUND,
+            MutexFailure => MH_STATUS::MH_ERROR_MUTEX_FAILURE,
+            FreezeMethodConflict { .. } => MH_STATUS::MH_ERROR_ALREADY_INITIALIZED,
+        }
+    }
+
+    /// Get the name of the corresponding library status, as reported
+    /// by `MH_StatusToString` where the library is linked, on Windows.
+    /// Elsewhere, the same names are used, and `"MH_UNKNOWN"` for
+    /// statuses unknown to these bindings.
+    pub fn status_name(&self) -> &'static str {
+        status_name(self.status())
+    }
+}
+
+#[cfg(windows)]
+fn status_name(status: MH_STATUS) -> &'static str {
+    // The library returns string literals.
+    let name = unsafe { CStr::from_ptr(minhook_ex_sys::MH_StatusToString(status)) };
+    name.to_str().unwrap_or("MH_UNKNOWN")
+}
+
+#[cfg(not(windows))]
+fn status_name(status: MH_STATUS) -> &'static str {
+    status.name().unwrap_or("MH_UNKNOWN")
+}
+
+impl TryFrom<MH_STATUS> for ErrorKind {
+    type Error = &'static str;
+    fn try_from(value: MH_STATUS) -> std::result::Result<Self, Self::Error> {
+        use ErrorKind::*;
+        match value {
+            MH_STATUS::MH_OK => Err("minhook status did not represent an error"),
+            MH_STATUS::MH_ERROR_ALREADY_INITIALIZED => Ok(AlreadyInitialized),
+            MH_STATUS::MH_ERROR_NOT_INITIALIZED => Ok(NotInitialized),
+            MH_STATUS::MH_ERROR_ALREADY_CREATED => Ok(AlreadyCreated),
+            MH_STATUS::MH_ERROR_NOT_CREATED => Ok(NotCreated),
+            MH_STATUS::MH_ERROR_ENABLED => Ok(HookEnabled),
+            MH_STATUS::MH_ERROR_DISABLED => Ok(HookDisabled),
+            MH_STATUS::MH_ERROR_NOT_EXECUTABLE => Ok(PointerNotExecutable),
+            MH_STATUS::MH_ERROR_UNSUPPORTED_FUNCTION => Ok(UnsupportedFunction),
+            MH_STATUS::MH_ERROR_MEMORY_ALLOC => Ok(AllocationFailure),
+            MH_STATUS::MH_ERROR_MEMORY_PROTECT => Ok(ProtectionFailure),
+            MH_STATUS::MH_ERROR_MODULE_NOT_FOUND => Ok(ModuleNotFound),
+            MH_STATUS::MH_ERROR_FUNCTION_NOT_FOUND => Ok(FunctionNotFound),
+            MH_STATUS::MH_ERROR_MUTEX_FAILURE => Ok(MutexFailure),
+            MH_STATUS(code) => Ok(Unknown(code)),
+        }
+    }
+}
+
+/// Lossless except for [`ErrorKind::FreezeMethodConflict`],
+/// see [`ErrorKind::status`].
+impl From<ErrorKind> for MH_STATUS {
+    fn from(kind: ErrorKind) -> Self {
+        kind.status()
+    }
+}
+
+impl std::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use ErrorKind::*;
+        let message = match self {
+            Unknown(code) => return write!(f, "unknown error (status {})", code),
+            AlreadyInitialized => "minhook already initialized",
+            NotInitialized => "minhook not initialized",
+            AlreadyCreated => "hook for a target function already created",
+            NotCreated => "hook for a target function not yet created",
+            HookEnabled => "hook for a target function already enabled",
+            HookDisabled => "hook for a target function not yet enabled or already disabled",
+            PointerNotExecutable => "target function pointer not executable",
+            UnsupportedFunction => "target function not hookable",
+            AllocationFailure => "internal allocation failed",
+            ProtectionFailure => "internal protection change failed",
+            ModuleNotFound => "target module not found",
+            FunctionNotFound => "target function not found",
+            MutexFailure => "internal mutex creation or wait failed",
+            FreezeMethodConflict { requested, active } => {
+                return write!(f, "thread freeze method {:?} conflicts with active {:?}",
+                    requested, active);
+            }
+        };
+        f.write_str(message)
+    }
+}
+
+/// Operation which may fail with an [`Error`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    /// Initializing the library.
+    Initialize,
+    /// Uninitializing the library.
+    Uninitialize,
+    /// Selecting a method of suspending/resuming threads.
+    SetThreadFreezeMethod,
+    /// Creating a hook.
+    CreateHook,
+    /// Creating a hook for an exported function.
+    CreateHookApi,
+    /// Removing a hook.
+    RemoveHook,
+    /// Removing disabled hooks.
+    RemoveDisabledHooks,
+    /// Enabling a hook.
+    EnableHook,
+    /// Disabling a hook.
+    DisableHook,
+    /// Queueing a hook to be enabled.
+    QueueEnableHook,
+    /// Queueing a hook to be disabled.
+    QueueDisableHook,
+    /// Applying queued changes.
+    ApplyQueued,
+}
+
+impl std::fmt::Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use Operation::*;
+        f.write_str(match self {
+            Initialize => "initialize",
+            Uninitialize => "uninitialize",
+            SetThreadFreezeMethod => "set thread freeze method",
+            CreateHook => "create hook",
+            CreateHookApi => "create api hook",
+            RemoveHook => "remove hook",
+            RemoveDisabledHooks => "remove disabled hooks",
+            EnableHook => "enable hook",
+            DisableHook => "disable hook",
+            QueueEnableHook => "queue enable hook",
+            QueueDisableHook => "queue disable hook",
+            ApplyQueued => "apply queued",
+        })
+    }
+}
+
+/// Error returned by the underlying implementation, along with
+/// the operation, target function and hook identifier it concerns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Error {
+    kind: ErrorKind,
+    operation: Option<Operation>,
+    target: Option<usize>,
+    ident: Option<HookIdent>,
+}
+
+impl Error {
+    /// Create an error without any context.
+    pub fn new(kind: ErrorKind) -> Self {
+        Self { kind, operation: None, target: None, ident: None }
+    }
+
+    /// Get the kind of this error.
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// Get the operation which failed, if known.
+    pub fn operation(&self) -> Option<Operation> {
+        self.operation
+    }
+
+    /// Get the target function of the failed operation, if any.
+    pub fn target(&self) -> Option<*const c_void> {
+        self.target.map(|target| target as *const c_void)
+    }
+
+    /// Get the hook identifier of the failed operation, if any.
+    pub fn ident(&self) -> Option<HookIdent> {
+        self.ident
+    }
+
+    /// Get the library status corresponding to this error.
+    pub fn status(&self) -> MH_STATUS {
+        self.kind.status()
+    }
+
+    /// Get the name of the corresponding library status,
+    /// see [`ErrorKind::status_name`].
+    pub fn status_name(&self) -> &'static str {
+        self.kind.status_name()
+    }
+
+    /// Attach the operation which failed.
+    pub fn with_operation(mut self, operation: Operation) -> Self {
+        self.operation = Some(operation);
+        self
+    }
+
+    /// Attach the target function and hook identifier of the failed operation.
+    pub fn with_hook(mut self, target: *const c_void, ident: HookIdent) -> Self {
+        self.target = Some(target as usize);
+        self.ident = Some(ident);
+        self
+    }
+
+    /// Attach the hook identifier of the failed operation on multiple hooks.
+    pub fn with_ident(mut self, ident: HookIdent) -> Self {
+        self.ident = Some(ident);
+        self
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Self::new(kind)
+    }
+}
+
+impl TryFrom<MH_STATUS> for Error {
+    type Error = &'static str;
+    fn try_from(value: MH_STATUS) -> std::result::Result<Self, Self::Error> {
+        ErrorKind::try_from(value).map(Self::new)
+    }
+}
+
+impl From<Error> for MH_STATUS {
+    fn from(err: Error) -> Self {
+        err.status()
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(operation) = self.operation {
+            write!(f, "failed to {}", operation)?;
+            if let Some(target) = self.target() {
+                write!(f, " for target {:p}", target)?;
+            }
+            if let Some(ident) = self.ident {
+                write!(f, " with ident {}", ident)?;
+            }
+            write!(f, ": ")?;
+        }
+        self.kind.fmt(f)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Internal trait to simplify [`MH_STATUS`] into [`Result`] conversion,
+/// and to attach context to results of a [`HookBackend`](crate::HookBackend).
+///
+/// Can't use the idiomatic [`From`]/[`Into`] because both types
+/// are defined out of crate.
+pub(crate) trait StatusExt {
+    fn into_result(self) -> Result<()>;
+
+    /// Convert, attaching the failed operation as context.
+    fn into_result_for(self, operation: Operation) -> Result<()>
+    where
+        Self: Sized,
+    {
+        self.into_result().map_err(|err| err.with_operation(operation))
+    }
+
+    /// Convert, attaching the failed operation and hook as context.
+    fn into_hook_result(self, operation: Operation, target: *const c_void,
+        ident: HookIdent) -> Result<()>
+    where
+        Self: Sized,
+    {
+        self.into_result().map_err(|err| err.with_operation(operation).with_hook(target, ident))
+    }
+
+    /// Convert, attaching the failed operation and identifier of hooks as context.
+    fn into_ident_result(self, operation: Operation, ident: HookIdent) -> Result<()>
+    where
+        Self: Sized,
+    {
+        self.into_result().map_err(|err| err.with_operation(operation).with_ident(ident))
+    }
+}
+
+impl StatusExt for MH_STATUS {
+    fn into_result(self) -> Result<()> {
+        match Error::try_from(self) {
+            Ok(err) => Err(err),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+impl StatusExt for Result<()> {
+    fn into_result(self) -> Result<()> {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statuses_round_trip() {
+        for code in -1..=20 {
+            let status = MH_STATUS(code);
+            match ErrorKind::try_from(status) {
+                Ok(kind) => {
+                    assert_eq!(kind.status(), status);
+                    assert_eq!(MH_STATUS::from(kind), status);
+                    assert_eq!(Error::try_from(status).map(|err| err.status()), Ok(status));
+                }
+                Err(_) => assert_eq!(status, MH_STATUS::MH_OK),
+            }
+        }
+        assert_eq!(ErrorKind::try_from(MH_STATUS::MH_UNKNOWN), Ok(ErrorKind::Unknown(-1)));
+        assert_eq!(ErrorKind::try_from(MH_STATUS(100)), Ok(ErrorKind::Unknown(100)));
+        assert_eq!(ErrorKind::try_from(MH_STATUS::MH_ERROR_MUTEX_FAILURE),
+            Ok(ErrorKind::MutexFailure));
+        assert_eq!(ErrorKind::Unknown(100).status(), MH_STATUS(100));
+        assert!(MH_STATUS::MH_OK.into_result().is_ok());
+    }
+
+    #[test]
+    fn freeze_method_conflicts_map_one_way() {
+        let kind = ErrorKind::FreezeMethodConflict {
+            requested: ThreadFreezeMethod::KernelNextThread,
+            active: ThreadFreezeMethod::OriginalSnapshot,
+        };
+        assert_eq!(kind.status(), MH_STATUS::MH_ERROR_ALREADY_INITIALIZED);
+        assert_eq!(kind.status_name(), "MH_ERROR_ALREADY_INITIALIZED");
+        assert_eq!(ErrorKind::try_from(kind.status()), Ok(ErrorKind::AlreadyInitialized));
+    }
+}
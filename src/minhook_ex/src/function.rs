@@ -0,0 +1,71 @@
+//! Function pointer types which can be hooked.
+
+use std::ffi::c_void;
+
+/// A plain function pointer type, like `extern "system" fn(u32) -> i32`.
+///
+/// Implemented for safe and unsafe `extern "C"` and `extern "system"`
+/// function pointers taking up to 12 arguments.
+///
+/// # Safety
+///
+/// Implementors must be pointer-sized function pointers, so that
+/// conversion to and from a raw code address is lossless.
+pub unsafe trait Function: Copy + Send + Sync + 'static {
+    /// Argument types of the function, as a tuple.
+    type Arguments;
+    /// Return type of the function.
+    type Output;
+
+    /// Reinterpret a raw code address as a function pointer.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be non-null and point to a function
+    /// with exactly this signature and calling convention.
+    unsafe fn from_ptr(ptr: *const c_void) -> Self;
+
+    /// Get the raw code address of this function.
+    fn to_ptr(self) -> *const c_void;
+}
+
+macro_rules! impl_function {
+    ($($arg:ident),*) => {
+        impl_function!(@abi "C"; $($arg),*);
+        impl_function!(@abi "system"; $($arg),*);
+    };
+    (@abi $abi:literal; $($arg:ident),*) => {
+        impl_function!(@impl (extern $abi fn($($arg),*) -> Ret); $($arg),*);
+        impl_function!(@impl (unsafe extern $abi fn($($arg),*) -> Ret); $($arg),*);
+    };
+    (@impl ($($fn_type:tt)*); $($arg:ident),*) => {
+        unsafe impl<Ret: 'static, $($arg: 'static),*> Function for $($fn_type)* {
+            type Arguments = ($($arg,)*);
+            type Output = Ret;
+
+            #[inline]
+            unsafe fn from_ptr(ptr: *const c_void) -> Self {
+                std::mem::transmute(ptr)
+            }
+
+            #[inline]
+            fn to_ptr(self) -> *const c_void {
+                self as *const c_void
+            }
+        }
+    };
+}
+
+impl_function!();
+impl_function!(A);
+impl_function!(A, B);
+impl_function!(A, B, C);
+impl_function!(A, B, C, D);
+impl_function!(A, B, C, D, E);
+impl_function!(A, B, C, D, E, G);
+impl_function!(A, B, C, D, E, G, H);
+impl_function!(A, B, C, D, E, G, H, I);
+impl_function!(A, B, C, D, E, G, H, I, J);
+impl_function!(A, B, C, D, E, G, H, I, J, K);
+impl_function!(A, B, C, D, E, G, H, I, J, K, L);
+impl_function!(A, B, C, D, E, G, H, I, J, K, L, M);
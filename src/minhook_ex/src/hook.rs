@@ -0,0 +1,135 @@
+//! Typed hook handles with automatic cleanup.
+
+use crate::{registry, ApiError, Export, Function, HookIdent, MinHook, Result};
+
+/// A created hook which is removed when dropped.
+///
+/// Owns the target, detour and trampoline of a single hook along with
+/// its identifier, so the same pair is always used for every call into
+/// the library. MinHook serializes all calls behind its own mutex, which
+/// makes the handle safe to share between threads.
+///
+/// The hook borrows the [`MinHook`] context it was created with,
+/// so it can't outlive the library initialization.
+#[derive(Debug)]
+pub struct Hook<'mh, F: Function> {
+    minhook: &'mh MinHook,
+    target: F,
+    detour: F,
+    trampoline: F,
+    ident: HookIdent,
+}
+
+impl<'mh, F: Function> Hook<'mh, F> {
+    /// Create a disabled hook for a `target` function.
+    ///
+    /// # Arguments
+    ///
+    /// * `minhook` - the initialized library context.
+    /// * `target` - the hooked function.
+    /// * `detour` - the overwriting function.
+    /// * `ident` - hook identifier, set different ones to create
+    ///     multiple hooks for the same target function.
+    ///
+    /// # Safety
+    ///
+    /// `target` must be a hookable function, and it must be sound to
+    /// call `detour` in its place for as long as the hook is enabled.
+    #[track_caller]
+    pub unsafe fn new(minhook: &'mh MinHook, target: F, detour: F,
+        ident: HookIdent) -> Result<Self>
+    {
+        let trampoline = crate::create_hook(target.to_ptr(), detour.to_ptr(), ident)?;
+        Ok(Self {
+            minhook,
+            target,
+            detour,
+            trampoline: F::from_ptr(trampoline),
+            ident,
+        })
+    }
+
+    /// Create a disabled hook for a function exported from a loaded module.
+    ///
+    /// # Arguments
+    ///
+    /// * `minhook` - the initialized library context.
+    /// * `module` - name of the loaded module, like `"user32"`.
+    /// * `export` - name or ordinal of the exported function.
+    /// * `detour` - the overwriting function.
+    /// * `ident` - hook identifier, set different ones to create
+    ///     multiple hooks for the same target function.
+    ///
+    /// # Safety
+    ///
+    /// The export must be a hookable function, and it must be sound to
+    /// call `detour` in its place for as long as the hook is enabled.
+    #[track_caller]
+    pub unsafe fn from_api(minhook: &'mh MinHook, module: &str, export: impl Into<Export>,
+        detour: F, ident: HookIdent) -> std::result::Result<Self, ApiError>
+    {
+        let (target, trampoline) = crate::create_hook_api(module, export, detour.to_ptr(), ident)?;
+        Ok(Self {
+            minhook,
+            target: F::from_ptr(target),
+            detour,
+            trampoline: F::from_ptr(trampoline),
+            ident,
+        })
+    }
+
+    /// Enable the hook, redirecting calls to the target into the detour.
+    pub fn enable(&self) -> Result<()> {
+        unsafe { crate::enable_hook(self.target.to_ptr(), self.ident) }
+    }
+
+    /// Disable the hook, restoring the original target function.
+    pub fn disable(&self) -> Result<()> {
+        unsafe { crate::disable_hook(self.target.to_ptr(), self.ident) }
+    }
+
+    /// Check whether the hook is currently enabled, including
+    /// by bulk or queued operations outside of this handle.
+    pub fn is_enabled(&self) -> bool {
+        registry::is_enabled(self.target.to_ptr(), self.ident).unwrap_or(false)
+    }
+
+    /// Get the hooked function.
+    pub fn target(&self) -> F {
+        self.target
+    }
+
+    /// Get the overwriting function.
+    pub fn detour(&self) -> F {
+        self.detour
+    }
+
+    /// Get the trampoline, which calls the original target function
+    /// regardless of whether the hook is enabled.
+    ///
+    /// # Safety
+    ///
+    /// The trampoline is freed along with the hook, so the returned
+    /// function must not be called once the hook is dropped.
+    pub unsafe fn trampoline(&self) -> F {
+        self.trampoline
+    }
+
+    /// Get the hook identifier.
+    pub fn ident(&self) -> HookIdent {
+        self.ident
+    }
+
+    /// Get the library context this hook was created with.
+    pub fn minhook(&self) -> &'mh MinHook {
+        self.minhook
+    }
+}
+
+impl<F: Function> Drop for Hook<'_, F> {
+    fn drop(&mut self) {
+        // Removal also disables an enabled hook. There is nothing
+        // sensible to do on failure.
+        let _ = unsafe { crate::remove_hook(self.target.to_ptr(), self.ident) };
+    }
+}
@@ -0,0 +1,287 @@
+//! Hooks of the import and export address tables of PE images.
+//!
+//! Neither kind of hook touches the code of the hooked function.
+//! [`IatHook`] redirects the calls a single module makes through one
+//! of its imports, while [`EatHook`] changes what the export resolves
+//! to, which affects `GetProcAddress` and imports bound afterwards,
+//! but not any address resolved before.
+
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+#[cfg(target_pointer_width = "64")]
+use std::sync::{Mutex, PoisonError};
+
+#[cfg(target_pointer_width = "64")]
+use crate::allocator::NearAllocator;
+use crate::pe::{ExportAddress, Image, PeError};
+use crate::vmt::VmtHook;
+use crate::{allocator, ApiError, Error, ErrorKind, Export, Function, Operation, Result};
+
+/// Size of a relay from an export address table into a detour.
+#[cfg(target_pointer_width = "64")]
+const RELAY_LEN: usize = 16;
+
+/// Relays of detours which are out of reach of the export address
+/// table, which only holds positive 32-bit offsets from the image base.
+#[cfg(target_pointer_width = "64")]
+static RELAYS: Mutex<NearAllocator> = Mutex::new(NearAllocator::new(RELAY_LEN, 0x7FFF_0000));
+
+fn invalid_image(module: &str) -> impl FnOnce(PeError) -> ApiError + '_ {
+    move |error| ApiError::InvalidImage { module: module.to_owned(), error }
+}
+
+/// A hook replacing an entry of the import address table of a module,
+/// restored when dropped.
+#[derive(Debug)]
+pub struct IatHook<F: Function> {
+    // An import address table entry is swapped just like a vtable slot.
+    slot: VmtHook<F>,
+    module: String,
+    export: Export,
+}
+
+impl<F: Function> IatHook<F> {
+    /// Create a disabled hook for a function imported by a module.
+    ///
+    /// # Arguments
+    ///
+    /// * `importer` - base address of the module whose import is hooked.
+    /// * `module` - name of the module the function is imported from,
+    ///     compared ignoring case and an omitted `.dll` extension.
+    /// * `export` - name or ordinal of the imported function.
+    /// * `detour` - the overwriting function.
+    ///
+    /// # Safety
+    ///
+    /// `importer` must be a mapped image which outlives the hook, and
+    /// the import must be of type `F`. It must be sound to call `detour`
+    /// in place of the import for as long as the hook is enabled.
+    pub unsafe fn new(importer: *const c_void, module: &str, export: impl Into<Export>,
+        detour: F) -> std::result::Result<Self, ApiError>
+    {
+        let export = export.into();
+        let image = Image::from_base(importer).map_err(invalid_image(module))?;
+        let import = image.find_import(module, &export).map_err(invalid_image(module))?
+            .ok_or_else(|| ApiError::FunctionNotFound {
+                module: module.to_owned(),
+                export: export.clone(),
+            })?;
+        let slot = (importer as usize + import.slot() as usize) as *const *const c_void;
+        Ok(Self {
+            slot: VmtHook::new(slot, 0, detour),
+            module: module.to_owned(),
+            export,
+        })
+    }
+
+    /// Enable the hook, redirecting calls through the import into the detour.
+    pub fn enable(&self) -> Result<()> {
+        self.slot.enable()
+    }
+
+    /// Disable the hook, restoring the original import.
+    pub fn disable(&self) -> Result<()> {
+        self.slot.disable()
+    }
+
+    /// Check whether the hook is currently enabled.
+    pub fn is_enabled(&self) -> bool {
+        self.slot.is_enabled()
+    }
+
+    /// Get the name of the module the function is imported from.
+    pub fn module(&self) -> &str {
+        &self.module
+    }
+
+    /// Get the name or ordinal of the imported function.
+    pub fn export(&self) -> &Export {
+        &self.export
+    }
+
+    /// Get the entry of the import address table.
+    pub fn slot(&self) -> *const c_void {
+        self.slot.vtable() as *const c_void
+    }
+
+    /// Get the overwriting function.
+    pub fn detour(&self) -> F {
+        self.slot.detour()
+    }
+
+    /// Get the function the import resolved to when the hook was created.
+    pub fn original(&self) -> F {
+        self.slot.original()
+    }
+}
+
+/// A hook replacing an entry of the export address table of a module,
+/// restored when dropped.
+///
+/// The table holds offsets from the image base, so on 64-bit targets a
+/// detour more than 4 GiB above the module is reached through a relay.
+#[derive(Debug)]
+pub struct EatHook<F: Function> {
+    slot: *const AtomicU32,
+    original_rva: u32,
+    detour_rva: u32,
+    relay: Option<usize>,
+    original: F,
+    detour: F,
+    export: Export,
+    enabled: AtomicBool,
+}
+
+// The slot is only changed atomically, and belongs to the hook.
+unsafe impl<F: Function> Send for EatHook<F> {}
+unsafe impl<F: Function> Sync for EatHook<F> {}
+
+impl<F: Function> EatHook<F> {
+    /// Create a disabled hook for a function exported by a module.
+    ///
+    /// Forwarded exports can't be hooked, as they don't point to code.
+    ///
+    /// # Arguments
+    ///
+    /// * `exporter` - base address of the module exporting the function.
+    /// * `export` - name or ordinal of the exported function.
+    /// * `detour` - the overwriting function.
+    ///
+    /// # Safety
+    ///
+    /// `exporter` must be a mapped image which outlives the hook, and
+    /// the export must be of type `F`. It must be sound to call `detour`
+    /// in place of the export, wherever it is resolved while the hook
+    /// is enabled, and for as long as such resolved addresses are used.
+    pub unsafe fn new(exporter: *const c_void, export: impl Into<Export>, detour: F)
+        -> std::result::Result<Self, ApiError>
+    {
+        let export = export.into();
+        let base = exporter as usize;
+        let unnamed = format!("{:p}", exporter);
+        let image = Image::from_base(exporter).map_err(invalid_image(&unnamed))?;
+        let exports = image.exports().map_err(invalid_image(&unnamed))?;
+        let module = exports.as_ref().map_or(unnamed.as_str(), |exports| exports.module());
+        let not_found = || ApiError::FunctionNotFound {
+            module: module.to_owned(),
+            export: export.clone(),
+        };
+        let symbol = match &exports {
+            Some(exports) => exports.find(&export).map_err(invalid_image(module))?,
+            None => None,
+        };
+        let symbol = symbol.ok_or_else(not_found)?;
+        let ExportAddress::Code(original_rva) = symbol.address() else {
+            let err = Error::new(ErrorKind::UnsupportedFunction).with_operation(Operation::CreateHook);
+            return Err(ApiError::Hook(err));
+        };
+
+        let (detour_rva, relay) = detour_rva(base, detour.to_ptr() as usize).ok_or_else(|| {
+            let err = Error::new(ErrorKind::AllocationFailure).with_operation(Operation::CreateHook);
+            ApiError::Hook(err)
+        })?;
+        Ok(Self {
+            slot: (base + symbol.slot() as usize) as *const AtomicU32,
+            original_rva,
+            detour_rva,
+            relay,
+            original: F::from_ptr(base.wrapping_add(original_rva as usize) as *const c_void),
+            detour,
+            export,
+            enabled: AtomicBool::new(false),
+        })
+    }
+
+    /// Enable the hook, resolving the export to the detour.
+    pub fn enable(&self) -> Result<()> {
+        self.write(self.detour_rva, Operation::EnableHook)?;
+        self.enabled.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Disable the hook, resolving the export to the original function.
+    pub fn disable(&self) -> Result<()> {
+        self.write(self.original_rva, Operation::DisableHook)?;
+        self.enabled.store(false, Ordering::Release);
+        Ok(())
+    }
+
+    /// Check whether the hook is currently enabled.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Acquire)
+    }
+
+    /// Get the name or ordinal of the exported function.
+    pub fn export(&self) -> &Export {
+        &self.export
+    }
+
+    /// Get the entry of the export address table.
+    pub fn slot(&self) -> *const c_void {
+        self.slot as *const c_void
+    }
+
+    /// Get the overwriting function.
+    pub fn detour(&self) -> F {
+        self.detour
+    }
+
+    /// Get the exported function.
+    pub fn original(&self) -> F {
+        self.original
+    }
+
+    fn write(&self, rva: u32, operation: Operation) -> Result<()> {
+        let written = unsafe {
+            allocator::with_writable(self.slot as usize, std::mem::size_of::<u32>(), || {
+                (*self.slot).store(rva, Ordering::Release)
+            })
+        };
+        written.ok_or_else(|| Error::new(ErrorKind::ProtectionFailure).with_operation(operation))
+    }
+}
+
+impl<F: Function> Drop for EatHook<F> {
+    fn drop(&mut self) {
+        // The relay is leaked if the export keeps resolving to it.
+        if !self.is_enabled() || self.disable().is_ok() {
+            #[cfg(target_pointer_width = "64")]
+            if let Some(relay) = self.relay {
+                unsafe { RELAYS.lock().unwrap_or_else(PoisonError::into_inner).free(relay) };
+            }
+        }
+    }
+}
+
+/// Get the offset of a detour from an image base for its export address
+/// table, along with the relay placed above the base if it's out of reach.
+#[cfg(target_pointer_width = "64")]
+fn detour_rva(base: usize, detour: usize) -> Option<(u32, Option<usize>)> {
+    if let Ok(rva) = u32::try_from(detour.wrapping_sub(base)) {
+        return Some((rva, None));
+    }
+    let relay = RELAYS.lock().unwrap_or_else(PoisonError::into_inner)
+        .allocate(base.checked_add(0x8000_0000)?)?;
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        // jmp [rip]
+        let mut code = [0xCC; RELAY_LEN];
+        code[..6].copy_from_slice(&[0xFF, 0x25, 0x00, 0x00, 0x00, 0x00]);
+        code[6..14].copy_from_slice(&(detour as u64).to_le_bytes());
+        std::ptr::copy_nonoverlapping(code.as_ptr(), relay as *mut u8, RELAY_LEN);
+    }
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        let code = crate::trampoline::aarch64::jump_absolute(detour);
+        std::ptr::copy_nonoverlapping(code.as_ptr(), relay as *mut u8, RELAY_LEN);
+        crate::trampoline::aarch64::flush_instruction_cache(relay, RELAY_LEN);
+    }
+    Some(((relay - base) as u32, Some(relay)))
+}
+
+/// Get the offset of a detour from an image base for its export address
+/// table, which always reaches, as addresses wrap around.
+#[cfg(not(target_pointer_width = "64"))]
+fn detour_rva(base: usize, detour: usize) -> Option<(u32, Option<usize>)> {
+    Some((detour.wrapping_sub(base) as u32, None))
+}
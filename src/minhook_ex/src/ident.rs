@@ -0,0 +1,67 @@
+//! Hook identifiers, which allow hooking the same target more than once.
+
+use std::ffi::c_ulonglong;
+
+use minhook_ex_sys::{MH_ALL_IDENTS, MH_DEFAULT_IDENT};
+
+/// Identifier of a hook.
+///
+/// Hooks for the same target function must have different identifiers,
+/// and bulk operations can be restricted to hooks with one identifier,
+/// e.g. so every component of a process only manages its own hooks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct HookIdent(c_ulonglong);
+
+impl HookIdent {
+    /// Identifier used by the library when none is specified.
+    pub const DEFAULT: Self = Self(MH_DEFAULT_IDENT);
+    /// Wildcard matching hooks with any identifier. Can only be
+    /// used for operations on existing hooks, not for creating them.
+    pub const ALL: Self = Self(MH_ALL_IDENTS);
+
+    /// Create a specific identifier from its raw value.
+    ///
+    /// Raw values of [`HookIdent::DEFAULT`] and [`HookIdent::ALL`]
+    /// produce those identifiers rather than distinct ones.
+    pub const fn new(ident: c_ulonglong) -> Self {
+        Self(ident)
+    }
+
+    /// Get the raw value of the identifier.
+    pub const fn get(self) -> c_ulonglong {
+        self.0
+    }
+
+    /// Check whether this is the [`HookIdent::ALL`] wildcard.
+    pub const fn is_all(self) -> bool {
+        self.0 == MH_ALL_IDENTS
+    }
+
+    /// Check whether this identifier matches the `other` one,
+    /// which is the case if either of them is the wildcard.
+    pub const fn matches(self, other: HookIdent) -> bool {
+        self.is_all() || other.is_all() || self.0 == other.0
+    }
+}
+
+impl Default for HookIdent {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl From<c_ulonglong> for HookIdent {
+    fn from(ident: c_ulonglong) -> Self {
+        Self::new(ident)
+    }
+}
+
+impl std::fmt::Display for HookIdent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Self::ALL => f.write_str("all"),
+            Self::DEFAULT => f.write_str("default"),
+            Self(ident) => ident.fmt(f),
+        }
+    }
+}
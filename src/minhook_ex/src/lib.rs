@@ -1,100 +1,69 @@
 #![allow(dead_code)]
 #![allow(unsafe_code)]
+// Argument lists continue their items with four spaces.
+#![allow(clippy::doc_overindented_list_items)]
 
 //! # MinHook EX
 //!
 //! A safe-ish wrapper around [`minhook_ex_sys`].
+//!
+//! On Linux, the library is replaced by a reimplementation of its API
+//! in Rust, so the same hooks work there on x86-64 and AArch64. Either
+//! engine can be replaced by another [`HookBackend`], see [`backend`].
 
-use std::ffi::{c_void, c_ulonglong};
-
-use minhook_ex_sys::{self, *};
-
-
-/// Return [`std::result::Result`] specialized for MinHook [`Error`]s.
-pub type Result<T> = std::result::Result<T, Error>;
-
-/// Possible errors returned by the underlying implementation.
-/// Directly map to error enumerations in [`minhook_ex_sys::MH_STATUS`].
-#[derive(Clone, Copy, Debug)]
-pub enum Error {
-    /// MinHook has already been initialized.
-    AlreadyInitialized,
-    /// MinHook has not been initialized yet,
-    /// or has already been uninitialized.
-    NotInitialized,
-    /// Hook for a target function has already been created.
-    AlreadyCreated,
-    /// Hook for a target function has not been created yet.
-    NotCreated,
-    /// Hook for a target function has already been enabled.
-    HookEnabled,
-    /// Hook for a target function has not enabled yet,
-    /// or has already been disabled.
-    HookDisabled,
-    /// Specified target pointer was invalid as it points to
-    /// a non-allocated and/or non-executable memory region.
-    PointerNotExecutable,
-    /// Specified target function could not be hooked.
-    UnsupportedFunction,
-    /// Internal memory allocation failed.
-    AllocationFailure,
-    /// Internal memory protection change failed.
-    ProtectionFailure,
-    /// Specified target module could not be found.
-    ModuleNotFound,
-    /// Specified target function could not be found.
-    FunctionNotFound,
-    /// Internal mutex creation/wait failed.
-    MutexFailure,
-}
-
-impl TryFrom<MH_STATUS> for Error {
-    type Error = &'static str;
-    fn try_from(value: MH_STATUS) -> std::result::Result<Self, Self::Error> {
-        use Error::*;
-        use MH_STATUS::*;
-        match value {
-            MH_ERROR_ALREADY_INITIALIZED => Ok(AlreadyInitialized),
-            MH_ERROR_NOT_INITIALIZED => Ok(NotInitialized),
-            MH_ERROR_ALREADY_CREATED => Ok(AlreadyCreated),
-            MH_ERROR_NOT_CREATED => Ok(NotCreated),
-            MH_ERROR_ENABLED => Ok(HookEnabled),
-            MH_ERROR_DISABLED => Ok(HookDisabled),
-            MH_ERROR_NOT_EXECUTABLE => Ok(PointerNotExecutable),
-            MH_ERROR_UNSUPPORTED_FUNCTION => Ok(UnsupportedFunction),
-            MH_ERROR_MEMORY_ALLOC => Ok(AllocationFailure),
-            MH_ERROR_MEMORY_PROTECT => Ok(ProtectionFailure),
-            MH_ERROR_MODULE_NOT_FOUND => Ok(ModuleNotFound),
-            MH_ERROR_FUNCTION_NOT_FOUND => Ok(FunctionNotFound),
-            MH_ERROR_MUTEX_FAILURE => Ok(MutexFailure),
-            _ => Err("minhook status did not represent an error"),
-        }
-    }
-}
+use std::ffi::c_void;
+use std::panic::Location;
 
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        use Error::*;
-        f.write_str(match self {
-            AlreadyInitialized => "minhook already initialized",
-            NotInitialized => "minhook not initialized",
-            AlreadyCreated => "hook for a target function already created",
-            NotCreated => "hook for a target function not yet created",
-            HookEnabled => "hook for a target function already enabled",
-            HookDisabled => "hook for a target function not yet created",
-            PointerNotExecutable => "target function pointer not executable",
-            UnsupportedFunction => "target function not hookable",
-            AllocationFailure => "internal allocation failed",
-            ProtectionFailure => "internal protection change failed",
-            ModuleNotFound => "target module not found",
-            FunctionNotFound => "target function not found",
-            MutexFailure => "internal mutex creation or wait failed",
-        })
-    }
-}
+use minhook_ex_sys::{self, MH_THREAD_FREEZE_METHOD};
+
+pub mod allocator;
+pub mod analysis;
+mod api;
+pub mod backend;
+mod context;
+pub mod decoder;
+mod detour;
+mod error;
+mod function;
+mod hook;
+pub mod iat;
+mod ident;
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod linux;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod midhook;
+pub mod mock;
+pub mod pe;
+pub mod registry;
+pub mod resolver;
+pub mod scan;
+mod transaction;
+pub mod trampoline;
+pub mod vmt;
+
+pub use api::{create_hook_api, ApiError, Export};
+pub use backend::HookBackend;
+pub use context::{FreezeOverride, MinHook};
+pub use detour::StaticDetour;
+pub use error::{Error, ErrorKind, Operation, Result};
+pub use function::Function;
+pub use hook::Hook;
+pub use ident::HookIdent;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use midhook::{Context, MidHook, XMM_COUNT};
+pub use registry::HookRecord;
+pub use transaction::{HookTransaction, TransactionError};
+
+use error::StatusExt;
+
+#[cfg(not(any(
+    windows,
+    all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")),
+)))]
+compile_error!("minhook_ex only supports Windows, and Linux on x86-64 or AArch64");
 
 /// Method to use for suspending/resuming threads.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ThreadFreezeMethod {
     /// Original method using `CreateToolhelp32Snapshot`, supported
     /// across Windows versions but is slow and a little unreliable.
@@ -102,63 +71,62 @@ pub enum ThreadFreezeMethod {
     /// Newer method using undocumented `NtGetNextThread`, supported
     /// starting from Windows Vista but is faster and more reliable.
     KernelNextThread,
+    /// Threads are neither suspended nor have their instruction pointers
+    /// adjusted. Can only be selected with [`ThreadFreezeMethod::none_unsafe`].
+    NoneUnsafe(UnsafeMarker),
 }
 
+impl ThreadFreezeMethod {
+    /// Select not suspending threads at all while changing hooks.
+    ///
+    /// # Safety
+    ///
+    /// No thread may execute code of the affected target functions,
+    /// or their trampolines, while hooks are being enabled or disabled.
+    pub const unsafe fn none_unsafe() -> Self {
+        ThreadFreezeMethod::NoneUnsafe(UnsafeMarker(()))
+    }
+}
+
+/// Proof that [`ThreadFreezeMethod::NoneUnsafe`]
+/// was selected through its `unsafe` constructor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnsafeMarker(());
+
 impl From<ThreadFreezeMethod> for MH_THREAD_FREEZE_METHOD {
     fn from(method: ThreadFreezeMethod) -> Self {
         match method {
             ThreadFreezeMethod::OriginalSnapshot => MH_THREAD_FREEZE_METHOD::MH_FREEZE_METHOD_ORIGINAL,
             ThreadFreezeMethod::KernelNextThread => MH_THREAD_FREEZE_METHOD::MH_FREEZE_METHOD_FAST_UNDOCUMENTED,
+            ThreadFreezeMethod::NoneUnsafe(_) => MH_THREAD_FREEZE_METHOD::MH_FREEZE_METHOD_NONE_UNSAFE,
         }
     }
 }
 
-/// Internal trait to simplify [`MH_STATUS`] into [`Result`] conversion.
-///
-/// Can't use the idiomatic [`From`]/[`Into`] because both types
-/// are defined out of crate.
-trait StatusExt {
-    fn into_result(self) -> Result<()>;
-}
-
-impl StatusExt for MH_STATUS {
-    fn into_result(self) -> Result<()> {
-        match self.try_into() {
-            Ok(err) => Err(err),
-            Err(_) => Ok(()),
-        }
-    }
-}
-
-/// Initialize the MinHook library and select an
-/// internal method of suspending/resuming threads.
-pub fn initialize(freeze: ThreadFreezeMethod) -> Result<()> {
-    unsafe { MH_Initialize() }.into_result()?;
-    unsafe { MH_SetThreadFreezeMethod(freeze.into()) }.into_result()
-}
-
-/// Uninitialize the MinHook library.
-pub fn uninitialize() -> Result<()> {
-    unsafe { MH_Uninitialize() }.into_result()
-}
-
 /// Create a disabled hook for a `target` function.
 /// Returns a pointer to the trampoline function.
 ///
+/// This is the unchecked building block of [`Hook`], which should be
+/// preferred as it ties the hook to an initialized [`MinHook`] context.
+///
 /// # Arguments
 ///
 /// * `target` - pointer to the hooked function.
 /// * `detour` - pointer to the overwriting function.
-/// * `ident` - optional hook identifier, provide to set multiple
-///     hooks for the same target function.
+/// * `ident` - hook identifier, set different ones to create
+///     multiple hooks for the same target function.
+///
+/// # Safety
+///
+/// `target` must be a hookable function, and it must be sound to
+/// call `detour` in its place for as long as the hook is enabled.
+#[track_caller]
 pub unsafe fn create_hook(target: *const c_void, detour: *const c_void,
-    ident: Option<c_ulonglong>) -> Result<*const c_void>
+    ident: HookIdent) -> Result<*const c_void>
 {
-    let mut trampoline: *mut c_void = std::ptr::null_mut();
-    match ident {
-        Some(ident) => MH_CreateHookEx(ident, target, detour, &mut trampoline),
-        None => MH_CreateHook(target, detour, &mut trampoline),
-    }.into_result()?;
+    let trampoline = backend::current().create_hook(target, detour, ident)
+        .map_err(|err| err.with_operation(Operation::CreateHook).with_hook(target, ident))?;
+    registry::insert(target, detour, trampoline, ident, Location::caller());
     Ok(trampoline)
 }
 
@@ -167,13 +135,16 @@ pub unsafe fn create_hook(target: *const c_void, detour: *const c_void,
 /// # Arguments
 ///
 /// * `target` - pointer to the hooked function.
-/// * `ident` - optional hook identifier, required to remove a hook
-///     which was created with one.
-pub unsafe fn remove_hook(target: *const c_void, ident: Option<c_ulonglong>) -> Result<()> {
-    match ident {
-        Some(ident) => MH_RemoveHookEx(ident, target),
-        None => MH_RemoveHook(target),
-    }.into_result()
+/// * `ident` - identifier the hook was created with.
+///
+/// # Safety
+///
+/// The trampoline of the hook must not be called anymore.
+pub unsafe fn remove_hook(target: *const c_void, ident: HookIdent) -> Result<()> {
+    backend::current().remove_hook(Some(target), ident)
+        .into_hook_result(Operation::RemoveHook, target, ident)?;
+    registry::remove(Some(target), ident);
+    Ok(())
 }
 
 /// Enable a previously created hook.
@@ -181,13 +152,16 @@ pub unsafe fn remove_hook(target: *const c_void, ident: Option<c_ulonglong>) ->
 /// # Arguments
 ///
 /// * `target` - pointer to the hooked function.
-/// * `ident` - optional hook identifier, required to enable a hook
-///     which was created with one.
-pub unsafe fn enable_hook(target: *const c_void, ident: Option<c_ulonglong>) -> Result<()> {
-    match ident {
-        Some(ident) => MH_EnableHookEx(ident, target),
-        None => MH_EnableHook(target),
-    }.into_result()
+/// * `ident` - identifier the hook was created with.
+///
+/// # Safety
+///
+/// It must be sound to call the detour in place of the target.
+pub unsafe fn enable_hook(target: *const c_void, ident: HookIdent) -> Result<()> {
+    backend::current().enable_hook(Some(target), ident)
+        .into_hook_result(Operation::EnableHook, target, ident)?;
+    registry::set_enabled(Some(target), ident, true);
+    Ok(())
 }
 
 /// Disable a previously created and enabled hook.
@@ -195,11 +169,219 @@ pub unsafe fn enable_hook(target: *const c_void, ident: Option<c_ulonglong>) ->
 /// # Arguments
 ///
 /// * `target` - pointer to the hooked function.
-/// * `ident` - optional hook identifier, required to enable a hook
-///     which was created and enabled with one.
-pub unsafe fn disable_hook(target: *const c_void, ident: Option<c_ulonglong>) -> Result<()> {
-    match ident {
-        Some(ident) => MH_DisableHookEx(ident, target),
-        None => MH_DisableHook(target),
-    }.into_result()
+/// * `ident` - identifier the hook was created with.
+///
+/// # Safety
+///
+/// Patches code which other threads may be running,
+/// unless they are suspended, see [`ThreadFreezeMethod`].
+pub unsafe fn disable_hook(target: *const c_void, ident: HookIdent) -> Result<()> {
+    backend::current().disable_hook(Some(target), ident)
+        .into_hook_result(Operation::DisableHook, target, ident)?;
+    registry::set_enabled(Some(target), ident, false);
+    Ok(())
+}
+
+/// Queue a previously created hook to be enabled by [`apply_queued`].
+///
+/// # Arguments
+///
+/// * `target` - pointer to the hooked function.
+/// * `ident` - identifier the hook was created with.
+///
+/// # Safety
+///
+/// Same as for [`enable_hook`], once the change is applied.
+pub unsafe fn queue_enable_hook(target: *const c_void, ident: HookIdent) -> Result<()> {
+    backend::current().queue_enable_hook(Some(target), ident)
+        .into_hook_result(Operation::QueueEnableHook, target, ident)?;
+    registry::set_queued(Some(target), ident, true);
+    Ok(())
+}
+
+/// Queue a previously created hook to be disabled by [`apply_queued`].
+///
+/// # Arguments
+///
+/// * `target` - pointer to the hooked function.
+/// * `ident` - identifier the hook was created with.
+///
+/// # Safety
+///
+/// Same as for [`disable_hook`], once the change is applied.
+pub unsafe fn queue_disable_hook(target: *const c_void, ident: HookIdent) -> Result<()> {
+    backend::current().queue_disable_hook(Some(target), ident)
+        .into_hook_result(Operation::QueueDisableHook, target, ident)?;
+    registry::set_queued(Some(target), ident, false);
+    Ok(())
+}
+
+/// Apply all queued changes in one go, suspending threads only once.
+///
+/// # Arguments
+///
+/// * `ident` - only apply changes queued for hooks with this identifier,
+///     or all changes for [`HookIdent::ALL`].
+///
+/// # Safety
+///
+/// Same as for [`enable_hook`] and [`disable_hook`],
+/// for every hook with a queued change.
+///
+/// If the batch fails part way, the remaining changes are applied one
+/// hook at a time, so that the registry keeps matching the state of the
+/// hooks, and the error of the batch is returned.
+pub unsafe fn apply_queued(ident: HookIdent) -> Result<()> {
+    let result = backend::current().apply_queued(ident)
+        .into_ident_result(Operation::ApplyQueued, ident);
+    if result.is_err() {
+        return resync_failed(result, ident, |record| {
+            let enable = record.is_queued_enabled();
+            if enable != record.is_enabled() {
+                switch_single(record, enable);
+            }
+        });
+    }
+    registry::apply_queued(ident);
+    Ok(())
+}
+
+/// Enable all created hooks with an identifier in one go.
+///
+/// # Arguments
+///
+/// * `ident` - identifier of the hooks, or [`HookIdent::ALL`].
+///
+/// # Safety
+///
+/// Same as for [`enable_hook`], for every affected hook.
+///
+/// Failures part way are handled as for [`apply_queued`].
+pub unsafe fn enable_hooks(ident: HookIdent) -> Result<()> {
+    let result = backend::current().enable_hook(None, ident)
+        .into_ident_result(Operation::EnableHook, ident);
+    if result.is_err() {
+        return resync_failed(result, ident, |record| {
+            if !record.is_enabled() {
+                switch_single(record, true);
+            }
+        });
+    }
+    registry::set_enabled(None, ident, true);
+    Ok(())
+}
+
+/// Disable all created hooks with an identifier in one go.
+///
+/// # Arguments
+///
+/// * `ident` - identifier of the hooks, or [`HookIdent::ALL`].
+///
+/// # Safety
+///
+/// Same as for [`disable_hook`], for every affected hook.
+///
+/// Failures part way are handled as for [`apply_queued`].
+pub unsafe fn disable_hooks(ident: HookIdent) -> Result<()> {
+    let result = backend::current().disable_hook(None, ident)
+        .into_ident_result(Operation::DisableHook, ident);
+    if result.is_err() {
+        return resync_failed(result, ident, |record| {
+            if record.is_enabled() {
+                switch_single(record, false);
+            }
+        });
+    }
+    registry::set_enabled(None, ident, false);
+    Ok(())
+}
+
+/// Remove all created hooks with an identifier in one go.
+///
+/// # Arguments
+///
+/// * `ident` - identifier of the hooks, or [`HookIdent::ALL`].
+///
+/// # Safety
+///
+/// Same as for [`remove_hook`], for every affected hook.
+///
+/// Failures part way are handled as for [`apply_queued`].
+pub unsafe fn remove_hooks(ident: HookIdent) -> Result<()> {
+    let result = backend::current().remove_hook(None, ident)
+        .into_ident_result(Operation::RemoveHook, ident);
+    if result.is_err() {
+        return resync_failed(result, ident, |record| remove_single(record));
+    }
+    registry::remove(None, ident);
+    Ok(())
+}
+
+/// Remove all created hooks with an identifier which are disabled.
+///
+/// # Arguments
+///
+/// * `ident` - identifier of the hooks, or [`HookIdent::ALL`].
+///
+/// # Safety
+///
+/// Same as for [`remove_hook`], for every affected hook.
+///
+/// Failures part way are handled as for [`apply_queued`].
+pub unsafe fn remove_disabled_hooks(ident: HookIdent) -> Result<()> {
+    let result = backend::current().remove_disabled_hooks(ident)
+        .into_ident_result(Operation::RemoveDisabledHooks, ident);
+    if result.is_err() {
+        return resync_failed(result, ident, |record| {
+            if !record.is_enabled() {
+                remove_single(record);
+            }
+        });
+    }
+    registry::remove_disabled(ident);
+    Ok(())
+}
+
+/// Bring the registry back in line with the backend after an operation
+/// on all hooks with an identifier failed, and return its `result`.
+///
+/// The backend may have changed some of the hooks before failing without
+/// telling which, so `finish` is called for every affected record to
+/// repeat the operation on its own hook. A hook which was already changed
+/// is reported as such by the backend, and one which fails again keeps
+/// its recorded state.
+unsafe fn resync_failed(result: Result<()>, ident: HookIdent,
+    mut finish: impl FnMut(&HookRecord)) -> Result<()>
+{
+    for record in registry::with_ident(ident) {
+        finish(&record);
+    }
+    result
+}
+
+/// Enable or disable a single hook after a failed bulk operation,
+/// recording its state if it ends up switched.
+unsafe fn switch_single(record: &HookRecord, enable: bool) {
+    let (target, ident) = (Some(record.target()), record.ident());
+    let (result, switched) = match enable {
+        true => (backend::current().enable_hook(target, ident), ErrorKind::HookEnabled),
+        false => (backend::current().disable_hook(target, ident), ErrorKind::HookDisabled),
+    };
+    if result.map_or_else(|err| err.kind() == switched, |()| true) {
+        registry::set_enabled(target, ident, enable);
+    }
+}
+
+/// Remove a single hook after a failed bulk operation,
+/// recording its state if it ends up removed or disabled.
+unsafe fn remove_single(record: &HookRecord) {
+    let (target, ident) = (Some(record.target()), record.ident());
+    match backend::current().remove_hook(target, ident) {
+        Ok(()) => registry::remove(target, ident),
+        Err(err) if err.kind() == ErrorKind::NotCreated => registry::remove(target, ident),
+        // Removal disables an enabled hook first,
+        // which the bulk operation may have done.
+        Err(_) if record.is_enabled() => switch_single(record, false),
+        Err(_) => {}
+    }
 }
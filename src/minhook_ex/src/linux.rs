@@ -0,0 +1,518 @@
+//! Reimplementation of the MinHook API for Linux on x86-64 and AArch64.
+//!
+//! Targets are patched with a `jmp rel32`, or a `b` on AArch64, into a
+//! relay next to them, just like the library does on Windows. Relays and
+//! trampolines are slots of a [`NearAllocator`] within reach of their
+//! targets, and code is made writable with `mprotect`.
+//!
+//! Every hook of a target gets its own relay to its detour and its own
+//! trampoline, which leads into the detour of the previously created
+//! enabled hook, or into the original function. This way, hooks with
+//! different identifiers can be enabled and disabled in any order.
+//!
+//! Other threads are not suspended while code is patched, so the thread
+//! freeze method is accepted but has no effect. Patches which fit in an
+//! aligned 8-byte word are written with a single store, which is always
+//! the case on AArch64, where the instruction cache is flushed afterwards.
+//! Others first get a jump to itself, which holds threads arriving at the
+//! target while the rest is written, and targets too close to the end of
+//! a word for that jump are refused.
+
+#![allow(non_snake_case)]
+
+use std::ffi::{c_ulonglong, c_void};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use minhook_ex_sys::{MH_ALL_IDENTS, MH_STATUS, MH_THREAD_FREEZE_METHOD};
+
+use arch::{JMP_INDIRECT, JMP_SELF, MAX_DISTANCE, PATCH_LEN};
+
+use crate::allocator::{self, maps, NearAllocator};
+
+/// Size of a slot, which holds either a trampoline copying the original
+/// function, or the relay and the trampoline of a single hook.
+const SLOT_SIZE: usize = 128;
+/// Number of bytes of a target read to build its trampoline.
+const MAX_PROLOGUE: usize = 64;
+
+/// Offset of a hook's relay into its detour in its slot.
+const RELAY: usize = 0;
+/// Offset of a hook's trampoline in its slot.
+const TRAMPOLINE: usize = 16;
+
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+
+#[derive(Debug)]
+struct State {
+    targets: Vec<Target>,
+    memory: NearAllocator,
+}
+
+/// A hooked function along with all of its hooks.
+#[derive(Debug)]
+struct Target {
+    address: usize,
+    /// Bytes overwritten by the patch.
+    backup: [u8; PATCH_LEN],
+    /// Slot of the trampoline copying the start of the function.
+    original: usize,
+    /// Hooks in the order they were created.
+    hooks: Vec<Hook>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Hook {
+    ident: c_ulonglong,
+    detour: usize,
+    slot: usize,
+    enabled: bool,
+    queued: bool,
+}
+
+impl Hook {
+    fn matches(&self, ident: c_ulonglong) -> bool {
+        ident == MH_ALL_IDENTS || self.ident == ident
+    }
+}
+
+/// Run `f` on the state of an initialized library.
+fn with_state(f: impl FnOnce(&mut State) -> MH_STATUS) -> MH_STATUS {
+    let Ok(mut state) = STATE.lock() else {
+        return MH_STATUS::MH_ERROR_MUTEX_FAILURE;
+    };
+    match state.as_mut() {
+        Some(state) => f(state),
+        None => MH_STATUS::MH_ERROR_NOT_INITIALIZED,
+    }
+}
+
+pub(crate) unsafe fn MH_Initialize() -> MH_STATUS {
+    let Ok(mut state) = STATE.lock() else {
+        return MH_STATUS::MH_ERROR_MUTEX_FAILURE;
+    };
+    if state.is_some() {
+        return MH_STATUS::MH_ERROR_ALREADY_INITIALIZED;
+    }
+    *state = Some(State {
+        targets: Vec::new(),
+        memory: NearAllocator::new(SLOT_SIZE, MAX_DISTANCE),
+    });
+    MH_STATUS::MH_OK
+}
+
+pub(crate) unsafe fn MH_Uninitialize() -> MH_STATUS {
+    let Ok(mut guard) = STATE.lock() else {
+        return MH_STATUS::MH_ERROR_MUTEX_FAILURE;
+    };
+    let Some(state) = guard.as_mut() else {
+        return MH_STATUS::MH_ERROR_NOT_INITIALIZED;
+    };
+    let status = state.remove(MH_ALL_IDENTS, None, |_| true);
+    if status != MH_STATUS::MH_OK {
+        return status;
+    }
+    state.memory.clear();
+    *guard = None;
+    MH_STATUS::MH_OK
+}
+
+pub(crate) unsafe fn MH_SetThreadFreezeMethod(_method: MH_THREAD_FREEZE_METHOD) -> MH_STATUS {
+    with_state(|_| MH_STATUS::MH_OK)
+}
+
+pub(crate) unsafe fn MH_CreateHookEx(hookIdent: c_ulonglong, pTarget: *const c_void,
+    pDetour: *const c_void, ppOriginal: *mut *mut c_void) -> MH_STATUS
+{
+    with_state(|state| {
+        if hookIdent == MH_ALL_IDENTS {
+            return MH_STATUS::MH_ERROR_UNSUPPORTED_FUNCTION;
+        }
+        let (target, detour) = (pTarget as usize, pDetour as usize);
+        if !maps::is_executable(target) || !maps::is_executable(detour) {
+            return MH_STATUS::MH_ERROR_NOT_EXECUTABLE;
+        }
+        match state.create(hookIdent, target, detour) {
+            Ok(trampoline) => {
+                if !ppOriginal.is_null() {
+                    *ppOriginal = trampoline as *mut c_void;
+                }
+                MH_STATUS::MH_OK
+            }
+            Err(status) => status,
+        }
+    })
+}
+
+pub(crate) unsafe fn MH_RemoveHookEx(hookIdent: c_ulonglong, pTarget: *const c_void) -> MH_STATUS {
+    with_state(|state| state.remove(hookIdent, target(pTarget), |_| true))
+}
+
+pub(crate) unsafe fn MH_RemoveDisabledHooksEx(hookIdent: c_ulonglong) -> MH_STATUS {
+    with_state(|state| state.remove(hookIdent, None, |hook| !hook.enabled))
+}
+
+pub(crate) unsafe fn MH_EnableHookEx(hookIdent: c_ulonglong, pTarget: *const c_void) -> MH_STATUS {
+    with_state(|state| state.set_enabled(hookIdent, target(pTarget), true))
+}
+
+pub(crate) unsafe fn MH_DisableHookEx(hookIdent: c_ulonglong, pTarget: *const c_void) -> MH_STATUS {
+    with_state(|state| state.set_enabled(hookIdent, target(pTarget), false))
+}
+
+pub(crate) unsafe fn MH_QueueEnableHookEx(hookIdent: c_ulonglong,
+    pTarget: *const c_void) -> MH_STATUS
+{
+    with_state(|state| state.queue(hookIdent, target(pTarget), true))
+}
+
+pub(crate) unsafe fn MH_QueueDisableHookEx(hookIdent: c_ulonglong,
+    pTarget: *const c_void) -> MH_STATUS
+{
+    with_state(|state| state.queue(hookIdent, target(pTarget), false))
+}
+
+pub(crate) unsafe fn MH_ApplyQueuedEx(hookIdent: c_ulonglong) -> MH_STATUS {
+    with_state(|state| state.apply_queued(hookIdent))
+}
+
+/// Translate [`minhook_ex_sys::MH_ALL_HOOKS`] into `None`.
+fn target(pTarget: *const c_void) -> Option<usize> {
+    (!pTarget.is_null()).then_some(pTarget as usize)
+}
+
+impl State {
+    /// Create a disabled hook, returning its trampoline.
+    unsafe fn create(&mut self, ident: c_ulonglong, target: usize,
+        detour: usize) -> Result<usize, MH_STATUS>
+    {
+        let index = match self.targets.iter().position(|entry| entry.address == target) {
+            Some(index) if self.targets[index].hooks.iter().any(|hook| hook.ident == ident) => {
+                return Err(MH_STATUS::MH_ERROR_ALREADY_CREATED);
+            }
+            Some(index) => index,
+            None => {
+                let entry = self.create_target(target)?;
+                self.targets.push(entry);
+                self.targets.len() - 1
+            }
+        };
+
+        let slot = match self.allocate(target) {
+            Ok(slot) => slot,
+            Err(status) => {
+                if self.targets[index].hooks.is_empty() {
+                    let entry = self.targets.swap_remove(index);
+                    self.free(entry.original);
+                }
+                return Err(status);
+            }
+        };
+        let code = slot as *mut u8;
+        std::ptr::copy_nonoverlapping(JMP_INDIRECT.as_ptr(), code.add(RELAY), JMP_INDIRECT.len());
+        std::ptr::copy_nonoverlapping(JMP_INDIRECT.as_ptr(), code.add(TRAMPOLINE), JMP_INDIRECT.len());
+        arch::flush(slot, SLOT_SIZE);
+        set_jump(slot + RELAY, detour);
+
+        // A disabled hook doesn't change the patch,
+        // and is the last one its trampoline can lead to.
+        let entry = &mut self.targets[index];
+        let next = entry.hooks.iter().rev()
+            .find(|hook| hook.enabled)
+            .map_or(entry.original, |hook| hook.detour);
+        set_jump(slot + TRAMPOLINE, next);
+        entry.hooks.push(Hook { ident, detour, slot, enabled: false, queued: false });
+        Ok(slot + TRAMPOLINE)
+    }
+
+    /// Copy the start of a function which has no hooks yet.
+    unsafe fn create_target(&mut self, target: usize) -> Result<Target, MH_STATUS> {
+        let readable = maps::readable_len(target).min(MAX_PROLOGUE);
+        let code = std::slice::from_raw_parts(target as *const u8, readable);
+        if code.len() < PATCH_LEN || !is_patchable(target) {
+            return Err(MH_STATUS::MH_ERROR_UNSUPPORTED_FUNCTION);
+        }
+
+        let slot = self.allocate(target)?;
+        let built = arch::build(code, target, slot)
+            .ok().filter(|built| built.code().len() <= SLOT_SIZE);
+        let Some(built) = built else {
+            self.free(slot);
+            return Err(MH_STATUS::MH_ERROR_UNSUPPORTED_FUNCTION);
+        };
+        std::ptr::copy_nonoverlapping(built.code().as_ptr(), slot as *mut u8, built.code().len());
+        arch::flush(slot, SLOT_SIZE);
+
+        let mut backup = [0; PATCH_LEN];
+        backup.copy_from_slice(&code[..PATCH_LEN]);
+        Ok(Target { address: target, backup, original: slot, hooks: Vec::new() })
+    }
+
+    /// Remove hooks matching an identifier, a target (or all, if `None`)
+    /// and a predicate, freeing the targets left without hooks.
+    unsafe fn remove(&mut self, ident: c_ulonglong, target: Option<usize>,
+        predicate: impl Fn(&Hook) -> bool) -> MH_STATUS
+    {
+        let mut found = false;
+        let mut freed = Vec::new();
+        for entry in self.targets.iter_mut().filter(|entry| entry.is(target)) {
+            let previous = entry.hooks.clone();
+            entry.hooks.retain(|hook| !(hook.matches(ident) && predicate(hook)));
+            if entry.hooks.len() == previous.len() {
+                continue;
+            }
+            found = true;
+            if entry.relink().is_err() {
+                entry.hooks = previous;
+                let _ = entry.relink();
+                return MH_STATUS::MH_ERROR_MEMORY_PROTECT;
+            }
+            freed.extend(previous.iter()
+                .filter(|hook| hook.matches(ident) && predicate(hook))
+                .map(|hook| hook.slot));
+        }
+        if target.is_some() && !found {
+            return MH_STATUS::MH_ERROR_NOT_CREATED;
+        }
+
+        let mut index = 0;
+        while index < self.targets.len() {
+            if self.targets[index].hooks.is_empty() {
+                freed.push(self.targets.swap_remove(index).original);
+            } else {
+                index += 1;
+            }
+        }
+        for slot in freed {
+            self.free(slot);
+        }
+        MH_STATUS::MH_OK
+    }
+
+    /// Enable or disable hooks right away.
+    unsafe fn set_enabled(&mut self, ident: c_ulonglong, target: Option<usize>,
+        enable: bool) -> MH_STATUS
+    {
+        let refusal = match enable {
+            true => MH_STATUS::MH_ERROR_ENABLED,
+            false => MH_STATUS::MH_ERROR_DISABLED,
+        };
+        self.update(ident, target, |hook| match hook.enabled == enable {
+            true => Err(refusal),
+            false => Ok(true),
+        }, |hook| {
+            hook.enabled = enable;
+            hook.queued = enable;
+        })
+    }
+
+    /// Select the state hooks are changed to by [`MH_ApplyQueuedEx`].
+    unsafe fn queue(&mut self, ident: c_ulonglong, target: Option<usize>,
+        enable: bool) -> MH_STATUS
+    {
+        let matching = self.targets.iter_mut()
+            .filter(|entry| entry.is(target))
+            .flat_map(|entry| entry.hooks.iter_mut())
+            .filter(|hook| hook.matches(ident));
+        let mut found = false;
+        for hook in matching {
+            hook.queued = enable;
+            found = true;
+        }
+        match found || target.is_none() {
+            true => MH_STATUS::MH_OK,
+            false => MH_STATUS::MH_ERROR_NOT_CREATED,
+        }
+    }
+
+    unsafe fn apply_queued(&mut self, ident: c_ulonglong) -> MH_STATUS {
+        self.update(ident, None, |hook| Ok(hook.enabled != hook.queued), |hook| {
+            hook.enabled = hook.queued;
+        })
+    }
+
+    /// Change hooks matching an identifier and a target (or all, if `None`),
+    /// then patch their targets. For a single target, `check` may refuse the
+    /// change, and otherwise it only filters which hooks are changed.
+    unsafe fn update(&mut self, ident: c_ulonglong, target: Option<usize>,
+        check: impl Fn(&Hook) -> Result<bool, MH_STATUS>, change: impl Fn(&mut Hook)) -> MH_STATUS
+    {
+        let mut found = false;
+        for entry in self.targets.iter_mut().filter(|entry| entry.is(target)) {
+            let mut changed = Vec::new();
+            for (index, hook) in entry.hooks.iter().enumerate().filter(|(_, hook)| hook.matches(ident)) {
+                found = true;
+                match check(hook) {
+                    Ok(true) => changed.push(index),
+                    Ok(false) => (),
+                    Err(status) if target.is_some() => return status,
+                    Err(_) => (),
+                }
+            }
+            if changed.is_empty() {
+                continue;
+            }
+
+            let previous = entry.hooks.clone();
+            for &index in &changed {
+                change(&mut entry.hooks[index]);
+            }
+            if entry.relink().is_err() {
+                entry.hooks = previous;
+                let _ = entry.relink();
+                return MH_STATUS::MH_ERROR_MEMORY_PROTECT;
+            }
+        }
+        match found || target.is_none() {
+            true => MH_STATUS::MH_OK,
+            false => MH_STATUS::MH_ERROR_NOT_CREATED,
+        }
+    }
+
+    /// Take a free slot within reach of `target`.
+    fn allocate(&mut self, target: usize) -> Result<usize, MH_STATUS> {
+        self.memory.allocate(target).ok_or(MH_STATUS::MH_ERROR_MEMORY_ALLOC)
+    }
+
+    /// Return a slot which is no longer used.
+    unsafe fn free(&mut self, slot: usize) {
+        self.memory.free(slot);
+    }
+}
+
+impl Target {
+    /// Check whether this is the `target`, or any for `None`.
+    fn is(&self, target: Option<usize>) -> bool {
+        target.is_none_or(|target| self.address == target)
+    }
+
+    /// Point every trampoline at the detour of the enabled hook created
+    /// before it, or at the original function, and patch the target into
+    /// the relay of the last enabled hook, or restore it if there is none.
+    unsafe fn relink(&self) -> Result<(), MH_STATUS> {
+        let mut next = self.original;
+        for hook in &self.hooks {
+            set_jump(hook.slot + TRAMPOLINE, next);
+            if hook.enabled {
+                next = hook.detour;
+            }
+        }
+
+        let patch = match self.hooks.iter().rev().find(|hook| hook.enabled) {
+            Some(hook) => arch::patch(self.address, hook.slot + RELAY),
+            None => self.backup,
+        };
+        write_code(self.address, &patch)
+    }
+}
+
+/// Change the destination of an indirect jump in a slot.
+unsafe fn set_jump(jump: usize, destination: usize) {
+    let pointer = (jump + JMP_INDIRECT.len()) as *const AtomicUsize;
+    (*pointer).store(destination, Ordering::Release);
+}
+
+/// Overwrite code, temporarily making its pages writable.
+unsafe fn write_code(address: usize, bytes: &[u8]) -> Result<(), MH_STATUS> {
+    allocator::with_writable(address, bytes.len(), || {
+        store_patch(address, bytes);
+        arch::flush(address, bytes.len());
+    })
+    .ok_or(MH_STATUS::MH_ERROR_MEMORY_PROTECT)
+}
+
+/// Check whether a patch at an address can be written by [`store_patch`].
+fn is_patchable(address: usize) -> bool {
+    let head = 8 - (address & 7);
+    head >= PATCH_LEN || head >= JMP_SELF.len()
+}
+
+/// Write a patch while other threads keep running, so that
+/// they never execute a mix of old and new instructions.
+unsafe fn store_patch(address: usize, bytes: &[u8]) {
+    let word = address & !7;
+    let next = word + 8;
+    if address + bytes.len() <= next {
+        store_in_word(word, address, bytes);
+        return;
+    }
+    // Hold arriving threads in a jump to itself while the part in the
+    // next word is written, then replace the jump with the rest.
+    let head = next - address;
+    store_in_word(word, address, &JMP_SELF);
+    store_in_word(next, next, &bytes[head..]);
+    store_in_word(word, address, &bytes[..head]);
+}
+
+/// Replace bytes within an aligned 8-byte word with a single store.
+unsafe fn store_in_word(word: usize, address: usize, bytes: &[u8]) {
+    let word = &*(word as *const AtomicU64);
+    let mut value = word.load(Ordering::Acquire).to_le_bytes();
+    value[address & 7..][..bytes.len()].copy_from_slice(bytes);
+    word.store(u64::from_le_bytes(value), Ordering::Release);
+}
+
+/// Code specific to the instruction set.
+#[cfg(target_arch = "x86_64")]
+mod arch {
+    use crate::decoder::Bitness;
+    use crate::trampoline::{self, Trampoline, TrampolineError, JMP_REL_LEN};
+
+    pub(super) const PATCH_LEN: usize = JMP_REL_LEN;
+    /// Maximum distance of a block from its targets, which leaves plenty of
+    /// the `rel32` range for RIP-relative operands of relocated instructions.
+    pub(super) const MAX_DISTANCE: usize = 0x4000_0000;
+    /// `jmp [rip+2]` followed by two bytes of padding, which
+    /// keeps the absolute address after it 8-byte aligned.
+    pub(super) const JMP_INDIRECT: [u8; 8] = [0xFF, 0x25, 0x02, 0x00, 0x00, 0x00, 0xCC, 0xCC];
+    /// `jmp $`, a short jump to itself.
+    pub(super) const JMP_SELF: [u8; 2] = [0xEB, 0xFE];
+
+    pub(super) fn build(code: &[u8], target: usize, slot: usize)
+        -> Result<Trampoline, TrampolineError>
+    {
+        trampoline::build(code, target, slot, Bitness::Bits64, PATCH_LEN)
+    }
+
+    /// Encode the `jmp rel32` from a target into a relay.
+    pub(super) fn patch(target: usize, relay: usize) -> [u8; PATCH_LEN] {
+        let offset = relay.wrapping_sub(target + PATCH_LEN) as i32;
+        let mut patch = [0xE9; PATCH_LEN];
+        patch[1..].copy_from_slice(&offset.to_le_bytes());
+        patch
+    }
+
+    /// Instruction caches are kept coherent by the processor.
+    pub(super) unsafe fn flush(_address: usize, _len: usize) {}
+}
+
+/// Code specific to the instruction set.
+#[cfg(target_arch = "aarch64")]
+mod arch {
+    use crate::trampoline::aarch64::{self, BRANCH_LEN};
+    use crate::trampoline::{Trampoline, TrampolineError};
+
+    pub(super) const PATCH_LEN: usize = BRANCH_LEN;
+    /// Maximum distance of a block from its targets, which is well
+    /// within the 128 MiB reach of `b` in either direction.
+    pub(super) const MAX_DISTANCE: usize = 0x400_0000;
+    /// `ldr x16, #8; br x16`, followed by the 8-byte aligned absolute address.
+    pub(super) const JMP_INDIRECT: [u8; 8] = [0x50, 0x00, 0x00, 0x58, 0x00, 0x02, 0x1F, 0xD6];
+    /// `b .`, a branch to itself, though aligned patches never need it.
+    pub(super) const JMP_SELF: [u8; 4] = [0x00, 0x00, 0x00, 0x14];
+
+    pub(super) fn build(code: &[u8], target: usize, slot: usize)
+        -> Result<Trampoline, TrampolineError>
+    {
+        aarch64::build(code, target, slot, PATCH_LEN)
+    }
+
+    /// Encode the `b` from a target into a relay.
+    pub(super) fn patch(target: usize, relay: usize) -> [u8; PATCH_LEN] {
+        aarch64::branch(target, relay).expect("relays are within reach of their targets")
+    }
+
+    pub(super) unsafe fn flush(address: usize, len: usize) {
+        aarch64::flush_instruction_cache(address, len);
+    }
+}
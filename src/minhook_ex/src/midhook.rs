@@ -0,0 +1,363 @@
+//! Hooks at arbitrary instructions, inspecting and changing registers.
+//!
+//! A mid-function hook is an ordinary hook whose detour is a generated
+//! stub instead of a function of the same signature. The stub saves all
+//! general purpose registers and flags, and optionally the XMM registers,
+//! into a [`Context`], calls a Rust callback with it, restores everything
+//! from it, and continues with the trampoline, which runs the relocated
+//! overwritten instructions and jumps back past the patch.
+
+use std::ffi::c_void;
+use std::sync::{Mutex, PoisonError};
+
+use crate::allocator::NearAllocator;
+use crate::{registry, Error, ErrorKind, HookIdent, MinHook, Operation, Result};
+
+/// Size of the slot holding a stub, which fits the largest one.
+const STUB_LEN: usize = 512;
+
+/// Memory of all stubs. They are reached through the relay of their hook,
+/// so they don't have to be near their target, but might as well be.
+static STUBS: Mutex<NearAllocator> = Mutex::new(NearAllocator::new(STUB_LEN, usize::MAX));
+
+/// Callback of a mid-function hook.
+type Callback = Box<dyn Fn(&mut Context) + Send + Sync>;
+
+/// Number of XMM registers which can be saved.
+#[cfg(target_arch = "x86_64")]
+pub const XMM_COUNT: usize = 16;
+/// Number of XMM registers which can be saved.
+#[cfg(target_arch = "x86")]
+pub const XMM_COUNT: usize = 8;
+
+/// Registers at the hooked instruction.
+///
+/// Changes to any register except the stack pointer
+/// take effect once the callback returns.
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+#[derive(Debug)]
+pub struct Context {
+    pub rax: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rbx: u64,
+    /// Stack pointer at the hooked instruction, changes are ignored.
+    pub rsp: u64,
+    pub rbp: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rflags: u64,
+    xmm: *mut [u128; XMM_COUNT],
+}
+
+/// Registers at the hooked instruction.
+///
+/// Changes to any register except the stack pointer
+/// take effect once the callback returns.
+#[cfg(target_arch = "x86")]
+#[repr(C)]
+#[derive(Debug)]
+pub struct Context {
+    pub edi: u32,
+    pub esi: u32,
+    pub ebp: u32,
+    /// Stack pointer at the hooked instruction, changes are ignored.
+    pub esp: u32,
+    pub ebx: u32,
+    pub edx: u32,
+    pub ecx: u32,
+    pub eax: u32,
+    pub eflags: u32,
+    xmm: *mut [u128; XMM_COUNT],
+}
+
+impl Context {
+    /// Get the XMM registers, if the hook saves them.
+    pub fn xmm(&mut self) -> Option<&mut [u128; XMM_COUNT]> {
+        unsafe { self.xmm.as_mut() }
+    }
+}
+
+/// Called by every stub with its context and callback.
+extern "C" fn dispatch(context: &mut Context, callback: &Callback) {
+    callback(context)
+}
+
+/// A created mid-function hook which is removed when dropped.
+///
+/// Like [`Hook`](crate::Hook), the hook borrows the [`MinHook`]
+/// context it was created with.
+#[derive(Debug)]
+pub struct MidHook<'mh> {
+    minhook: &'mh MinHook,
+    target: *const c_void,
+    stub: usize,
+    trampoline: *const c_void,
+    ident: HookIdent,
+    callback: *mut Callback,
+}
+
+// The callback is `Send + Sync`, and the rest are addresses.
+unsafe impl Send for MidHook<'_> {}
+unsafe impl Sync for MidHook<'_> {}
+
+impl<'mh> MidHook<'mh> {
+    /// Create a disabled hook at a `target` instruction.
+    ///
+    /// The instructions overwritten by the patch are relocated into the
+    /// trampoline as for any other hook, so they must not be branched into
+    /// by the rest of the function, see [`analysis`](crate::analysis).
+    ///
+    /// A panicking callback aborts the process.
+    ///
+    /// # Arguments
+    ///
+    /// * `minhook` - the initialized library context.
+    /// * `target` - the hooked instruction.
+    /// * `callback` - function called with the registers
+    ///     every time the instruction is reached.
+    /// * `save_xmm` - whether to save and restore the XMM registers
+    ///     around the callback, see [`Context::xmm`].
+    /// * `ident` - hook identifier, set different ones to create
+    ///     multiple hooks for the same target instruction.
+    ///
+    /// # Safety
+    ///
+    /// `target` must be the start of an instruction, and it must be sound
+    /// to run `callback` there, with any changes it makes to the registers.
+    ///
+    /// The hook must not be dropped while another thread may be running
+    /// its stub or callback, as dropping it frees both once the hook is
+    /// removed. Suspending threads while removing the hook doesn't help,
+    /// as it only moves them out of the patched instructions.
+    #[track_caller]
+    pub unsafe fn new<C>(minhook: &'mh MinHook, target: *const c_void, callback: C,
+        save_xmm: bool, ident: HookIdent) -> Result<Self>
+    where
+        C: Fn(&mut Context) + Send + Sync + 'static,
+    {
+        let callback: *mut Callback = Box::into_raw(Box::new(Box::new(callback)));
+        let Some(stub) = STUBS.lock().unwrap_or_else(PoisonError::into_inner)
+            .allocate(target as usize) else {
+            drop(Box::from_raw(callback));
+            let err = Error::new(ErrorKind::AllocationFailure);
+            return Err(err.with_operation(Operation::CreateHook).with_hook(target, ident));
+        };
+
+        let (code, trampoline_offset) = arch::build(stub, save_xmm, callback);
+        std::ptr::copy_nonoverlapping(code.as_ptr(), stub as *mut u8, code.len());
+
+        let trampoline = match crate::create_hook(target, stub as *const c_void, ident) {
+            Ok(trampoline) => trampoline,
+            Err(err) => {
+                STUBS.lock().unwrap_or_else(PoisonError::into_inner).free(stub);
+                drop(Box::from_raw(callback));
+                return Err(err);
+            }
+        };
+        ((stub + trampoline_offset) as *mut usize).write_unaligned(trampoline as usize);
+
+        Ok(Self { minhook, target, stub, trampoline, ident, callback })
+    }
+
+    /// Enable the hook, running the callback whenever the target is reached.
+    pub fn enable(&self) -> Result<()> {
+        unsafe { crate::enable_hook(self.target, self.ident) }
+    }
+
+    /// Disable the hook, restoring the original target instruction.
+    pub fn disable(&self) -> Result<()> {
+        unsafe { crate::disable_hook(self.target, self.ident) }
+    }
+
+    /// Check whether the hook is currently enabled, including
+    /// by bulk or queued operations outside of this handle.
+    pub fn is_enabled(&self) -> bool {
+        registry::is_enabled(self.target, self.ident).unwrap_or(false)
+    }
+
+    /// Get the hooked instruction.
+    pub fn target(&self) -> *const c_void {
+        self.target
+    }
+
+    /// Get the generated stub calling the callback, which is the detour of the hook.
+    pub fn stub(&self) -> *const c_void {
+        self.stub as *const c_void
+    }
+
+    /// Get the trampoline, which runs the overwritten instructions
+    /// and continues past them.
+    pub fn trampoline(&self) -> *const c_void {
+        self.trampoline
+    }
+
+    /// Get the hook identifier.
+    pub fn ident(&self) -> HookIdent {
+        self.ident
+    }
+
+    /// Get the library context this hook was created with.
+    pub fn minhook(&self) -> &'mh MinHook {
+        self.minhook
+    }
+}
+
+impl Drop for MidHook<'_> {
+    fn drop(&mut self) {
+        // The stub and the callback are only freed once the hook is gone,
+        // otherwise they are leaked as they may still be reached. Threads
+        // already past the patch are ruled out by the contract of `new`.
+        if unsafe { crate::remove_hook(self.target, self.ident) }.is_ok() {
+            unsafe {
+                STUBS.lock().unwrap_or_else(PoisonError::into_inner).free(self.stub);
+                drop(Box::from_raw(self.callback));
+            }
+        }
+    }
+}
+
+/// Machine code of the stubs.
+#[cfg(target_arch = "x86_64")]
+mod arch {
+    use super::{dispatch, Callback};
+
+    /// Size of [`Context`](super::Context).
+    const CONTEXT_LEN: u8 = 144;
+    /// Bytes below the stack pointer which leaf functions
+    /// may use without adjusting it on Linux.
+    const RED_ZONE: u8 = 128;
+
+    /// Build a stub placed at `address`, and return its code along
+    /// with the offset of the trampoline address to fill in.
+    pub(super) fn build(_address: usize, save_xmm: bool, callback: *const Callback)
+        -> (Vec<u8>, usize)
+    {
+        let mut code = Vec::with_capacity(super::STUB_LEN);
+        // lea rsp, [rsp - 128]; push 0 (XMM area); pushfq; cld
+        code.extend([0x48, 0x8D, 0x64, 0x24, RED_ZONE.wrapping_neg(), 0x6A, 0x00, 0x9C, 0xFC]);
+        // push r15 .. r8
+        for register in (0..8).rev() {
+            code.extend([0x41, 0x50 + register]);
+        }
+        // push rdi, rsi, rbp, rsp, rbx, rdx, rcx, rax
+        code.extend([0x57, 0x56, 0x55, 0x54, 0x53, 0x52, 0x51, 0x50]);
+        // lea rax, [rsp + CONTEXT_LEN + RED_ZONE]; mov [rsp + 32], rax
+        code.extend([0x48, 0x8D, 0x84, 0x24]);
+        code.extend((CONTEXT_LEN as u32 + RED_ZONE as u32).to_le_bytes());
+        code.extend([0x48, 0x89, 0x44, 0x24, 0x20]);
+        // mov rbx, rsp; and rsp, -16
+        code.extend([0x48, 0x89, 0xE3, 0x48, 0x83, 0xE4, 0xF0]);
+        if save_xmm {
+            // sub rsp, 256; movups [rsp + 16 * n], xmmn; mov [rbx + 136], rsp
+            code.extend([0x48, 0x81, 0xEC, 0x00, 0x01, 0x00, 0x00]);
+            for register in 0..16 {
+                move_xmm(&mut code, 0x11, register);
+            }
+            code.extend([0x48, 0x89, 0xA3, CONTEXT_LEN - 8, 0x00, 0x00, 0x00]);
+        }
+        // Windows passes the first arguments in rcx and rdx, and the others in rdi and rsi.
+        if cfg!(windows) {
+            // mov rcx, rbx; mov rdx, callback
+            code.extend([0x48, 0x89, 0xD9, 0x48, 0xBA]);
+        } else {
+            // mov rdi, rbx; mov rsi, callback
+            code.extend([0x48, 0x89, 0xDF, 0x48, 0xBE]);
+        }
+        code.extend((callback as u64).to_le_bytes());
+        // mov rax, dispatch; sub rsp, 32 (shadow space); call rax; add rsp, 32
+        code.extend([0x48, 0xB8]);
+        code.extend((dispatch as *const () as u64).to_le_bytes());
+        code.extend([0x48, 0x83, 0xEC, 0x20, 0xFF, 0xD0, 0x48, 0x83, 0xC4, 0x20]);
+        if save_xmm {
+            // movups xmmn, [rsp + 16 * n]
+            for register in 0..16 {
+                move_xmm(&mut code, 0x10, register);
+            }
+        }
+        // mov rsp, rbx; pop rax, rcx, rdx, rbx; lea rsp, [rsp + 8]; pop rbp, rsi, rdi
+        code.extend([0x48, 0x89, 0xDC, 0x58, 0x59, 0x5A, 0x5B]);
+        code.extend([0x48, 0x8D, 0x64, 0x24, 0x08, 0x5D, 0x5E, 0x5F]);
+        // pop r8 .. r15
+        for register in 0..8 {
+            code.extend([0x41, 0x58 + register]);
+        }
+        // popfq; lea rsp, [rsp + 8 + RED_ZONE]
+        code.extend([0x9D, 0x48, 0x8D, 0xA4, 0x24]);
+        code.extend((8 + RED_ZONE as u32).to_le_bytes());
+        // jmp [rip]
+        code.extend([0xFF, 0x25, 0x00, 0x00, 0x00, 0x00]);
+        let trampoline = code.len();
+        code.extend(0u64.to_le_bytes());
+        (code, trampoline)
+    }
+
+    /// Emit `movups` between an XMM register and its slot at the stack pointer.
+    fn move_xmm(code: &mut Vec<u8>, opcode: u8, register: u8) {
+        if register >= 8 {
+            code.push(0x44);
+        }
+        code.extend([0x0F, opcode, 0x84 | (register & 7) << 3, 0x24]);
+        code.extend((16 * register as u32).to_le_bytes());
+    }
+}
+
+/// Machine code of the stubs.
+#[cfg(target_arch = "x86")]
+mod arch {
+    use super::{dispatch, Callback};
+
+    /// Size of [`Context`](super::Context).
+    const CONTEXT_LEN: u8 = 40;
+
+    /// Build a stub placed at `address`, and return its code along
+    /// with the offset of the trampoline address to fill in.
+    pub(super) fn build(address: usize, save_xmm: bool, callback: *const Callback)
+        -> (Vec<u8>, usize)
+    {
+        let mut code = Vec::with_capacity(super::STUB_LEN);
+        // push 0 (XMM area); pushfd; cld; pushad
+        code.extend([0x6A, 0x00, 0x9C, 0xFC, 0x60]);
+        // lea eax, [esp + CONTEXT_LEN]; mov [esp + 12], eax
+        code.extend([0x8D, 0x44, 0x24, CONTEXT_LEN, 0x89, 0x44, 0x24, 0x0C]);
+        // mov ebx, esp; and esp, -16
+        code.extend([0x89, 0xE3, 0x83, 0xE4, 0xF0]);
+        if save_xmm {
+            // sub esp, 128; movups [esp + 16 * n], xmmn; mov [ebx + 36], esp
+            code.extend([0x81, 0xEC, 0x80, 0x00, 0x00, 0x00]);
+            for register in 0..8 {
+                code.extend([0x0F, 0x11, 0x44 | register << 3, 0x24, 16 * register]);
+            }
+            code.extend([0x89, 0x63, CONTEXT_LEN - 4]);
+        }
+        // sub esp, 8; push callback; push ebx; mov eax, dispatch; call eax; add esp, 16
+        code.extend([0x83, 0xEC, 0x08, 0x68]);
+        code.extend((callback as u32).to_le_bytes());
+        code.extend([0x53, 0xB8]);
+        code.extend((dispatch as *const () as u32).to_le_bytes());
+        code.extend([0xFF, 0xD0, 0x83, 0xC4, 0x10]);
+        if save_xmm {
+            // movups xmmn, [esp + 16 * n]
+            for register in 0..8 {
+                code.extend([0x0F, 0x10, 0x44 | register << 3, 0x24, 16 * register]);
+            }
+        }
+        // mov esp, ebx; popad; popfd; lea esp, [esp + 4]
+        code.extend([0x89, 0xDC, 0x61, 0x9D, 0x8D, 0x64, 0x24, 0x04]);
+        // jmp [trampoline]
+        code.extend([0xFF, 0x25]);
+        let trampoline = code.len() + 4;
+        code.extend(((address + trampoline) as u32).to_le_bytes());
+        code.extend(0u32.to_le_bytes());
+        (code, trampoline)
+    }
+}
@@ -0,0 +1,615 @@
+//! In-memory backend for testing code which creates hooks.
+//!
+//! [`MockBackend`] follows the state machine of the MinHook library,
+//! including its errors, identifiers and queued changes, but never
+//! touches code. It records every call made to it, and can be told to
+//! fail upcoming calls with any error, such as [`ErrorKind::MutexFailure`].
+//!
+//! As the backend and the [`registry`](crate::registry) are process-wide,
+//! tests selecting a mock have to run one at a time. Selecting it with
+//! [`MockBackend::select`] takes care of that, as the returned guard holds
+//! a process-wide lock until the native backend is selected again.
+
+use std::collections::VecDeque;
+use std::ffi::c_void;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use crate::{
+    backend, registry, Error, ErrorKind, HookBackend, HookIdent, Operation, Result,
+    ThreadFreezeMethod,
+};
+
+/// Call made to a [`MockBackend`], with addresses of functions as integers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Call {
+    /// See [`HookBackend::initialize`].
+    Initialize,
+    /// See [`HookBackend::uninitialize`].
+    Uninitialize,
+    /// See [`HookBackend::set_thread_freeze_method`].
+    SetThreadFreezeMethod(ThreadFreezeMethod),
+    /// See [`HookBackend::create_hook`].
+    CreateHook { target: usize, detour: usize, ident: HookIdent },
+    /// See [`HookBackend::remove_hook`], with `None` for all targets.
+    RemoveHook { target: Option<usize>, ident: HookIdent },
+    /// See [`HookBackend::remove_disabled_hooks`].
+    RemoveDisabledHooks { ident: HookIdent },
+    /// See [`HookBackend::enable_hook`], with `None` for all targets.
+    EnableHook { target: Option<usize>, ident: HookIdent },
+    /// See [`HookBackend::disable_hook`], with `None` for all targets.
+    DisableHook { target: Option<usize>, ident: HookIdent },
+    /// See [`HookBackend::queue_enable_hook`], with `None` for all targets.
+    QueueEnableHook { target: Option<usize>, ident: HookIdent },
+    /// See [`HookBackend::queue_disable_hook`], with `None` for all targets.
+    QueueDisableHook { target: Option<usize>, ident: HookIdent },
+    /// See [`HookBackend::apply_queued`].
+    ApplyQueued { ident: HookIdent },
+}
+
+impl Call {
+    /// Get the operation performed by this call.
+    pub fn operation(&self) -> Operation {
+        match self {
+            Call::Initialize => Operation::Initialize,
+            Call::Uninitialize => Operation::Uninitialize,
+            Call::SetThreadFreezeMethod(_) => Operation::SetThreadFreezeMethod,
+            Call::CreateHook { .. } => Operation::CreateHook,
+            Call::RemoveHook { .. } => Operation::RemoveHook,
+            Call::RemoveDisabledHooks { .. } => Operation::RemoveDisabledHooks,
+            Call::EnableHook { .. } => Operation::EnableHook,
+            Call::DisableHook { .. } => Operation::DisableHook,
+            Call::QueueEnableHook { .. } => Operation::QueueEnableHook,
+            Call::QueueDisableHook { .. } => Operation::QueueDisableHook,
+            Call::ApplyQueued { .. } => Operation::ApplyQueued,
+        }
+    }
+}
+
+/// Hook simulated by a [`MockBackend`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MockHook {
+    /// Address of the hooked function.
+    pub target: usize,
+    /// Address of the overwriting function.
+    pub detour: usize,
+    /// Identifier the hook was created with.
+    pub ident: HookIdent,
+    /// Whether the hook is enabled.
+    pub enabled: bool,
+    /// Whether the hook is enabled once queued changes are applied.
+    pub queued: bool,
+}
+
+impl MockHook {
+    fn matches(&self, target: Option<usize>, ident: HookIdent) -> bool {
+        target.is_none_or(|target| self.target == target) && ident.matches(self.ident)
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    initialized: bool,
+    freeze: Option<ThreadFreezeMethod>,
+    hooks: Vec<MockHook>,
+    calls: Vec<Call>,
+    failures: VecDeque<(Option<Operation>, ErrorKind)>,
+}
+
+/// Lock held while a mock is selected through [`MockBackend::select`].
+static SELECTION: Mutex<()> = Mutex::new(());
+
+/// Backend simulating the MinHook library without patching code.
+///
+/// Trampolines of created hooks are their target functions themselves,
+/// which are never changed, so calling them calls the original function.
+/// The mock is usually kept in a `static` to be selected with
+/// [`MockBackend::select`].
+#[derive(Debug)]
+pub struct MockBackend {
+    state: Mutex<State>,
+}
+
+impl MockBackend {
+    /// Create an uninitialized mock without hooks.
+    pub const fn new() -> Self {
+        Self { state: Mutex::new(State::new()) }
+    }
+
+    fn state(&self) -> Result<MutexGuard<'_, State>> {
+        self.state.lock().map_err(|_| Error::new(ErrorKind::MutexFailure))
+    }
+
+    fn snapshot<T>(&self, f: impl FnOnce(&State) -> T) -> T {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        f(&state)
+    }
+
+    /// Record a call, then run `f` on the state of an initialized mock,
+    /// unless a failure was injected for the operation of the call.
+    fn call<T>(&self, call: Call, f: impl FnOnce(&mut State) -> std::result::Result<T, ErrorKind>)
+        -> Result<T>
+    {
+        let mut state = self.state()?;
+        state.calls.push(call);
+        let operation = call.operation();
+        let injected = state.failures.iter()
+            .position(|(target, _)| target.is_none_or(|target| target == operation));
+        if let Some(index) = injected {
+            let (_, kind) = state.failures.remove(index).expect("position is in bounds");
+            return Err(Error::new(kind));
+        }
+        if !state.initialized && operation != Operation::Initialize {
+            return Err(Error::new(ErrorKind::NotInitialized));
+        }
+        f(&mut state).map_err(Error::new)
+    }
+
+    /// Fail the next call performing an `operation`, or the next call
+    /// of any operation for `None`, with an error of some `kind`.
+    ///
+    /// Failures are used up in the order they were injected.
+    pub fn fail_next(&self, operation: Option<Operation>, kind: ErrorKind) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.failures.push_back((operation, kind));
+    }
+
+    /// Get all calls made so far, including failed ones.
+    pub fn calls(&self) -> Vec<Call> {
+        self.snapshot(|state| state.calls.clone())
+    }
+
+    /// Get all calls made so far, and forget about them.
+    pub fn take_calls(&self) -> Vec<Call> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        std::mem::take(&mut state.calls)
+    }
+
+    /// Get snapshots of all created hooks, in order of creation.
+    pub fn hooks(&self) -> Vec<MockHook> {
+        self.snapshot(|state| state.hooks.clone())
+    }
+
+    /// Get a snapshot of the hook for a `target` function with an identifier.
+    pub fn hook(&self, target: *const c_void, ident: HookIdent) -> Option<MockHook> {
+        self.snapshot(|state| state.hooks.iter()
+            .find(|hook| hook.target == target as usize && hook.ident == ident)
+            .copied())
+    }
+
+    /// Check whether the mock is initialized.
+    pub fn is_initialized(&self) -> bool {
+        self.snapshot(|state| state.initialized)
+    }
+
+    /// Get the selected method of suspending/resuming threads, if any.
+    pub fn thread_freeze_method(&self) -> Option<ThreadFreezeMethod> {
+        self.snapshot(|state| state.freeze)
+    }
+
+    /// Return to the state of [`MockBackend::new`],
+    /// forgetting hooks, calls and injected failures.
+    pub fn reset(&self) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        *state = State::new();
+    }
+
+    /// Reset the mock and select it as the backend
+    /// until the returned guard is dropped.
+    ///
+    /// The guard holds a process-wide lock, so a test selecting a mock
+    /// this way waits for others doing the same, even if they use another
+    /// mock. Contexts created while the mock is selected have to be dropped
+    /// before the guard, which otherwise uninitializes the mock itself.
+    ///
+    /// Fails with [`ErrorKind::AlreadyInitialized`] while another backend
+    /// is initialized, as selecting it with [`backend::set`] would.
+    pub fn select(&'static self) -> Result<MockSelection> {
+        // A test panicking while holding the lock leaves nothing to repair.
+        let lock = SELECTION.lock().unwrap_or_else(PoisonError::into_inner);
+        self.reset();
+        backend::set(self)?;
+        Ok(MockSelection { mock: self, _lock: lock })
+    }
+}
+
+/// Guard of a selected mock, see [`MockBackend::select`].
+///
+/// Dropping the guard uninitializes the mock if needed, selects the
+/// native backend again, and resets the mock.
+#[must_use = "the mock is deselected when the guard is dropped"]
+#[derive(Debug)]
+pub struct MockSelection {
+    mock: &'static MockBackend,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl MockSelection {
+    /// Get the selected mock.
+    pub fn mock(&self) -> &'static MockBackend {
+        self.mock
+    }
+}
+
+impl Drop for MockSelection {
+    fn drop(&mut self) {
+        // Failures left over by the test must not keep the mock initialized.
+        self.mock.state.lock().unwrap_or_else(PoisonError::into_inner).failures.clear();
+        if self.mock.is_initialized() {
+            let _ = backend::uninitialize();
+            registry::clear();
+        }
+        let _ = backend::set(backend::native());
+        self.mock.reset();
+    }
+}
+
+impl Default for MockBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl State {
+    const fn new() -> Self {
+        Self {
+            initialized: false,
+            freeze: None,
+            hooks: Vec::new(),
+            calls: Vec::new(),
+            failures: VecDeque::new(),
+        }
+    }
+
+    /// Change hooks matching a target (or any, if `None`) and an identifier.
+    /// For a single target, `check` may refuse the change, and otherwise
+    /// it only filters which hooks are changed.
+    fn update(&mut self, target: Option<usize>, ident: HookIdent,
+        check: impl Fn(&MockHook) -> std::result::Result<bool, ErrorKind>,
+        change: impl Fn(&mut MockHook)) -> std::result::Result<(), ErrorKind>
+    {
+        let mut changed = Vec::new();
+        let mut found = false;
+        for (index, hook) in self.hooks.iter().enumerate() {
+            if !hook.matches(target, ident) {
+                continue;
+            }
+            found = true;
+            match check(hook) {
+                Ok(true) => changed.push(index),
+                Ok(false) => (),
+                Err(kind) if target.is_some() => return Err(kind),
+                Err(_) => (),
+            }
+        }
+        if target.is_some() && !found {
+            return Err(ErrorKind::NotCreated);
+        }
+        for index in changed {
+            change(&mut self.hooks[index]);
+        }
+        Ok(())
+    }
+
+    fn set_enabled(&mut self, target: Option<usize>, ident: HookIdent,
+        enable: bool) -> std::result::Result<(), ErrorKind>
+    {
+        let refusal = match enable {
+            true => ErrorKind::HookEnabled,
+            false => ErrorKind::HookDisabled,
+        };
+        self.update(target, ident, |hook| match hook.enabled == enable {
+            true => Err(refusal),
+            false => Ok(true),
+        }, |hook| {
+            hook.enabled = enable;
+            hook.queued = enable;
+        })
+    }
+
+    fn remove(&mut self, target: Option<usize>, ident: HookIdent,
+        predicate: impl Fn(&MockHook) -> bool) -> std::result::Result<(), ErrorKind>
+    {
+        let count = self.hooks.len();
+        self.hooks.retain(|hook| !(hook.matches(target, ident) && predicate(hook)));
+        match target.is_some() && self.hooks.len() == count {
+            true => Err(ErrorKind::NotCreated),
+            false => Ok(()),
+        }
+    }
+}
+
+impl HookBackend for MockBackend {
+    fn initialize(&self) -> Result<()> {
+        self.call(Call::Initialize, |state| {
+            if state.initialized {
+                return Err(ErrorKind::AlreadyInitialized);
+            }
+            state.initialized = true;
+            Ok(())
+        })
+    }
+
+    fn uninitialize(&self) -> Result<()> {
+        self.call(Call::Uninitialize, |state| {
+            state.initialized = false;
+            state.freeze = None;
+            state.hooks.clear();
+            Ok(())
+        })
+    }
+
+    fn set_thread_freeze_method(&self, freeze: ThreadFreezeMethod) -> Result<()> {
+        self.call(Call::SetThreadFreezeMethod(freeze), |state| {
+            state.freeze = Some(freeze);
+            Ok(())
+        })
+    }
+
+    unsafe fn create_hook(&self, target: *const c_void, detour: *const c_void,
+        ident: HookIdent) -> Result<*const c_void>
+    {
+        let (target, detour) = (target as usize, detour as usize);
+        self.call(Call::CreateHook { target, detour, ident }, |state| {
+            if ident.is_all() {
+                return Err(ErrorKind::UnsupportedFunction);
+            }
+            if target == 0 || detour == 0 {
+                return Err(ErrorKind::PointerNotExecutable);
+            }
+            if state.hooks.iter().any(|hook| hook.target == target && hook.ident == ident) {
+                return Err(ErrorKind::AlreadyCreated);
+            }
+            state.hooks.push(MockHook { target, detour, ident, enabled: false, queued: false });
+            Ok(target as *const c_void)
+        })
+    }
+
+    unsafe fn remove_hook(&self, target: Option<*const c_void>, ident: HookIdent) -> Result<()> {
+        let target = target.map(|target| target as usize);
+        self.call(Call::RemoveHook { target, ident }, |state| {
+            state.remove(target, ident, |_| true)
+        })
+    }
+
+    unsafe fn remove_disabled_hooks(&self, ident: HookIdent) -> Result<()> {
+        self.call(Call::RemoveDisabledHooks { ident }, |state| {
+            state.remove(None, ident, |hook| !hook.enabled)
+        })
+    }
+
+    unsafe fn enable_hook(&self, target: Option<*const c_void>, ident: HookIdent) -> Result<()> {
+        let target = target.map(|target| target as usize);
+        self.call(Call::EnableHook { target, ident }, |state| {
+            state.set_enabled(target, ident, true)
+        })
+    }
+
+    unsafe fn disable_hook(&self, target: Option<*const c_void>, ident: HookIdent) -> Result<()> {
+        let target = target.map(|target| target as usize);
+        self.call(Call::DisableHook { target, ident }, |state| {
+            state.set_enabled(target, ident, false)
+        })
+    }
+
+    unsafe fn queue_enable_hook(&self, target: Option<*const c_void>, ident: HookIdent)
+        -> Result<()>
+    {
+        let target = target.map(|target| target as usize);
+        self.call(Call::QueueEnableHook { target, ident }, |state| {
+            state.update(target, ident, |_| Ok(true), |hook| hook.queued = true)
+        })
+    }
+
+    unsafe fn queue_disable_hook(&self, target: Option<*const c_void>, ident: HookIdent)
+        -> Result<()>
+    {
+        let target = target.map(|target| target as usize);
+        self.call(Call::QueueDisableHook { target, ident }, |state| {
+            state.update(target, ident, |_| Ok(true), |hook| hook.queued = false)
+        })
+    }
+
+    unsafe fn apply_queued(&self, ident: HookIdent) -> Result<()> {
+        self.call(Call::ApplyQueued { ident }, |state| {
+            state.update(None, ident, |hook| Ok(hook.enabled != hook.queued), |hook| {
+                hook.enabled = hook.queued;
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MinHook;
+
+    const FIRST: usize = 0x1000;
+    const SECOND: usize = 0x2000;
+    const DETOUR: usize = 0x9000;
+    const IDENT: HookIdent = HookIdent::new(7);
+
+    fn ptr(address: usize) -> *const c_void {
+        address as *const c_void
+    }
+
+    fn kind<T>(result: Result<T>) -> Option<ErrorKind> {
+        result.err().map(|err| err.kind())
+    }
+
+    fn enabled(mock: &MockBackend) -> Vec<(usize, bool, bool)> {
+        mock.hooks().iter().map(|hook| (hook.target, hook.enabled, hook.queued)).collect()
+    }
+
+    /// Initialize a mock with disabled hooks of `IDENT` for both targets.
+    fn created() -> MockBackend {
+        let mock = MockBackend::new();
+        mock.initialize().unwrap();
+        unsafe {
+            mock.create_hook(ptr(FIRST), ptr(DETOUR), IDENT).unwrap();
+            mock.create_hook(ptr(SECOND), ptr(DETOUR), IDENT).unwrap();
+        }
+        mock
+    }
+
+    #[test]
+    fn initialization() {
+        let mock = MockBackend::new();
+        assert_eq!(kind(unsafe { mock.create_hook(ptr(FIRST), ptr(DETOUR), IDENT) }),
+            Some(ErrorKind::NotInitialized));
+        assert_eq!(kind(mock.uninitialize()), Some(ErrorKind::NotInitialized));
+        mock.initialize().unwrap();
+        assert_eq!(kind(mock.initialize()), Some(ErrorKind::AlreadyInitialized));
+        unsafe { mock.create_hook(ptr(FIRST), ptr(DETOUR), IDENT).unwrap() };
+        mock.uninitialize().unwrap();
+        assert!(!mock.is_initialized());
+        assert!(mock.hooks().is_empty());
+    }
+
+    #[test]
+    fn creating_hooks() {
+        let mock = created();
+        unsafe {
+            assert_eq!(mock.create_hook(ptr(0x3000), ptr(DETOUR), IDENT).unwrap(), ptr(0x3000));
+            assert_eq!(kind(mock.create_hook(ptr(FIRST), ptr(DETOUR), IDENT)),
+                Some(ErrorKind::AlreadyCreated));
+            assert_eq!(kind(mock.create_hook(ptr(FIRST), ptr(DETOUR), HookIdent::ALL)),
+                Some(ErrorKind::UnsupportedFunction));
+            assert_eq!(kind(mock.create_hook(std::ptr::null(), ptr(DETOUR), IDENT)),
+                Some(ErrorKind::PointerNotExecutable));
+            mock.create_hook(ptr(FIRST), ptr(DETOUR), HookIdent::DEFAULT).unwrap();
+        }
+        assert_eq!(mock.hook(ptr(FIRST), IDENT).map(|hook| hook.enabled), Some(false));
+        assert_eq!(mock.hooks().len(), 4);
+    }
+
+    #[test]
+    fn enabling_and_disabling_single_hooks() {
+        let mock = created();
+        unsafe {
+            assert_eq!(kind(mock.disable_hook(Some(ptr(FIRST)), IDENT)),
+                Some(ErrorKind::HookDisabled));
+            mock.enable_hook(Some(ptr(FIRST)), IDENT).unwrap();
+            assert_eq!(kind(mock.enable_hook(Some(ptr(FIRST)), IDENT)),
+                Some(ErrorKind::HookEnabled));
+            assert_eq!(kind(mock.enable_hook(Some(ptr(0x3000)), IDENT)),
+                Some(ErrorKind::NotCreated));
+            assert_eq!(kind(mock.enable_hook(Some(ptr(FIRST)), HookIdent::DEFAULT)),
+                Some(ErrorKind::NotCreated));
+            assert_eq!(enabled(&mock), [(FIRST, true, true), (SECOND, false, false)]);
+            mock.disable_hook(Some(ptr(FIRST)), IDENT).unwrap();
+            assert_eq!(enabled(&mock), [(FIRST, false, false), (SECOND, false, false)]);
+        }
+    }
+
+    #[test]
+    fn all_hooks_skip_hooks_already_in_state() {
+        let mock = created();
+        unsafe {
+            mock.enable_hook(Some(ptr(FIRST)), IDENT).unwrap();
+            mock.enable_hook(None, IDENT).unwrap();
+            assert_eq!(enabled(&mock), [(FIRST, true, true), (SECOND, true, true)]);
+            mock.enable_hook(None, IDENT).unwrap();
+            mock.disable_hook(None, HookIdent::ALL).unwrap();
+            assert_eq!(enabled(&mock), [(FIRST, false, false), (SECOND, false, false)]);
+            // Hooks of other identifiers are left alone.
+            mock.enable_hook(None, HookIdent::DEFAULT).unwrap();
+            assert_eq!(enabled(&mock), [(FIRST, false, false), (SECOND, false, false)]);
+        }
+    }
+
+    #[test]
+    fn removing_hooks() {
+        let mock = created();
+        unsafe {
+            assert_eq!(kind(mock.remove_hook(Some(ptr(0x3000)), IDENT)),
+                Some(ErrorKind::NotCreated));
+            mock.enable_hook(Some(ptr(FIRST)), IDENT).unwrap();
+            mock.remove_disabled_hooks(IDENT).unwrap();
+            assert_eq!(enabled(&mock), [(FIRST, true, true)]);
+            mock.remove_hook(Some(ptr(FIRST)), IDENT).unwrap();
+            assert!(mock.hooks().is_empty());
+            mock.remove_hook(None, IDENT).unwrap();
+        }
+    }
+
+    #[test]
+    fn queued_changes() {
+        let mock = created();
+        unsafe {
+            mock.queue_enable_hook(None, IDENT).unwrap();
+            assert_eq!(enabled(&mock), [(FIRST, false, true), (SECOND, false, true)]);
+            mock.queue_disable_hook(Some(ptr(SECOND)), IDENT).unwrap();
+            assert_eq!(kind(mock.queue_enable_hook(Some(ptr(0x3000)), IDENT)),
+                Some(ErrorKind::NotCreated));
+            mock.apply_queued(IDENT).unwrap();
+            assert_eq!(enabled(&mock), [(FIRST, true, true), (SECOND, false, false)]);
+            // Enabling directly also settles the queued state.
+            mock.queue_disable_hook(Some(ptr(FIRST)), IDENT).unwrap();
+            mock.disable_hook(Some(ptr(FIRST)), IDENT).unwrap();
+            mock.apply_queued(HookIdent::ALL).unwrap();
+            assert_eq!(enabled(&mock), [(FIRST, false, false), (SECOND, false, false)]);
+        }
+    }
+
+    #[test]
+    fn injected_failures() {
+        let mock = created();
+        mock.take_calls();
+        mock.fail_next(Some(Operation::EnableHook), ErrorKind::MutexFailure);
+        mock.fail_next(None, ErrorKind::AllocationFailure);
+        unsafe {
+            assert_eq!(kind(mock.disable_hook(Some(ptr(FIRST)), IDENT)),
+                Some(ErrorKind::AllocationFailure));
+            assert_eq!(kind(mock.enable_hook(Some(ptr(FIRST)), IDENT)),
+                Some(ErrorKind::MutexFailure));
+            assert_eq!(enabled(&mock), [(FIRST, false, false), (SECOND, false, false)]);
+            mock.enable_hook(Some(ptr(FIRST)), IDENT).unwrap();
+        }
+        let target = Some(FIRST);
+        assert_eq!(mock.take_calls(), [
+            Call::DisableHook { target, ident: IDENT },
+            Call::EnableHook { target, ident: IDENT },
+            Call::EnableHook { target, ident: IDENT },
+        ]);
+    }
+
+    #[test]
+    fn selection_keeps_the_registry_in_line() {
+        static MOCK: MockBackend = MockBackend::new();
+        let selection = MOCK.select().unwrap();
+        let mock = selection.mock();
+        let minhook = MinHook::initialize(ThreadFreezeMethod::OriginalSnapshot).unwrap();
+        unsafe {
+            crate::create_hook(ptr(FIRST), ptr(DETOUR), IDENT).unwrap();
+            crate::create_hook(ptr(SECOND), ptr(DETOUR), IDENT).unwrap();
+            mock.fail_next(Some(Operation::EnableHook), ErrorKind::MutexFailure);
+            let err = crate::enable_hooks(IDENT).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::MutexFailure);
+        }
+        for hook in mock.hooks() {
+            let record = registry::get(ptr(hook.target), hook.ident).unwrap();
+            assert_eq!(record.is_enabled(), hook.enabled);
+        }
+        assert_eq!(registry::hooks().len(), 2);
+        drop(minhook);
+        assert!(!mock.is_initialized());
+        assert!(registry::hooks().is_empty());
+    }
+
+    #[test]
+    fn dropping_the_selection_uninitializes_the_mock() {
+        static MOCK: MockBackend = MockBackend::new();
+        let selection = MOCK.select().unwrap();
+        let minhook = MinHook::initialize(ThreadFreezeMethod::OriginalSnapshot).unwrap();
+        unsafe { crate::create_hook(ptr(FIRST), ptr(DETOUR), IDENT).unwrap() };
+        // Uninitializing fails, so the library is left initialized.
+        MOCK.fail_next(Some(Operation::Uninitialize), ErrorKind::MutexFailure);
+        drop(minhook);
+        assert!(MOCK.is_initialized());
+        drop(selection);
+        assert!(!MOCK.is_initialized());
+        assert!(registry::hooks().is_empty());
+        let _selection = MOCK.select().unwrap();
+        let _minhook = MinHook::initialize(ThreadFreezeMethod::OriginalSnapshot).unwrap();
+    }
+}
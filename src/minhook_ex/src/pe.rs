@@ -0,0 +1,624 @@
+//! Parser of PE images, as mapped into memory or as stored in files.
+//!
+//! Only the headers, the section table and the import and export
+//! directories are read, which is what hooking by import or export and
+//! scanning code need. The parser works on
+//! plain byte buffers and never touches process memory on its own, so
+//! images of any machine type can be inspected on any platform.
+
+use std::ffi::c_void;
+use std::ops::Range;
+
+use crate::Export;
+
+/// Index of the export directory among the data directories.
+pub const DIRECTORY_EXPORT: usize = 0;
+/// Index of the import directory among the data directories.
+pub const DIRECTORY_IMPORT: usize = 1;
+
+/// Size of the headers of a mapped image read before its full size is known.
+const HEADERS_LEN: usize = 0x1000;
+/// Size of a section header.
+const SECTION_LEN: usize = 40;
+/// Size of an import descriptor.
+const IMPORT_DESCRIPTOR_LEN: usize = 20;
+/// Section characteristic of sections holding executable code.
+const SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+
+/// Arrangement of an image in a buffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Layout {
+    /// Sections are at their virtual addresses, as mapped by the loader.
+    Mapped,
+    /// Sections are at their raw data offsets, as stored in a file.
+    File,
+}
+
+/// Failure to parse an image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeError {
+    /// The buffer ends in the middle of a structure or string.
+    Truncated {
+        /// Offset of the structure or string in the buffer.
+        offset: usize,
+    },
+    /// The DOS or NT header signature doesn't match.
+    InvalidSignature,
+    /// The optional header is neither PE32 nor PE32+.
+    UnsupportedMagic {
+        /// Magic number of the optional header.
+        magic: u16,
+    },
+    /// A relative virtual address is not backed by any data of the file.
+    InvalidRva {
+        /// The address.
+        rva: u32,
+    },
+    /// A name is not valid UTF-8.
+    InvalidName {
+        /// Relative virtual address of the name.
+        rva: u32,
+    },
+    /// The API set schema has a version other than 6.
+    UnsupportedApiSetVersion {
+        /// Version of the schema.
+        version: u32,
+    },
+}
+
+impl std::fmt::Display for PeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Truncated { offset } => write!(f, "image is truncated at offset {:#x}", offset),
+            Self::InvalidSignature => f.write_str("image has no valid PE signature"),
+            Self::UnsupportedMagic { magic } => {
+                write!(f, "optional header magic {:#x} is not supported", magic)
+            }
+            Self::InvalidRva { rva } => write!(f, "address {:#x} is outside of the image", rva),
+            Self::InvalidName { rva } => write!(f, "name at {:#x} is not valid UTF-8", rva),
+            Self::UnsupportedApiSetVersion { version } => {
+                write!(f, "API set schema version {} is not supported", version)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PeError {}
+
+/// A parsed PE image.
+#[derive(Clone, Copy, Debug)]
+pub struct Image<'a> {
+    data: &'a [u8],
+    layout: Layout,
+    is_64: bool,
+    optional_header: usize,
+    sections: usize,
+    section_count: usize,
+    directory_count: usize,
+}
+
+impl<'a> Image<'a> {
+    /// Parse the headers of an image.
+    pub fn parse(data: &'a [u8], layout: Layout) -> Result<Self, PeError> {
+        if read_u16(data, 0)? != 0x5A4D {
+            return Err(PeError::InvalidSignature);
+        }
+        let nt_headers = read_u32(data, 0x3C)? as usize;
+        if read_u32(data, nt_headers)? != 0x4550 {
+            return Err(PeError::InvalidSignature);
+        }
+        let file_header = nt_headers + 4;
+        let section_count = read_u16(data, file_header + 2)? as usize;
+        let optional_header = file_header + 20;
+        let sections = optional_header + read_u16(data, file_header + 16)? as usize;
+        let is_64 = match read_u16(data, optional_header)? {
+            0x10B => false,
+            0x20B => true,
+            magic => return Err(PeError::UnsupportedMagic { magic }),
+        };
+        let directory_count = read_u32(data, optional_header + if is_64 { 108 } else { 92 })?;
+        Ok(Self {
+            data,
+            layout,
+            is_64,
+            optional_header,
+            sections,
+            section_count,
+            directory_count: directory_count as usize,
+        })
+    }
+
+    /// Parse the headers of an image mapped into the current process.
+    ///
+    /// # Safety
+    ///
+    /// `base` must be the base address of a mapped image, which must
+    /// stay mapped and unchanged for the lifetime `'a`.
+    pub unsafe fn from_base(base: *const c_void) -> Result<Self, PeError> {
+        let headers = std::slice::from_raw_parts(base as *const u8, HEADERS_LEN);
+        let size = Self::parse(headers, Layout::Mapped)?.size_of_image() as usize;
+        let data = std::slice::from_raw_parts(base as *const u8, size.max(HEADERS_LEN));
+        Self::parse(data, Layout::Mapped)
+    }
+
+    /// Get the underlying buffer.
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Get the arrangement of the image in the buffer.
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    /// Check whether the image is PE32+, which has 64-bit addresses.
+    pub fn is_64(&self) -> bool {
+        self.is_64
+    }
+
+    /// Get the machine type from the file header.
+    pub fn machine(&self) -> u16 {
+        read_u16(self.data, self.optional_header - 20).unwrap_or(0)
+    }
+
+    /// Get the preferred base address of the image.
+    pub fn image_base(&self) -> u64 {
+        match self.is_64 {
+            true => read_u64(self.data, self.optional_header + 24).unwrap_or(0),
+            false => read_u32(self.data, self.optional_header + 28).unwrap_or(0) as u64,
+        }
+    }
+
+    /// Get the size of the image once mapped.
+    pub fn size_of_image(&self) -> u32 {
+        read_u32(self.data, self.optional_header + 56).unwrap_or(0)
+    }
+
+    /// Get the size of a pointer in the image.
+    pub fn pointer_len(&self) -> usize {
+        if self.is_64 { 8 } else { 4 }
+    }
+
+    /// Get the address range of a data directory, if present.
+    pub fn directory(&self, index: usize) -> Option<Range<u32>> {
+        if index >= self.directory_count {
+            return None;
+        }
+        let entry = self.optional_header + if self.is_64 { 112 } else { 96 } + 8 * index;
+        let rva = read_u32(self.data, entry).ok()?;
+        let size = read_u32(self.data, entry + 4).ok()?;
+        (rva != 0 && size != 0).then(|| rva..rva.saturating_add(size))
+    }
+
+    /// Get the offset of a relative virtual address in the buffer.
+    pub fn offset(&self, rva: u32) -> Result<usize, PeError> {
+        if self.layout == Layout::Mapped {
+            return Ok(rva as usize);
+        }
+        let size_of_headers = read_u32(self.data, self.optional_header + 60)?;
+        if rva < size_of_headers {
+            return Ok(rva as usize);
+        }
+        for section in 0..self.section_count {
+            let header = self.sections + SECTION_LEN * section;
+            let address = read_u32(self.data, header + 12)?;
+            let raw_size = read_u32(self.data, header + 16)?;
+            let raw_offset = read_u32(self.data, header + 20)?;
+            if rva >= address && rva - address < raw_size {
+                return (raw_offset as usize).checked_add((rva - address) as usize)
+                    .ok_or(PeError::InvalidRva { rva });
+            }
+        }
+        Err(PeError::InvalidRva { rva })
+    }
+
+    /// Get `len` bytes at a relative virtual address.
+    pub fn bytes(&self, rva: u32, len: usize) -> Result<&'a [u8], PeError> {
+        read_bytes(self.data, self.offset(rva)?, len)
+    }
+
+    /// Get a nul-terminated name at a relative virtual address.
+    pub fn name(&self, rva: u32) -> Result<&'a str, PeError> {
+        let offset = self.offset(rva)?;
+        let rest = self.data.get(offset..).ok_or(PeError::Truncated { offset })?;
+        let len = rest.iter().position(|&byte| byte == 0).ok_or(PeError::Truncated { offset })?;
+        std::str::from_utf8(&rest[..len]).map_err(|_| PeError::InvalidName { rva })
+    }
+
+    fn u16(&self, rva: u32) -> Result<u16, PeError> {
+        read_u16(self.data, self.offset(rva)?)
+    }
+
+    fn u32(&self, rva: u32) -> Result<u32, PeError> {
+        read_u32(self.data, self.offset(rva)?)
+    }
+
+    fn pointer(&self, rva: u32) -> Result<u64, PeError> {
+        match self.is_64 {
+            true => read_u64(self.data, self.offset(rva)?),
+            false => self.u32(rva).map(u64::from),
+        }
+    }
+
+    /// Get the sections of the image, in the order of the section table.
+    pub fn sections(&self) -> Result<Vec<Section<'a>>, PeError> {
+        (0..self.section_count).map(|section| {
+            let header = self.sections + SECTION_LEN * section;
+            let name = read_bytes(self.data, header, 8)?;
+            let name = &name[..name.iter().position(|&byte| byte == 0).unwrap_or(8)];
+            let name = std::str::from_utf8(name)
+                .map_err(|_| PeError::InvalidName { rva: header as u32 })?;
+            let virtual_size = read_u32(self.data, header + 8)?;
+            let rva = read_u32(self.data, header + 12)?;
+            let raw_size = read_u32(self.data, header + 16)?;
+            let raw_offset = read_u32(self.data, header + 20)?;
+            let characteristics = read_u32(self.data, header + 36)?;
+            // Linkers may leave the virtual size out, and the raw size
+            // is rounded up to the file alignment.
+            let (offset, len) = match (self.layout, virtual_size) {
+                (Layout::Mapped, 0) => (rva as usize, raw_size),
+                (Layout::Mapped, _) => (rva as usize, virtual_size),
+                (Layout::File, 0) => (raw_offset as usize, raw_size),
+                (Layout::File, _) => (raw_offset as usize, raw_size.min(virtual_size)),
+            };
+            let data = read_bytes(self.data, offset, len as usize)?;
+            Ok(Section { name, rva, virtual_size, characteristics, data })
+        }).collect()
+    }
+
+    /// Get the export directory, if present.
+    pub fn exports(&self) -> Result<Option<Exports<'a>>, PeError> {
+        let Some(range) = self.directory(DIRECTORY_EXPORT) else {
+            return Ok(None);
+        };
+        let directory = range.start;
+        Ok(Some(Exports {
+            image: *self,
+            module: self.name(self.u32(directory + 12)?)?,
+            ordinal_base: self.u32(directory + 16)?,
+            function_count: self.u32(directory + 20)?,
+            name_count: self.u32(directory + 24)?,
+            functions: self.u32(directory + 28)?,
+            names: self.u32(directory + 32)?,
+            name_ordinals: self.u32(directory + 36)?,
+            range,
+        }))
+    }
+
+    /// Get all functions imported through the import directory.
+    pub fn imports(&self) -> Result<Vec<ImportedSymbol<'a>>, PeError> {
+        let Some(range) = self.directory(DIRECTORY_IMPORT) else {
+            return Ok(Vec::new());
+        };
+        let mut imports = Vec::new();
+        for index in 0.. {
+            let descriptor = entry_rva(range.start, IMPORT_DESCRIPTOR_LEN as u32, index)?;
+            let lookup = self.u32(descriptor)?;
+            // The name and the import address table are its 4th and 5th fields.
+            let name = self.u32(entry_rva(descriptor, 4, 3)?)?;
+            let slots = self.u32(entry_rva(descriptor, 4, 4)?)?;
+            if name == 0 && slots == 0 {
+                break;
+            }
+            let module = self.name(name)?;
+            // Bound or mapped images have addresses in their import address
+            // table, so names come from the lookup table whenever there is one.
+            let lookup = if lookup != 0 { lookup } else { slots };
+            let pointer_len = self.pointer_len() as u32;
+            for index in 0.. {
+                let thunk = self.pointer(entry_rva(lookup, pointer_len, index)?)?;
+                if thunk == 0 {
+                    break;
+                }
+                let ordinal_flag = if self.is_64 { 1 << 63 } else { 1 << 31 };
+                let symbol = if thunk & ordinal_flag != 0 {
+                    ImportedName::Ordinal(thunk as u16)
+                } else {
+                    let hint_name = thunk as u32;
+                    let name = self.name(entry_rva(hint_name, 2, 1)?)?;
+                    ImportedName::Name { hint: self.u16(hint_name)?, name }
+                };
+                let slot = entry_rva(slots, pointer_len, index)?;
+                imports.push(ImportedSymbol { module, symbol, slot });
+            }
+        }
+        Ok(imports)
+    }
+
+    /// Find a function imported from a module.
+    ///
+    /// # Arguments
+    ///
+    /// * `module` - name of the imported module, compared ignoring
+    ///     case and an omitted `.dll` extension, like `"kernel32"`.
+    /// * `export` - name or ordinal of the imported function.
+    pub fn find_import(&self, module: &str, export: &Export)
+        -> Result<Option<ImportedSymbol<'a>>, PeError>
+    {
+        let imports = self.imports()?;
+        Ok(imports.into_iter().find(|import| {
+            module_matches(import.module, module) && import.symbol.matches(export)
+        }))
+    }
+}
+
+/// Check whether a module name refers to the same module as another one,
+/// ignoring case and an omitted `.dll` extension.
+pub fn module_matches(name: &str, query: &str) -> bool {
+    strip_dll(name).eq_ignore_ascii_case(strip_dll(query))
+}
+
+/// Strip a `.dll` extension from a module name, ignoring case.
+pub(crate) fn strip_dll(name: &str) -> &str {
+    match name.len().checked_sub(4) {
+        Some(len) if name.is_char_boundary(len) && name[len..].eq_ignore_ascii_case(".dll") => {
+            &name[..len]
+        }
+        _ => name,
+    }
+}
+
+/// A section of an image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Section<'a> {
+    name: &'a str,
+    rva: u32,
+    virtual_size: u32,
+    characteristics: u32,
+    data: &'a [u8],
+}
+
+impl<'a> Section<'a> {
+    /// Get the name of the section, like `".text"`.
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    /// Get the relative virtual address of the section.
+    pub fn rva(&self) -> u32 {
+        self.rva
+    }
+
+    /// Get the size of the section once mapped.
+    pub fn virtual_size(&self) -> u32 {
+        self.virtual_size
+    }
+
+    /// Get the characteristics flags of the section.
+    pub fn characteristics(&self) -> u32 {
+        self.characteristics
+    }
+
+    /// Check whether the section holds executable code.
+    pub fn is_executable(&self) -> bool {
+        self.characteristics & SCN_MEM_EXECUTE != 0
+    }
+
+    /// Get the contents of the section in the buffer, which leaves
+    /// out the zeroed tail of a section read from a file.
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+}
+
+/// The export directory of an image.
+#[derive(Clone, Debug)]
+pub struct Exports<'a> {
+    image: Image<'a>,
+    range: Range<u32>,
+    module: &'a str,
+    ordinal_base: u32,
+    function_count: u32,
+    name_count: u32,
+    functions: u32,
+    names: u32,
+    name_ordinals: u32,
+}
+
+impl<'a> Exports<'a> {
+    /// Get the name of the module as recorded in the directory.
+    pub fn module(&self) -> &'a str {
+        self.module
+    }
+
+    /// Get the ordinal of the first function.
+    pub fn ordinal_base(&self) -> u32 {
+        self.ordinal_base
+    }
+
+    /// Get the number of entries in the export address table.
+    pub fn len(&self) -> usize {
+        self.function_count as usize
+    }
+
+    /// Check whether the export address table is empty.
+    pub fn is_empty(&self) -> bool {
+        self.function_count == 0
+    }
+
+    /// Get the exported function with an ordinal, without its name.
+    pub fn by_ordinal(&self, ordinal: u16) -> Result<Option<ExportedSymbol<'a>>, PeError> {
+        let Some(index) = (ordinal as u32).checked_sub(self.ordinal_base) else {
+            return Ok(None);
+        };
+        self.symbol(index, None)
+    }
+
+    /// Get the exported function with a name.
+    pub fn by_name(&self, name: &str) -> Result<Option<ExportedSymbol<'a>>, PeError> {
+        for entry in 0..self.name_count {
+            let entry_name = self.entry_name(entry)?;
+            if entry_name == name {
+                let index = self.image.u16(entry_rva(self.name_ordinals, 2, entry)?)? as u32;
+                return self.symbol(index, Some(entry_name));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Find an exported function by name or ordinal.
+    pub fn find(&self, export: &Export) -> Result<Option<ExportedSymbol<'a>>, PeError> {
+        match export {
+            Export::Name(name) => self.by_name(name),
+            Export::Ordinal(ordinal) => self.by_ordinal(*ordinal),
+        }
+    }
+
+    /// Get all exported functions along with their names.
+    pub fn symbols(&self) -> Result<Vec<ExportedSymbol<'a>>, PeError> {
+        // Every entry takes 4 bytes of the image, so no more than fit
+        // in it can be read, whatever the directory claims.
+        let mut names = vec![None; self.len().min(self.image.data.len() / 4)];
+        for entry in 0..self.name_count {
+            let index = self.image.u16(entry_rva(self.name_ordinals, 2, entry)?)? as usize;
+            if let Some(name) = names.get_mut(index) {
+                *name = Some(self.entry_name(entry)?);
+            }
+        }
+        let mut symbols = Vec::new();
+        for index in 0..self.function_count {
+            let name = names.get(index as usize).copied().flatten();
+            symbols.extend(self.symbol(index, name)?);
+        }
+        Ok(symbols)
+    }
+
+    fn entry_name(&self, entry: u32) -> Result<&'a str, PeError> {
+        self.image.name(self.image.u32(entry_rva(self.names, 4, entry)?)?)
+    }
+
+    fn symbol(&self, index: u32, name: Option<&'a str>)
+        -> Result<Option<ExportedSymbol<'a>>, PeError>
+    {
+        if index >= self.function_count {
+            return Ok(None);
+        }
+        let slot = entry_rva(self.functions, 4, index)?;
+        let rva = self.image.u32(slot)?;
+        let address = match rva {
+            0 => return Ok(None),
+            rva if self.range.contains(&rva) => ExportAddress::Forwarder(self.image.name(rva)?),
+            rva => ExportAddress::Code(rva),
+        };
+        let ordinal = self.ordinal_base.checked_add(index)
+            .ok_or(PeError::InvalidRva { rva: self.functions })? as u16;
+        Ok(Some(ExportedSymbol { ordinal, name, address, slot }))
+    }
+}
+
+/// Where an exported function is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ExportAddress<'a> {
+    /// Relative virtual address of the function in the image.
+    Code(u32),
+    /// Reference to an export of another module, like `"NTDLL.RtlAllocateHeap"`.
+    Forwarder(&'a str),
+}
+
+/// A function exported from an image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ExportedSymbol<'a> {
+    ordinal: u16,
+    name: Option<&'a str>,
+    address: ExportAddress<'a>,
+    slot: u32,
+}
+
+impl<'a> ExportedSymbol<'a> {
+    /// Get the ordinal of the function.
+    pub fn ordinal(&self) -> u16 {
+        self.ordinal
+    }
+
+    /// Get the name of the function, if it's exported by name.
+    pub fn name(&self) -> Option<&'a str> {
+        self.name
+    }
+
+    /// Get where the function is.
+    pub fn address(&self) -> ExportAddress<'a> {
+        self.address
+    }
+
+    /// Get the relative virtual address of the function's
+    /// entry in the export address table.
+    pub fn slot(&self) -> u32 {
+        self.slot
+    }
+}
+
+/// How an imported function is referenced.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ImportedName<'a> {
+    /// Imported by name, with a hint into the export name table.
+    Name {
+        /// Likely index of the name in the exporting module.
+        hint: u16,
+        /// Name of the function.
+        name: &'a str,
+    },
+    /// Imported by ordinal.
+    Ordinal(u16),
+}
+
+impl ImportedName<'_> {
+    /// Check whether this is a reference to an export.
+    pub fn matches(&self, export: &Export) -> bool {
+        match (self, export) {
+            (Self::Name { name, .. }, Export::Name(export)) => name == export,
+            (Self::Ordinal(ordinal), Export::Ordinal(export)) => ordinal == export,
+            _ => false,
+        }
+    }
+}
+
+/// A function imported by an image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ImportedSymbol<'a> {
+    module: &'a str,
+    symbol: ImportedName<'a>,
+    slot: u32,
+}
+
+impl<'a> ImportedSymbol<'a> {
+    /// Get the name of the module the function is imported from.
+    pub fn module(&self) -> &'a str {
+        self.module
+    }
+
+    /// Get how the function is referenced.
+    pub fn symbol(&self) -> ImportedName<'a> {
+        self.symbol
+    }
+
+    /// Get the relative virtual address of the function's
+    /// entry in the import address table.
+    pub fn slot(&self) -> u32 {
+        self.slot
+    }
+}
+
+/// Get the address of an entry of a table, failing if it overflows.
+fn entry_rva(table: u32, entry_len: u32, index: u32) -> Result<u32, PeError> {
+    entry_len.checked_mul(index).and_then(|offset| table.checked_add(offset))
+        .ok_or(PeError::InvalidRva { rva: table })
+}
+
+/// Get `len` bytes at an offset, failing if they
+/// are out of bounds or their end overflows.
+fn read_bytes(data: &[u8], offset: usize, len: usize) -> Result<&[u8], PeError> {
+    offset.checked_add(len).and_then(|end| data.get(offset..end))
+        .ok_or(PeError::Truncated { offset })
+}
+
+pub(crate) fn read_u16(data: &[u8], offset: usize) -> Result<u16, PeError> {
+    read_bytes(data, offset, 2).map(|bytes| u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+pub(crate) fn read_u32(data: &[u8], offset: usize) -> Result<u32, PeError> {
+    read_bytes(data, offset, 4).map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+pub(crate) fn read_u64(data: &[u8], offset: usize) -> Result<u64, PeError> {
+    read_bytes(data, offset, 8).map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+}
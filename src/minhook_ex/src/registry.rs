@@ -0,0 +1,268 @@
+//! Records of all hooks created through this crate.
+//!
+//! The library's own hook table is opaque, so every function of this
+//! crate which creates, changes or removes hooks also updates a record
+//! here, including bulk and queued operations. Hooks created by calling
+//! [`minhook_ex_sys`] directly are not known to the registry.
+
+use std::collections::BTreeMap;
+use std::ffi::{c_ulonglong, c_void};
+use std::panic::Location;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use crate::HookIdent;
+
+/// Snapshot of a created hook.
+#[derive(Clone, Copy, Debug)]
+pub struct HookRecord {
+    target: usize,
+    detour: usize,
+    trampoline: usize,
+    ident: HookIdent,
+    enabled: bool,
+    queued: bool,
+    location: &'static Location<'static>,
+}
+
+impl HookRecord {
+    /// Get the hooked function.
+    pub fn target(&self) -> *const c_void {
+        self.target as *const c_void
+    }
+
+    /// Get the overwriting function.
+    pub fn detour(&self) -> *const c_void {
+        self.detour as *const c_void
+    }
+
+    /// Get the trampoline calling the original function.
+    pub fn trampoline(&self) -> *const c_void {
+        self.trampoline as *const c_void
+    }
+
+    /// Get the hook identifier.
+    pub fn ident(&self) -> HookIdent {
+        self.ident
+    }
+
+    /// Check whether the hook was enabled at the time of the snapshot.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Check whether the hook is queued to be enabled, see [`crate::apply_queued`].
+    pub fn is_queued_enabled(&self) -> bool {
+        self.queued
+    }
+
+    /// Get the source location which created the hook.
+    pub fn location(&self) -> &'static Location<'static> {
+        self.location
+    }
+}
+
+type Records = BTreeMap<(usize, HookIdent), HookRecord>;
+
+static RECORDS: Mutex<Records> = Mutex::new(BTreeMap::new());
+
+fn records() -> MutexGuard<'static, Records> {
+    // Records are only changed by single insertions, removals
+    // and flag stores, so they are never left inconsistent.
+    RECORDS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Apply `f` to records matching a `target` (or any, if `None`) and an `ident`.
+fn update_matching(target: Option<*const c_void>, ident: HookIdent, f: impl Fn(&mut HookRecord)) {
+    records().values_mut()
+        .filter(|record| target.is_none_or(|target| record.target() == target))
+        .filter(|record| ident.matches(record.ident))
+        .for_each(f);
+}
+
+/// Get snapshots of all hooks.
+pub fn hooks() -> Vec<HookRecord> {
+    records().values().copied().collect()
+}
+
+/// Get snapshots of all hooks for a `target` function.
+pub fn find(target: *const c_void) -> Vec<HookRecord> {
+    let start = (target as usize, HookIdent::new(0));
+    let end = (target as usize, HookIdent::new(c_ulonglong::MAX));
+    records().range(start..=end).map(|(_, record)| *record).collect()
+}
+
+/// Get snapshots of all hooks with an identifier,
+/// or of all hooks for [`HookIdent::ALL`].
+pub fn with_ident(ident: HookIdent) -> Vec<HookRecord> {
+    records().values().filter(|record| ident.matches(record.ident)).copied().collect()
+}
+
+/// Get a snapshot of the hook for a `target` function with an identifier.
+pub fn get(target: *const c_void, ident: HookIdent) -> Option<HookRecord> {
+    records().get(&(target as usize, ident)).copied()
+}
+
+/// Check whether the hook for a `target` function with an identifier
+/// is enabled, or get `None` if there is no such hook.
+pub fn is_enabled(target: *const c_void, ident: HookIdent) -> Option<bool> {
+    get(target, ident).map(|record| record.enabled)
+}
+
+pub(crate) fn insert(target: *const c_void, detour: *const c_void, trampoline: *const c_void,
+    ident: HookIdent, location: &'static Location<'static>)
+{
+    records().insert((target as usize, ident), HookRecord {
+        target: target as usize,
+        detour: detour as usize,
+        trampoline: trampoline as usize,
+        ident,
+        enabled: false,
+        queued: false,
+        location,
+    });
+}
+
+pub(crate) fn remove(target: Option<*const c_void>, ident: HookIdent) {
+    records().retain(|_, record| {
+        !(target.is_none_or(|target| record.target() == target) && ident.matches(record.ident))
+    });
+}
+
+pub(crate) fn remove_disabled(ident: HookIdent) {
+    records().retain(|_, record| record.enabled || !ident.matches(record.ident));
+}
+
+pub(crate) fn clear() {
+    records().clear();
+}
+
+/// Record an immediate state change, which also resets the queued state.
+pub(crate) fn set_enabled(target: Option<*const c_void>, ident: HookIdent, enabled: bool) {
+    update_matching(target, ident, |record| {
+        record.enabled = enabled;
+        record.queued = enabled;
+    });
+}
+
+pub(crate) fn set_queued(target: Option<*const c_void>, ident: HookIdent, enabled: bool) {
+    update_matching(target, ident, |record| record.queued = enabled);
+}
+
+pub(crate) fn apply_queued(ident: HookIdent) {
+    update_matching(None, ident, |record| record.enabled = record.queued);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockBackend;
+    use crate::{ErrorKind, HookBackend, MinHook, Operation, ThreadFreezeMethod};
+
+    const FIRST: usize = 0x1000;
+    const SECOND: usize = 0x2000;
+    const DETOUR: usize = 0x9000;
+    const IDENT: HookIdent = HookIdent::new(7);
+
+    static MOCK: MockBackend = MockBackend::new();
+
+    fn ptr(address: usize) -> *const c_void {
+        address as *const c_void
+    }
+
+    /// Create disabled hooks of `IDENT` for both targets,
+    /// and one of [`HookIdent::DEFAULT`] for the second.
+    fn create_hooks() {
+        unsafe {
+            crate::create_hook(ptr(FIRST), ptr(DETOUR), IDENT).unwrap();
+            crate::create_hook(ptr(SECOND), ptr(DETOUR), IDENT).unwrap();
+            crate::create_hook(ptr(SECOND), ptr(DETOUR), HookIdent::DEFAULT).unwrap();
+        }
+    }
+
+    /// Get the enabled and queued states of all records,
+    /// checking that the mock agrees with them.
+    fn states() -> Vec<(usize, HookIdent, bool, bool)> {
+        assert_eq!(hooks().len(), MOCK.hooks().len());
+        hooks().iter().map(|record| {
+            let hook = MOCK.hook(record.target(), record.ident()).unwrap();
+            assert_eq!((hook.enabled, hook.queued), (record.enabled, record.queued));
+            (record.target, record.ident, record.enabled, record.queued)
+        }).collect()
+    }
+
+    #[test]
+    fn lookups() {
+        let _selection = MOCK.select().unwrap();
+        let _minhook = MinHook::initialize(ThreadFreezeMethod::OriginalSnapshot).unwrap();
+        let location = Location::caller();
+        create_hooks();
+        let record = get(ptr(FIRST), IDENT).unwrap();
+        assert_eq!((record.target(), record.detour()), (ptr(FIRST), ptr(DETOUR)));
+        assert_eq!(record.location().file(), location.file());
+        assert!(get(ptr(FIRST), HookIdent::DEFAULT).is_none());
+        assert_eq!(is_enabled(ptr(SECOND), IDENT), Some(false));
+        assert_eq!(find(ptr(SECOND)).len(), 2);
+        assert_eq!(with_ident(IDENT).len(), 2);
+        assert_eq!(with_ident(HookIdent::ALL).len(), 3);
+    }
+
+    #[test]
+    fn immediate_changes_clear_queued_ones() {
+        let _selection = MOCK.select().unwrap();
+        let _minhook = MinHook::initialize(ThreadFreezeMethod::OriginalSnapshot).unwrap();
+        create_hooks();
+        unsafe {
+            crate::queue_enable_hook(ptr(FIRST), IDENT).unwrap();
+            crate::queue_enable_hook(ptr(SECOND), IDENT).unwrap();
+            crate::enable_hook(ptr(FIRST), IDENT).unwrap();
+            crate::disable_hook(ptr(FIRST), IDENT).unwrap();
+            assert_eq!(states(), [(FIRST, IDENT, false, false), (SECOND, HookIdent::DEFAULT,
+                false, false), (SECOND, IDENT, false, true)]);
+            crate::enable_hooks(HookIdent::ALL).unwrap();
+            crate::queue_disable_hook(ptr(SECOND), IDENT).unwrap();
+            crate::disable_hooks(HookIdent::DEFAULT).unwrap();
+            assert_eq!(states(), [(FIRST, IDENT, true, true), (SECOND, HookIdent::DEFAULT,
+                false, false), (SECOND, IDENT, true, false)]);
+        }
+    }
+
+    #[test]
+    fn bulk_failures_fall_back_to_single_hooks() {
+        let _selection = MOCK.select().unwrap();
+        let _minhook = MinHook::initialize(ThreadFreezeMethod::OriginalSnapshot).unwrap();
+        create_hooks();
+        unsafe {
+            // Have the failed batch switch the second hook behind the registry's back,
+            // and switching the first one on its own fail as well.
+            MOCK.enable_hook(Some(ptr(SECOND)), IDENT).unwrap();
+            MOCK.fail_next(Some(Operation::EnableHook), ErrorKind::MutexFailure);
+            MOCK.fail_next(Some(Operation::EnableHook), ErrorKind::ProtectionFailure);
+            let error = crate::enable_hooks(IDENT).unwrap_err();
+            assert_eq!((error.kind(), error.ident()), (ErrorKind::MutexFailure, Some(IDENT)));
+            assert_eq!(states(), [(FIRST, IDENT, false, false), (SECOND, HookIdent::DEFAULT,
+                false, false), (SECOND, IDENT, true, true)]);
+
+            MOCK.fail_next(Some(Operation::DisableHook), ErrorKind::MutexFailure);
+            assert!(crate::disable_hooks(HookIdent::ALL).is_err());
+            assert_eq!(states(), [(FIRST, IDENT, false, false), (SECOND, HookIdent::DEFAULT,
+                false, false), (SECOND, IDENT, false, false)]);
+        }
+    }
+
+    #[test]
+    fn removing_disabled_hooks() {
+        let _selection = MOCK.select().unwrap();
+        let _minhook = MinHook::initialize(ThreadFreezeMethod::OriginalSnapshot).unwrap();
+        create_hooks();
+        unsafe {
+            crate::enable_hook(ptr(FIRST), IDENT).unwrap();
+            crate::remove_disabled_hooks(IDENT).unwrap();
+            assert_eq!(states(), [(FIRST, IDENT, true, true),
+                (SECOND, HookIdent::DEFAULT, false, false)]);
+            // Hooks are removed one at a time if the batch fails.
+            MOCK.fail_next(Some(Operation::RemoveDisabledHooks), ErrorKind::MutexFailure);
+            assert!(crate::remove_disabled_hooks(HookIdent::ALL).is_err());
+            assert_eq!(states(), [(FIRST, IDENT, true, true)]);
+        }
+    }
+}
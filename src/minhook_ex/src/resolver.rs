@@ -0,0 +1,334 @@
+//! Resolution of exports the way the Windows loader does it.
+//!
+//! `GetProcAddress` only knows modules mapped by the loader, and hooking
+//! what it returns for a forwarded export hooks the forwarder's module
+//! only by accident. [`Resolver`] walks the export directories itself:
+//! it follows forwarder chains across modules, redirects API set names
+//! like `api-ms-win-core-synch-l1-2-0` to their host modules through the
+//! [`ApiSetSchema`], and accepts manually mapped modules or images read
+//! from files, so resolution works on any platform.
+
+use std::ffi::c_void;
+
+use crate::pe::{self, module_matches, ExportAddress, Image, PeError};
+use crate::{ApiError, Export, HookIdent};
+
+/// Maximum number of forwarders followed before giving up.
+pub const MAX_FORWARDS: usize = 16;
+
+/// Size of an API set namespace entry.
+const API_SET_ENTRY_LEN: u32 = 24;
+/// Size of an API set value entry.
+const API_SET_VALUE_LEN: u32 = 20;
+
+/// The API set schema, which maps API set names to host modules.
+///
+/// Only version 6 of the schema, used since Windows 10, is supported.
+#[derive(Clone, Copy, Debug)]
+pub struct ApiSetSchema<'a> {
+    data: &'a [u8],
+    count: u32,
+    entries: u32,
+}
+
+impl<'a> ApiSetSchema<'a> {
+    /// Parse the schema, as found in the `.apiset` section
+    /// of `apisetschema.dll` or mapped into every process.
+    pub fn parse(data: &'a [u8]) -> Result<Self, PeError> {
+        let version = pe::read_u32(data, 0)?;
+        if version != 6 {
+            return Err(PeError::UnsupportedApiSetVersion { version });
+        }
+        Ok(Self { data, count: pe::read_u32(data, 12)?, entries: pe::read_u32(data, 16)? })
+    }
+
+    /// Get the schema mapped into the current process.
+    ///
+    /// # Safety
+    ///
+    /// The schema pointed to by the process environment block
+    /// must not be changed while it is in use.
+    #[cfg(windows)]
+    pub unsafe fn current() -> Result<ApiSetSchema<'static>, PeError> {
+        let peb: usize;
+        #[cfg(target_arch = "x86_64")]
+        std::arch::asm!("mov {}, gs:[0x60]", out(reg) peb, options(nostack, readonly));
+        #[cfg(target_arch = "x86")]
+        std::arch::asm!("mov {}, fs:[0x30]", out(reg) peb, options(nostack, readonly));
+        #[cfg(target_arch = "aarch64")]
+        {
+            let teb: usize;
+            std::arch::asm!("mov {}, x18", out(reg) teb, options(nomem, nostack));
+            peb = *((teb + 0x60) as *const usize);
+        }
+        let offset = if cfg!(target_pointer_width = "64") { 0x68 } else { 0x38 };
+        let map = *((peb + offset) as *const *const u8);
+        let size = *(map.add(4) as *const u32) as usize;
+        ApiSetSchema::parse(std::slice::from_raw_parts(map, size))
+    }
+
+    /// Get the host module of an API set.
+    ///
+    /// Returns `None` if the name isn't a known API set, or the API
+    /// set has no host, as is the case for some extension sets.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - name of the API set, with or without a `.dll` extension
+    ///     and with any version, like `"api-ms-win-core-synch-l1-2-0"`.
+    /// * `importer` - module referencing the API set, which may
+    ///     have a host of its own.
+    pub fn resolve(&self, name: &str, importer: Option<&str>) -> Result<Option<String>, PeError> {
+        let name = pe::strip_dll(name);
+        // Entries are hashed up to the last hyphen, leaving out the minor version.
+        let Some((key, _)) = name.rsplit_once('-') else {
+            return Ok(None);
+        };
+        for index in 0..self.count {
+            let entry = entry_offset(self.entries, API_SET_ENTRY_LEN, index)?;
+            let hashed_len = self.field(entry, 3)?;
+            if !self.string(self.field(entry, 1)?, hashed_len)?.eq_ignore_ascii_case(key) {
+                continue;
+            }
+            let values = self.field(entry, 4)?;
+            let mut host = None;
+            for value in 0..self.field(entry, 5)? {
+                let value = entry_offset(values, API_SET_VALUE_LEN, value)?;
+                let importer_name = self.string(self.field(value, 1)?, self.field(value, 2)?)?;
+                let value_name = self.string(self.field(value, 3)?, self.field(value, 4)?)?;
+                if importer_name.is_empty() {
+                    host.get_or_insert(value_name);
+                } else if importer.is_some_and(|importer| module_matches(&importer_name, importer)) {
+                    host = Some(value_name);
+                    break;
+                }
+            }
+            return Ok(host.filter(|host| !host.is_empty()));
+        }
+        Ok(None)
+    }
+
+    /// Read a field of an entry or value, which are all 32-bit.
+    fn field(&self, entry: u32, index: u32) -> Result<u32, PeError> {
+        pe::read_u32(self.data, entry_offset(entry, 4, index)? as usize)
+    }
+
+    fn string(&self, offset: u32, len: u32) -> Result<String, PeError> {
+        let (offset, len) = (offset as usize, len as usize);
+        let bytes = offset.checked_add(len).and_then(|end| self.data.get(offset..end))
+            .ok_or(PeError::Truncated { offset })?;
+        let units = bytes.chunks_exact(2).map(|unit| u16::from_le_bytes([unit[0], unit[1]]));
+        Ok(char::decode_utf16(units).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect())
+    }
+}
+
+/// Get the offset of an entry of a table in the schema, failing if it overflows.
+fn entry_offset(table: u32, entry_len: u32, index: u32) -> Result<u32, PeError> {
+    entry_len.checked_mul(index).and_then(|offset| table.checked_add(offset))
+        .ok_or(PeError::Truncated { offset: table as usize })
+}
+
+/// Check whether a module name is an API set rather than a file.
+pub fn is_api_set(name: &str) -> bool {
+    let prefix = name.get(..4).unwrap_or_default();
+    prefix.eq_ignore_ascii_case("api-") || prefix.eq_ignore_ascii_case("ext-")
+}
+
+/// A module known to a [`Resolver`].
+#[derive(Clone, Debug)]
+struct Module<'a> {
+    name: String,
+    base: usize,
+    image: Image<'a>,
+}
+
+/// An export resolved to code.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Resolved {
+    module: String,
+    export: Export,
+    address: usize,
+    forwards: usize,
+}
+
+impl Resolved {
+    /// Get the name of the module containing the code.
+    pub fn module(&self) -> &str {
+        &self.module
+    }
+
+    /// Get the export of that module which refers to the code.
+    pub fn export(&self) -> &Export {
+        &self.export
+    }
+
+    /// Get the address of the code.
+    pub fn address(&self) -> *const c_void {
+        self.address as *const c_void
+    }
+
+    /// Get the number of forwarders followed.
+    pub fn forwards(&self) -> usize {
+        self.forwards
+    }
+}
+
+/// Resolver of exports over a set of modules.
+#[derive(Clone, Debug, Default)]
+pub struct Resolver<'a> {
+    modules: Vec<Module<'a>>,
+    api_set: Option<ApiSetSchema<'a>>,
+    loaded: bool,
+}
+
+impl<'a> Resolver<'a> {
+    /// Create a resolver without any modules.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a resolver of the modules loaded into the current process,
+    /// using its API set schema, which modules can be added to.
+    ///
+    /// # Safety
+    ///
+    /// Loaded modules must not be unloaded while the resolver is in use.
+    #[cfg(windows)]
+    pub unsafe fn loaded() -> Self {
+        Self { modules: Vec::new(), api_set: ApiSetSchema::current().ok(), loaded: true }
+    }
+
+    /// Add a module, which takes precedence over one with the same name.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - name of the module, like `"kernel32.dll"`.
+    /// * `base` - address the module is mapped at, or its preferred
+    ///     base address if it's not mapped, which resolved addresses
+    ///     are relative to.
+    /// * `image` - the parsed module.
+    pub fn add_module(&mut self, name: &str, base: usize, image: Image<'a>) {
+        self.modules.insert(0, Module { name: name.to_owned(), base, image });
+    }
+
+    /// Set the API set schema to redirect API set names with.
+    pub fn set_api_set(&mut self, api_set: ApiSetSchema<'a>) {
+        self.api_set = Some(api_set);
+    }
+
+    /// Resolve an export of a module to its code.
+    ///
+    /// # Arguments
+    ///
+    /// * `module` - name of the module or of an API set,
+    ///     compared ignoring case and an omitted `.dll` extension.
+    /// * `export` - name or ordinal of the exported function.
+    pub fn resolve(&self, module: &str, export: &Export) -> Result<Resolved, ApiError> {
+        let mut module = module.to_owned();
+        let mut export = export.clone();
+        let mut importer = None;
+        for forwards in 0..=MAX_FORWARDS {
+            let host = self.redirect(&module, importer.as_deref())?;
+            let Module { name, base, image } = self.find(&host)
+                .ok_or_else(|| ApiError::ModuleNotFound { module: host.clone() })?;
+            let invalid = |error| ApiError::InvalidImage { module: name.clone(), error };
+            let symbol = match image.exports().map_err(invalid)? {
+                Some(exports) => exports.find(&export).map_err(invalid)?,
+                None => None,
+            };
+            let symbol = symbol.ok_or_else(|| ApiError::FunctionNotFound {
+                module: name.clone(),
+                export: export.clone(),
+            })?;
+            match symbol.address() {
+                ExportAddress::Code(rva) => {
+                    let address = base.wrapping_add(rva as usize);
+                    return Ok(Resolved { module: name, export, address, forwards });
+                }
+                ExportAddress::Forwarder(forwarder) => {
+                    (module, export) = parse_forwarder(forwarder)?;
+                    importer = Some(name);
+                }
+            }
+        }
+        Err(ApiError::InvalidForwarder { forwarder: format!("{}.{}", module, export) })
+    }
+
+    /// Resolve an export and create a disabled hook for it.
+    /// Returns pointers to the resolved target and the trampoline function.
+    ///
+    /// # Arguments
+    ///
+    /// * `module` - name of the module or of an API set.
+    /// * `export` - name or ordinal of the exported function.
+    /// * `detour` - pointer to the overwriting function.
+    /// * `ident` - hook identifier, set different ones to create
+    ///     multiple hooks for the same target function.
+    ///
+    /// # Safety
+    ///
+    /// The modules must be mapped at their bases, otherwise
+    /// same as for [`create_hook`](crate::create_hook).
+    #[track_caller]
+    pub unsafe fn create_hook(&self, module: &str, export: impl Into<Export>,
+        detour: *const c_void, ident: HookIdent) -> Result<(*const c_void, *const c_void), ApiError>
+    {
+        let target = self.resolve(module, &export.into())?.address();
+        let trampoline = crate::create_hook(target, detour, ident)?;
+        Ok((target, trampoline))
+    }
+
+    /// Get the host module of an API set, or the module itself.
+    fn redirect(&self, module: &str, importer: Option<&str>) -> Result<String, ApiError> {
+        let (Some(api_set), true) = (&self.api_set, is_api_set(module)) else {
+            return Ok(module.to_owned());
+        };
+        let invalid = |error| ApiError::InvalidImage { module: "apiset".to_owned(), error };
+        api_set.resolve(module, importer).map_err(invalid)?
+            .ok_or_else(|| ApiError::ModuleNotFound { module: module.to_owned() })
+    }
+
+    fn find(&self, module: &str) -> Option<Module<'a>> {
+        if let Some(found) = self.modules.iter().find(|found| module_matches(&found.name, module)) {
+            return Some(found.clone());
+        }
+        #[cfg(windows)]
+        if self.loaded {
+            return unsafe { find_loaded(module) };
+        }
+        None
+    }
+}
+
+/// Split a forwarder like `"NTDLL.RtlAllocateHeap"` or `"NTDLL.#12"`
+/// into a module name and an export.
+fn parse_forwarder(forwarder: &str) -> Result<(String, Export), ApiError> {
+    let invalid = || ApiError::InvalidForwarder { forwarder: forwarder.to_owned() };
+    let (module, name) = forwarder.split_once('.').ok_or_else(invalid)?;
+    let export = match name.strip_prefix('#') {
+        Some(ordinal) => Export::Ordinal(ordinal.parse().map_err(|_| invalid())?),
+        None => Export::Name(name.to_owned()),
+    };
+    Ok((module.to_owned(), export))
+}
+
+#[cfg(windows)]
+#[link(name = "kernel32")]
+extern "system" {
+    fn GetModuleHandleW(lpModuleName: *const u16) -> *mut c_void;
+}
+
+/// Find a module mapped by the loader.
+#[cfg(windows)]
+unsafe fn find_loaded<'a>(module: &str) -> Option<Module<'a>> {
+    let wide: Vec<u16> = module.encode_utf16().chain(Some(0)).collect();
+    if wide[..wide.len() - 1].contains(&0) {
+        return None;
+    }
+    let base = GetModuleHandleW(wide.as_ptr());
+    if base.is_null() {
+        return None;
+    }
+    let image = Image::from_base(base).ok()?;
+    Some(Module { name: module.to_owned(), base: base as usize, image })
+}
@@ -0,0 +1,752 @@
+//! Scanning of code for byte patterns, to find functions which
+//! aren't exported.
+//!
+//! Patterns are written the way IDA and most signature tools print them,
+//! as hexadecimal bytes with `??` for any byte and `?` for any digit,
+//! like `"48 8B ?? ?? E8 ?? ?? ?? ??"`. A [`Pattern`] searches plain
+//! buffers, such as the [`data`](crate::pe::Section::data) of a section
+//! read from a file, while [`scan`] and [`scan_module`] search memory of
+//! the current process. Their [`Match`]es can follow the call or the
+//! RIP-relative operand they point at, and be passed to
+//! [`create_hook`](crate::create_hook) as they are.
+//!
+//! Two known bytes of a pattern are compared at 16 or 32 positions at once
+//! with SSE2, AVX2 or NEON where available, and the whole pattern is only
+//! compared where both of them match.
+
+use std::ffi::c_void;
+use std::ops::Range;
+use std::str::FromStr;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::decoder::{self, Bitness, Instruction, MAX_INSTRUCTION_LEN};
+use crate::pe::PeError;
+#[cfg(not(target_os = "linux"))]
+use crate::pe::Image;
+
+/// Failure to parse a pattern.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PatternError {
+    /// The pattern has no bytes.
+    Empty,
+    /// A byte is neither two hexadecimal digits nor a wildcard.
+    InvalidByte {
+        /// Index of the byte in the pattern.
+        index: usize,
+        /// The byte as written.
+        token: String,
+    },
+}
+
+impl std::fmt::Display for PatternError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => f.write_str("pattern has no bytes"),
+            Self::InvalidByte { index, token } => {
+                write!(f, "pattern byte {} {:?} is neither hexadecimal nor a wildcard", index, token)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PatternError {}
+
+/// Failure to find a single match of a pattern.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScanError {
+    /// The pattern doesn't match anywhere.
+    NotFound,
+    /// The pattern matches more than once.
+    Ambiguous {
+        /// Number of matches.
+        count: usize,
+    },
+    /// No module is mapped at the scanned address.
+    ModuleNotFound,
+    /// The scanned module is not a valid image.
+    InvalidImage {
+        /// Why the image couldn't be parsed.
+        error: PeError,
+    },
+}
+
+impl std::fmt::Display for ScanError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound => f.write_str("pattern not found"),
+            Self::Ambiguous { count } => write!(f, "pattern found {} times instead of once", count),
+            Self::ModuleNotFound => f.write_str("no module mapped at the scanned address"),
+            Self::InvalidImage { error } => write!(f, "scanned module is not a valid image: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for ScanError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::InvalidImage { error } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Offsets of two known bytes of a pattern, searched for first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct Anchor {
+    first: usize,
+    second: usize,
+}
+
+/// A byte pattern with wildcards.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Pattern {
+    // Bytes are stored masked, so data matches if it equals them once masked.
+    bytes: Vec<u8>,
+    mask: Vec<u8>,
+    // The first and the last known byte, or none if every byte has a wildcard.
+    anchor: Option<Anchor>,
+}
+
+impl Pattern {
+    /// Parse a pattern of whitespace-separated bytes, each of which is
+    /// either two hexadecimal digits, `??` or `?` for any byte, or a digit
+    /// and a `?` for any digit, like `"48 8B ?? ?? E8 ?? ?? ?? ??"`.
+    pub fn parse(pattern: &str) -> Result<Self, PatternError> {
+        let mut bytes = Vec::new();
+        let mut mask = Vec::new();
+        for (index, token) in pattern.split_whitespace().enumerate() {
+            let (byte, byte_mask) = parse_byte(token)
+                .ok_or_else(|| PatternError::InvalidByte { index, token: token.to_owned() })?;
+            bytes.push(byte);
+            mask.push(byte_mask);
+        }
+        if bytes.is_empty() {
+            return Err(PatternError::Empty);
+        }
+        let first = mask.iter().position(|&byte_mask| byte_mask == 0xFF);
+        let second = mask.iter().rposition(|&byte_mask| byte_mask == 0xFF);
+        let anchor = first.zip(second).map(|(first, second)| Anchor { first, second });
+        Ok(Self { bytes, mask, anchor })
+    }
+
+    /// Get the number of bytes of the pattern.
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Check whether the pattern has no bytes, which is never
+    /// the case for a parsed pattern.
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Check whether the pattern matches the start of a buffer.
+    pub fn matches(&self, data: &[u8]) -> bool {
+        data.len() >= self.len() && data.iter().zip(&self.bytes).zip(&self.mask)
+            .all(|((&byte, &expected), &mask)| byte & mask == expected)
+    }
+
+    /// Find the offset of the first match in a buffer.
+    pub fn find(&self, data: &[u8]) -> Option<usize> {
+        self.find_from(data, 0)
+    }
+
+    /// Find the offsets of all matches in a buffer, including
+    /// ones overlapping each other.
+    pub fn find_iter<'a>(&'a self, data: &'a [u8]) -> impl Iterator<Item = usize> + 'a {
+        let mut start = 0;
+        std::iter::from_fn(move || {
+            let found = self.find_from(data, start)?;
+            start = found + 1;
+            Some(found)
+        })
+    }
+
+    /// Find the offset of the only match in a buffer.
+    pub fn find_unique(&self, data: &[u8]) -> Result<usize, ScanError> {
+        unique(self.find_iter(data))
+    }
+
+    fn find_from(&self, data: &[u8], start: usize) -> Option<usize> {
+        let last = data.len().checked_sub(self.len())?;
+        if start > last {
+            return None;
+        }
+        let Some(anchor) = self.anchor else {
+            return (start..=last).find(|&position| self.matches(&data[position..]));
+        };
+        let mut position = start;
+        if let Some(found) = simd::find(self, anchor, data, &mut position, last) {
+            return Some(found);
+        }
+        let (first, second) = (self.bytes[anchor.first], self.bytes[anchor.second]);
+        (position..=last).find(|&position| {
+            data[position + anchor.first] == first && data[position + anchor.second] == second
+                && self.matches(&data[position..])
+        })
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = PatternError;
+
+    fn from_str(pattern: &str) -> Result<Self, PatternError> {
+        Self::parse(pattern)
+    }
+}
+
+impl std::fmt::Display for Pattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (index, (&byte, &mask)) in self.bytes.iter().zip(&self.mask).enumerate() {
+            if index != 0 {
+                f.write_str(" ")?;
+            }
+            for shift in [4, 0] {
+                match (mask >> shift) & 0xF {
+                    0 => f.write_str("?")?,
+                    _ => write!(f, "{:X}", (byte >> shift) & 0xF)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse a byte of a pattern into its value and mask.
+fn parse_byte(token: &str) -> Option<(u8, u8)> {
+    if token == "?" {
+        return Some((0, 0));
+    }
+    let mut digits = token.chars();
+    let (Some(high), Some(low), None) = (digits.next(), digits.next(), digits.next()) else {
+        return None;
+    };
+    let (high, high_mask) = parse_digit(high)?;
+    let (low, low_mask) = parse_digit(low)?;
+    Some((high << 4 | low, high_mask << 4 | low_mask))
+}
+
+fn parse_digit(digit: char) -> Option<(u8, u8)> {
+    match digit {
+        '?' => Some((0, 0)),
+        _ => digit.to_digit(16).map(|value| (value as u8, 0xF)),
+    }
+}
+
+/// Get the only item of an iterator of matches.
+fn unique<T>(mut matches: impl Iterator<Item = T>) -> Result<T, ScanError> {
+    let found = matches.next().ok_or(ScanError::NotFound)?;
+    match matches.count() {
+        0 => Ok(found),
+        more => Err(ScanError::Ambiguous { count: more + 1 }),
+    }
+}
+
+/// Vectorized search for the anchor of a pattern.
+///
+/// Each function checks chunks of consecutive positions, as long as the
+/// whole chunk is within `last`, and leaves `position` at the first one
+/// it didn't check.
+mod simd {
+    use super::{Anchor, Pattern};
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub(super) fn find(pattern: &Pattern, anchor: Anchor, data: &[u8], position: &mut usize,
+        last: usize) -> Option<usize>
+    {
+        if std::arch::is_x86_feature_detected!("avx2") {
+            return unsafe { x86::find_avx2(pattern, anchor, data, position, last) };
+        }
+        if std::arch::is_x86_feature_detected!("sse2") {
+            return unsafe { x86::find_sse2(pattern, anchor, data, position, last) };
+        }
+        None
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub(super) fn find(pattern: &Pattern, anchor: Anchor, data: &[u8], position: &mut usize,
+        last: usize) -> Option<usize>
+    {
+        use std::arch::aarch64::{vandq_u8, vceqq_u8, vdupq_n_u8, vld1q_u8, vmaxvq_u8};
+
+        const LANES: usize = 16;
+        // NEON is part of the baseline of AArch64 targets.
+        unsafe {
+            let first = vdupq_n_u8(pattern.bytes[anchor.first]);
+            let second = vdupq_n_u8(pattern.bytes[anchor.second]);
+            while *position + LANES <= last + 1 {
+                let chunk = data.as_ptr().add(*position);
+                let hits = vandq_u8(vceqq_u8(vld1q_u8(chunk.add(anchor.first)), first),
+                    vceqq_u8(vld1q_u8(chunk.add(anchor.second)), second));
+                // There is no movemask, so hits are rare enough to find one by one.
+                if vmaxvq_u8(hits) != 0 {
+                    let mut chunk = *position..*position + LANES;
+                    if let Some(found) = chunk.find(|&found| pattern.matches(&data[found..])) {
+                        return Some(found);
+                    }
+                }
+                *position += LANES;
+            }
+        }
+        None
+    }
+
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    pub(super) fn find(_pattern: &Pattern, _anchor: Anchor, _data: &[u8], _position: &mut usize,
+        _last: usize) -> Option<usize>
+    {
+        None
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub(super) mod x86 {
+        #[cfg(target_arch = "x86")]
+        use std::arch::x86::*;
+        #[cfg(target_arch = "x86_64")]
+        use std::arch::x86_64::*;
+
+        use super::{Anchor, Pattern};
+
+        /// Check the positions whose bits are set in a mask of hits.
+        fn check(pattern: &Pattern, data: &[u8], position: usize, mut hits: u32) -> Option<usize> {
+            while hits != 0 {
+                let found = position + hits.trailing_zeros() as usize;
+                if pattern.matches(&data[found..]) {
+                    return Some(found);
+                }
+                hits &= hits - 1;
+            }
+            None
+        }
+
+        #[target_feature(enable = "sse2")]
+        pub(in crate::scan) unsafe fn find_sse2(pattern: &Pattern, anchor: Anchor, data: &[u8],
+            position: &mut usize, last: usize) -> Option<usize>
+        {
+            const LANES: usize = 16;
+            let first = _mm_set1_epi8(pattern.bytes[anchor.first] as i8);
+            let second = _mm_set1_epi8(pattern.bytes[anchor.second] as i8);
+            while *position + LANES <= last + 1 {
+                let chunk = data.as_ptr().add(*position);
+                let hits = _mm_and_si128(
+                    _mm_cmpeq_epi8(_mm_loadu_si128(chunk.add(anchor.first) as *const __m128i), first),
+                    _mm_cmpeq_epi8(_mm_loadu_si128(chunk.add(anchor.second) as *const __m128i), second));
+                if let Some(found) = check(pattern, data, *position, _mm_movemask_epi8(hits) as u32) {
+                    return Some(found);
+                }
+                *position += LANES;
+            }
+            None
+        }
+
+        #[target_feature(enable = "avx2")]
+        pub(in crate::scan) unsafe fn find_avx2(pattern: &Pattern, anchor: Anchor, data: &[u8],
+            position: &mut usize, last: usize) -> Option<usize>
+        {
+            const LANES: usize = 32;
+            let first = _mm256_set1_epi8(pattern.bytes[anchor.first] as i8);
+            let second = _mm256_set1_epi8(pattern.bytes[anchor.second] as i8);
+            while *position + LANES <= last + 1 {
+                let chunk = data.as_ptr().add(*position);
+                let hits = _mm256_and_si256(
+                    _mm256_cmpeq_epi8(_mm256_loadu_si256(chunk.add(anchor.first) as *const __m256i), first),
+                    _mm256_cmpeq_epi8(_mm256_loadu_si256(chunk.add(anchor.second) as *const __m256i), second));
+                if let Some(found) = check(pattern, data, *position, _mm256_movemask_epi8(hits) as u32) {
+                    return Some(found);
+                }
+                *position += LANES;
+            }
+            None
+        }
+    }
+}
+
+/// A match of a pattern in memory of the current process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Match {
+    address: usize,
+}
+
+impl Match {
+    /// Create a match at an address, to follow what it refers to.
+    pub fn new(address: *const c_void) -> Self {
+        Self { address: address as usize }
+    }
+
+    /// Get the address of the match.
+    pub fn address(&self) -> *const c_void {
+        self.address as *const c_void
+    }
+
+    /// Get the match moved by a number of bytes, like to
+    /// an instruction in the middle of the pattern.
+    pub fn offset(self, offset: isize) -> Self {
+        Self { address: self.address.wrapping_add_signed(offset) }
+    }
+
+    /// Follow a 32-bit displacement from the end of an instruction
+    /// at the match, which works for any encoding.
+    ///
+    /// # Arguments
+    ///
+    /// * `operand` - offset of the displacement from the match,
+    ///     like 1 for a `call rel32`.
+    /// * `instruction_len` - length of the instruction, whose end the
+    ///     displacement is relative to, like 5 for a `call rel32`.
+    ///
+    /// # Safety
+    ///
+    /// The displacement must be readable.
+    pub unsafe fn rel32(self, operand: usize, instruction_len: usize) -> Self {
+        let displacement = ((self.address + operand) as *const i32).read_unaligned();
+        Self { address: self.address.wrapping_add(instruction_len).wrapping_add_signed(displacement as isize) }
+    }
+
+    /// Follow the relative call or jump at the match.
+    ///
+    /// Returns `None` if the instruction is not a relative branch.
+    ///
+    /// # Safety
+    ///
+    /// The match must be at the start of an instruction.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub unsafe fn branch_target(self) -> Option<Self> {
+        let address = self.decode()?.branch_target(self.address)?;
+        Some(Self { address })
+    }
+
+    /// Follow the RIP-relative memory operand of the instruction at the match,
+    /// like the data loaded by a `mov rax, [rip + disp32]`.
+    ///
+    /// Returns `None` if the instruction has no such operand,
+    /// which is always the case on x86.
+    ///
+    /// # Safety
+    ///
+    /// The match must be at the start of an instruction.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub unsafe fn rip_target(self) -> Option<Self> {
+        let address = self.decode()?.rip_target(self.address)?;
+        Some(Self { address })
+    }
+
+    /// Follow the pointer stored at the match, like a global
+    /// found by [`Match::rip_target`].
+    ///
+    /// # Safety
+    ///
+    /// The pointer must be readable.
+    pub unsafe fn read_pointer(self) -> Self {
+        Self { address: (self.address as *const usize).read_unaligned() }
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    unsafe fn decode(&self) -> Option<Instruction> {
+        let len = crate::allocator::readable_len(self.address).min(MAX_INSTRUCTION_LEN);
+        let code = std::slice::from_raw_parts(self.address as *const u8, len);
+        decoder::decode(code, Bitness::NATIVE).ok()
+    }
+}
+
+impl From<Match> for *const c_void {
+    fn from(found: Match) -> Self {
+        found.address()
+    }
+}
+
+/// Find all matches of a pattern in memory of the current process.
+///
+/// # Arguments
+///
+/// * `start` - address to start scanning at.
+/// * `len` - number of bytes to scan.
+/// * `pattern` - the pattern to find.
+///
+/// # Safety
+///
+/// The scanned memory must be readable, and not change while it's scanned.
+pub unsafe fn scan(start: *const c_void, len: usize, pattern: &Pattern) -> Vec<Match> {
+    let data = std::slice::from_raw_parts(start as *const u8, len);
+    pattern.find_iter(data).map(|offset| Match { address: start as usize + offset }).collect()
+}
+
+/// Find the only match of a pattern in memory of the current process.
+///
+/// # Safety
+///
+/// Same as for [`scan`].
+pub unsafe fn scan_unique(start: *const c_void, len: usize, pattern: &Pattern)
+    -> Result<Match, ScanError>
+{
+    let data = std::slice::from_raw_parts(start as *const u8, len);
+    let offset = pattern.find_unique(data)?;
+    Ok(Match { address: start as usize + offset })
+}
+
+/// Find all matches of a pattern in the executable code of a module,
+/// which are its executable sections or, on Linux, segments.
+///
+/// # Arguments
+///
+/// * `module` - base address of a module mapped into the current process,
+///     like a handle from `GetModuleHandleW`. On Linux, any address
+///     within the module, like one from `dlsym`.
+/// * `pattern` - the pattern to find.
+///
+/// # Safety
+///
+/// The module must stay mapped, and its code must not change while it's scanned.
+pub unsafe fn scan_module(module: *const c_void, pattern: &Pattern) -> Result<Vec<Match>, ScanError> {
+    let mut matches = Vec::new();
+    for code in code_ranges(module)? {
+        matches.extend(scan(code.start as *const c_void, code.len(), pattern));
+    }
+    Ok(matches)
+}
+
+/// Find the only match of a pattern in the executable code of a module.
+///
+/// # Safety
+///
+/// Same as for [`scan_module`].
+pub unsafe fn scan_module_unique(module: *const c_void, pattern: &Pattern)
+    -> Result<Match, ScanError>
+{
+    unique(scan_module(module, pattern)?.into_iter())
+}
+
+/// Get the address ranges of the executable sections of a mapped image.
+#[cfg(not(target_os = "linux"))]
+unsafe fn code_ranges(module: *const c_void) -> Result<Vec<Range<usize>>, ScanError> {
+    let invalid = |error| ScanError::InvalidImage { error };
+    let sections = Image::from_base(module).and_then(|image| image.sections()).map_err(invalid)?;
+    Ok(sections.iter().filter(|section| section.is_executable()).map(|section| {
+        let start = section.data().as_ptr() as usize;
+        start..start + section.data().len()
+    }).collect())
+}
+
+/// Get the address ranges of the executable segments of the loaded
+/// object containing an address.
+#[cfg(target_os = "linux")]
+unsafe fn code_ranges(module: *const c_void) -> Result<Vec<Range<usize>>, ScanError> {
+    struct Search {
+        address: usize,
+        code: Option<Vec<Range<usize>>>,
+    }
+
+    unsafe extern "C" fn visit(info: *mut libc::dl_phdr_info, _size: usize, search: *mut c_void)
+        -> libc::c_int
+    {
+        let (info, search) = (&*info, &mut *(search as *mut Search));
+        if info.dlpi_phdr.is_null() {
+            return 0;
+        }
+        let headers = std::slice::from_raw_parts(info.dlpi_phdr, info.dlpi_phnum as usize);
+        let segments = headers.iter().filter(|header| header.p_type == libc::PT_LOAD).map(|header| {
+            let start = (info.dlpi_addr as usize).wrapping_add(header.p_vaddr as usize);
+            (start..start + header.p_memsz as usize, header.p_flags & libc::PF_X != 0)
+        });
+        if !segments.clone().any(|(segment, _)| segment.contains(&search.address)) {
+            return 0;
+        }
+        search.code = Some(segments.filter(|&(_, executable)| executable)
+            .map(|(segment, _)| segment).collect());
+        1
+    }
+
+    let mut search = Search { address: module as usize, code: None };
+    libc::dl_iterate_phdr(Some(visit), &mut search as *mut Search as *mut c_void);
+    search.code.ok_or(ScanError::ModuleNotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Search = fn(&Pattern, Anchor, &[u8], &mut usize, usize) -> Option<usize>;
+
+    /// Lengths of buffers around the edges of 16 and 32 byte chunks.
+    const LENS: &[usize] = &[1, 2, 15, 16, 17, 31, 32, 33, 47, 48, 49, 63, 64, 65, 100];
+
+    /// Patterns anchored on their first and last byte, on a single byte,
+    /// on bytes with nibble wildcards around them, and not at all.
+    const PATTERNS: &[&str] = &["AA", "AA BB", "AA ?? BB", "AA ? ? ? BB", "?? AA ?? BB ??",
+        "A? BB", "?? AA ??", "AA A? ?A BB", "??", "?? ?A"];
+
+    /// Find the matches one position at a time.
+    fn scalar(pattern: &Pattern, data: &[u8]) -> Vec<usize> {
+        (0..data.len()).filter(|&position| pattern.matches(&data[position..])).collect()
+    }
+
+    /// Vectorized searches supported by the processor, with their numbers of lanes.
+    fn vectorized() -> Vec<(&'static str, Search, usize)> {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        let searches: [(_, Search, _, _); 2] = [
+            ("sse2", |pattern, anchor, data, position, last| unsafe {
+                simd::x86::find_sse2(pattern, anchor, data, position, last)
+            }, 16, std::arch::is_x86_feature_detected!("sse2")),
+            ("avx2", |pattern, anchor, data, position, last| unsafe {
+                simd::x86::find_avx2(pattern, anchor, data, position, last)
+            }, 32, std::arch::is_x86_feature_detected!("avx2")),
+        ];
+        #[cfg(target_arch = "aarch64")]
+        let searches: [(_, Search, _, _); 1] = [("neon", simd::find, 16, true)];
+        #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+        let searches: [(_, Search, _, _); 0] = [];
+        searches.into_iter().filter(|&(_, _, _, supported)| supported)
+            .map(|(name, search, lanes, _)| (name, search, lanes)).collect()
+    }
+
+    /// Check every way of searching a buffer against the scalar search.
+    fn check(pattern: &Pattern, data: &[u8]) {
+        let expected = scalar(pattern, data);
+        let context = format!("{} in {:02X?}", pattern, data);
+        assert_eq!(pattern.find(data), expected.first().copied(), "{}", context);
+        assert_eq!(pattern.find_iter(data).collect::<Vec<_>>(), expected, "{}", context);
+        let unique = match expected[..] {
+            [] => Err(ScanError::NotFound),
+            [found] => Ok(found),
+            _ => Err(ScanError::Ambiguous { count: expected.len() }),
+        };
+        assert_eq!(pattern.find_unique(data), unique, "{}", context);
+
+        // Vectorized searches only check whole chunks, from any start.
+        let (Some(anchor), Some(last)) = (pattern.anchor, data.len().checked_sub(pattern.len()))
+        else {
+            return;
+        };
+        for (name, search, lanes) in vectorized() {
+            for start in 0..=last {
+                let mut position = start;
+                let found = search(pattern, anchor, data, &mut position, last);
+                let next = expected.iter().copied().find(|&found| found >= start);
+                let context = format!("{} from {} with {}", context, start, name);
+                assert_eq!((position - start) % lanes, 0, "{}", context);
+                assert!(position + lanes > last + 1 || found.is_some(), "{}", context);
+                match found {
+                    Some(_) => assert_eq!(found, next, "{}", context),
+                    None => assert!(next.is_none_or(|next| next >= position), "{}", context),
+                }
+            }
+        }
+    }
+
+    /// Generate bytes from a few values, which match patterns now and then.
+    fn noise(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            [0x00, 0xAA, 0xBB, 0xA0, 0x0A][state as usize % 5]
+        }).collect()
+    }
+
+    #[test]
+    fn searching_chunk_edges() {
+        for pattern in PATTERNS {
+            let pattern = Pattern::parse(pattern).unwrap();
+            let matching: Vec<u8> = pattern.bytes.clone();
+            for &len in LENS {
+                // Nothing to find, and bytes matching the anchor everywhere.
+                check(&pattern, &vec![0; len]);
+                check(&pattern, &vec![0xAA; len]);
+                let Some(last) = len.checked_sub(pattern.len()) else {
+                    continue;
+                };
+                // A match at each position, first and last included.
+                for position in 0..=last {
+                    let mut data = vec![0; len];
+                    data[position..][..pattern.len()].copy_from_slice(&matching);
+                    check(&pattern, &data);
+                }
+                let mut data = vec![0; len];
+                data[..pattern.len()].copy_from_slice(&matching);
+                data[last..].copy_from_slice(&matching);
+                check(&pattern, &data);
+            }
+        }
+    }
+
+    #[test]
+    fn searching_noise() {
+        for (seed, pattern) in PATTERNS.iter().enumerate() {
+            let pattern = Pattern::parse(pattern).unwrap();
+            for &len in LENS {
+                check(&pattern, &noise(len, seed as u32 + 1));
+            }
+            check(&pattern, &noise(1000, seed as u32 + 1));
+        }
+    }
+
+    #[test]
+    fn parsing() {
+        assert_eq!(Pattern::parse(""), Err(PatternError::Empty));
+        assert_eq!(" \t\n".parse::<Pattern>(), Err(PatternError::Empty));
+        let invalid = [("48 8G", 1, "8G"), ("488B", 0, "488B"), ("48 ???", 1, "???"),
+            ("4", 0, "4"), ("48 8B, C3", 1, "8B,"), ("E8 ?? -1", 2, "-1")];
+        for (pattern, index, token) in invalid {
+            let error = PatternError::InvalidByte { index, token: token.to_owned() };
+            assert_eq!(Pattern::parse(pattern), Err(error), "{}", pattern);
+        }
+
+        let pattern = Pattern::parse("48 8b ?? ? 4? ?C").unwrap();
+        assert_eq!((pattern.len(), pattern.anchor), (6, Some(Anchor { first: 0, second: 1 })));
+        assert_eq!(pattern.to_string(), "48 8B ?? ?? 4? ?C");
+        assert_eq!(pattern.to_string().parse(), Ok(pattern.clone()));
+        assert!(pattern.matches(&[0x48, 0x8B, 0x00, 0xFF, 0x40, 0x0C, 0x90]));
+        assert!(pattern.matches(&[0x48, 0x8B, 0x12, 0x34, 0x4F, 0xFC]));
+        assert!(!pattern.matches(&[0x48, 0x8B, 0x12, 0x34, 0x5F, 0xFC]));
+        assert!(!pattern.matches(&[0x48, 0x8B, 0x12, 0x34, 0x4F, 0xFD]));
+        assert!(!pattern.matches(&[0x48, 0x8B, 0x12, 0x34, 0x4F]));
+
+        let wildcards = Pattern::parse("?? ?").unwrap();
+        assert_eq!((wildcards.to_string().as_str(), wildcards.anchor), ("?? ??", None));
+        let single = Pattern::parse("?? C3 ?0").unwrap();
+        assert_eq!(single.anchor, Some(Anchor { first: 1, second: 1 }));
+    }
+
+    #[test]
+    fn following_displacements() {
+        // call -0x20; mov rax, [rip + 0x10]
+        let code: [u8; 12] = [0xE8, 0xE0, 0xFF, 0xFF, 0xFF,
+            0x48, 0x8B, 0x05, 0x10, 0x00, 0x00, 0x00];
+        let start = Match::new(code.as_ptr().cast());
+        let address = code.as_ptr() as usize;
+        unsafe {
+            assert_eq!(start.rel32(1, 5).address() as usize, address + 5 - 0x20);
+            assert_eq!(start.offset(5).rel32(3, 7).address() as usize, address + 12 + 0x10);
+            assert_eq!(start.offset(5).offset(-5), start);
+        }
+
+        let pointer = [address];
+        let stored = unsafe { Match::new(pointer.as_ptr().cast()).read_pointer() };
+        assert_eq!(stored, start);
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn following_instructions() {
+        // call -0x20; jmp +0x10; jne -2; nop; mov eax, [rip + 0x10], absolute on x86
+        let code: [u8; 16] = [0xE8, 0xE0, 0xFF, 0xFF, 0xFF, 0xEB, 0x10, 0x75, 0xFE, 0x90,
+            0x8B, 0x05, 0x10, 0x00, 0x00, 0x00];
+        let start = Match::new(code.as_ptr().cast());
+        let address = code.as_ptr() as usize;
+        let target = |found: Option<Match>| found.map(|found| found.address() as usize);
+        unsafe {
+            assert_eq!(target(start.branch_target()), Some(address + 5 - 0x20));
+            assert_eq!(target(start.offset(5).branch_target()), Some(address + 7 + 0x10));
+            assert_eq!(target(start.offset(7).branch_target()), Some(address + 7));
+            assert_eq!(target(start.offset(9).branch_target()), None);
+            assert_eq!(target(start.offset(10).branch_target()), None);
+            assert_eq!(target(start.rip_target()), None);
+            let rip_target = target(start.offset(10).rip_target());
+            if cfg!(target_arch = "x86_64") {
+                assert_eq!(rip_target, Some(address + 16 + 0x10));
+            } else {
+                assert_eq!(rip_target, None);
+            }
+        }
+    }
+}
@@ -0,0 +1,730 @@
+//! Trampoline construction on plain byte buffers.
+//!
+//! Mirrors what the library does when creating a hook: the instructions
+//! overwritten by the patch are copied elsewhere, followed by a jump back
+//! to the rest of the target function. Copied instructions which address
+//! memory or branch relative to themselves are rewritten to keep their
+//! meaning at the new location. Nothing here touches process memory, so
+//! both the target and the trampoline can be at any address.
+
+use crate::decoder::{self, Bitness, BranchKind, DecodeError, Instruction, OpcodeMap};
+
+pub mod aarch64;
+
+/// Size of the `jmp rel32` patch written over a target function,
+/// the usual number of bytes a trampoline has to cover.
+pub const JMP_REL_LEN: usize = 5;
+
+/// Failure to build a trampoline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrampolineError {
+    /// An instruction of the target function couldn't be decoded.
+    Decode {
+        /// Offset of the instruction in the target function.
+        offset: usize,
+        /// Underlying error.
+        error: DecodeError,
+    },
+    /// A relative operand can't reach its destination from the trampoline,
+    /// and the instruction has no equivalent with an absolute one.
+    OutOfRange {
+        /// Offset of the instruction in the target function.
+        offset: usize,
+    },
+    /// A branch can't be relocated, as it has a 16-bit operand
+    /// or leads into the middle of a copied instruction.
+    UnsupportedBranch {
+        /// Offset of the instruction in the target function.
+        offset: usize,
+    },
+    /// The function ends before the patch, and isn't followed by padding.
+    TooShort {
+        /// Length of the function in bytes.
+        len: usize,
+    },
+}
+
+impl std::fmt::Display for TrampolineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Decode { offset, error } => write!(f, "at offset {}: {}", offset, error),
+            Self::OutOfRange { offset } => {
+                write!(f, "relative operand at offset {} is out of range", offset)
+            }
+            Self::UnsupportedBranch { offset } => {
+                write!(f, "branch at offset {} can't be relocated", offset)
+            }
+            Self::TooShort { len } => write!(f, "function is too short ({} bytes)", len),
+        }
+    }
+}
+
+impl std::error::Error for TrampolineError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Decode { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Start of a copied instruction in the target function and in the trampoline.
+///
+/// Threads suspended in the middle of either one are moved to the
+/// corresponding instruction of the other when hooks change state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Boundary {
+    /// Offset of the instruction in the target function.
+    pub original: usize,
+    /// Offset of the instruction in the trampoline.
+    pub trampoline: usize,
+}
+
+/// A built trampoline.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Trampoline {
+    code: Vec<u8>,
+    boundaries: Vec<Boundary>,
+    original_len: usize,
+    jumps_back: bool,
+}
+
+impl Trampoline {
+    /// Get the code of the trampoline.
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    /// Take the code of the trampoline.
+    pub fn into_code(self) -> Vec<u8> {
+        self.code
+    }
+
+    /// Get the starts of all copied instructions.
+    pub fn boundaries(&self) -> &[Boundary] {
+        &self.boundaries
+    }
+
+    /// Get the number of bytes copied from the target function.
+    pub fn original_len(&self) -> usize {
+        self.original_len
+    }
+
+    /// Check whether the trampoline ends by jumping back into the target
+    /// function, rather than the whole function having been copied.
+    pub fn jumps_back(&self) -> bool {
+        self.jumps_back
+    }
+
+    /// Map an instruction offset in the target function to the trampoline.
+    pub fn to_trampoline(&self, original: usize) -> Option<usize> {
+        self.boundaries.iter()
+            .find(|boundary| boundary.original == original)
+            .map(|boundary| boundary.trampoline)
+    }
+
+    /// Map an instruction offset in the trampoline to the target function.
+    pub fn to_original(&self, trampoline: usize) -> Option<usize> {
+        self.boundaries.iter()
+            .find(|boundary| boundary.trampoline == trampoline)
+            .map(|boundary| boundary.original)
+    }
+}
+
+/// Build a trampoline for the start of a function.
+///
+/// # Arguments
+///
+/// * `code` - bytes of the target function, at least up to the end of
+///     the instruction which crosses `patch_len`.
+/// * `address` - address of the target function.
+/// * `trampoline` - address the trampoline code will be placed at.
+/// * `bitness` - mode of the target function's code.
+/// * `patch_len` - number of bytes which will be overwritten,
+///     normally [`JMP_REL_LEN`].
+pub fn build(code: &[u8], address: usize, trampoline: usize, bitness: Bitness,
+    patch_len: usize) -> Result<Trampoline, TrampolineError>
+{
+    let mut builder = Builder {
+        code: Vec::new(),
+        boundaries: Vec::new(),
+        fixups: Vec::new(),
+        address,
+        trampoline,
+        bitness,
+        patch_len,
+    };
+
+    let mut offset = 0;
+    // Furthest destination of branches within the patched bytes,
+    // up to which the function continues past any returns.
+    let mut internal_end = 0;
+    let jumps_back = loop {
+        if offset >= patch_len {
+            break true;
+        }
+        let instruction = decoder::decode(&code[offset..], bitness)
+            .map_err(|error| TrampolineError::Decode { offset, error })?;
+        let bytes = &code[offset..offset + instruction.len()];
+        if let Some(destination) = builder.internal_destination(offset, &instruction) {
+            internal_end = internal_end.max(destination);
+        }
+        let ends = builder.copy(offset, &instruction, bytes)?;
+        offset += instruction.len();
+
+        if ends && offset > internal_end {
+            let padded = code.get(offset..patch_len)
+                .is_some_and(|tail| tail.iter().all(|&byte| is_padding(byte)));
+            if offset < patch_len && !padded {
+                return Err(TrampolineError::TooShort { len: offset });
+            }
+            break false;
+        }
+    };
+
+    if jumps_back {
+        builder.jump(address.wrapping_add(offset));
+    }
+    builder.apply_fixups()?;
+
+    Ok(Trampoline {
+        code: builder.code,
+        boundaries: builder.boundaries,
+        original_len: offset,
+        jumps_back,
+    })
+}
+
+/// Check whether a byte is used by compilers to pad between functions.
+pub(crate) fn is_padding(byte: u8) -> bool {
+    matches!(byte, 0x00 | 0x90 | 0xCC)
+}
+
+/// A branch within the copied instructions, patched once they are all laid out.
+#[derive(Debug)]
+struct Fixup {
+    /// Offset of the instruction in the target function.
+    original: usize,
+    /// Offset of the relative operand in the trampoline.
+    field: usize,
+    size: usize,
+    /// Offset of the end of the instruction in the trampoline.
+    end: usize,
+    /// Offset of the destination in the target function.
+    destination: usize,
+}
+
+struct Builder {
+    code: Vec<u8>,
+    boundaries: Vec<Boundary>,
+    fixups: Vec<Fixup>,
+    address: usize,
+    trampoline: usize,
+    bitness: Bitness,
+    patch_len: usize,
+}
+
+impl Builder {
+    /// Copy a single instruction, rewriting it as needed.
+    /// Returns whether control flow never continues past it.
+    fn copy(&mut self, offset: usize, instruction: &Instruction, bytes: &[u8])
+        -> Result<bool, TrampolineError>
+    {
+        let start = self.code.len();
+        self.boundaries.push(Boundary { original: offset, trampoline: start });
+        let source = self.address.wrapping_add(offset);
+
+        if let Some(target) = instruction.rip_target(source) {
+            let displacement = instruction.displacement().unwrap();
+            let end = self.here().wrapping_add(bytes.len());
+            let value = self.relative(end, target).ok_or(TrampolineError::OutOfRange { offset })?;
+            self.code.extend_from_slice(bytes);
+            self.write(start + displacement.offset(), 4, value);
+            return Ok(is_indirect_jump(instruction));
+        }
+
+        let Some(kind) = instruction.relative_branch() else {
+            self.code.extend_from_slice(bytes);
+            return Ok(ends_function(instruction));
+        };
+        let operand = instruction.immediate().unwrap();
+        if operand.size() == 2 {
+            return Err(TrampolineError::UnsupportedBranch { offset });
+        }
+        let target = instruction.branch_target(source).unwrap();
+
+        if let Some(destination) = self.internal_destination(offset, instruction) {
+            self.code.extend_from_slice(bytes);
+            self.fixups.push(Fixup {
+                original: offset,
+                field: start + operand.offset(),
+                size: operand.size(),
+                end: self.code.len(),
+                destination,
+            });
+            return Ok(kind == BranchKind::Jump);
+        }
+
+        match kind {
+            BranchKind::Jump => {
+                self.jump(target);
+                return Ok(true);
+            }
+            BranchKind::Call => self.call(target),
+            // xbegin can only be moved as it is.
+            BranchKind::Conditional if instruction.opcode_map() == OpcodeMap::Primary
+                && instruction.opcode() == 0xC7 =>
+            {
+                let end = self.here().wrapping_add(bytes.len());
+                let value = self.relative(end, target)
+                    .ok_or(TrampolineError::OutOfRange { offset })?;
+                self.code.extend_from_slice(bytes);
+                self.write(start + operand.offset(), 4, value);
+            }
+            BranchKind::Conditional => self.conditional_jump(instruction.opcode() & 0x0F, target),
+            BranchKind::Loop => {
+                // Keep the instruction, with address size prefixes selecting the counter,
+                // branching over a jump which is taken when it falls through.
+                let mut jump = self.detached(bytes.len() + 2);
+                jump.jump(target);
+                self.code.extend_from_slice(&bytes[..operand.offset()]);
+                self.code.push(2);
+                self.code.extend_from_slice(&[0xEB, jump.code.len() as u8]);
+                self.code.extend_from_slice(&jump.code);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Get the offset of an instruction's branch destination if
+    /// it is within the patched bytes, which are copied as well.
+    fn internal_destination(&self, offset: usize, instruction: &Instruction) -> Option<usize> {
+        let source = self.address.wrapping_add(offset);
+        let destination = instruction.branch_target(source)?.wrapping_sub(self.address);
+        (destination < self.patch_len).then_some(destination)
+    }
+
+    /// Point branches within the copied instructions to their new locations.
+    fn apply_fixups(&mut self) -> Result<(), TrampolineError> {
+        for fixup in std::mem::take(&mut self.fixups) {
+            let unsupported = TrampolineError::UnsupportedBranch { offset: fixup.original };
+            let destination = self.boundaries.iter()
+                .find(|boundary| boundary.original == fixup.destination)
+                .ok_or(unsupported)?
+                .trampoline;
+            let value = destination as i64 - fixup.end as i64;
+            let fits = match fixup.size {
+                1 => i8::try_from(value).is_ok(),
+                _ => i32::try_from(value).is_ok(),
+            };
+            if !fits {
+                return Err(TrampolineError::OutOfRange { offset: fixup.original });
+            }
+            self.write(fixup.field, fixup.size, value);
+        }
+        Ok(())
+    }
+
+    /// Emit an unconditional jump.
+    fn jump(&mut self, target: usize) {
+        match self.relative(self.here().wrapping_add(5), target) {
+            Some(value) => {
+                self.code.push(0xE9);
+                self.code.extend_from_slice(&(value as i32).to_le_bytes());
+            }
+            None => self.absolute(&[0xFF, 0x25, 0, 0, 0, 0], target),
+        }
+    }
+
+    /// Emit a call, which returns to the following instruction.
+    fn call(&mut self, target: usize) {
+        match self.relative(self.here().wrapping_add(5), target) {
+            Some(value) => {
+                self.code.push(0xE8);
+                self.code.extend_from_slice(&(value as i32).to_le_bytes());
+            }
+            // call [rip+2]; jmp +8; dq target
+            None => self.absolute(&[0xFF, 0x15, 0x02, 0, 0, 0, 0xEB, 0x08], target),
+        }
+    }
+
+    /// Emit a `jcc` with a `condition` from the low nibble of its opcode.
+    fn conditional_jump(&mut self, condition: u8, target: usize) {
+        match self.relative(self.here().wrapping_add(6), target) {
+            Some(value) => {
+                self.code.extend_from_slice(&[0x0F, 0x80 | condition]);
+                self.code.extend_from_slice(&(value as i32).to_le_bytes());
+            }
+            // Skip an absolute jump on the inverted condition.
+            None => {
+                self.code.extend_from_slice(&[0x70 | (condition ^ 1), 14]);
+                self.absolute(&[0xFF, 0x25, 0, 0, 0, 0], target);
+            }
+        }
+    }
+
+    /// Emit an instruction with an indirect 64-bit operand right after it.
+    fn absolute(&mut self, instruction: &[u8], target: usize) {
+        self.code.extend_from_slice(instruction);
+        self.code.extend_from_slice(&(target as u64).to_le_bytes());
+    }
+
+    /// Get a 32-bit offset from the end of an instruction to `target`,
+    /// if it can be reached. In 32-bit mode, everything can be.
+    fn relative(&self, end: usize, target: usize) -> Option<i64> {
+        let value = target.wrapping_sub(end);
+        match self.bitness {
+            Bitness::Bits32 => Some(value as u32 as i32 as i64),
+            Bitness::Bits64 => i32::try_from(value as isize).ok().map(i64::from),
+        }
+    }
+
+    /// Get the address the next emitted byte will be placed at.
+    fn here(&self) -> usize {
+        self.trampoline.wrapping_add(self.code.len())
+    }
+
+    /// Overwrite a little-endian field of emitted code.
+    fn write(&mut self, offset: usize, size: usize, value: i64) {
+        self.code[offset..offset + size].copy_from_slice(&value.to_le_bytes()[..size]);
+    }
+
+    /// Create a builder for code to be placed `ahead` bytes after the current end.
+    fn detached(&self, ahead: usize) -> Builder {
+        Builder {
+            code: Vec::new(),
+            boundaries: Vec::new(),
+            fixups: Vec::new(),
+            trampoline: self.here().wrapping_add(ahead),
+            ..*self
+        }
+    }
+}
+
+/// Check whether an instruction is an indirect `jmp`.
+fn is_indirect_jump(instruction: &Instruction) -> bool {
+    instruction.opcode_map() == OpcodeMap::Primary && instruction.opcode() == 0xFF
+        && matches!(instruction.modrm_reg(), Some(4 | 5))
+}
+
+/// Check whether control flow never continues past an instruction
+/// without a relative operand, like returns and indirect jumps.
+pub(crate) fn ends_function(instruction: &Instruction) -> bool {
+    if instruction.opcode_map() != OpcodeMap::Primary {
+        return false;
+    }
+    matches!(instruction.opcode(), 0xC2 | 0xC3 | 0xCA | 0xCB) || is_indirect_jump(instruction)
+}
+
+// Addresses of the tests are above 4 GiB.
+#[cfg(all(test, target_pointer_width = "64"))]
+mod tests {
+    use super::*;
+
+    const TARGET: usize = 0x1_4000_1000;
+    /// Trampoline within reach of 32-bit offsets from the target.
+    const NEAR: usize = TARGET + 0x1000;
+    /// Trampoline out of reach of 32-bit offsets from the target.
+    const FAR: usize = TARGET + 0x1_0000_0000;
+
+    /// Expected trampoline code, boundaries, original length, and whether it jumps back.
+    type Built = (Vec<u8>, &'static [(usize, usize)], usize, bool);
+
+    struct Case {
+        name: &'static str,
+        code: &'static [u8],
+        trampoline: usize,
+        patch_len: usize,
+        expected: Result<Built, TrampolineError>,
+    }
+
+    fn concat(parts: &[&[u8]]) -> Vec<u8> {
+        parts.concat()
+    }
+
+    fn addr(address: usize) -> [u8; 8] {
+        (address as u64).to_le_bytes()
+    }
+
+    const JMP_ABS: &[u8] = &[0xFF, 0x25, 0, 0, 0, 0];
+
+    fn cases() -> Vec<Case> {
+        vec![
+            Case {
+                name: "plain prologue",
+                code: &[0x55, 0x48, 0x89, 0xE5, 0x48, 0x83, 0xEC, 0x20, 0xCC],
+                trampoline: NEAR,
+                patch_len: JMP_REL_LEN,
+                expected: Ok((
+                    concat(&[
+                        &[0x55, 0x48, 0x89, 0xE5, 0x48, 0x83, 0xEC, 0x20],
+                        &[0xE9, 0xFB, 0xEF, 0xFF, 0xFF],
+                    ]),
+                    &[(0, 0), (1, 1), (4, 4)], 8, true,
+                )),
+            },
+            Case {
+                name: "plain prologue, jumping back from afar",
+                code: &[0x55, 0x48, 0x89, 0xE5, 0x48, 0x83, 0xEC, 0x20, 0xCC],
+                trampoline: FAR,
+                patch_len: JMP_REL_LEN,
+                expected: Ok((
+                    concat(&[
+                        &[0x55, 0x48, 0x89, 0xE5, 0x48, 0x83, 0xEC, 0x20],
+                        JMP_ABS, &addr(TARGET + 8),
+                    ]),
+                    &[(0, 0), (1, 1), (4, 4)], 8, true,
+                )),
+            },
+            Case {
+                name: "RIP-relative operand",
+                code: &[0x48, 0x8B, 0x05, 0x10, 0x00, 0x00, 0x00],
+                trampoline: NEAR,
+                patch_len: JMP_REL_LEN,
+                expected: Ok((
+                    concat(&[
+                        &[0x48, 0x8B, 0x05, 0x10, 0xF0, 0xFF, 0xFF],
+                        &[0xE9, 0xFB, 0xEF, 0xFF, 0xFF],
+                    ]),
+                    &[(0, 0)], 7, true,
+                )),
+            },
+            Case {
+                name: "RIP-relative operand out of reach",
+                code: &[0x48, 0x8B, 0x05, 0x10, 0x00, 0x00, 0x00],
+                trampoline: FAR,
+                patch_len: JMP_REL_LEN,
+                expected: Err(TrampolineError::OutOfRange { offset: 0 }),
+            },
+            Case {
+                name: "jmp rel32",
+                code: &[0xE9, 0x00, 0x01, 0x00, 0x00],
+                trampoline: NEAR,
+                patch_len: JMP_REL_LEN,
+                expected: Ok((vec![0xE9, 0x00, 0xF1, 0xFF, 0xFF], &[(0, 0)], 5, false)),
+            },
+            Case {
+                name: "jmp rel32 to absolute",
+                code: &[0xE9, 0x00, 0x01, 0x00, 0x00],
+                trampoline: FAR,
+                patch_len: JMP_REL_LEN,
+                expected: Ok((concat(&[JMP_ABS, &addr(TARGET + 0x105)]), &[(0, 0)], 5, false)),
+            },
+            Case {
+                name: "jmp rel8 followed by padding",
+                code: &[0xEB, 0x10, 0xCC, 0xCC, 0xCC],
+                trampoline: NEAR,
+                patch_len: JMP_REL_LEN,
+                expected: Ok((vec![0xE9, 0x0D, 0xF0, 0xFF, 0xFF], &[(0, 0)], 2, false)),
+            },
+            Case {
+                name: "jmp rel8 to absolute",
+                code: &[0xEB, 0x10, 0x90, 0x90, 0x90],
+                trampoline: FAR,
+                patch_len: JMP_REL_LEN,
+                expected: Ok((concat(&[JMP_ABS, &addr(TARGET + 0x12)]), &[(0, 0)], 2, false)),
+            },
+            Case {
+                name: "call rel32",
+                code: &[0xE8, 0x00, 0x01, 0x00, 0x00],
+                trampoline: NEAR,
+                patch_len: JMP_REL_LEN,
+                expected: Ok((
+                    vec![0xE8, 0x00, 0xF1, 0xFF, 0xFF, 0xE9, 0xFB, 0xEF, 0xFF, 0xFF],
+                    &[(0, 0)], 5, true,
+                )),
+            },
+            Case {
+                name: "call rel32 to absolute",
+                code: &[0xE8, 0x00, 0x01, 0x00, 0x00],
+                trampoline: FAR,
+                patch_len: JMP_REL_LEN,
+                expected: Ok((
+                    concat(&[
+                        &[0xFF, 0x15, 0x02, 0x00, 0x00, 0x00, 0xEB, 0x08], &addr(TARGET + 0x105),
+                        JMP_ABS, &addr(TARGET + 5),
+                    ]),
+                    &[(0, 0)], 5, true,
+                )),
+            },
+            Case {
+                name: "jcc rel8",
+                code: &[0x74, 0x10, 0x48, 0x89, 0xE5],
+                trampoline: NEAR,
+                patch_len: JMP_REL_LEN,
+                expected: Ok((
+                    concat(&[
+                        &[0x0F, 0x84, 0x0C, 0xF0, 0xFF, 0xFF],
+                        &[0x48, 0x89, 0xE5],
+                        &[0xE9, 0xF7, 0xEF, 0xFF, 0xFF],
+                    ]),
+                    &[(0, 0), (2, 6)], 5, true,
+                )),
+            },
+            Case {
+                name: "jcc rel8 inverted over an absolute jump",
+                code: &[0x74, 0x10, 0x48, 0x89, 0xE5],
+                trampoline: FAR,
+                patch_len: JMP_REL_LEN,
+                expected: Ok((
+                    concat(&[
+                        &[0x75, 0x0E], JMP_ABS, &addr(TARGET + 0x12),
+                        &[0x48, 0x89, 0xE5],
+                        JMP_ABS, &addr(TARGET + 5),
+                    ]),
+                    &[(0, 0), (2, 16)], 5, true,
+                )),
+            },
+            Case {
+                name: "jrcxz",
+                code: &[0xE3, 0x10, 0x48, 0x89, 0xE5],
+                trampoline: NEAR,
+                patch_len: JMP_REL_LEN,
+                expected: Ok((
+                    concat(&[
+                        &[0xE3, 0x02, 0xEB, 0x05, 0xE9, 0x09, 0xF0, 0xFF, 0xFF],
+                        &[0x48, 0x89, 0xE5],
+                        &[0xE9, 0xF4, 0xEF, 0xFF, 0xFF],
+                    ]),
+                    &[(0, 0), (2, 9)], 5, true,
+                )),
+            },
+            Case {
+                name: "loop to absolute",
+                code: &[0xE2, 0x10, 0x48, 0x89, 0xE5],
+                trampoline: FAR,
+                patch_len: JMP_REL_LEN,
+                expected: Ok((
+                    concat(&[
+                        &[0xE2, 0x02, 0xEB, 0x0E], JMP_ABS, &addr(TARGET + 0x12),
+                        &[0x48, 0x89, 0xE5],
+                        JMP_ABS, &addr(TARGET + 5),
+                    ]),
+                    &[(0, 0), (2, 18)], 5, true,
+                )),
+            },
+            Case {
+                name: "internal branch over a growing instruction",
+                code: &[0x74, 0x05, 0xE8, 0x00, 0x01, 0x00, 0x00, 0x31, 0xC0],
+                trampoline: FAR,
+                patch_len: 8,
+                expected: Ok((
+                    concat(&[
+                        &[0x74, 0x10],
+                        &[0xFF, 0x15, 0x02, 0x00, 0x00, 0x00, 0xEB, 0x08], &addr(TARGET + 0x107),
+                        &[0x31, 0xC0],
+                        JMP_ABS, &addr(TARGET + 9),
+                    ]),
+                    &[(0, 0), (2, 2), (7, 18)], 9, true,
+                )),
+            },
+            Case {
+                name: "internal branch past a return",
+                code: &[0x74, 0x01, 0xC3, 0x31, 0xC0, 0xC3],
+                trampoline: NEAR,
+                patch_len: JMP_REL_LEN,
+                expected: Ok((
+                    vec![0x74, 0x01, 0xC3, 0x31, 0xC0, 0xE9, 0xFB, 0xEF, 0xFF, 0xFF],
+                    &[(0, 0), (2, 2), (3, 3)], 5, true,
+                )),
+            },
+            Case {
+                name: "internal branch into an instruction",
+                code: &[0x74, 0x01, 0x48, 0x89, 0xE5],
+                trampoline: NEAR,
+                patch_len: JMP_REL_LEN,
+                expected: Err(TrampolineError::UnsupportedBranch { offset: 0 }),
+            },
+            Case {
+                name: "xbegin with a 16-bit offset",
+                code: &[0x66, 0xC7, 0xF8, 0x10, 0x00],
+                trampoline: NEAR,
+                patch_len: JMP_REL_LEN,
+                expected: Err(TrampolineError::UnsupportedBranch { offset: 0 }),
+            },
+            Case {
+                name: "return followed by padding",
+                code: &[0xC3, 0xCC, 0x90, 0x00, 0xCC],
+                trampoline: NEAR,
+                patch_len: JMP_REL_LEN,
+                expected: Ok((vec![0xC3], &[(0, 0)], 1, false)),
+            },
+            Case {
+                name: "return followed by another function",
+                code: &[0xC3, 0x55, 0x48, 0x89, 0xE5],
+                trampoline: NEAR,
+                patch_len: JMP_REL_LEN,
+                expected: Err(TrampolineError::TooShort { len: 1 }),
+            },
+            Case {
+                name: "jmp rel8 followed by another function",
+                code: &[0xEB, 0x10, 0x55, 0x48, 0x89, 0xE5],
+                trampoline: NEAR,
+                patch_len: JMP_REL_LEN,
+                expected: Err(TrampolineError::TooShort { len: 2 }),
+            },
+            Case {
+                name: "invalid instruction",
+                code: &[0x55, 0x06],
+                trampoline: NEAR,
+                patch_len: JMP_REL_LEN,
+                expected: Err(TrampolineError::Decode { offset: 1, error: DecodeError::Invalid }),
+            },
+            Case {
+                name: "truncated instruction",
+                code: &[0x55, 0x48, 0x8B],
+                trampoline: NEAR,
+                patch_len: JMP_REL_LEN,
+                expected: Err(TrampolineError::Decode { offset: 1, error: DecodeError::Truncated }),
+            },
+        ]
+    }
+
+    #[test]
+    fn build_64() {
+        for case in cases() {
+            let built = build(case.code, TARGET, case.trampoline, Bitness::Bits64, case.patch_len)
+                .map(|trampoline| {
+                    let boundaries: Vec<_> = trampoline.boundaries().iter()
+                        .map(|boundary| (boundary.original, boundary.trampoline))
+                        .collect();
+                    let code = trampoline.code().to_vec();
+                    (code, boundaries, trampoline.original_len(), trampoline.jumps_back())
+                });
+            let expected = case.expected.map(|(code, boundaries, original_len, jumps_back)| {
+                (code, boundaries.to_vec(), original_len, jumps_back)
+            });
+            assert_eq!(built, expected, "{}", case.name);
+        }
+    }
+
+    #[test]
+    fn build_32() {
+        const ADDRESS: usize = 0x40_1000;
+        const TRAMPOLINE: usize = 0x1000_0000;
+
+        // Relative operands reach everything, wrapping around the address space.
+        let code = [0xE9, 0x00, 0x01, 0x00, 0x00];
+        let trampoline = build(&code, ADDRESS, TRAMPOLINE, Bitness::Bits32, JMP_REL_LEN).unwrap();
+        assert_eq!(trampoline.code(), [0xE9, 0x00, 0x11, 0x40, 0xF0]);
+
+        // Absolute memory operands are copied as they are.
+        let code = [0x8B, 0x05, 0x00, 0x20, 0x40, 0x00];
+        let trampoline = build(&code, ADDRESS, TRAMPOLINE, Bitness::Bits32, JMP_REL_LEN).unwrap();
+        assert_eq!(&trampoline.code()[..6], code);
+
+        let code = [0x66, 0xE9, 0x34, 0x12];
+        assert_eq!(build(&code, ADDRESS, TRAMPOLINE, Bitness::Bits32, JMP_REL_LEN),
+            Err(TrampolineError::UnsupportedBranch { offset: 0 }));
+    }
+
+    #[test]
+    fn boundary_mapping() {
+        let code = [0x74, 0x10, 0x48, 0x89, 0xE5];
+        let trampoline = build(&code, TARGET, FAR, Bitness::Bits64, JMP_REL_LEN).unwrap();
+        assert_eq!(trampoline.to_trampoline(2), Some(16));
+        assert_eq!(trampoline.to_original(16), Some(2));
+        assert_eq!(trampoline.to_trampoline(1), None);
+        assert_eq!(trampoline.to_original(6), None);
+    }
+}
@@ -0,0 +1,572 @@
+//! Trampoline construction for AArch64 code.
+//!
+//! Instructions are all four bytes long, so the start of a function is
+//! copied word by word. Those relative to the program counter are given
+//! a new offset, or are rewritten when their destination is out of reach
+//! from the trampoline: `adr`, `adrp` and `ldr` (literal) load the address
+//! from a literal placed next to them, and branches go through `x16`, which
+//! the procedure call standard reserves for veneers like these.
+
+use super::{Boundary, Trampoline, TrampolineError};
+use crate::decoder::DecodeError;
+
+/// Size of every instruction.
+pub const INSTRUCTION_LEN: usize = 4;
+/// Size of the `b` patch written over a target function,
+/// which reaches 128 MiB in either direction.
+pub const BRANCH_LEN: usize = 4;
+/// Size of an absolute jump, `ldr x16, #8; br x16` followed by the destination.
+pub const JUMP_ABS_LEN: usize = 16;
+
+const NOP: u32 = 0xD503_201F;
+/// `br x16`.
+const BR_X16: u32 = 0xD61F_0200;
+/// `blr x16`.
+const BLR_X16: u32 = 0xD63F_0200;
+const X16: u32 = 16;
+const X17: u32 = 17;
+
+/// Encode a `b` from one address to another, if it is within reach.
+pub fn branch(from: usize, to: usize) -> Option<[u8; BRANCH_LEN]> {
+    let offset = scaled_offset(from, to, 26)?;
+    Some((0x1400_0000 | offset).to_le_bytes())
+}
+
+/// Encode an absolute jump to a destination, which can be placed anywhere.
+pub fn jump_absolute(destination: usize) -> [u8; JUMP_ABS_LEN] {
+    let mut code = [0; JUMP_ABS_LEN];
+    code[..4].copy_from_slice(&load_literal(X16, 8).to_le_bytes());
+    code[4..8].copy_from_slice(&BR_X16.to_le_bytes());
+    code[8..].copy_from_slice(&(destination as u64).to_le_bytes());
+    code
+}
+
+/// Make the instruction cache see code written to memory.
+///
+/// # Safety
+///
+/// The whole range has to be mapped.
+#[cfg(target_arch = "aarch64")]
+pub unsafe fn flush_instruction_cache(address: usize, len: usize) {
+    use std::arch::asm;
+
+    let ctr: usize;
+    asm!("mrs {}, ctr_el0", out(reg) ctr, options(nomem, nostack, preserves_flags));
+    let data_line = 4 << ((ctr >> 16) & 0xF);
+    let instruction_line = 4 << (ctr & 0xF);
+    let end = address + len;
+
+    let mut line = address & !(data_line - 1);
+    while line < end {
+        asm!("dc cvau, {}", in(reg) line, options(nostack, preserves_flags));
+        line += data_line;
+    }
+    asm!("dsb ish", options(nostack, preserves_flags));
+    let mut line = address & !(instruction_line - 1);
+    while line < end {
+        asm!("ic ivau, {}", in(reg) line, options(nostack, preserves_flags));
+        line += instruction_line;
+    }
+    asm!("dsb ish", "isb", options(nostack, preserves_flags));
+}
+
+/// Build a trampoline for the start of a function.
+///
+/// # Arguments
+///
+/// * `code` - bytes of the target function, at least up to `patch_len`.
+/// * `address` - address of the target function.
+/// * `trampoline` - address the trampoline code will be placed at.
+/// * `patch_len` - number of bytes which will be overwritten,
+///     [`BRANCH_LEN`] or [`JUMP_ABS_LEN`].
+pub fn build(code: &[u8], address: usize, trampoline: usize, patch_len: usize)
+    -> Result<Trampoline, TrampolineError>
+{
+    let mut builder = Builder {
+        code: Vec::new(),
+        boundaries: Vec::new(),
+        fixups: Vec::new(),
+        address,
+        trampoline,
+        patch_len,
+    };
+
+    let mut offset = 0;
+    // Furthest destination of branches within the patched bytes,
+    // up to which the function continues past any returns.
+    let mut internal_end = 0;
+    let jumps_back = loop {
+        if offset >= patch_len {
+            break true;
+        }
+        let word = read(code, offset)
+            .ok_or(TrampolineError::Decode { offset, error: DecodeError::Truncated })?;
+        if let Some(destination) = builder.internal_destination(offset, word) {
+            internal_end = internal_end.max(destination);
+        }
+        let ends = builder.copy(offset, word)?;
+        offset += INSTRUCTION_LEN;
+
+        if ends && offset > internal_end {
+            let padded = (offset..patch_len).step_by(INSTRUCTION_LEN)
+                .all(|offset| read(code, offset).is_some_and(is_padding));
+            if offset < patch_len && !padded {
+                return Err(TrampolineError::TooShort { len: offset });
+            }
+            break false;
+        }
+    };
+
+    if jumps_back {
+        builder.jump(address.wrapping_add(offset));
+    }
+    builder.apply_fixups()?;
+
+    Ok(Trampoline {
+        code: builder.code,
+        boundaries: builder.boundaries,
+        original_len: offset,
+        jumps_back,
+    })
+}
+
+fn read(code: &[u8], offset: usize) -> Option<u32> {
+    let bytes = code.get(offset..offset + INSTRUCTION_LEN)?;
+    Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Check whether a word is used by compilers to pad between functions,
+/// which are `nop`s and zeros, the permanently undefined instruction.
+fn is_padding(word: u32) -> bool {
+    matches!(word, 0 | NOP)
+}
+
+/// Instruction relative to the program counter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Relative {
+    /// `b` or `bl`, with a 26-bit offset.
+    Branch { link: bool },
+    /// `b.cond` or `cbz`/`cbnz` with a 19-bit offset,
+    /// or `tbz`/`tbnz` with a 14-bit offset.
+    Conditional { bits: u32 },
+    /// `adr`, or `adrp` addressing 4 KiB pages.
+    Address { page: bool },
+    /// `ldr` (literal) into a register, `ldrsw` or `prfm`.
+    Literal,
+}
+
+impl Relative {
+    fn classify(word: u32) -> Option<Self> {
+        match word {
+            _ if word & 0x7C00_0000 == 0x1400_0000 => Some(Self::Branch { link: word >> 31 != 0 }),
+            _ if word & 0xFF00_0000 == 0x5400_0000 => Some(Self::Conditional { bits: 19 }),
+            _ if word & 0x7E00_0000 == 0x3400_0000 => Some(Self::Conditional { bits: 19 }),
+            _ if word & 0x7E00_0000 == 0x3600_0000 => Some(Self::Conditional { bits: 14 }),
+            _ if word & 0x1F00_0000 == 0x1000_0000 => Some(Self::Address { page: word >> 31 != 0 }),
+            _ if word & 0x3B00_0000 == 0x1800_0000 => Some(Self::Literal),
+            _ => None,
+        }
+    }
+
+    /// Get the width and position of the offset field.
+    fn field(self) -> (u32, u32) {
+        match self {
+            Self::Branch { .. } => (26, 0),
+            Self::Conditional { bits } => (bits, 5),
+            Self::Address { .. } => (21, 5),
+            Self::Literal => (19, 5),
+        }
+    }
+
+    /// Get the address an instruction at `pc` refers to.
+    fn target(self, word: u32, pc: usize) -> usize {
+        match self {
+            Self::Address { page } => {
+                let immediate = ((word >> 5) & 0x7_FFFF) << 2 | (word >> 29) & 0x3;
+                let value = sign_extend(immediate, 21);
+                match page {
+                    true => (pc & !0xFFF).wrapping_add_signed(value << 12),
+                    false => pc.wrapping_add_signed(value),
+                }
+            }
+            _ => {
+                let (bits, shift) = self.field();
+                let value = sign_extend((word >> shift) & mask(bits), bits);
+                pc.wrapping_add_signed(value * INSTRUCTION_LEN as isize)
+            }
+        }
+    }
+
+    /// Re-encode an instruction placed at `pc` to refer to the same
+    /// `target`, if it is within reach.
+    fn retarget(self, word: u32, pc: usize, target: usize) -> Option<u32> {
+        match self {
+            Self::Address { page } => {
+                let value = match page {
+                    true => ((target & !0xFFF).wrapping_sub(pc & !0xFFF) as isize) >> 12,
+                    false => target.wrapping_sub(pc) as isize,
+                };
+                let value = fit(value, 21)?;
+                let word = word & !(0x3 << 29 | 0x7_FFFF << 5);
+                Some(word | (value & 0x3) << 29 | (value >> 2) << 5)
+            }
+            _ => {
+                let (bits, shift) = self.field();
+                let value = scaled_offset(pc, target, bits)?;
+                Some(word & !(mask(bits) << shift) | value << shift)
+            }
+        }
+    }
+}
+
+fn mask(bits: u32) -> u32 {
+    (1 << bits) - 1
+}
+
+fn sign_extend(value: u32, bits: u32) -> isize {
+    ((value << (32 - bits)) as i32 >> (32 - bits)) as isize
+}
+
+/// Truncate a value to a signed field of some width, if it fits.
+fn fit(value: isize, bits: u32) -> Option<u32> {
+    let limit = 1 << (bits - 1);
+    (-limit..limit).contains(&value).then_some(value as u32 & mask(bits))
+}
+
+/// Get the offset between instructions in words, as a field of some width.
+fn scaled_offset(from: usize, to: usize, bits: u32) -> Option<u32> {
+    let value = to.wrapping_sub(from) as isize;
+    if value % INSTRUCTION_LEN as isize != 0 {
+        return None;
+    }
+    fit(value / INSTRUCTION_LEN as isize, bits)
+}
+
+/// Encode `ldr` (literal) of a 64-bit register, `offset` bytes ahead.
+fn load_literal(register: u32, offset: u32) -> u32 {
+    0x5800_0000 | (offset / 4) << 5 | register
+}
+
+/// Encode the load of a literal-loading instruction as a load from
+/// the address in a `base` register, or `None` for `prfm`.
+fn load_from_register(word: u32, base: u32) -> Option<u32> {
+    let register = word & 0x1F;
+    let opcode = match (word >> 30, word >> 26 & 1) {
+        // ldr wt; ldr xt; ldrsw xt
+        (0, 0) => 0xB940_0000,
+        (1, 0) => 0xF940_0000,
+        (2, 0) => 0xB980_0000,
+        // ldr st; ldr dt; ldr qt
+        (0, 1) => 0xBD40_0000,
+        (1, 1) => 0xFD40_0000,
+        (2, 1) => 0x3DC0_0000,
+        _ => return None,
+    };
+    Some(opcode | base << 5 | register)
+}
+
+/// A branch within the copied instructions, patched once they are all laid out.
+#[derive(Debug)]
+struct Fixup {
+    /// Offset of the instruction in the target function.
+    original: usize,
+    /// Offset of the instruction in the trampoline.
+    offset: usize,
+    relative: Relative,
+    /// Offset of the destination in the target function.
+    destination: usize,
+}
+
+struct Builder {
+    code: Vec<u8>,
+    boundaries: Vec<Boundary>,
+    fixups: Vec<Fixup>,
+    address: usize,
+    trampoline: usize,
+    patch_len: usize,
+}
+
+impl Builder {
+    /// Copy a single instruction, rewriting it as needed.
+    /// Returns whether control flow never continues past it.
+    fn copy(&mut self, offset: usize, word: u32) -> Result<bool, TrampolineError> {
+        let start = self.code.len();
+        self.boundaries.push(Boundary { original: offset, trampoline: start });
+
+        let Some(relative) = Relative::classify(word) else {
+            self.emit(word);
+            return Ok(ends_function(word));
+        };
+        let target = relative.target(word, self.address.wrapping_add(offset));
+
+        if let Some(destination) = self.internal_destination(offset, word) {
+            self.emit(word);
+            self.fixups.push(Fixup { original: offset, offset: start, relative, destination });
+            return Ok(relative == Relative::Branch { link: false });
+        }
+        if let Some(word) = relative.retarget(word, self.here(), target) {
+            self.emit(word);
+            return Ok(relative == Relative::Branch { link: false });
+        }
+
+        // `b.al` and `b.nv` always branch.
+        let always = word & 0xFF00_000E == 0x5400_000E;
+        match relative {
+            Relative::Branch { link: false } | Relative::Conditional { .. } if always => {
+                self.code.extend_from_slice(&jump_absolute(target));
+                return Ok(true);
+            }
+            Relative::Branch { link: false } => {
+                self.code.extend_from_slice(&jump_absolute(target));
+                return Ok(true);
+            }
+            Relative::Branch { link: true } => {
+                self.literal(X16, target);
+                self.emit(BLR_X16);
+            }
+            Relative::Conditional { .. } => {
+                // Skip an absolute jump on the inverted condition, where
+                // `b.cond` has it in the lowest bit, and the others above the offset.
+                let inverted = match word & 0xFF00_0000 == 0x5400_0000 {
+                    true => word ^ 1,
+                    false => word ^ 1 << 24,
+                };
+                let skip = (INSTRUCTION_LEN + JUMP_ABS_LEN) as isize;
+                let (bits, shift) = relative.field();
+                let value = fit(skip / INSTRUCTION_LEN as isize, bits).unwrap();
+                self.emit(inverted & !(mask(bits) << shift) | value << shift);
+                self.code.extend_from_slice(&jump_absolute(target));
+            }
+            Relative::Address { page } => {
+                let value = match page {
+                    true => target & !0xFFF,
+                    false => target,
+                };
+                self.literal(word & 0x1F, value);
+            }
+            Relative::Literal => match load_from_register(word, X17) {
+                Some(load) => {
+                    self.literal(X17, target);
+                    self.emit(load);
+                }
+                // Prefetching is only a hint.
+                None => self.emit(NOP),
+            },
+        }
+        Ok(false)
+    }
+
+    /// Get the offset of an instruction's branch destination if
+    /// it is within the patched bytes, which are copied as well.
+    fn internal_destination(&self, offset: usize, word: u32) -> Option<usize> {
+        let relative = Relative::classify(word)
+            .filter(|relative| matches!(relative, Relative::Branch { .. } | Relative::Conditional { .. }))?;
+        let source = self.address.wrapping_add(offset);
+        let destination = relative.target(word, source).wrapping_sub(self.address);
+        (destination < self.patch_len).then_some(destination)
+    }
+
+    /// Point branches within the copied instructions to their new locations.
+    fn apply_fixups(&mut self) -> Result<(), TrampolineError> {
+        for fixup in std::mem::take(&mut self.fixups) {
+            let unsupported = TrampolineError::UnsupportedBranch { offset: fixup.original };
+            let destination = self.boundaries.iter()
+                .find(|boundary| boundary.original == fixup.destination)
+                .ok_or(unsupported)?
+                .trampoline;
+            let word = read(&self.code, fixup.offset).unwrap();
+            let pc = self.trampoline.wrapping_add(fixup.offset);
+            let word = fixup.relative.retarget(word, pc, self.trampoline.wrapping_add(destination))
+                .ok_or(TrampolineError::OutOfRange { offset: fixup.original })?;
+            self.code[fixup.offset..fixup.offset + INSTRUCTION_LEN]
+                .copy_from_slice(&word.to_le_bytes());
+        }
+        Ok(())
+    }
+
+    /// Emit an unconditional jump.
+    fn jump(&mut self, target: usize) {
+        match branch(self.here(), target) {
+            Some(code) => self.code.extend_from_slice(&code),
+            None => self.code.extend_from_slice(&jump_absolute(target)),
+        }
+    }
+
+    /// Emit a load of a 64-bit value into a register,
+    /// followed by the value itself which is branched over.
+    fn literal(&mut self, register: u32, value: usize) {
+        self.emit(load_literal(register, 8));
+        self.emit(0x1400_0000 | 3);
+        self.code.extend_from_slice(&(value as u64).to_le_bytes());
+    }
+
+    fn emit(&mut self, word: u32) {
+        self.code.extend_from_slice(&word.to_le_bytes());
+    }
+
+    /// Get the address the next emitted instruction will be placed at.
+    fn here(&self) -> usize {
+        self.trampoline.wrapping_add(self.code.len())
+    }
+}
+
+/// Check whether control flow never continues past an instruction
+/// without a relative operand, like returns and indirect jumps.
+fn ends_function(word: u32) -> bool {
+    // br, ret; braa, retaa and their variants
+    word & 0xFFFF_FC1F == 0xD61F_0000 || word & 0xFFFF_FC1F == 0xD65F_0000
+        || word & 0xFEFF_F800 == 0xD61F_0800 || word & 0xFFFF_FBFF == 0xD65F_0BFF
+}
+
+// Addresses of the tests are above 4 GiB.
+#[cfg(all(test, target_pointer_width = "64"))]
+mod tests {
+    use super::*;
+
+    const TARGET: usize = 0x1_4000_1000;
+    /// Trampoline within reach of every relative instruction from the target.
+    const NEAR: usize = TARGET + 0x1000;
+    /// Trampoline out of reach of every relative instruction from the target.
+    const FAR: usize = TARGET + 0x10_0000_0000;
+
+    /// `ldr x16, #8; br x16`, followed by the destination.
+    const JUMP_ABS: [u32; 2] = [0x5800_0050, BR_X16];
+    /// `b #12` over a literal.
+    const SKIP_LITERAL: u32 = 0x1400_0003;
+    /// `b #-0x1000`, from after a relocated instruction back to the target.
+    const BACK: u32 = 0x17FF_FC00;
+
+    struct Case {
+        name: &'static str,
+        word: u32,
+        trampoline: usize,
+        /// Expected trampoline code, and whether it jumps back.
+        expected: (Vec<u8>, bool),
+    }
+
+    fn concat(parts: &[&[u8]]) -> Vec<u8> {
+        parts.concat()
+    }
+
+    fn words(words: &[u32]) -> Vec<u8> {
+        words.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+
+    fn addr(address: usize) -> [u8; 8] {
+        (address as u64).to_le_bytes()
+    }
+
+    /// Expect a single relocated instruction followed by a `b` back to the target.
+    fn near(name: &'static str, word: u32, relocated: u32) -> Case {
+        let code = words(&[relocated, BACK]);
+        Case { name, word, trampoline: NEAR, expected: (code, true) }
+    }
+
+    /// Expect some rewritten code followed by an absolute jump back to the target.
+    fn far(name: &'static str, word: u32, rewritten: &[&[u8]]) -> Case {
+        let code = concat(&[&rewritten.concat(), &words(&JUMP_ABS), &addr(TARGET + 4)]);
+        Case { name, word, trampoline: FAR, expected: (code, true) }
+    }
+
+    fn cases() -> Vec<Case> {
+        let jump = |destination| concat(&[&words(&JUMP_ABS), &addr(destination)]);
+        let literal = |register: u32, value| {
+            concat(&[&words(&[load_literal(register, 8), SKIP_LITERAL]), &addr(value)])
+        };
+        vec![
+            // b #0x100; b #-0xf00
+            Case {
+                name: "b",
+                word: 0x1400_0040,
+                trampoline: NEAR,
+                expected: (words(&[0x17FF_FC40]), false),
+            },
+            Case {
+                name: "b to absolute",
+                word: 0x1400_0040,
+                trampoline: FAR,
+                expected: (jump(TARGET + 0x100), false),
+            },
+            // bl #0x100; bl #-0xf00
+            near("bl", 0x9400_0040, 0x97FF_FC40),
+            far("bl to absolute", 0x9400_0040,
+                &[&literal(X16, TARGET + 0x100), &words(&[BLR_X16])]),
+            // b.eq #0x100; b.eq #-0xf00
+            near("b.cond", 0x5400_0800, 0x54FF_8800),
+            // b.ne #20
+            far("b.cond to absolute", 0x5400_0800,
+                &[&words(&[0x5400_00A1]), &jump(TARGET + 0x100)]),
+            // cbz x0, #0x100; cbz x0, #-0xf00
+            near("cbz", 0xB400_0800, 0xB4FF_8800),
+            // cbnz x0, #20
+            far("cbz to absolute", 0xB400_0800, &[&words(&[0xB500_00A0]), &jump(TARGET + 0x100)]),
+            // tbz w1, #3, #0x100; tbz w1, #3, #-0xf00
+            near("tbz", 0x3618_0801, 0x361F_8801),
+            // tbnz w1, #3, #20
+            far("tbz to absolute", 0x3618_0801, &[&words(&[0x3718_00A1]), &jump(TARGET + 0x100)]),
+            // adr x0, #0x100; adr x0, #-0xf00
+            near("adr", 0x1000_0800, 0x10FF_8800),
+            far("adr to literal", 0x1000_0800, &[&literal(0, TARGET + 0x100)]),
+            // adrp x1, #0x2000; adrp x1, #0x1000
+            near("adrp", 0xD000_0001, 0xB000_0001),
+            far("adrp to literal", 0xD000_0001, &[&literal(1, TARGET + 0x2000)]),
+            // ldr x2, #0x100; ldr x2, #-0xf00
+            near("ldr literal", 0x5800_0802, 0x58FF_8802),
+            // ldr x2, [x17]
+            far("ldr literal to absolute", 0x5800_0802,
+                &[&literal(X17, TARGET + 0x100), &words(&[0xF940_0222])]),
+            // ldr w3, [x17]
+            far("ldr 32-bit literal to absolute", 0x1800_0803,
+                &[&literal(X17, TARGET + 0x100), &words(&[0xB940_0223])]),
+            // ldrsw x4, [x17]
+            far("ldrsw literal to absolute", 0x9800_0804,
+                &[&literal(X17, TARGET + 0x100), &words(&[0xB980_0224])]),
+            // ldr d5, [x17]
+            far("ldr d literal to absolute", 0x5C00_0805,
+                &[&literal(X17, TARGET + 0x100), &words(&[0xFD40_0225])]),
+            // ldr q6, [x17]
+            far("ldr q literal to absolute", 0x9C00_0806,
+                &[&literal(X17, TARGET + 0x100), &words(&[0x3DC0_0226])]),
+            // prfm pldl1keep, #0x100
+            far("prfm literal to nop", 0xD800_0800, &[&words(&[NOP])]),
+        ]
+    }
+
+    #[test]
+    fn relocation() {
+        for case in cases() {
+            let trampoline = build(&case.word.to_le_bytes(), TARGET, case.trampoline, BRANCH_LEN)
+                .unwrap_or_else(|err| panic!("{}: {err:?}", case.name));
+            let built = (trampoline.code().to_vec(), trampoline.jumps_back());
+            assert_eq!(built, case.expected, "{}", case.name);
+            assert_eq!(trampoline.original_len(), INSTRUCTION_LEN, "{}", case.name);
+        }
+    }
+
+    #[test]
+    fn internal_branch() {
+        // cbz x0, #12; adr x1, #0x100; nop; nop
+        let code = words(&[0xB400_0060, 0x1000_0801, NOP, NOP]);
+        let trampoline = build(&code, TARGET, FAR, JUMP_ABS_LEN).unwrap();
+        // The destination moves past the literal `adr` turned into.
+        let expected = concat(&[
+            // cbz x0, #24; ldr x1, #8; b #12
+            &words(&[0xB400_00C0, 0x5800_0041, SKIP_LITERAL]), &addr(TARGET + 0x104),
+            &words(&[NOP, NOP]),
+            &words(&JUMP_ABS), &addr(TARGET + 16),
+        ]);
+        assert_eq!(trampoline.code(), expected);
+        let boundaries: Vec<_> = trampoline.boundaries().iter()
+            .map(|boundary| (boundary.original, boundary.trampoline))
+            .collect();
+        assert_eq!(boundaries, [(0, 0), (4, 4), (8, 20), (12, 24)]);
+    }
+
+    #[test]
+    fn helpers() {
+        assert_eq!(branch(TARGET, TARGET + 0x100), Some(0x1400_0040u32.to_le_bytes()));
+        assert_eq!(branch(TARGET, TARGET + 0x800_0000), None);
+        assert_eq!(branch(TARGET, TARGET + 2), None);
+        assert_eq!(jump_absolute(TARGET).to_vec(), concat(&[&words(&JUMP_ABS), &addr(TARGET)]));
+    }
+}
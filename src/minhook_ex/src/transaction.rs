@@ -0,0 +1,433 @@
+//! Batched hook state changes applied in a single thread freeze.
+
+use std::ffi::c_void;
+use std::marker::PhantomData;
+
+use crate::{registry, Error, Function, Hook, HookIdent, HookRecord};
+
+/// Hooks selected by an added operation.
+#[derive(Clone, Copy, Debug)]
+enum Selection {
+    /// A single hook.
+    Hook(*const c_void, HookIdent),
+    /// All hooks with an identifier, or all hooks for [`HookIdent::ALL`].
+    Ident(HookIdent),
+}
+
+/// State change of a single hook selected by an added operation.
+#[derive(Debug)]
+struct Change {
+    index: usize,
+    target: *const c_void,
+    ident: HookIdent,
+    enable: bool,
+    /// State of the hook before the transaction.
+    previous: bool,
+}
+
+impl Change {
+    fn new(index: usize, target: *const c_void, ident: HookIdent, enable: bool) -> Self {
+        let previous = registry::is_enabled(target, ident).unwrap_or(false);
+        Self { index, target, ident, enable, previous }
+    }
+
+    /// Queue the change.
+    unsafe fn queue(&self) -> crate::Result<()> {
+        queue_state(self.target, self.ident, self.enable)
+    }
+
+    /// Queue a change back to the state before the transaction.
+    unsafe fn queue_previous(&self) -> crate::Result<()> {
+        queue_state(self.target, self.ident, self.previous)
+    }
+
+    /// Apply the change on its own.
+    unsafe fn apply_single(&self) -> crate::Result<()> {
+        match self.enable {
+            true => crate::enable_hook(self.target, self.ident),
+            false => crate::disable_hook(self.target, self.ident),
+        }
+    }
+
+    /// Revert a change previously applied by [`Self::apply_single`].
+    unsafe fn revert_single(&self) -> crate::Result<()> {
+        match self.enable {
+            true => crate::disable_hook(self.target, self.ident),
+            false => crate::enable_hook(self.target, self.ident),
+        }
+    }
+
+    /// Check whether the state of the hook actually changes.
+    fn is_change(&self) -> bool {
+        self.previous != self.enable
+    }
+
+    fn is_for(&self, record: &HookRecord) -> bool {
+        self.target == record.target() && self.ident == record.ident()
+    }
+}
+
+unsafe fn queue_state(target: *const c_void, ident: HookIdent, enable: bool) -> crate::Result<()> {
+    match enable {
+        true => crate::queue_enable_hook(target, ident),
+        false => crate::queue_disable_hook(target, ident),
+    }
+}
+
+/// Failure to commit a [`HookTransaction`].
+///
+/// By the time this is returned, all hooks in the
+/// transaction have been rolled back to their previous state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TransactionError {
+    index: usize,
+    target: usize,
+    ident: HookIdent,
+    error: Error,
+}
+
+impl TransactionError {
+    /// Get the index of the failed operation, in the order they were added.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Get the target function of the hook whose change failed.
+    pub fn target(&self) -> *const c_void {
+        self.target as *const c_void
+    }
+
+    /// Get the identifier of the hook whose change failed.
+    pub fn ident(&self) -> HookIdent {
+        self.ident
+    }
+
+    /// Get the underlying error.
+    pub fn error(&self) -> Error {
+        self.error
+    }
+}
+
+impl std::fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "hook transaction failed at operation {}: {}", self.index, self.error)
+    }
+}
+
+impl std::error::Error for TransactionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Builder which collects enable/disable operations for many hooks and
+/// commits them at once, suspending all threads only a single time.
+///
+/// Built on the MinHook queue. Changes which were queued through other
+/// means are set aside while committing, and stay queued afterwards.
+#[derive(Debug, Default)]
+pub struct HookTransaction<'a> {
+    selections: Vec<(Selection, bool)>,
+    _hooks: PhantomData<&'a ()>,
+}
+
+impl<'a> HookTransaction<'a> {
+    /// Create an empty transaction.
+    pub fn new() -> Self {
+        Self { selections: Vec::new(), _hooks: PhantomData }
+    }
+
+    /// Add an operation enabling a `hook`.
+    pub fn enable<F: Function>(&mut self, hook: &'a Hook<'_, F>) -> &mut Self {
+        self.push(Selection::Hook(hook.target().to_ptr(), hook.ident()), true)
+    }
+
+    /// Add an operation disabling a `hook`.
+    pub fn disable<F: Function>(&mut self, hook: &'a Hook<'_, F>) -> &mut Self {
+        self.push(Selection::Hook(hook.target().to_ptr(), hook.ident()), false)
+    }
+
+    /// Add an operation enabling all hooks with an identifier,
+    /// or all hooks for [`HookIdent::ALL`], as created when committing.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`crate::enable_hooks`], once committed.
+    pub unsafe fn enable_hooks(&mut self, ident: HookIdent) -> &mut Self {
+        self.push(Selection::Ident(ident), true)
+    }
+
+    /// Add an operation disabling all hooks with an identifier,
+    /// or all hooks for [`HookIdent::ALL`], as created when committing.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`crate::disable_hooks`], once committed.
+    pub unsafe fn disable_hooks(&mut self, ident: HookIdent) -> &mut Self {
+        self.push(Selection::Ident(ident), false)
+    }
+
+    /// Get the number of collected operations.
+    pub fn len(&self) -> usize {
+        self.selections.len()
+    }
+
+    /// Check whether no operations were collected.
+    pub fn is_empty(&self) -> bool {
+        self.selections.is_empty()
+    }
+
+    /// Apply all collected operations in one go.
+    ///
+    /// If any operation fails, hooks which were already changed are
+    /// reverted and the failed one is reported. As the library doesn't
+    /// tell which hook made a batch fail, it is located by replaying the
+    /// operations one by one, which is only ever done on failure.
+    pub fn commit(self) -> Result<(), TransactionError> {
+        let changes = self.changes();
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let foreign = set_aside_foreign(&changes);
+        let result = commit_changes(&changes);
+        for record in &foreign {
+            let _ = unsafe { queue_state(record.target(), record.ident(), record.is_queued_enabled()) };
+        }
+        result
+    }
+
+    fn push(&mut self, selection: Selection, enable: bool) -> &mut Self {
+        self.selections.push((selection, enable));
+        self
+    }
+
+    /// Expand the added operations into a change for every selected hook.
+    fn changes(&self) -> Vec<Change> {
+        let mut changes = Vec::new();
+        for (index, &(selection, enable)) in self.selections.iter().enumerate() {
+            match selection {
+                Selection::Hook(target, ident) => {
+                    changes.push(Change::new(index, target, ident, enable));
+                }
+                Selection::Ident(ident) => {
+                    changes.extend(registry::with_ident(ident).iter().map(|record| {
+                        Change::new(index, record.target(), record.ident(), enable)
+                    }));
+                }
+            }
+        }
+        changes
+    }
+}
+
+/// Queue hooks outside of a transaction which have pending changes back
+/// to their current state, so applying the queue leaves them alone.
+/// Returns their records, to queue the changes again afterwards.
+fn set_aside_foreign(changes: &[Change]) -> Vec<HookRecord> {
+    let foreign: Vec<_> = registry::hooks().into_iter()
+        .filter(|record| record.is_queued_enabled() != record.is_enabled())
+        .filter(|record| !changes.iter().any(|change| change.is_for(record)))
+        .collect();
+    for record in &foreign {
+        let _ = unsafe { queue_state(record.target(), record.ident(), record.is_enabled()) };
+    }
+    foreign
+}
+
+fn commit_changes(changes: &[Change]) -> Result<(), TransactionError> {
+    for (position, change) in changes.iter().enumerate() {
+        if let Err(error) = unsafe { change.queue() } {
+            unqueue(&changes[..position]);
+            return Err(change_error(change, error));
+        }
+    }
+
+    match unsafe { crate::apply_queued(HookIdent::ALL) } {
+        Ok(()) => Ok(()),
+        Err(error) => {
+            unqueue(changes);
+            let _ = unsafe { crate::apply_queued(HookIdent::ALL) };
+            Err(locate_failure(changes, error))
+        }
+    }
+}
+
+fn unqueue(changes: &[Change]) {
+    for change in changes {
+        let _ = unsafe { change.queue_previous() };
+    }
+}
+
+/// Replay changes individually to find the first failing one,
+/// then revert the ones which succeeded before it.
+fn locate_failure(changes: &[Change], batch_error: Error) -> TransactionError {
+    let mut applied = Vec::new();
+    let mut failure = None;
+    for change in changes {
+        if !change.is_change() {
+            continue;
+        }
+        match unsafe { change.apply_single() } {
+            Ok(()) => applied.push(change),
+            Err(error) => {
+                failure = Some(change_error(change, error));
+                break;
+            }
+        }
+    }
+    for change in applied.into_iter().rev() {
+        let _ = unsafe { change.revert_single() };
+    }
+    // The batch may fail for reasons which don't reproduce with
+    // single changes, in which case blame the first one.
+    failure.unwrap_or_else(|| change_error(&changes[0], batch_error))
+}
+
+fn change_error(change: &Change, error: Error) -> TransactionError {
+    let (index, target, ident) = (change.index, change.target as usize, change.ident);
+    TransactionError { index, target, ident, error }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{Call, MockBackend};
+    use crate::{ErrorKind, MinHook, Operation, ThreadFreezeMethod};
+
+    type Target = extern "C" fn();
+
+    const FIRST: usize = 0x1000;
+    const SECOND: usize = 0x2000;
+    const THIRD: usize = 0x3000;
+    const DETOUR: usize = 0x9000;
+    const IDENT: HookIdent = HookIdent::new(7);
+
+    static MOCK: MockBackend = MockBackend::new();
+
+    fn ptr(address: usize) -> *const c_void {
+        address as *const c_void
+    }
+
+    fn hook(minhook: &MinHook, target: usize) -> Hook<'_, Target> {
+        unsafe {
+            let (target, detour) = (Target::from_ptr(ptr(target)), Target::from_ptr(ptr(DETOUR)));
+            Hook::new(minhook, target, detour, IDENT).unwrap()
+        }
+    }
+
+    /// Get the enabled and queued states of all hooks,
+    /// checking that the registry agrees with the mock.
+    fn states() -> Vec<(usize, bool, bool)> {
+        MOCK.hooks().iter().map(|hook| {
+            let record = registry::get(ptr(hook.target), hook.ident).unwrap();
+            let state = (record.is_enabled(), record.is_queued_enabled());
+            assert_eq!(state, (hook.enabled, hook.queued));
+            (hook.target, hook.enabled, hook.queued)
+        }).collect()
+    }
+
+    #[test]
+    fn committing() {
+        let _selection = MOCK.select().unwrap();
+        let minhook = MinHook::initialize(ThreadFreezeMethod::OriginalSnapshot).unwrap();
+        let (first, second) = (hook(&minhook, FIRST), hook(&minhook, SECOND));
+        MOCK.take_calls();
+        HookTransaction::new().commit().unwrap();
+        assert!(MOCK.calls().is_empty());
+
+        let mut transaction = HookTransaction::new();
+        transaction.enable(&first).enable(&second);
+        assert_eq!(transaction.len(), 2);
+        transaction.commit().unwrap();
+        assert_eq!(states(), [(FIRST, true, true), (SECOND, true, true)]);
+        // Both hooks are switched by applying the queue once.
+        assert_eq!(MOCK.take_calls(), [
+            Call::QueueEnableHook { target: Some(FIRST), ident: IDENT },
+            Call::QueueEnableHook { target: Some(SECOND), ident: IDENT },
+            Call::ApplyQueued { ident: HookIdent::ALL },
+        ]);
+
+        let mut transaction = HookTransaction::new();
+        unsafe { transaction.disable_hooks(IDENT) };
+        transaction.commit().unwrap();
+        assert_eq!(states(), [(FIRST, false, false), (SECOND, false, false)]);
+    }
+
+    #[test]
+    fn failed_queueing() {
+        let _selection = MOCK.select().unwrap();
+        let minhook = MinHook::initialize(ThreadFreezeMethod::OriginalSnapshot).unwrap();
+        let (first, second) = (hook(&minhook, FIRST), hook(&minhook, SECOND));
+        second.enable().unwrap();
+        MOCK.fail_next(Some(Operation::QueueDisableHook), ErrorKind::MutexFailure);
+        let mut transaction = HookTransaction::new();
+        transaction.enable(&first).disable(&second);
+        let error = transaction.commit().unwrap_err();
+        assert_eq!((error.index(), error.target(), error.ident()), (1, ptr(SECOND), IDENT));
+        assert_eq!(error.error().kind(), ErrorKind::MutexFailure);
+        assert_eq!(error.error().operation(), Some(Operation::QueueDisableHook));
+        assert_eq!(states(), [(FIRST, false, false), (SECOND, true, true)]);
+    }
+
+    #[test]
+    fn failed_changes_are_located_and_rolled_back() {
+        let _selection = MOCK.select().unwrap();
+        let minhook = MinHook::initialize(ThreadFreezeMethod::OriginalSnapshot).unwrap();
+        let hooks = [hook(&minhook, FIRST), hook(&minhook, SECOND), hook(&minhook, THIRD)];
+        hooks[1].enable().unwrap();
+        // Disabling the second hook fails both when `apply_queued` brings
+        // the registry back in line with the failed batch, and when the
+        // transaction replays its changes one by one.
+        MOCK.fail_next(Some(Operation::ApplyQueued), ErrorKind::MutexFailure);
+        MOCK.fail_next(Some(Operation::DisableHook), ErrorKind::ProtectionFailure);
+        MOCK.fail_next(Some(Operation::DisableHook), ErrorKind::ProtectionFailure);
+        let mut transaction = HookTransaction::new();
+        transaction.enable(&hooks[0]).disable(&hooks[1]).enable(&hooks[2]);
+        let error = transaction.commit().unwrap_err();
+        assert_eq!((error.index(), error.target(), error.ident()), (1, ptr(SECOND), IDENT));
+        assert_eq!(error.error(), Error::new(ErrorKind::ProtectionFailure)
+            .with_operation(Operation::DisableHook).with_hook(ptr(SECOND), IDENT));
+        // The first hook was enabled by the replay, and disabled again.
+        assert_eq!(states(), [(FIRST, false, false), (SECOND, true, true), (THIRD, false, false)]);
+    }
+
+    #[test]
+    fn batch_failures_blame_the_first_change() {
+        let _selection = MOCK.select().unwrap();
+        let minhook = MinHook::initialize(ThreadFreezeMethod::OriginalSnapshot).unwrap();
+        let (first, second) = (hook(&minhook, FIRST), hook(&minhook, SECOND));
+        MOCK.fail_next(Some(Operation::ApplyQueued), ErrorKind::MutexFailure);
+        let mut transaction = HookTransaction::new();
+        transaction.enable(&first).enable(&second);
+        let error = transaction.commit().unwrap_err();
+        assert_eq!((error.index(), error.target()), (0, ptr(FIRST)));
+        assert_eq!(error.error(), Error::new(ErrorKind::MutexFailure)
+            .with_operation(Operation::ApplyQueued).with_ident(HookIdent::ALL));
+        assert_eq!(states(), [(FIRST, false, false), (SECOND, false, false)]);
+    }
+
+    #[test]
+    fn foreign_changes_stay_queued() {
+        let _selection = MOCK.select().unwrap();
+        let minhook = MinHook::initialize(ThreadFreezeMethod::OriginalSnapshot).unwrap();
+        let hooks = [hook(&minhook, FIRST), hook(&minhook, SECOND), hook(&minhook, THIRD)];
+        hooks[2].enable().unwrap();
+        unsafe {
+            crate::queue_enable_hook(ptr(SECOND), IDENT).unwrap();
+            crate::queue_disable_hook(ptr(THIRD), IDENT).unwrap();
+        }
+        let mut transaction = HookTransaction::new();
+        transaction.enable(&hooks[0]);
+        transaction.commit().unwrap();
+        assert_eq!(states(), [(FIRST, true, true), (SECOND, false, true), (THIRD, true, false)]);
+
+        unsafe { crate::apply_queued(HookIdent::ALL).unwrap() };
+        assert_eq!(states(), [(FIRST, true, true), (SECOND, true, true), (THIRD, false, false)]);
+    }
+
+    #[test]
+    fn errors_are_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<TransactionError>();
+    }
+}
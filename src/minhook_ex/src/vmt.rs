@@ -0,0 +1,425 @@
+//! Hooks of C++ virtual methods through their virtual method tables.
+//!
+//! Patching the code of a virtual method affects every class sharing the
+//! implementation, while its vtable slot only affects the classes using
+//! that vtable. [`VmtHook`] swaps a slot of a shared vtable, affecting
+//! all objects of a class, and [`ShadowVmt`] gives a single object its
+//! own copy of its vtable, affecting that object only.
+//!
+//! Neither goes through the library, so they don't need a [`MinHook`]
+//! context and don't show up in the [`registry`](crate::registry).
+//!
+//! [`MinHook`]: crate::MinHook
+
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+use crate::{allocator, Error, ErrorKind, Function, Operation, Result};
+
+/// Number of entries in front of the methods of a vtable, which are
+/// the RTTI complete object locator for MSVC.
+#[cfg(target_env = "msvc")]
+pub const PREFIX_LEN: usize = 1;
+/// Number of entries in front of the methods of a vtable, which are
+/// the offset to the top of the object and its type info on Itanium,
+/// which MinGW follows on Windows as well.
+#[cfg(not(target_env = "msvc"))]
+pub const PREFIX_LEN: usize = 2;
+
+/// Get the vtable of a polymorphic object, which is its first pointer.
+///
+/// # Safety
+///
+/// `object` must point to an object with a vtable.
+pub unsafe fn vtable(object: *const c_void) -> *const *const c_void {
+    *(object as *const *const *const c_void)
+}
+
+/// A hook replacing one slot of a shared vtable, restored when dropped.
+///
+/// The hook is created disabled, and the slot is made writable only
+/// while it is being changed.
+#[derive(Debug)]
+pub struct VmtHook<F: Function> {
+    slot: *const AtomicPtr<c_void>,
+    index: usize,
+    original: F,
+    detour: F,
+    enabled: AtomicBool,
+}
+
+// The slot is only changed atomically, and belongs to the hook.
+unsafe impl<F: Function> Send for VmtHook<F> {}
+unsafe impl<F: Function> Sync for VmtHook<F> {}
+
+impl<F: Function> VmtHook<F> {
+    /// Create a disabled hook for a method of a vtable.
+    ///
+    /// # Arguments
+    ///
+    /// * `vtable` - the vtable, pointing to its first method.
+    /// * `index` - index of the method in the vtable.
+    /// * `detour` - the overwriting function.
+    ///
+    /// # Safety
+    ///
+    /// The vtable must have a method at `index` of type `F`, and outlive
+    /// the hook. It must be sound to call `detour` in place of the method
+    /// for as long as the hook is enabled.
+    pub unsafe fn new(vtable: *const *const c_void, index: usize, detour: F) -> Self {
+        let slot = vtable.add(index) as *const AtomicPtr<c_void>;
+        Self {
+            slot,
+            index,
+            original: F::from_ptr((*slot).load(Ordering::Acquire)),
+            detour,
+            enabled: AtomicBool::new(false),
+        }
+    }
+
+    /// Create a disabled hook for a method of the vtable of an object,
+    /// which affects all objects sharing the vtable.
+    ///
+    /// # Safety
+    ///
+    /// `object` must point to an object with a vtable, otherwise
+    /// same as for [`VmtHook::new`].
+    pub unsafe fn from_object(object: *const c_void, index: usize, detour: F) -> Self {
+        Self::new(vtable(object), index, detour)
+    }
+
+    /// Enable the hook, redirecting calls through the slot into the detour.
+    pub fn enable(&self) -> Result<()> {
+        self.write(self.detour, Operation::EnableHook)?;
+        self.enabled.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Disable the hook, restoring the original method in the slot.
+    pub fn disable(&self) -> Result<()> {
+        self.write(self.original, Operation::DisableHook)?;
+        self.enabled.store(false, Ordering::Release);
+        Ok(())
+    }
+
+    /// Check whether the hook is currently enabled.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Acquire)
+    }
+
+    /// Get the vtable the hooked slot is in.
+    pub fn vtable(&self) -> *const *const c_void {
+        unsafe { (self.slot as *const *const c_void).sub(self.index) }
+    }
+
+    /// Get the index of the hooked method.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Get the overwriting function.
+    pub fn detour(&self) -> F {
+        self.detour
+    }
+
+    /// Get the method the slot held when the hook was created.
+    pub fn original(&self) -> F {
+        self.original
+    }
+
+    fn write(&self, function: F, operation: Operation) -> Result<()> {
+        let slot = self.slot as usize;
+        let written = unsafe {
+            allocator::with_writable(slot, std::mem::size_of::<usize>(), || {
+                (*self.slot).store(function.to_ptr() as *mut c_void, Ordering::Release)
+            })
+        };
+        written.ok_or_else(|| Error::new(ErrorKind::ProtectionFailure).with_operation(operation))
+    }
+}
+
+impl<F: Function> Drop for VmtHook<F> {
+    fn drop(&mut self) {
+        // There is nothing sensible to do on failure.
+        if self.is_enabled() {
+            let _ = self.disable();
+        }
+    }
+}
+
+/// A copy of the vtable of a single object, which the object uses
+/// instead of its own until dropped.
+///
+/// Methods of the copy can be replaced without changing memory
+/// protection, and without affecting any other object.
+#[derive(Debug)]
+pub struct ShadowVmt {
+    object: *const AtomicPtr<c_void>,
+    original: *const *const c_void,
+    entries: Box<[AtomicPtr<c_void>]>,
+}
+
+// Entries are only changed atomically, and the object belongs to the copy.
+unsafe impl Send for ShadowVmt {}
+unsafe impl Sync for ShadowVmt {}
+
+impl ShadowVmt {
+    /// Copy the vtable of an object, along with the [`PREFIX_LEN`]
+    /// entries in front of it, and point the object at the copy.
+    ///
+    /// # Arguments
+    ///
+    /// * `object` - the object, which must have a vtable.
+    /// * `len` - number of methods in the vtable to copy.
+    ///
+    /// # Safety
+    ///
+    /// The vtable must have at least `len` methods, and the object
+    /// must outlive the copy. The object must not be pointed at
+    /// another vtable until the copy is dropped.
+    pub unsafe fn new(object: *mut c_void, len: usize) -> Self {
+        let object = object as *const AtomicPtr<c_void>;
+        let original = (*object).load(Ordering::Acquire) as *const *const c_void;
+        let entries = (0..PREFIX_LEN + len)
+            .map(|entry| AtomicPtr::new(*original.add(entry).sub(PREFIX_LEN) as *mut c_void))
+            .collect::<Box<[_]>>();
+        let shadow = Self { object, original, entries };
+        (*object).store(shadow.vtable() as *mut c_void, Ordering::Release);
+        shadow
+    }
+
+    /// Replace a method of the copy, returning the original one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of the copied methods.
+    ///
+    /// # Safety
+    ///
+    /// The method at `index` must be of type `F`, and it must be sound
+    /// to call `detour` in its place for as long as it is replaced.
+    pub unsafe fn hook<F: Function>(&self, index: usize, detour: F) -> F {
+        let original = self.original(index);
+        self.entry(index).store(detour.to_ptr() as *mut c_void, Ordering::Release);
+        original
+    }
+
+    /// Restore a method of the copy to the original one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of the copied methods.
+    pub fn unhook(&self, index: usize) {
+        let entry = self.entry(index);
+        entry.store(unsafe { *self.original.add(index) } as *mut c_void, Ordering::Release);
+    }
+
+    /// Check whether a method of the copy is replaced.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of the copied methods.
+    pub fn is_hooked(&self, index: usize) -> bool {
+        let entry = self.entry(index);
+        !std::ptr::eq(entry.load(Ordering::Acquire), unsafe { *self.original.add(index) })
+    }
+
+    /// Get the original method at `index`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of the copied methods.
+    ///
+    /// # Safety
+    ///
+    /// The method at `index` must be of type `F`.
+    pub unsafe fn original<F: Function>(&self, index: usize) -> F {
+        assert!(index < self.len(), "vtable index {} out of {} methods", index, self.len());
+        F::from_ptr(*self.original.add(index))
+    }
+
+    /// Get the object using the copy.
+    pub fn object(&self) -> *mut c_void {
+        self.object as *mut c_void
+    }
+
+    /// Get the vtable the object used before.
+    pub fn original_vtable(&self) -> *const *const c_void {
+        self.original
+    }
+
+    /// Get the copy, pointing to its first method.
+    pub fn vtable(&self) -> *const *const c_void {
+        self.entries[PREFIX_LEN..].as_ptr() as *const *const c_void
+    }
+
+    /// Get the number of copied methods.
+    pub fn len(&self) -> usize {
+        self.entries.len() - PREFIX_LEN
+    }
+
+    /// Check whether no methods were copied.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn entry(&self, index: usize) -> &AtomicPtr<c_void> {
+        assert!(index < self.len(), "vtable index {} out of {} methods", index, self.len());
+        &self.entries[PREFIX_LEN + index]
+    }
+}
+
+impl Drop for ShadowVmt {
+    fn drop(&mut self) {
+        // Only restore the object if nothing else has replaced the copy since.
+        let _ = unsafe { &*self.object }.compare_exchange(self.vtable() as *mut c_void,
+            self.original as *mut c_void, Ordering::AcqRel, Ordering::Acquire);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Method = extern "C" fn(*const Object) -> usize;
+
+    /// A polymorphic object whose methods add to its value.
+    #[repr(C)]
+    struct Object {
+        vtable: *const *const c_void,
+        value: usize,
+    }
+
+    extern "C" fn first(object: *const Object) -> usize {
+        unsafe { (*object).value + 1 }
+    }
+
+    extern "C" fn second(object: *const Object) -> usize {
+        unsafe { (*object).value + 2 }
+    }
+
+    extern "C" fn third(object: *const Object) -> usize {
+        unsafe { (*object).value + 3 }
+    }
+
+    extern "C" fn detour(object: *const Object) -> usize {
+        unsafe { (*object).value + 100 }
+    }
+
+    /// Entries in front of the methods, standing in for type information.
+    const PREFIX: [usize; PREFIX_LEN] = {
+        let mut prefix = [0; PREFIX_LEN];
+        let mut entry = 0;
+        while entry < PREFIX_LEN {
+            prefix[entry] = 0x7E57_0000 + entry;
+            entry += 1;
+        }
+        prefix
+    };
+
+    /// Build a vtable with its prefix, of which the methods start at `PREFIX_LEN`.
+    fn vtable() -> Box<[*const c_void]> {
+        let methods: [Method; 3] = [first, second, third];
+        PREFIX.iter().map(|&entry| entry as *const c_void)
+            .chain(methods.iter().map(|&method| method as *const c_void))
+            .collect()
+    }
+
+    fn call(object: &Object, index: usize) -> usize {
+        let method: Method = unsafe { Method::from_ptr(*object.vtable.add(index)) };
+        method(object)
+    }
+
+    #[test]
+    fn vmt_hooks() {
+        let table = vtable();
+        let methods = table[PREFIX_LEN..].as_ptr();
+        let object = Object { vtable: methods, value: 10 };
+        let hook = unsafe { VmtHook::new(methods, 1, detour as Method) };
+        assert!(!hook.is_enabled());
+        assert_eq!((hook.vtable(), hook.index()), (methods, 1));
+        assert_eq!(hook.original().to_ptr(), (second as Method).to_ptr());
+        assert_eq!(call(&object, 1), 12);
+
+        hook.enable().unwrap();
+        assert!(hook.is_enabled());
+        assert_eq!([call(&object, 0), call(&object, 1), call(&object, 2)], [11, 110, 13]);
+        hook.disable().unwrap();
+        assert!(!hook.is_enabled());
+        assert_eq!(call(&object, 1), 12);
+
+        // Dropping an enabled hook restores the slot.
+        let address = (&object as *const Object).cast();
+        let hook = unsafe { VmtHook::from_object(address, 2, detour as Method) };
+        assert_eq!(hook.vtable(), methods);
+        hook.enable().unwrap();
+        assert_eq!(call(&object, 2), 110);
+        drop(hook);
+        assert_eq!(call(&object, 2), 13);
+        assert_eq!(table[PREFIX_LEN + 2], (third as Method).to_ptr());
+    }
+
+    #[test]
+    fn shadow_vmts() {
+        let table = vtable();
+        let methods = table[PREFIX_LEN..].as_ptr();
+        let mut object = Object { vtable: methods, value: 10 };
+        let other = Object { vtable: methods, value: 20 };
+        let shadow = unsafe { ShadowVmt::new((&mut object as *mut Object).cast(), 3) };
+        assert_eq!((shadow.len(), shadow.original_vtable()), (3, methods));
+        assert_eq!(object.vtable, shadow.vtable());
+        assert_ne!(shadow.vtable(), methods);
+        // The prefix is copied along with the methods.
+        let start = unsafe { shadow.vtable().sub(PREFIX_LEN) };
+        let copied = unsafe { std::slice::from_raw_parts(start, table.len()) };
+        assert_eq!(copied, &table[..]);
+
+        let original: Method = unsafe { shadow.hook(1, detour as Method) };
+        assert_eq!(original.to_ptr(), (second as Method).to_ptr());
+        assert!(shadow.is_hooked(1) && !shadow.is_hooked(0) && !shadow.is_hooked(2));
+        assert_eq!([call(&object, 0), call(&object, 1), call(&object, 2)], [11, 110, 13]);
+        // Other objects and the original vtable are left alone.
+        assert_eq!(call(&other, 1), 22);
+        assert_eq!(table[PREFIX_LEN + 1], (second as Method).to_ptr());
+
+        shadow.unhook(1);
+        assert!(!shadow.is_hooked(1));
+        assert_eq!(call(&object, 1), 12);
+        unsafe { shadow.hook(2, detour as Method) };
+        drop(shadow);
+        assert_eq!(object.vtable, methods);
+        assert_eq!(call(&object, 2), 13);
+    }
+
+    #[test]
+    fn shadow_vmt_replaced() {
+        let table = vtable();
+        let replacement = vtable();
+        let methods = table[PREFIX_LEN..].as_ptr();
+        let mut object = Object { vtable: methods, value: 10 };
+        let shadow = unsafe { ShadowVmt::new((&mut object as *mut Object).cast(), 3) };
+        // Something else points the object at another vtable,
+        // like a constructor, which dropping the copy keeps.
+        let replaced = replacement[PREFIX_LEN..].as_ptr();
+        unsafe { (*shadow.object().cast::<Object>()).vtable = replaced };
+        drop(shadow);
+        assert_eq!(object.vtable, replaced);
+    }
+
+    #[test]
+    fn shadow_vmt_indices() {
+        let table = vtable();
+        let mut object = Object { vtable: table[PREFIX_LEN..].as_ptr(), value: 10 };
+        let shadow = unsafe { ShadowVmt::new((&mut object as *mut Object).cast(), 2) };
+        assert!(!shadow.is_hooked(1));
+        let panics = |index: usize| [
+            std::panic::catch_unwind(|| unsafe { shadow.hook(index, detour as Method) }).is_err(),
+            std::panic::catch_unwind(|| shadow.unhook(index)).is_err(),
+            std::panic::catch_unwind(|| shadow.is_hooked(index)).is_err(),
+            std::panic::catch_unwind(|| unsafe { shadow.original::<Method>(index) }).is_err(),
+        ];
+        assert_eq!(panics(2), [true; 4]);
+        assert_eq!(panics(usize::MAX), [true; 4]);
+        assert_eq!(call(&object, 1), 12);
+    }
+}
@@ -0,0 +1,195 @@
+"""Generate the PE and API set schema fixtures of the tests.
+
+Run from this directory with `python3 generate.py`. The images are not
+meant to be loaded, only to be parsed: they have a `.text` section with
+two `ret`s, and an `.rdata` section with the export and import directories.
+Sections are at different offsets in the file than in memory, so reading
+them with the wrong layout fails.
+"""
+
+import struct
+
+FILE_ALIGNMENT = 0x200
+SECTION_ALIGNMENT = 0x1000
+TEXT_RVA = 0x1000
+RDATA_RVA = 0x2000
+ORDINAL_BASE = 5
+
+# Exports by index, with a name or None, and a code RVA, a forwarder or None.
+EXPORTS = [
+    ("code", TEXT_RVA),
+    (None, TEXT_RVA + 0x10),
+    ("chain", "HOP.step"),
+    (None, None),
+    ("step", "HOP.code"),
+    ("loop", "HOP.loop"),
+    ("by_ordinal", "HOP.#6"),
+    ("api", "api-ms-win-test-l1-1-0.code"),
+]
+
+# Imports by module, with names and hints or ordinals.
+IMPORTS = [
+    ("kernel32.dll", [("Sleep", 3), 7]),
+    ("user32.dll", [("MessageBoxW", 0)]),
+]
+
+
+def align(value, alignment):
+    return (value + alignment - 1) // alignment * alignment
+
+
+class Rdata:
+    """Contents of `.rdata`, laid out in order of placement."""
+
+    def __init__(self):
+        self.data = bytearray()
+
+    def rva(self):
+        return RDATA_RVA + len(self.data)
+
+    def place(self, data, alignment=4):
+        self.data += bytes(align(len(self.data), alignment) - len(self.data))
+        rva = self.rva()
+        self.data += data
+        return rva
+
+    def string(self, string):
+        return self.place(string.encode() + b"\0", 1)
+
+    def patch(self, rva, fmt, *values):
+        struct.pack_into(fmt, self.data, rva - RDATA_RVA, *values)
+
+
+def exports(rdata):
+    directory = rdata.place(bytes(40))
+    functions = rdata.place(bytes(4 * len(EXPORTS)))
+    named = sorted((name, index) for index, (name, _) in enumerate(EXPORTS) if name)
+    names = rdata.place(bytes(4 * len(named)))
+    ordinals = rdata.place(bytes(2 * len(named)))
+    module = rdata.string("fixture.dll")
+    for index, (_, address) in enumerate(EXPORTS):
+        if isinstance(address, str):
+            address = rdata.string(address)
+        rdata.patch(functions + 4 * index, "<I", address or 0)
+    for entry, (name, index) in enumerate(named):
+        rdata.patch(names + 4 * entry, "<I", rdata.string(name))
+        rdata.patch(ordinals + 2 * entry, "<H", index)
+    rdata.patch(directory + 12, "<7I", module, ORDINAL_BASE, len(EXPORTS), len(named),
+        functions, names, ordinals)
+    return directory, rdata.rva() - directory
+
+
+def imports(rdata, is_64):
+    pointer = "<Q" if is_64 else "<I"
+    pointer_len = 8 if is_64 else 4
+    ordinal_flag = 1 << (63 if is_64 else 31)
+    directory = rdata.place(bytes(20 * (len(IMPORTS) + 1)))
+    for index, (module, symbols) in enumerate(IMPORTS):
+        thunks = []
+        for symbol in symbols:
+            if isinstance(symbol, int):
+                thunks.append(ordinal_flag | symbol)
+            else:
+                name, hint = symbol
+                thunks.append(rdata.place(struct.pack("<H", hint) + name.encode() + b"\0", 2))
+        table = b"".join(struct.pack(pointer, thunk) for thunk in thunks + [0])
+        lookup = rdata.place(table, pointer_len)
+        slots = rdata.place(table, pointer_len)
+        name = rdata.string(module)
+        rdata.patch(directory + 20 * index, "<5I", lookup, 0, 0, name, slots)
+    return directory, 20 * (len(IMPORTS) + 1)
+
+
+def image(is_64):
+    rdata = Rdata()
+    export_directory = exports(rdata)
+    import_directory = imports(rdata, is_64)
+    text = b"\xC3" + b"\xCC" * 15 + b"\xC3" + b"\xCC" * 15
+
+    directories = [export_directory, import_directory] + [(0, 0)] * 14
+    optional_len = (112 if is_64 else 96) + 8 * len(directories)
+    headers_len = align(0x80 + 24 + optional_len + 40 * 2, FILE_ALIGNMENT)
+    text_raw = headers_len
+    rdata_raw = text_raw + align(len(text), FILE_ALIGNMENT)
+    size_of_image = RDATA_RVA + align(len(rdata.data), SECTION_ALIGNMENT)
+
+    dos = bytearray(0x80)
+    struct.pack_into("<H", dos, 0, 0x5A4D)
+    struct.pack_into("<I", dos, 0x3C, 0x80)
+    machine = 0x8664 if is_64 else 0x14C
+    characteristics = 0x2022 if is_64 else 0x2102
+    file_header = b"PE\0\0" + struct.pack("<HHIIIHH", machine, 2, 0, 0, 0, optional_len,
+        characteristics)
+
+    optional = bytearray(optional_len)
+    struct.pack_into("<HBBIII", optional, 0, 0x20B if is_64 else 0x10B, 14, 0,
+        FILE_ALIGNMENT, align(len(rdata.data), FILE_ALIGNMENT), 0)
+    struct.pack_into("<II", optional, 16, 0, TEXT_RVA)
+    if is_64:
+        struct.pack_into("<Q", optional, 24, 0x1_8000_0000)
+    else:
+        struct.pack_into("<II", optional, 24, RDATA_RVA, 0x1000_0000)
+    struct.pack_into("<IIHHHHHHIIII", optional, 32, SECTION_ALIGNMENT, FILE_ALIGNMENT,
+        6, 0, 0, 0, 6, 0, 0, size_of_image, headers_len, 0)
+    struct.pack_into("<HH", optional, 68, 3, 0x0160 if is_64 else 0x0140)
+    stack = "<QQQQII" if is_64 else "<IIIIII"
+    struct.pack_into(stack, optional, 72, 0x100000, 0x1000, 0x100000, 0x1000, 0,
+        len(directories))
+    directory_start = 112 if is_64 else 96
+    for index, (rva, size) in enumerate(directories):
+        struct.pack_into("<II", optional, directory_start + 8 * index, rva, size)
+
+    sections = b"".join([
+        struct.pack("<8sIIIIIIHHI", b".text", len(text), TEXT_RVA,
+            align(len(text), FILE_ALIGNMENT), text_raw, 0, 0, 0, 0, 0x6000_0020),
+        struct.pack("<8sIIIIIIHHI", b".rdata", len(rdata.data), RDATA_RVA,
+            align(len(rdata.data), FILE_ALIGNMENT), rdata_raw, 0, 0, 0, 0, 0x4000_0040),
+    ])
+
+    data = bytearray(dos + file_header + optional + sections)
+    data += bytes(headers_len - len(data))
+    data += text + bytes(rdata_raw - text_raw - len(text))
+    data += rdata.data + bytes(align(len(rdata.data), FILE_ALIGNMENT) - len(rdata.data))
+    return bytes(data)
+
+
+def api_set():
+    """Version 6 schema with a set hosted by `default.dll`, and by
+    `special_host.dll` for `special.dll`, and an extension set without
+    a host."""
+    entries = [
+        ("api-ms-win-test-l1-1-0", [("", "default.dll"), ("special.dll", "special_host.dll")]),
+        ("ext-ms-win-none-l1-1-0", [("", "")]),
+    ]
+    strings = bytearray()
+
+    def string(value):
+        offset = 28 + 24 * len(entries) + 20 * sum(len(values) for _, values in entries)
+        offset += len(strings)
+        strings.extend(value.encode("utf-16-le"))
+        return offset, 2 * len(value)
+
+    entry_data = bytearray()
+    value_data = bytearray()
+    values_start = 28 + 24 * len(entries)
+    for name, values in entries:
+        name_offset, name_len = string(name)
+        hashed_len = 2 * name.rindex("-")
+        value_offset = values_start + len(value_data)
+        entry_data += struct.pack("<6I", 0, name_offset, name_len, hashed_len, value_offset,
+            len(values))
+        for importer, host in values:
+            importer_offset, importer_len = string(importer)
+            host_offset, host_len = string(host)
+            value_data += struct.pack("<5I", 0, importer_offset, importer_len, host_offset,
+                host_len)
+    size = 28 + len(entry_data) + len(value_data) + len(strings)
+    header = struct.pack("<7I", 6, size, 0, len(entries), 28, 0, 0x1F)
+    return header + entry_data + value_data + strings
+
+
+if __name__ == "__main__":
+    for name, data in [("fixture32.dll", image(False)), ("fixture64.dll", image(True)),
+            ("apiset.bin", api_set())]:
+        with open(name, "wb") as file:
+            file.write(data)
@@ -0,0 +1,102 @@
+//! Differential test of the instruction decoder against HDE,
+//! the decoder compiled into the library, see `build.rs`.
+#![cfg(hde64)]
+
+use std::ffi::{c_uint, c_void};
+
+use minhook_ex::decoder::{decode, Bitness, Encoding, Prefixes};
+
+/// `hde64s` as declared in `hde64.h`, which packs it.
+#[repr(C, packed)]
+#[derive(Default)]
+struct Hde64 {
+    len: u8,
+    p_rep: u8,
+    p_lock: u8,
+    p_seg: u8,
+    p_66: u8,
+    p_67: u8,
+    rex: u8,
+    rex_w: u8,
+    rex_r: u8,
+    rex_x: u8,
+    rex_b: u8,
+    opcode: u8,
+    opcode2: u8,
+    modrm: u8,
+    modrm_mod: u8,
+    modrm_reg: u8,
+    modrm_rm: u8,
+    sib: u8,
+    sib_scale: u8,
+    sib_index: u8,
+    sib_base: u8,
+    imm: u64,
+    disp: u32,
+    flags: u32,
+}
+
+const F_ERROR: u32 = 0x0000_1000;
+
+extern "C" {
+    fn hde64_disasm(code: *const c_void, hs: *mut Hde64) -> c_uint;
+}
+
+/// Length of an instruction according to HDE, unless it reports an error.
+fn hde_len(code: &[u8]) -> Option<usize> {
+    let mut hs = Hde64::default();
+    let len = unsafe { hde64_disasm(code.as_ptr().cast(), &mut hs) };
+    (hs.flags & F_ERROR == 0).then_some(len as usize)
+}
+
+const PREFIXES: &[&[u8]] = &[&[], &[0x66], &[0x67], &[0xF3], &[0x48], &[0x66, 0x48], &[0x67, 0x41]];
+const ESCAPES: &[&[u8]] = &[&[], &[0x0F], &[0x0F, 0x38], &[0x0F, 0x3A]];
+/// ModRM bytes followed by the SIB bytes and displacements they select.
+const OPERANDS: &[&[u8]] = &[
+    &[0xC1],
+    &[0x00],
+    &[0x45, 0x10],
+    &[0x85, 0x10, 0x20, 0x30, 0x40],
+    &[0x05, 0x10, 0x20, 0x30, 0x40],
+    &[0x44, 0x24, 0x08],
+    &[0x04, 0x25, 0x10, 0x20, 0x30, 0x40],
+    &[0xF8],
+];
+
+#[test]
+fn lengths_match_hde() {
+    let mut compared = 0;
+    let mut mismatches = Vec::new();
+    for prefix in PREFIXES {
+        for escape in ESCAPES {
+            for opcode in 0..=u8::MAX {
+                for operand in OPERANDS {
+                    // The rest stands in for immediates.
+                    let code = [prefix, escape, &[opcode][..], operand, &[0x11; 16]].concat();
+                    let decoded = decode(&code, Bitness::Bits64);
+                    let (Ok(instruction), Some(len)) = (decoded, hde_len(&code)) else {
+                        continue;
+                    };
+                    // HDE knows nothing of VEX, EVEX and XOP, and follows AMD in
+                    // shortening relative branches with an operand size prefix,
+                    // which Intel processors ignore in 64-bit mode.
+                    if instruction.encoding() != Encoding::Legacy
+                        || instruction.relative_branch().is_some()
+                            && instruction.prefixes().contains(Prefixes::OPERAND_SIZE)
+                            && instruction.immediate().is_some_and(|field| field.size() == 4)
+                    {
+                        continue;
+                    }
+                    compared += 1;
+                    if instruction.len() != len {
+                        let end = instruction.len().max(len);
+                        mismatches.push((code[..end].to_vec(), instruction.len(), len));
+                    }
+                }
+            }
+        }
+    }
+    assert!(mismatches.is_empty(),
+        "lengths differ from HDE (code, decoder, HDE): {:02X?}", mismatches);
+    assert!(compared > 10_000, "only {} instructions compared", compared);
+}
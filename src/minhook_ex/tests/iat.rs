@@ -0,0 +1,216 @@
+//! Import and export address table hooks of the PE fixtures generated
+//! by `fixtures/generate.py`, mapped into memory the way the loader would.
+
+use std::ffi::c_void;
+
+use minhook_ex::iat::{EatHook, IatHook};
+use minhook_ex::pe::{ExportAddress, Image, Layout};
+use minhook_ex::{ApiError, ErrorKind, Export, Function};
+
+#[cfg(target_pointer_width = "64")]
+const FIXTURE: &[u8] = include_bytes!("fixtures/fixture64.dll");
+#[cfg(target_pointer_width = "32")]
+const FIXTURE: &[u8] = include_bytes!("fixtures/fixture32.dll");
+
+type Imported = extern "C" fn(u32) -> u32;
+
+extern "C" fn sleep(value: u32) -> u32 {
+    value + 1
+}
+
+extern "C" fn message_box(value: u32) -> u32 {
+    value + 2
+}
+
+extern "C" fn detour(value: u32) -> u32 {
+    value + 100
+}
+
+/// A fixture with its headers and sections at their addresses,
+/// in a buffer aligned for the entries of its tables.
+struct Mapped {
+    memory: Vec<u64>,
+}
+
+impl Mapped {
+    /// Map the fixture, binding its imports of `Sleep` and `MessageBoxW`.
+    fn new() -> Self {
+        let image = Image::parse(FIXTURE, Layout::File).unwrap();
+        let mut mapped = Self { memory: vec![0; image.size_of_image() as usize / 8] };
+        let data = mapped.data_mut();
+        let sections = image.sections().unwrap();
+        let headers_len = sections.iter().map(|section| image.offset(section.rva()).unwrap()).min();
+        let headers_len = headers_len.unwrap();
+        data[..headers_len].copy_from_slice(&FIXTURE[..headers_len]);
+        for section in sections {
+            let rva = section.rva() as usize;
+            data[rva..rva + section.data().len()].copy_from_slice(section.data());
+        }
+        for (module, export, function) in [("kernel32", "Sleep", sleep as Imported),
+            ("user32", "MessageBoxW", message_box as Imported)]
+        {
+            let slot = mapped.import_slot(module, export);
+            unsafe { *slot = function.to_ptr() };
+        }
+        mapped
+    }
+
+    fn data(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.memory.as_ptr().cast(), self.memory.len() * 8) }
+    }
+
+    fn data_mut(&mut self) -> &mut [u8] {
+        let len = self.memory.len() * 8;
+        unsafe { std::slice::from_raw_parts_mut(self.memory.as_mut_ptr().cast(), len) }
+    }
+
+    fn base(&self) -> *const c_void {
+        self.memory.as_ptr().cast()
+    }
+
+    fn image(&self) -> Image<'_> {
+        unsafe { Image::from_base(self.base()) }.unwrap()
+    }
+
+    fn import_slot(&self, module: &str, export: &str) -> *mut *const c_void {
+        let import = self.image().find_import(module, &Export::from(export)).unwrap().unwrap();
+        (self.base() as usize + import.slot() as usize) as *mut *const c_void
+    }
+
+    fn call_import(&self, module: &str, export: &str, value: u32) -> u32 {
+        let function: Imported = unsafe { Imported::from_ptr(*self.import_slot(module, export)) };
+        function(value)
+    }
+
+    fn export_address(&self, export: &str) -> ExportAddress<'_> {
+        self.image().exports().unwrap().unwrap().by_name(export).unwrap().unwrap().address()
+    }
+}
+
+#[test]
+fn import_hooks() {
+    let mapped = Mapped::new();
+    let hook = unsafe { IatHook::new(mapped.base(), "KERNEL32.dll", "Sleep", detour as Imported) }
+        .unwrap();
+    assert!(!hook.is_enabled());
+    assert_eq!((hook.module(), hook.export()), ("KERNEL32.dll", &Export::from("Sleep")));
+    assert_eq!(hook.slot(), mapped.import_slot("kernel32", "Sleep") as *const c_void);
+    assert_eq!(hook.original().to_ptr(), (sleep as Imported).to_ptr());
+    assert_eq!(hook.detour().to_ptr(), (detour as Imported).to_ptr());
+
+    hook.enable().unwrap();
+    assert!(hook.is_enabled());
+    assert_eq!(mapped.call_import("kernel32", "Sleep", 1), 101);
+    // Other imports are left alone.
+    assert_eq!(mapped.call_import("user32", "MessageBoxW", 1), 3);
+    hook.disable().unwrap();
+    assert_eq!(mapped.call_import("kernel32", "Sleep", 1), 2);
+
+    // Dropping an enabled hook restores the import.
+    hook.enable().unwrap();
+    drop(hook);
+    assert_eq!(mapped.call_import("kernel32", "Sleep", 1), 2);
+
+    let hook = unsafe { IatHook::new(mapped.base(), "user32", "MessageBoxW", detour as Imported) }
+        .unwrap();
+    hook.enable().unwrap();
+    assert_eq!(mapped.call_import("user32", "MessageBoxW", 1), 101);
+    assert_eq!(mapped.call_import("kernel32", "Sleep", 1), 2);
+}
+
+#[test]
+fn missing_imports() {
+    let mapped = Mapped::new();
+    let hook = |module, export: Export| unsafe {
+        IatHook::new(mapped.base(), module, export, detour as Imported)
+    };
+    // The ordinal import is found, unbound as it is.
+    assert!(hook("kernel32", Export::Ordinal(7)).is_ok());
+    let missing = [("kernel32", Export::from("MessageBoxW")), ("user32", Export::Ordinal(7)),
+        ("ntdll", Export::from("Sleep"))];
+    for (module, export) in missing {
+        let error = hook(module, export.clone()).unwrap_err();
+        assert!(matches!(&error, ApiError::FunctionNotFound { module: found, export: missing }
+            if found == module && *missing == export), "{:?}", error);
+    }
+}
+
+#[test]
+fn export_hooks() {
+    let mapped = Mapped::new();
+    // A detour within the image, which the table reaches directly.
+    let unnamed = (mapped.base() as usize + 0x1010) as *const c_void;
+    let detour = unsafe { Imported::from_ptr(unnamed) };
+    let hook = unsafe { EatHook::new(mapped.base(), "code", detour) }.unwrap();
+    assert!(!hook.is_enabled());
+    assert_eq!(hook.export(), &Export::from("code"));
+    assert_eq!(hook.original().to_ptr() as usize, mapped.base() as usize + 0x1000);
+    let slot = hook.slot() as usize - mapped.base() as usize;
+    assert_eq!(mapped.data()[slot..slot + 4], 0x1000u32.to_le_bytes());
+
+    hook.enable().unwrap();
+    assert!(hook.is_enabled());
+    assert_eq!(mapped.export_address("code"), ExportAddress::Code(0x1010));
+    hook.disable().unwrap();
+    assert_eq!(mapped.export_address("code"), ExportAddress::Code(0x1000));
+
+    // Dropping an enabled hook restores the export.
+    hook.enable().unwrap();
+    drop(hook);
+    assert_eq!(mapped.export_address("code"), ExportAddress::Code(0x1000));
+}
+
+#[cfg(target_pointer_width = "64")]
+#[test]
+fn export_relays() {
+    let mapped = Mapped::new();
+    let base = mapped.base() as usize;
+    // The detour is out of reach of offsets from the image, at least
+    // when the image is above it, as a buffer on the heap usually is.
+    let far = (detour as Imported).to_ptr() as usize;
+    if far.checked_sub(base).is_some_and(|offset| offset <= u32::MAX as usize) {
+        return;
+    }
+    let hook = unsafe { EatHook::new(mapped.base(), "code", detour as Imported) }.unwrap();
+    hook.enable().unwrap();
+    let ExportAddress::Code(rva) = mapped.export_address("code") else {
+        panic!("export turned into a forwarder");
+    };
+    // The export resolves to a relay above the image, which jumps to the detour.
+    let relay = base + rva as usize;
+    assert!(rva > 0x3000, "relay at {:#X} within the image", rva);
+    let resolved = unsafe { Imported::from_ptr(relay as *const c_void) };
+    assert_eq!(resolved(1), 101);
+
+    drop(hook);
+    assert_eq!(mapped.export_address("code"), ExportAddress::Code(0x1000));
+    #[cfg(target_os = "linux")]
+    assert!(!mapped_at(relay), "relay still mapped after the hook is dropped");
+}
+
+#[test]
+fn unsupported_exports() {
+    let mapped = Mapped::new();
+    let error = unsafe { EatHook::new(mapped.base(), "chain", detour as Imported) }.unwrap_err();
+    let unsupported = matches!(&error, ApiError::Hook(error)
+        if error.kind() == ErrorKind::UnsupportedFunction);
+    assert!(unsupported, "{:?}", error);
+    let error = unsafe { EatHook::new(mapped.base(), Export::Ordinal(8), detour as Imported) }
+        .unwrap_err();
+    let not_found = matches!(&error, ApiError::FunctionNotFound { module, .. }
+        if module == "fixture.dll");
+    assert!(not_found, "{:?}", error);
+}
+
+/// Check whether an address is mapped, as in `/proc/self/maps`.
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+fn mapped_at(address: usize) -> bool {
+    let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+    maps.lines().any(|line| {
+        let range = line.split(' ').next().unwrap();
+        let (start, end) = range.split_once('-').unwrap();
+        let start = usize::from_str_radix(start, 16).unwrap();
+        let end = usize::from_str_radix(end, 16).unwrap();
+        (start..end).contains(&address)
+    })
+}
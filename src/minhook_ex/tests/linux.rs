@@ -0,0 +1,293 @@
+//! Hooks of local functions through the Linux backend.
+#![cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+
+use std::ffi::c_void;
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use minhook_ex::*;
+
+type Function = extern "C" fn(i32) -> i32;
+
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Initialize the library for a single test, which
+/// keeps other tests out, as hooks are process-wide.
+fn initialize() -> (MutexGuard<'static, ()>, MinHook) {
+    let guard = LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+    let minhook = MinHook::initialize(ThreadFreezeMethod::OriginalSnapshot).unwrap();
+    (guard, minhook)
+}
+
+/// Call a function through a pointer the compiler can't see through.
+fn call(function: Function, value: i32) -> i32 {
+    black_box(function)(value)
+}
+
+fn pointer(function: Function) -> *const c_void {
+    function as *const c_void
+}
+
+/// Trampolines called by the detours, one for each identifier.
+static TRAMPOLINES: [AtomicUsize; 3] = [const { AtomicUsize::new(0) }; 3];
+
+fn trampoline(index: usize) -> Function {
+    unsafe { std::mem::transmute::<usize, Function>(TRAMPOLINES[index].load(Ordering::Acquire)) }
+}
+
+/// Create a hook whose trampoline the detour of the same `index` calls.
+unsafe fn create(target: Function, detour: Function, index: usize) -> Result<()> {
+    let trampoline = create_hook(pointer(target), pointer(detour), ident(index))?;
+    TRAMPOLINES[index].store(trampoline as usize, Ordering::Release);
+    Ok(())
+}
+
+fn ident(index: usize) -> HookIdent {
+    HookIdent::new(index as u64 + 1)
+}
+
+extern "C" fn add_100(value: i32) -> i32 {
+    call(trampoline(0), value) + 100
+}
+
+extern "C" fn add_1000(value: i32) -> i32 {
+    call(trampoline(1), value) + 1000
+}
+
+extern "C" fn negate(value: i32) -> i32 {
+    -call(trampoline(2), value)
+}
+
+#[inline(never)]
+extern "C" fn double_plus_one(value: i32) -> i32 {
+    black_box(value) * 2 + 1
+}
+
+#[inline(never)]
+extern "C" fn triple(value: i32) -> i32 {
+    black_box(value) * 3
+}
+
+#[inline(never)]
+extern "C" fn square(value: i32) -> i32 {
+    black_box(value) * black_box(value)
+}
+
+#[inline(never)]
+extern "C" fn subtract_seven(value: i32) -> i32 {
+    black_box(value) - 7
+}
+
+#[inline(never)]
+extern "C" fn shift_left(value: i32) -> i32 {
+    black_box(value) << 4
+}
+
+#[test]
+fn detour_and_trampoline() {
+    let (_guard, _minhook) = initialize();
+    unsafe {
+        create(double_plus_one, add_100, 0).unwrap();
+        assert_eq!(call(double_plus_one, 5), 11);
+        assert_eq!(call(trampoline(0), 5), 11);
+        assert_eq!(registry::is_enabled(pointer(double_plus_one), ident(0)), Some(false));
+
+        enable_hook(pointer(double_plus_one), ident(0)).unwrap();
+        assert_eq!(call(double_plus_one, 5), 111);
+        assert_eq!(call(trampoline(0), 5), 11);
+        assert_eq!(registry::is_enabled(pointer(double_plus_one), ident(0)), Some(true));
+
+        disable_hook(pointer(double_plus_one), ident(0)).unwrap();
+        assert_eq!(call(double_plus_one, 5), 11);
+
+        enable_hook(pointer(double_plus_one), ident(0)).unwrap();
+        remove_hook(pointer(double_plus_one), ident(0)).unwrap();
+        assert_eq!(call(double_plus_one, 5), 11);
+        assert!(registry::get(pointer(double_plus_one), ident(0)).is_none());
+    }
+}
+
+#[test]
+fn idents_chain_in_creation_order() {
+    let (_guard, _minhook) = initialize();
+    let target = pointer(triple);
+    unsafe {
+        create(triple, add_100, 0).unwrap();
+        create(triple, add_1000, 1).unwrap();
+        create(triple, negate, 2).unwrap();
+
+        // Each enabled hook calls the one created before it.
+        enable_hook(target, ident(2)).unwrap();
+        assert_eq!(call(triple, 2), -6);
+        enable_hook(target, ident(0)).unwrap();
+        assert_eq!(call(triple, 2), -106);
+        enable_hook(target, ident(1)).unwrap();
+        assert_eq!(call(triple, 2), -1106);
+
+        // Disabled hooks are skipped, whichever one it is.
+        disable_hook(target, ident(1)).unwrap();
+        assert_eq!(call(triple, 2), -106);
+        disable_hook(target, ident(2)).unwrap();
+        assert_eq!(call(triple, 2), 106);
+        enable_hook(target, ident(1)).unwrap();
+        assert_eq!(call(triple, 2), 1106);
+
+        remove_hook(target, ident(0)).unwrap();
+        assert_eq!(call(triple, 2), 1006);
+        assert_eq!(registry::find(target).len(), 2);
+
+        enable_hooks(ident(2)).unwrap();
+        assert_eq!(call(triple, 2), -1006);
+        disable_hooks(HookIdent::ALL).unwrap();
+        assert_eq!(call(triple, 2), 6);
+        remove_hooks(HookIdent::ALL).unwrap();
+        assert!(registry::find(target).is_empty());
+    }
+}
+
+#[test]
+fn queued_changes() {
+    let (_guard, _minhook) = initialize();
+    let target = pointer(square);
+    unsafe {
+        create(square, add_100, 0).unwrap();
+        create(square, add_1000, 1).unwrap();
+
+        queue_enable_hook(target, ident(0)).unwrap();
+        queue_enable_hook(target, ident(1)).unwrap();
+        assert_eq!(call(square, 3), 9);
+
+        // Only changes of the given identifier are applied.
+        apply_queued(ident(1)).unwrap();
+        assert_eq!(call(square, 3), 1009);
+        apply_queued(HookIdent::ALL).unwrap();
+        assert_eq!(call(square, 3), 1109);
+
+        queue_disable_hook(target, ident(1)).unwrap();
+        assert_eq!(registry::get(target, ident(1)).map(|record| record.is_queued_enabled()),
+            Some(false));
+        apply_queued(HookIdent::ALL).unwrap();
+        assert_eq!(call(square, 3), 109);
+        assert_eq!(registry::is_enabled(target, ident(1)), Some(false));
+
+        remove_disabled_hooks(HookIdent::ALL).unwrap();
+        assert_eq!(registry::find(target).len(), 1);
+        remove_hooks(HookIdent::ALL).unwrap();
+        assert_eq!(call(square, 3), 9);
+    }
+}
+
+#[test]
+fn errors() {
+    static DATA: [u8; 16] = [0x90; 16];
+    let kind = |result: Result<()>| result.unwrap_err().kind();
+
+    let (_guard, minhook) = initialize();
+    let target = pointer(subtract_seven);
+    assert_eq!(MinHook::initialize(ThreadFreezeMethod::OriginalSnapshot).unwrap_err().kind(),
+        ErrorKind::AlreadyInitialized);
+    unsafe {
+        assert_eq!(kind(enable_hook(target, ident(0))), ErrorKind::NotCreated);
+        assert_eq!(kind(queue_enable_hook(target, ident(0))), ErrorKind::NotCreated);
+        assert_eq!(kind(remove_hook(target, ident(0))), ErrorKind::NotCreated);
+
+        create(subtract_seven, add_100, 0).unwrap();
+        assert_eq!(kind(create(subtract_seven, add_100, 0)), ErrorKind::AlreadyCreated);
+        assert_eq!(kind(disable_hook(target, ident(0))), ErrorKind::HookDisabled);
+        assert_eq!(kind(enable_hook(target, ident(1))), ErrorKind::NotCreated);
+        enable_hook(target, ident(0)).unwrap();
+        assert_eq!(kind(enable_hook(target, ident(0))), ErrorKind::HookEnabled);
+
+        let error = create_hook(DATA.as_ptr().cast(), pointer(add_100), ident(1)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::PointerNotExecutable);
+        assert_eq!(error.operation(), Some(Operation::CreateHook));
+        let error = create_hook(pointer(shift_left), DATA.as_ptr().cast(), ident(1)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::PointerNotExecutable);
+        let error = create_hook(pointer(shift_left), pointer(add_100), HookIdent::ALL).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnsupportedFunction);
+    }
+
+    minhook.uninitialize().unwrap();
+    assert_eq!(call(subtract_seven, 10), 3);
+    assert!(registry::find(target).is_empty());
+    unsafe {
+        let error = create_hook(target, pointer(add_100), ident(0)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotInitialized);
+        assert_eq!(kind(apply_queued(HookIdent::ALL)), ErrorKind::NotInitialized);
+    }
+}
+
+/// Copy `code` into a fresh mapping of two pages at `offset`, as a function.
+#[cfg(target_arch = "x86_64")]
+unsafe fn map_function(code: &[u8], offset: usize) -> (Function, usize) {
+    let page_size = libc::sysconf(libc::_SC_PAGESIZE) as usize;
+    let protection = libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC;
+    let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+    let mapping = libc::mmap(std::ptr::null_mut(), 2 * page_size, protection, flags, -1, 0);
+    assert_ne!(mapping, libc::MAP_FAILED);
+    let address = mapping as usize + offset;
+    std::ptr::copy_nonoverlapping(code.as_ptr(), address as *mut u8, code.len());
+    (std::mem::transmute::<usize, Function>(address), mapping as usize)
+}
+
+/// Get the protection of the mapping containing an address, as in `/proc/self/maps`.
+#[cfg(target_arch = "x86_64")]
+fn protection(address: usize) -> String {
+    let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+    maps.lines().find_map(|line| {
+        let (range, rest) = line.split_once(' ')?;
+        let (start, end) = range.split_once('-')?;
+        let start = usize::from_str_radix(start, 16).ok()?;
+        let end = usize::from_str_radix(end, 16).ok()?;
+        (start..end).contains(&address).then(|| rest[..3].to_owned())
+    }).unwrap()
+}
+
+/// `lea eax, [rdi + rdi + 1]; ret`, the same as [`double_plus_one`].
+#[cfg(target_arch = "x86_64")]
+const LEA_RET: [u8; 5] = [0x8D, 0x44, 0x3F, 0x01, 0xC3];
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+fn patch_across_words_and_pages() {
+    let (_guard, _minhook) = initialize();
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize };
+    unsafe {
+        // The patch crosses both an 8-byte word and a page boundary,
+        // into a page which stays writable while the other one isn't.
+        let (function, mapping) = map_function(&LEA_RET, page_size - 2);
+        create(function, add_100, 0).unwrap();
+        let first = mapping;
+        let second = mapping + page_size;
+        libc::mprotect(first as *mut c_void, page_size, libc::PROT_READ | libc::PROT_EXEC);
+
+        enable_hook(pointer(function), ident(0)).unwrap();
+        assert_eq!(call(function, 4), 109);
+        assert_eq!((protection(first), protection(second)), ("r-x".to_owned(), "rwx".to_owned()));
+
+        disable_hook(pointer(function), ident(0)).unwrap();
+        assert_eq!(call(function, 4), 9);
+        assert_eq!(std::slice::from_raw_parts(function as *const u8, LEA_RET.len()), LEA_RET);
+        assert_eq!((protection(first), protection(second)), ("r-x".to_owned(), "rwx".to_owned()));
+        remove_hook(pointer(function), ident(0)).unwrap();
+    }
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+fn patch_at_end_of_word_is_refused() {
+    let (_guard, _minhook) = initialize();
+    unsafe {
+        let (function, _) = map_function(&LEA_RET, 7);
+        let error = create(function, add_100, 0).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnsupportedFunction);
+
+        let (function, _) = map_function(&LEA_RET, 13);
+        create(function, add_100, 0).unwrap();
+        enable_hook(pointer(function), ident(0)).unwrap();
+        assert_eq!(call(function, 4), 109);
+        remove_hook(pointer(function), ident(0)).unwrap();
+        assert_eq!(call(function, 4), 9);
+    }
+}
@@ -0,0 +1,97 @@
+//! Mid-function hooks of local functions through the Linux backend.
+#![cfg(all(target_os = "linux", target_arch = "x86_64"))]
+
+use std::ffi::c_void;
+use std::hint::black_box;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use minhook_ex::*;
+
+type Function = unsafe extern "C" fn(u64) -> u64;
+
+// Functions with known instructions to hook in the middle of.
+std::arch::global_asm!(
+    ".pushsection .text.midhook_tests,\"ax\",@progbits",
+    ".p2align 4",
+    // Return the argument plus 3.
+    "midhook_add:",
+    "mov rax, rdi",
+    "add rax, 1",
+    "add rax, 2",
+    "ret",
+    ".p2align 4, 0xCC",
+    // Return the argument, passing it through xmm0.
+    "midhook_xmm:",
+    "movq xmm0, rdi",
+    "movq rax, xmm0",
+    "ret",
+    ".p2align 4, 0xCC",
+    ".popsection",
+);
+
+extern "C" {
+    fn midhook_add(value: u64) -> u64;
+    fn midhook_xmm(value: u64) -> u64;
+}
+
+/// Offset of `add rax, 1` in `midhook_add`.
+const ADD_OFFSET: usize = 3;
+/// Offset of `movq rax, xmm0` in `midhook_xmm`.
+const XMM_OFFSET: usize = 5;
+
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Initialize the library for a single test, which
+/// keeps other tests out, as hooks are process-wide.
+fn initialize() -> (MutexGuard<'static, ()>, MinHook) {
+    let guard = LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+    let minhook = MinHook::initialize(ThreadFreezeMethod::OriginalSnapshot).unwrap();
+    (guard, minhook)
+}
+
+/// Call a function through a pointer the compiler can't see through.
+fn call(function: Function, value: u64) -> u64 {
+    unsafe { black_box(function)(value) }
+}
+
+fn instruction(function: Function, offset: usize) -> *const c_void {
+    (function as usize + offset) as *const c_void
+}
+
+#[test]
+fn changing_registers() {
+    let (_guard, minhook) = initialize();
+    let add: Function = midhook_add;
+    for save_xmm in [false, true] {
+        let hook = unsafe {
+            MidHook::new(&minhook, instruction(add, ADD_OFFSET), move |context: &mut Context| {
+                assert_eq!(context.xmm().is_some(), save_xmm);
+                assert_eq!(context.rdi, 5);
+                context.rax += 100;
+            }, save_xmm, HookIdent::DEFAULT).unwrap()
+        };
+        assert_eq!(call(add, 5), 8);
+        hook.enable().unwrap();
+        assert_eq!(call(add, 5), 108, "save_xmm: {}", save_xmm);
+        hook.disable().unwrap();
+        assert_eq!(call(add, 5), 8);
+    }
+}
+
+#[test]
+fn changing_xmm_registers() {
+    let (_guard, minhook) = initialize();
+    let xmm: Function = midhook_xmm;
+    let hook = unsafe {
+        MidHook::new(&minhook, instruction(xmm, XMM_OFFSET), |context: &mut Context| {
+            let registers = context.xmm().unwrap();
+            assert_eq!(registers[0], 5);
+            registers[0] = 42;
+        }, true, HookIdent::DEFAULT).unwrap()
+    };
+    assert_eq!(call(xmm, 5), 5);
+    hook.enable().unwrap();
+    assert_eq!(call(xmm, 5), 42);
+    drop(hook);
+    assert_eq!(call(xmm, 5), 5);
+}
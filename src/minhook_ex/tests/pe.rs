@@ -0,0 +1,196 @@
+//! Parsing of the PE fixtures generated by `fixtures/generate.py`.
+
+use minhook_ex::pe::{ExportAddress, Image, ImportedName, Layout, PeError};
+use minhook_ex::Export;
+
+const FIXTURE_32: &[u8] = include_bytes!("fixtures/fixture32.dll");
+const FIXTURE_64: &[u8] = include_bytes!("fixtures/fixture64.dll");
+
+/// Offset of the export directory in the fixtures once mapped.
+const EXPORT_DIRECTORY: usize = 0x2000;
+
+/// Place the headers and sections of an image at their addresses, as the loader would.
+fn map(file: &[u8]) -> Vec<u8> {
+    let image = Image::parse(file, Layout::File).unwrap();
+    let mut mapped = vec![0; image.size_of_image() as usize];
+    let sections = image.sections().unwrap();
+    let headers_len = sections.iter().map(|section| image.offset(section.rva()).unwrap()).min();
+    let headers_len = headers_len.unwrap();
+    mapped[..headers_len].copy_from_slice(&file[..headers_len]);
+    for section in sections {
+        let rva = section.rva() as usize;
+        mapped[rva..rva + section.data().len()].copy_from_slice(section.data());
+    }
+    mapped
+}
+
+/// Parse both fixtures in both layouts.
+fn for_each_image(test: impl Fn(Image, bool)) {
+    for (file, is_64) in [(FIXTURE_32, false), (FIXTURE_64, true)] {
+        let mapped = map(file);
+        test(Image::parse(file, Layout::File).unwrap(), is_64);
+        test(Image::parse(&mapped, Layout::Mapped).unwrap(), is_64);
+    }
+}
+
+#[test]
+fn headers() {
+    for_each_image(|image, is_64| {
+        assert_eq!(image.is_64(), is_64);
+        assert_eq!(image.machine(), if is_64 { 0x8664 } else { 0x14C });
+        assert_eq!(image.image_base(), if is_64 { 0x1_8000_0000 } else { 0x1000_0000 });
+        assert_eq!(image.pointer_len(), if is_64 { 8 } else { 4 });
+        assert_eq!(image.size_of_image(), 0x3000);
+
+        let sections = image.sections().unwrap();
+        let names: Vec<_> = sections.iter().map(|section| section.name()).collect();
+        assert_eq!(names, [".text", ".rdata"]);
+        assert!(sections[0].is_executable() && !sections[1].is_executable());
+        assert_eq!(sections[0].rva(), 0x1000);
+        assert_eq!(&sections[0].data()[..2], [0xC3, 0xCC]);
+        assert_eq!(image.bytes(0x1010, 1).unwrap(), [0xC3]);
+    });
+}
+
+#[test]
+fn wrong_layout() {
+    // Sections are stored at lower offsets than their addresses.
+    let image = Image::parse(FIXTURE_64, Layout::Mapped).unwrap();
+    assert!(image.exports().is_err());
+    let mapped = map(FIXTURE_64);
+    let image = Image::parse(&mapped, Layout::File).unwrap();
+    let module = image.exports().ok().flatten().map(|exports| exports.module());
+    assert_ne!(module, Some("fixture.dll"));
+}
+
+#[test]
+fn invalid_headers() {
+    assert_eq!(Image::parse(&FIXTURE_32[..0x40], Layout::File).unwrap_err(),
+        PeError::Truncated { offset: 0x80 });
+    let mut data = FIXTURE_32.to_vec();
+    data[0x80] = b'X';
+    assert_eq!(Image::parse(&data, Layout::File).unwrap_err(), PeError::InvalidSignature);
+    let mut data = FIXTURE_32.to_vec();
+    data[0x98..0x9A].copy_from_slice(&0x107u16.to_le_bytes());
+    assert_eq!(Image::parse(&data, Layout::File).unwrap_err(),
+        PeError::UnsupportedMagic { magic: 0x107 });
+}
+
+#[test]
+fn imports() {
+    for_each_image(|image, _| {
+        let imports = image.imports().unwrap();
+        let symbols: Vec<_> = imports.iter()
+            .map(|import| (import.module(), import.symbol()))
+            .collect();
+        assert_eq!(symbols, [
+            ("kernel32.dll", ImportedName::Name { hint: 3, name: "Sleep" }),
+            ("kernel32.dll", ImportedName::Ordinal(7)),
+            ("user32.dll", ImportedName::Name { hint: 0, name: "MessageBoxW" }),
+        ]);
+        // Slots of a module follow each other in its import address table.
+        let pointer_len = image.pointer_len() as u32;
+        assert_eq!(imports[1].slot(), imports[0].slot() + pointer_len);
+
+        let sleep = image.find_import("KERNEL32", &Export::from("Sleep")).unwrap().unwrap();
+        assert_eq!(sleep, imports[0]);
+        let ordinal = image.find_import("kernel32.dll", &Export::Ordinal(7)).unwrap().unwrap();
+        assert_eq!(ordinal, imports[1]);
+        assert_eq!(image.find_import("user32", &Export::from("Sleep")).unwrap(), None);
+        assert_eq!(image.find_import("kernel32", &Export::Ordinal(3)).unwrap(), None);
+
+        let thunk = image.bytes(sleep.slot(), image.pointer_len()).unwrap();
+        assert!(thunk.iter().any(|&byte| byte != 0));
+    });
+}
+
+#[test]
+fn exports() {
+    for_each_image(|image, _| {
+        let exports = image.exports().unwrap().unwrap();
+        assert_eq!(exports.module(), "fixture.dll");
+        assert_eq!(exports.ordinal_base(), 5);
+        assert_eq!(exports.len(), 8);
+
+        let code = exports.by_name("code").unwrap().unwrap();
+        assert_eq!((code.ordinal(), code.name()), (5, Some("code")));
+        assert_eq!(code.address(), ExportAddress::Code(0x1000));
+        assert_eq!(image.bytes(code.slot(), 4).unwrap(), 0x1000u32.to_le_bytes());
+
+        let unnamed = exports.by_ordinal(6).unwrap().unwrap();
+        assert_eq!((unnamed.name(), unnamed.address()), (None, ExportAddress::Code(0x1010)));
+        assert_eq!(exports.find(&Export::Ordinal(5)).unwrap().unwrap().address(), code.address());
+        assert_eq!(exports.find(&Export::from("code")).unwrap(), Some(code));
+
+        // Ordinals below the base, past the table, or of empty entries.
+        assert_eq!(exports.by_ordinal(4).unwrap(), None);
+        assert_eq!(exports.by_ordinal(8).unwrap(), None);
+        assert_eq!(exports.by_ordinal(13).unwrap(), None);
+        assert_eq!(exports.by_name("missing").unwrap(), None);
+    });
+}
+
+#[test]
+fn forwarders() {
+    for_each_image(|image, _| {
+        let exports = image.exports().unwrap().unwrap();
+        let chain = exports.by_name("chain").unwrap().unwrap();
+        assert_eq!(chain.address(), ExportAddress::Forwarder("HOP.step"));
+        let by_ordinal = exports.by_ordinal(11).unwrap().unwrap();
+        assert_eq!(by_ordinal.name(), None);
+        assert_eq!(by_ordinal.address(), ExportAddress::Forwarder("HOP.#6"));
+    });
+}
+
+#[test]
+fn symbols() {
+    for_each_image(|image, _| {
+        let exports = image.exports().unwrap().unwrap();
+        let symbols: Vec<_> = exports.symbols().unwrap().iter()
+            .map(|symbol| (symbol.ordinal(), symbol.name(), symbol.address()))
+            .collect();
+        assert_eq!(symbols, [
+            (5, Some("code"), ExportAddress::Code(0x1000)),
+            (6, None, ExportAddress::Code(0x1010)),
+            (7, Some("chain"), ExportAddress::Forwarder("HOP.step")),
+            (9, Some("step"), ExportAddress::Forwarder("HOP.code")),
+            (10, Some("loop"), ExportAddress::Forwarder("HOP.loop")),
+            (11, Some("by_ordinal"), ExportAddress::Forwarder("HOP.#6")),
+            (12, Some("api"), ExportAddress::Forwarder("api-ms-win-test-l1-1-0.code")),
+        ]);
+    });
+}
+
+#[test]
+fn overflowing_tables() {
+    let mut mapped = map(FIXTURE_64);
+    let set = |mapped: &mut Vec<u8>, offset: usize, value: u32| {
+        mapped[EXPORT_DIRECTORY + offset..][..4].copy_from_slice(&value.to_le_bytes());
+    };
+
+    // The address of the last function wraps around.
+    set(&mut mapped, 28, 0xFFFF_FFF0);
+    let image = Image::parse(&mapped, Layout::Mapped).unwrap();
+    let exports = image.exports().unwrap().unwrap();
+    assert_eq!(exports.by_ordinal(12).unwrap_err(), PeError::InvalidRva { rva: 0xFFFF_FFF0 });
+
+    // So does the ordinal of any function past the first.
+    let mut mapped = map(FIXTURE_64);
+    set(&mut mapped, 16, u32::MAX);
+    let image = Image::parse(&mapped, Layout::Mapped).unwrap();
+    let exports = image.exports().unwrap().unwrap();
+    assert_eq!(exports.by_name("chain").unwrap_err(), PeError::InvalidRva { rva: 0x2028 });
+
+    // A huge function count is only read as far as the image goes.
+    let mut mapped = map(FIXTURE_64);
+    set(&mut mapped, 20, u32::MAX);
+    let image = Image::parse(&mapped, Layout::Mapped).unwrap();
+    let exports = image.exports().unwrap().unwrap();
+    assert!(matches!(exports.symbols(), Err(PeError::Truncated { .. })));
+
+    // Lengths whose end wraps around.
+    let image = Image::parse(&mapped, Layout::Mapped).unwrap();
+    assert_eq!(image.bytes(0x1000, usize::MAX).unwrap_err(), PeError::Truncated { offset: 0x1000 });
+    let image = Image::parse(FIXTURE_64, Layout::File).unwrap();
+    assert_eq!(image.bytes(0x1000, usize::MAX).unwrap_err(), PeError::Truncated { offset: 0x200 });
+}
@@ -0,0 +1,125 @@
+//! Resolution of exports of the PE fixtures generated by `fixtures/generate.py`.
+
+use minhook_ex::pe::{Image, Layout, PeError};
+use minhook_ex::resolver::{ApiSetSchema, Resolved, Resolver};
+use minhook_ex::{ApiError, Export};
+
+const FIXTURE: &[u8] = include_bytes!("fixtures/fixture64.dll");
+const API_SET: &[u8] = include_bytes!("fixtures/apiset.bin");
+
+/// Names the fixture is added under, and the bases it is "mapped" at.
+/// Its forwarders lead to `HOP`, and its API set to the other modules.
+const MODULES: [(&str, usize); 5] = [
+    ("fixture.dll", 0x10_0000),
+    ("hop.dll", 0x20_0000),
+    ("default.dll", 0x30_0000),
+    ("special.dll", 0x40_0000),
+    ("special_host.dll", 0x50_0000),
+];
+
+fn modules(api_set: bool) -> Resolver<'static> {
+    let mut resolver = Resolver::new();
+    for (name, base) in MODULES {
+        resolver.add_module(name, base, Image::parse(FIXTURE, Layout::File).unwrap());
+    }
+    if api_set {
+        resolver.set_api_set(ApiSetSchema::parse(API_SET).unwrap());
+    }
+    resolver
+}
+
+/// Get the module, export, address and number of forwards of a resolved export.
+fn parts(resolved: &Resolved) -> (&str, &Export, usize, usize) {
+    let address = resolved.address() as usize;
+    (resolved.module(), resolved.export(), address, resolved.forwards())
+}
+
+#[test]
+fn names_and_ordinals() {
+    let resolver = modules(false);
+    let code = Export::from("code");
+    let resolved = resolver.resolve("FIXTURE", &code).unwrap();
+    assert_eq!(parts(&resolved), ("fixture.dll", &code, 0x10_1000, 0));
+    let unnamed = Export::Ordinal(6);
+    let resolved = resolver.resolve("hop.dll", &unnamed).unwrap();
+    assert_eq!(parts(&resolved), ("hop.dll", &unnamed, 0x20_1010, 0));
+
+    let missing = resolver.resolve("fixture", &Export::Ordinal(8)).unwrap_err();
+    assert!(matches!(missing, ApiError::FunctionNotFound { export: Export::Ordinal(8), .. }));
+    let missing = resolver.resolve("fixture", &Export::from("missing")).unwrap_err();
+    assert!(matches!(missing, ApiError::FunctionNotFound { .. }));
+    let missing = resolver.resolve("missing", &code).unwrap_err();
+    assert!(matches!(missing, ApiError::ModuleNotFound { module } if module == "missing"));
+}
+
+#[test]
+fn forwarder_chains() {
+    let resolver = modules(false);
+    // chain -> HOP.step -> HOP.code
+    let resolved = resolver.resolve("fixture", &Export::from("chain")).unwrap();
+    assert_eq!(parts(&resolved), ("hop.dll", &Export::from("code"), 0x20_1000, 2));
+    // by_ordinal -> HOP.#6
+    let resolved = resolver.resolve("fixture", &Export::from("by_ordinal")).unwrap();
+    assert_eq!(parts(&resolved), ("hop.dll", &Export::Ordinal(6), 0x20_1010, 1));
+}
+
+#[test]
+fn forwarder_loops() {
+    // loop -> HOP.loop -> HOP.loop ...
+    let resolver = modules(false);
+    let error = resolver.resolve("fixture", &Export::from("loop")).unwrap_err();
+    assert!(matches!(error, ApiError::InvalidForwarder { forwarder } if forwarder == "HOP.loop"));
+}
+
+#[test]
+fn api_sets() {
+    let resolver = modules(true);
+    let code = Export::from("code");
+    for name in ["api-ms-win-test-l1-1-0", "API-MS-WIN-TEST-L1-1-1.dll"] {
+        let resolved = resolver.resolve(name, &code).unwrap();
+        assert_eq!(parts(&resolved), ("default.dll", &code, 0x30_1000, 0));
+    }
+    // api -> api-ms-win-test-l1-1-0.code, whose host depends on the importer.
+    let resolved = resolver.resolve("fixture", &Export::from("api")).unwrap();
+    assert_eq!(parts(&resolved), ("default.dll", &code, 0x30_1000, 1));
+    let resolved = resolver.resolve("special", &Export::from("api")).unwrap();
+    assert_eq!(parts(&resolved), ("special_host.dll", &code, 0x50_1000, 1));
+
+    for name in ["ext-ms-win-none-l1-1-0", "api-ms-win-unknown-l1-1-0"] {
+        let error = resolver.resolve(name, &code).unwrap_err();
+        assert!(matches!(error, ApiError::ModuleNotFound { module } if module == name));
+    }
+    // Without a schema, API sets are looked up as modules.
+    let error = modules(false).resolve("api-ms-win-test-l1-1-0", &code).unwrap_err();
+    assert!(matches!(error, ApiError::ModuleNotFound { .. }));
+}
+
+#[test]
+fn api_set_schema() {
+    let schema = ApiSetSchema::parse(API_SET).unwrap();
+    let host = |name, importer| schema.resolve(name, importer).unwrap();
+    assert_eq!(host("api-ms-win-test-l1-1-0", None).as_deref(), Some("default.dll"));
+    assert_eq!(host("api-ms-win-test-l1-1-0", Some("other")).as_deref(), Some("default.dll"));
+    assert_eq!(host("api-ms-win-test-l1-1-0", Some("SPECIAL")).as_deref(),
+        Some("special_host.dll"));
+    assert_eq!(host("ext-ms-win-none-l1-1-0", None), None);
+    assert_eq!(host("api-ms-win-test-l2-1-0", None), None);
+    assert_eq!(host("noversion", None), None);
+
+    let mut data = API_SET.to_vec();
+    data[0] = 5;
+    assert_eq!(ApiSetSchema::parse(&data).unwrap_err(),
+        PeError::UnsupportedApiSetVersion { version: 5 });
+
+    // Entries claimed past the end of the schema.
+    let mut data = API_SET.to_vec();
+    data[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+    let schema = ApiSetSchema::parse(&data).unwrap();
+    let error = schema.resolve("api-ms-win-other-l1-1-0", None).unwrap_err();
+    assert!(matches!(error, PeError::Truncated { .. }));
+    let mut data = API_SET.to_vec();
+    data[16..20].copy_from_slice(&u32::MAX.to_le_bytes());
+    let schema = ApiSetSchema::parse(&data).unwrap();
+    let error = schema.resolve("api-ms-win-test-l1-1-0", None).unwrap_err();
+    assert!(matches!(error, PeError::Truncated { .. }));
+}
@@ -1,12 +1,83 @@
-fn main() {
-    use std::env;
-    let hde_source_file: &'static str = {
-        let arch = env::var("CARGO_CFG_TARGET_ARCH");
-        match arch.unwrap().as_str() {
-            "x86" => "minhook/src/hde/hde32.c",
-            "x86_64" => "minhook/src/hde/hde64.c",
-            _ => panic!("unsupported target arch"),
+use std::fmt::Write;
+
+/// Extract names and values of a C enumeration declared in `header`.
+fn parse_enum(header: &str, name: &str) -> Vec<(String, i64)> {
+    let declaration = format!("enum {}", name);
+    let start = header.find(&declaration)
+        .unwrap_or_else(|| panic!("enum {} not found in MinHook.h", name));
+    let body = &header[start..];
+    let body = &body[body.find('{').unwrap() + 1..body.find('}').unwrap()];
+
+    let mut entries = Vec::new();
+    let mut next_value = 0;
+    for line in body.lines() {
+        let line = line.split("//").next().unwrap().trim();
+        for entry in line.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (entry_name, value) = match entry.split_once('=') {
+                Some((entry_name, value)) => {
+                    let value = value.trim().parse()
+                        .unwrap_or_else(|_| panic!("unsupported value of {}", entry_name));
+                    (entry_name.trim(), value)
+                }
+                None => (entry, next_value),
+            };
+            entries.push((entry_name.to_owned(), value));
+            next_value = value + 1;
+        }
+    }
+    entries
+}
+
+/// Write enumeration values from `MinHook.h` into a Rust module, so the
+/// bindings can check their constants against them at compile time.
+fn generate_header_values(header_path: &str) {
+    use std::{env, fs, path::Path};
+
+    let header = fs::read_to_string(header_path).expect("failed to read MinHook.h");
+    let mut output = String::new();
+    for (module, name) in [("status", "MH_STATUS"), ("thread_freeze_method", "MH_THREAD_FREEZE_METHOD")] {
+        writeln!(output, "pub mod {} {{", module).unwrap();
+        for (entry_name, value) in parse_enum(&header, name) {
+            writeln!(output, "    pub const {}: std::ffi::c_int = {};", entry_name, value).unwrap();
         }
+        writeln!(output, "}}").unwrap();
+    }
+
+    let out_dir = env::var("OUT_DIR").unwrap();
+    fs::write(Path::new(&out_dir).join("header.rs"), output).unwrap();
+}
+
+fn main() {
+    use std::{env, path::Path};
+    const HEADER_PATH: &str = "minhook/include/MinHook.h";
+
+    // Check the bindings against the header on every platform, not just
+    // those that build the library, as long as the sources are present.
+    // Set `MINHOOK_REQUIRE_HEADER` to fail rather than skip the check.
+    println!("cargo:rustc-check-cfg=cfg(minhook_header)");
+    println!("cargo:rerun-if-changed={}", HEADER_PATH);
+    println!("cargo:rerun-if-env-changed=MINHOOK_REQUIRE_HEADER");
+    if Path::new(HEADER_PATH).exists() {
+        generate_header_values(HEADER_PATH);
+        println!("cargo:rustc-cfg=minhook_header");
+    } else if env::var_os("MINHOOK_REQUIRE_HEADER").is_some() {
+        panic!("{} not found, check out the minhook submodule", HEADER_PATH);
+    } else {
+        println!("cargo:warning={} not found, the bindings are not checked against it",
+            HEADER_PATH);
+    }
+
+    // The library hooks through the Windows API, while other
+    // platforms are served by a backend in the safe wrapper.
+    if env::var("CARGO_CFG_TARGET_OS").unwrap() != "windows" {
+        return;
+    }
+
+    let arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap();
+    let hde_source_file: &'static str = match arch.as_str() {
+        "x86" => "minhook/src/hde/hde32.c",
+        "x86_64" => "minhook/src/hde/hde64.c",
+        _ => panic!("MinHook only supports x86 and x86-64 Windows targets, not {}", arch),
     };
 
     let includes = [
@@ -21,8 +92,21 @@ fn main() {
         hde_source_file,
     ];
 
-    cc::Build::new()
-        .includes(includes)
-        .files(sources)
-        .compile("minhook");
+    let mut build = cc::Build::new();
+    build.includes(includes).files(sources);
+
+    // MinGW-w64, either native or cross-compiling from another host, which
+    // `cc` finds as `x86_64-w64-mingw32-gcc` or `i686-w64-mingw32-gcc`.
+    if env::var("CARGO_CFG_TARGET_ENV").unwrap() == "gnu" {
+        // Its headers hide declarations newer than their default Windows
+        // version, so select Vista as the fast freeze method requires.
+        build.define("_WIN32_WINNT", "0x0600")
+            .define("WIN32_LEAN_AND_MEAN", None)
+            // Keep the archive free of references to libssp, which
+            // isn't linked into Rust artifacts.
+            .flag_if_supported("-fno-stack-protector");
+    }
+
+    // Either way, the archive is linked statically, see the bindings.
+    build.compile("minhook");
 }
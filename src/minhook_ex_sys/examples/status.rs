@@ -1,6 +1,9 @@
+#[cfg(windows)]
 use std::ffi::CStr;
+#[cfg(windows)]
 use minhook_ex_sys::*;
 
+#[cfg(windows)]
 #[inline]
 fn get_status_name(status: MH_STATUS) -> &'static str {
     let cstr = unsafe {
@@ -10,6 +13,7 @@ fn get_status_name(status: MH_STATUS) -> &'static str {
     cstr.to_str().expect("failed to convert returned bytes to a utf-8 slice")
 }
 
+#[cfg(windows)]
 fn main() {
     let statuses = [
         MH_STATUS::MH_UNKNOWN,
@@ -33,3 +37,8 @@ fn main() {
             status, get_status_name(status));
     }
 }
+
+#[cfg(not(windows))]
+fn main() {
+    eprintln!("The MinHook library is only built for Windows.");
+}
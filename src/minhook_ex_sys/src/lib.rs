@@ -1,63 +1,133 @@
 #![allow(dead_code)]
 #![allow(unsafe_code)]
+// Argument lists continue their items with four spaces.
+#![allow(clippy::doc_overindented_list_items)]
 #![allow(non_camel_case_types)]
 
-use std::ffi::{c_char, c_short, c_ulonglong, c_void};
+use std::ffi::{c_char, c_int, c_short, c_ulonglong, c_void};
 
 /// MinHook error codes.
-#[repr(C)]
-#[derive(Clone, Copy, Debug)]
-pub enum MH_STATUS {
+///
+/// Represented as a plain integer rather than a Rust enum, so that codes
+/// unknown to these bindings can't cause undefined behaviour when returned.
+#[repr(transparent)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MH_STATUS(pub c_int);
+
+impl MH_STATUS {
     /// Unknown error. Should not be returned.
-    MH_UNKNOWN = -1,
+    pub const MH_UNKNOWN: Self = Self(-1);
     /// Successful.
-    MH_OK = 0,
+    pub const MH_OK: Self = Self(0);
     /// MinHook is already initialized.
-    MH_ERROR_ALREADY_INITIALIZED,
+    pub const MH_ERROR_ALREADY_INITIALIZED: Self = Self(1);
     /// MinHook is not initialized yet, or already uninitialized.
-    MH_ERROR_NOT_INITIALIZED,
+    pub const MH_ERROR_NOT_INITIALIZED: Self = Self(2);
     /// The hook for the specified target function is already created.
-    MH_ERROR_ALREADY_CREATED,
+    pub const MH_ERROR_ALREADY_CREATED: Self = Self(3);
     /// The hook for the specified target function is not created yet.
-    MH_ERROR_NOT_CREATED,
+    pub const MH_ERROR_NOT_CREATED: Self = Self(4);
     /// The hook for the specified target function is already enabled.
-    MH_ERROR_ENABLED,
+    pub const MH_ERROR_ENABLED: Self = Self(5);
     /// The hook for the specified target function is not enabled yet,
     /// or already disabled.
-    MH_ERROR_DISABLED,
+    pub const MH_ERROR_DISABLED: Self = Self(6);
     /// The specified pointer is invalid. It points the address of
     /// non-allocated and/or non-executable region.
-    MH_ERROR_NOT_EXECUTABLE,
+    pub const MH_ERROR_NOT_EXECUTABLE: Self = Self(7);
     /// The specified target function cannot be hooked.
-    MH_ERROR_UNSUPPORTED_FUNCTION,
+    pub const MH_ERROR_UNSUPPORTED_FUNCTION: Self = Self(8);
     /// Failed to allocate memory.
-    MH_ERROR_MEMORY_ALLOC,
+    pub const MH_ERROR_MEMORY_ALLOC: Self = Self(9);
     /// Failed to change the memory protection.
-    MH_ERROR_MEMORY_PROTECT,
+    pub const MH_ERROR_MEMORY_PROTECT: Self = Self(10);
     /// The specified module is not loaded.
-    MH_ERROR_MODULE_NOT_FOUND,
+    pub const MH_ERROR_MODULE_NOT_FOUND: Self = Self(11);
     /// The specified function is not found.
-    MH_ERROR_FUNCTION_NOT_FOUND,
+    pub const MH_ERROR_FUNCTION_NOT_FOUND: Self = Self(12);
     /// Failed to create, or to wait for the main mutex.
-    MH_ERROR_MUTEX_FAILURE,
+    pub const MH_ERROR_MUTEX_FAILURE: Self = Self(13);
+
+    /// Get the name of a status known to these bindings.
+    pub const fn name(self) -> Option<&'static str> {
+        Some(match self {
+            Self::MH_UNKNOWN => "MH_UNKNOWN",
+            Self::MH_OK => "MH_OK",
+            Self::MH_ERROR_ALREADY_INITIALIZED => "MH_ERROR_ALREADY_INITIALIZED",
+            Self::MH_ERROR_NOT_INITIALIZED => "MH_ERROR_NOT_INITIALIZED",
+            Self::MH_ERROR_ALREADY_CREATED => "MH_ERROR_ALREADY_CREATED",
+            Self::MH_ERROR_NOT_CREATED => "MH_ERROR_NOT_CREATED",
+            Self::MH_ERROR_ENABLED => "MH_ERROR_ENABLED",
+            Self::MH_ERROR_DISABLED => "MH_ERROR_DISABLED",
+            Self::MH_ERROR_NOT_EXECUTABLE => "MH_ERROR_NOT_EXECUTABLE",
+            Self::MH_ERROR_UNSUPPORTED_FUNCTION => "MH_ERROR_UNSUPPORTED_FUNCTION",
+            Self::MH_ERROR_MEMORY_ALLOC => "MH_ERROR_MEMORY_ALLOC",
+            Self::MH_ERROR_MEMORY_PROTECT => "MH_ERROR_MEMORY_PROTECT",
+            Self::MH_ERROR_MODULE_NOT_FOUND => "MH_ERROR_MODULE_NOT_FOUND",
+            Self::MH_ERROR_FUNCTION_NOT_FOUND => "MH_ERROR_FUNCTION_NOT_FOUND",
+            Self::MH_ERROR_MUTEX_FAILURE => "MH_ERROR_MUTEX_FAILURE",
+            _ => return None,
+        })
+    }
+}
+
+impl std::fmt::Debug for MH_STATUS {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "MH_STATUS({})", self.0),
+        }
+    }
 }
 
 /// The method of suspending and resuming threads.
-#[repr(C)]
-pub enum MH_THREAD_FREEZE_METHOD {
+///
+/// Represented as a plain integer for the same reasons as [`MH_STATUS`].
+#[repr(transparent)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MH_THREAD_FREEZE_METHOD(pub c_int);
+
+impl MH_THREAD_FREEZE_METHOD {
     /// The original MinHook method, using CreateToolhelp32Snapshot. Documented
     /// and supported on all Windows versions, but very slow and less reliable.
-    MH_FREEZE_METHOD_ORIGINAL = 0,
+    pub const MH_FREEZE_METHOD_ORIGINAL: Self = Self(0);
     /// A much faster and more reliable, but undocumented method, using
     /// NtGetNextThread. Supported since Windows Vista, on older versions falls
     /// back to MH_ORIGINAL.
-    MH_FREEZE_METHOD_FAST_UNDOCUMENTED,
+    pub const MH_FREEZE_METHOD_FAST_UNDOCUMENTED: Self = Self(1);
     /// Threads are not suspended and instruction pointer registers are not
     /// adjusted. Don't use this method unless you understand the implications
     /// and know that it's safe.
-    MH_FREEZE_METHOD_NONE_UNSAFE
+    pub const MH_FREEZE_METHOD_NONE_UNSAFE: Self = Self(2);
 }
 
+/// Values of the enumerations as declared in the vendored `MinHook.h`,
+/// extracted by the build script wherever the sources are present.
+#[cfg(minhook_header)]
+mod header {
+    include!(concat!(env!("OUT_DIR"), "/header.rs"));
+}
+
+/// Fail to compile if a constant doesn't match its value in the header.
+macro_rules! assert_header_values {
+    ($type:ident in $module:ident: $($name:ident),* $(,)?) => {
+        $(#[cfg(minhook_header)] const _: () = assert!($type::$name.0 == header::$module::$name,
+            concat!("value of ", stringify!($name), " doesn't match MinHook.h"));)*
+    };
+}
+
+assert_header_values!(MH_STATUS in status:
+    MH_UNKNOWN, MH_OK, MH_ERROR_ALREADY_INITIALIZED, MH_ERROR_NOT_INITIALIZED,
+    MH_ERROR_ALREADY_CREATED, MH_ERROR_NOT_CREATED, MH_ERROR_ENABLED, MH_ERROR_DISABLED,
+    MH_ERROR_NOT_EXECUTABLE, MH_ERROR_UNSUPPORTED_FUNCTION, MH_ERROR_MEMORY_ALLOC,
+    MH_ERROR_MEMORY_PROTECT, MH_ERROR_MODULE_NOT_FOUND, MH_ERROR_FUNCTION_NOT_FOUND,
+    MH_ERROR_MUTEX_FAILURE,
+);
+
+assert_header_values!(MH_THREAD_FREEZE_METHOD in thread_freeze_method:
+    MH_FREEZE_METHOD_ORIGINAL, MH_FREEZE_METHOD_FAST_UNDOCUMENTED, MH_FREEZE_METHOD_NONE_UNSAFE,
+);
+
 /// Can be passed as a parameter to [`MH_EnableHook`], [`MH_DisableHook`],
 /// [`MH_QueueEnableHook`] or [`MH_QueueDisableHook`].
 pub const MH_ALL_HOOKS: *const c_void = std::ptr::null::<c_void>();
@@ -65,8 +135,11 @@ pub const MH_ALL_HOOKS: *const c_void = std::ptr::null::<c_void>();
 pub const MH_ALL_IDENTS: c_ulonglong = 0;
 pub const MH_DEFAULT_IDENT: c_ulonglong = 1;
 
-#[link(name = "minhook", kind = "static")]
-extern "C" {
+// The library is only built for Windows, so the
+// functions can't be called on other platforms.
+// Functions are declared `WINAPI`, which is `stdcall` on x86.
+#[cfg_attr(windows, link(name = "minhook", kind = "static"))]
+extern "system" {
 
     /// Initialize the MinHook library. You must call this function **exactly
     /// once** at the beginning of your program.